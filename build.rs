@@ -0,0 +1,16 @@
+fn main() {
+    println!("cargo:rerun-if-changed=tests/ffi_smoke.c");
+    println!("cargo:rerun-if-changed=include/minisql.h");
+    if std::env::var_os("CARGO_FEATURE_FFI").is_none() {
+        return;
+    }
+    // tests/ffi_smoke.rs calls the C program, which calls back into the
+    // library through include/minisql.h
+    cc::Build::new()
+        .file("tests/ffi_smoke.c")
+        .include("include")
+        .cargo_metadata(false)
+        .compile("ffi_smoke");
+    let out_dir = std::env::var("OUT_DIR").unwrap();
+    println!("cargo:rustc-link-arg-tests={}/libffi_smoke.a", out_dir);
+}
@@ -0,0 +1,32 @@
+/* C interface of minisql. All strings are NUL-terminated UTF-8. */
+#ifndef MINISQL_H
+#define MINISQL_H
+
+#include <stdint.h>
+
+#define MINISQL_OK 0
+#define MINISQL_ERROR 1
+#define MINISQL_MISUSE 2
+#define MINISQL_PANIC 3
+#define MINISQL_ABORT 4
+
+typedef struct MinisqlDb MinisqlDb;
+
+/* Called for each result row. A nonzero return stops the statement with MINISQL_ABORT.
+   The strings are valid only during the call. */
+typedef int (*minisql_row_callback)(void *ctx, int64_t id, const char *name, const char *email);
+
+int minisql_open(const char *path, MinisqlDb **out);
+/* Flushes and frees the handle, even when flushing fails. */
+int minisql_close(MinisqlDb *db);
+/* callback and count may be NULL. count receives the result of a count statement, or -1. */
+int minisql_exec(MinisqlDb *db, const char *statement, minisql_row_callback callback, void *ctx,
+                 int64_t *count);
+/* Message of the last failed call on this thread, empty after a success. */
+const char *minisql_errmsg(void);
+/* Code of the database error behind the last failed call on this thread, such as
+   "MINISQL_DUP_KEY". Empty after a success and for MINISQL_MISUSE, MINISQL_PANIC and
+   MINISQL_ABORT. */
+const char *minisql_errcode(void);
+
+#endif
@@ -0,0 +1,237 @@
+//! Tokio variant of `Table`. Files are read and written with `tokio::fs` when
+//! opening and closing; in between every page is cached, so node and cursor
+//! code runs synchronously without touching the file.
+//! The journal is still appended synchronously on each mutation, and so is
+//! the WAL the changed pages are committed to before they are written.
+use std::path::Path;
+
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+};
+
+use crate::{
+    commands::{prepare_statement, QueryResult},
+    cursor::Cursor,
+    journal::{journal_path, Journal},
+    pager::{open_error, Pager},
+    sql_error::{SqlError, SqlResult},
+    table::Table,
+    wal::{wal_path, Wal},
+};
+
+/// Loads and flushes the pages of a `Pager` asynchronously
+pub struct AsyncPager {
+    file: File,
+}
+
+impl AsyncPager {
+    /// Open a database and its WAL, write back the pages the WAL committed
+    /// and read all of its pages
+    pub async fn open(filename: &str) -> SqlResult<(Self, Pager, Wal)> {
+        if let Some(parent) = Path::new(filename).parent() {
+            if !parent.as_os_str().is_empty() && !parent.exists() {
+                tokio::fs::create_dir_all(parent)
+                    .await
+                    .map_err(|e| open_error(e, filename))?;
+            }
+        }
+        let mut file = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(filename)
+            .await
+            .map_err(|e| open_error(e, filename))?;
+        let std_file = file
+            .try_clone()
+            .await
+            .map_err(|e| open_error(e, filename))?
+            .into_std()
+            .await;
+        let mut pager = Pager::from_file(std_file)?;
+        let mut wal = Wal::open(&wal_path(filename), pager.page_size)?;
+        wal.recover(&mut pager)?;
+
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)
+            .await
+            .map_err(|e| SqlError::IOError(e, "Failed to read".to_string()))?;
+        pager.load_pages(&data)?;
+        Ok((AsyncPager { file }, pager, wal))
+    }
+
+    /// Write every changed page of `pager` to the file as `Pager::flush_dirty`
+    /// does, awaiting only the writes. A failed write stops the flush, and
+    /// the pages not written stay dirty, the meta page among them.
+    pub async fn flush_all(&mut self, pager: &Pager) -> SqlResult<()> {
+        for (page_num, count) in pager.dirty_runs() {
+            let buf = pager.seal_run(page_num, count)?;
+            self.file
+                .seek(std::io::SeekFrom::Start(
+                    (page_num * pager.page_size) as u64,
+                ))
+                .await
+                .map_err(|e| SqlError::IOError(e, "Failed to seek to write".to_string()))?;
+            self.file
+                .write_all(&buf)
+                .await
+                .map_err(|e| SqlError::IOError(e, "Failed to write".to_string()))?;
+            pager.finish_run(page_num, count);
+        }
+        self.file
+            .sync_data()
+            .await
+            .map_err(|e| SqlError::IOError(e, "Failed to sync".to_string()))
+    }
+}
+
+pub struct AsyncTable {
+    pub table: Table,
+    pager: AsyncPager,
+}
+
+impl AsyncTable {
+    pub async fn open(filename: &str) -> SqlResult<Self> {
+        let (pager, sync_pager, wal) = AsyncPager::open(filename).await?;
+        let journal = Journal::open(&journal_path(filename))?;
+        let table = Table::with_logs(sync_pager, Some(journal), Some(wal))?;
+        Ok(AsyncTable { table, pager })
+    }
+
+    /// Commit the changed pages to the WAL and flush them. A close stopped
+    /// partway, by an error or by dropping it, leaves the table open, and the
+    /// WAL recovers the pages if the process stops there.
+    /// Closing an already closed table does nothing.
+    pub async fn close(&mut self) -> SqlResult<()> {
+        if self.table.pager.closed.get() {
+            return Ok(());
+        }
+        self.table.commit_wal(true)?;
+        self.pager.flush_all(&self.table.pager).await?;
+        if let Some(journal) = &mut self.table.journal {
+            journal.truncate(true)?;
+        }
+        if let Some(wal) = &mut self.table.wal {
+            wal.truncate(true)?;
+        }
+        self.table.release();
+        Ok(())
+    }
+
+    pub async fn find(&mut self, key: i64) -> SqlResult<Cursor<'_>> {
+        self.table.find(key)
+    }
+
+    /// Prepare and execute a statement
+    pub async fn exec(&mut self, statement: &str) -> SqlResult<QueryResult> {
+        prepare_statement(statement)?.execute(&mut self.table)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{future::Future, pin::pin, task::Poll};
+
+    use super::*;
+    use crate::test::clean_db;
+
+    /// Table with `rows` rows closed, then more rows inserted and not flushed
+    async fn changed_table(path: &str, rows: i64) -> AsyncTable {
+        let mut table = AsyncTable::open(path).await.unwrap();
+        for i in 0..rows {
+            let statement = format!("insert {} name{} {}@a", i, i, i);
+            table.exec(&statement).await.unwrap();
+            if i == 10 {
+                table.close().await.unwrap();
+                table = AsyncTable::open(path).await.unwrap();
+            }
+        }
+        table
+    }
+
+    /// Drop the close of `table` once it has waited `polls` times, returning
+    /// whether it finished first
+    async fn interrupt_close(table: &mut AsyncTable, polls: usize) -> bool {
+        let mut close = pin!(table.close());
+        for _ in 0..polls {
+            let poll = std::future::poll_fn(|cx| Poll::Ready(close.as_mut().poll(cx))).await;
+            if let Poll::Ready(result) = poll {
+                result.unwrap();
+                return true;
+            }
+            tokio::task::yield_now().await;
+        }
+        false
+    }
+
+    #[tokio::test]
+    async fn async_reopen() {
+        let path = clean_db("async_reopen");
+
+        let mut table = AsyncTable::open(&path).await.unwrap();
+        // Enough rows to split the root leaf
+        for i in 0..10 {
+            let statement = format!("insert {} name{} {}@a", i, i, i);
+            table.exec(&statement).await.unwrap();
+        }
+        assert!(table.find(9).await.unwrap().check_key(9).unwrap());
+        table.close().await.unwrap();
+        assert!(matches!(
+            table.exec("select 1").await,
+            Err(SqlError::Closed)
+        ));
+        drop(table);
+
+        let mut table = AsyncTable::open(&path).await.unwrap();
+        let rows = table.exec("select").await.unwrap();
+        assert_eq!(rows.len(), 10);
+        assert_eq!(rows[3].name_str(), "name3");
+        assert_eq!(table.exec("count").await.unwrap().count, Some(10));
+        table.close().await.unwrap();
+        drop(table);
+
+        // The sync API reads what the async one wrote
+        let mut table = Table::open(&path).unwrap();
+        assert_eq!(table.get(9).unwrap().unwrap().email_str(), "9@a");
+        table.check().unwrap();
+    }
+
+    #[tokio::test]
+    async fn interrupted_close_recovers() {
+        for polls in [1, 2, 5, 20] {
+            let path = clean_db(&format!("interrupted_close_recovers_{}", polls));
+            let mut table = changed_table(&path, 60).await;
+            if interrupt_close(&mut table, polls).await {
+                continue;
+            }
+            // The process stops once the write in flight lands
+            table.pager.file.flush().await.unwrap();
+            table.table.release();
+            drop(table);
+
+            let mut table = AsyncTable::open(&path).await.unwrap();
+            assert_eq!(table.exec("count").await.unwrap().count, Some(60));
+            table.table.check().unwrap();
+            table.close().await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn interrupted_close_resumes() {
+        let path = clean_db("interrupted_close_resumes");
+        let mut table = changed_table(&path, 60).await;
+        assert!(!interrupt_close(&mut table, 2).await);
+        // The table stays open, and closing again writes what was left
+        assert_eq!(table.exec("count").await.unwrap().count, Some(60));
+        table.close().await.unwrap();
+        drop(table);
+        assert_eq!(std::fs::metadata(wal_path(&path)).unwrap().len(), 0);
+
+        let mut table = Table::open(&path).unwrap();
+        assert_eq!(table.len().unwrap(), 60);
+        assert_eq!(table.get(42).unwrap().unwrap().name_str(), "name42");
+        table.check().unwrap();
+    }
+}
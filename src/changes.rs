@@ -0,0 +1,221 @@
+//! Record of when each page was last written, for incremental backups.
+//! A counter goes up with every flush and every backup, and each page written
+//! is marked with the counter at the time. The pages marked above the counter
+//! a backup returned are the ones written since. A page is marked before it
+//! is written, so a crash can leave a page marked that was not written, which
+//! only makes a delta larger, but never a written page unmarked.
+//!
+//! A delta holds those pages and the meta page. Applied onto the backup taken
+//! at its starting counter, it gives the database as it was when the delta
+//! was taken.
+#[cfg(feature = "fs")]
+use std::io::Read;
+use std::{
+    fs::File,
+    io::{Seek, SeekFrom, Write},
+};
+
+use crate::{
+    crc32::crc32,
+    sql_error::{SqlError, SqlResult},
+};
+#[cfg(feature = "fs")]
+use crate::{
+    meta::{header_page_size, META_NODE_NUM},
+    pager::MIN_PAGE_SIZE,
+    storage::{FileStorage, Storage},
+};
+
+// FILE: {COUNTER, MARK of page 0, MARK of page 1, ...}
+const MARK_SIZE: usize = 8;
+
+// DELTA: {MAGIC, PAGE_SIZE, SINCE, COUNTER, PAGE_COUNT, FRAME..., CHECKSUM}
+// FRAME: {PAGE_NUM, PAGE}
+// The checksum covers everything before it.
+const DELTA_MAGIC: &[u8; 8] = b"MINISQLD";
+const DELTA_HEADER_SIZE: usize = 8 + 4 + 8 + 8 + 8;
+const PAGE_NUM_SIZE: usize = 8;
+const DELTA_CHECKSUM_SIZE: usize = 4;
+
+pub fn changes_path(filename: &str) -> String {
+    format!("{}.changes", filename)
+}
+
+fn io_error(e: std::io::Error, what: &str) -> SqlError {
+    SqlError::IOError(e, what.to_string())
+}
+
+pub struct ChangeMap {
+    file: File,
+    counter: u64,
+    marks: Vec<u64>,
+}
+
+impl ChangeMap {
+    #[cfg(feature = "fs")]
+    pub fn open(path: &str) -> SqlResult<Self> {
+        let mut file = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .map_err(|e| io_error(e, "Failed to open change map"))?;
+        let mut buf = Vec::new();
+        file.read_to_end(&mut buf)
+            .map_err(|e| io_error(e, "Failed to read change map"))?;
+        let mut values = buf
+            .chunks_exact(MARK_SIZE)
+            .map(|chunk| u64::from_le_bytes(chunk.try_into().unwrap()));
+        let counter = values.next().unwrap_or(0);
+        Ok(ChangeMap {
+            file,
+            counter,
+            marks: values.collect(),
+        })
+    }
+
+    /// Forget every mark, for a new database in place of an old one
+    pub fn clear(&mut self) -> SqlResult<()> {
+        self.file
+            .set_len(0)
+            .map_err(|e| io_error(e, "Failed to truncate change map"))?;
+        self.counter = 0;
+        self.marks.clear();
+        Ok(())
+    }
+
+    pub fn counter(&self) -> u64 {
+        self.counter
+    }
+
+    fn write_at(&mut self, index: usize, value: u64) -> SqlResult<()> {
+        self.file
+            .seek(SeekFrom::Start((index * MARK_SIZE) as u64))
+            .map_err(|e| io_error(e, "Failed to seek change map"))?;
+        self.file
+            .write_all(&value.to_le_bytes())
+            .map_err(|e| io_error(e, "Failed to write change map"))
+    }
+
+    /// Move on to the next flush or backup, returning the counter before
+    pub fn bump(&mut self) -> SqlResult<u64> {
+        let before = self.counter;
+        self.write_at(0, before + 1)?;
+        self.counter = before + 1;
+        Ok(before)
+    }
+
+    /// Mark a page about to be written
+    pub fn mark(&mut self, page_num: usize) -> SqlResult<()> {
+        if self.marks.get(page_num) == Some(&self.counter) {
+            return Ok(());
+        }
+        self.write_at(page_num + 1, self.counter)?;
+        if page_num >= self.marks.len() {
+            self.marks.resize(page_num + 1, 0);
+        }
+        self.marks[page_num] = self.counter;
+        Ok(())
+    }
+
+    /// Pages below `num_pages` written after a backup that returned `since`
+    pub fn changed_since(&self, since: u64, num_pages: usize) -> Vec<usize> {
+        (0..num_pages)
+            .filter(|&n| self.marks.get(n).is_some_and(|&mark| mark > since))
+            .collect()
+    }
+
+    pub fn sync(&mut self) -> SqlResult<()> {
+        self.file
+            .sync_data()
+            .map_err(|e| io_error(e, "Failed to sync change map"))
+    }
+}
+
+/// Bytes of a delta of `pages`, each sealed as stored in the database
+pub fn encode_delta(
+    page_size: usize,
+    since: u64,
+    counter: u64,
+    num_pages: usize,
+    pages: &[(usize, Vec<u8>)],
+) -> Vec<u8> {
+    let mut delta = Vec::with_capacity(
+        DELTA_HEADER_SIZE + pages.len() * (PAGE_NUM_SIZE + page_size) + DELTA_CHECKSUM_SIZE,
+    );
+    delta.extend_from_slice(DELTA_MAGIC);
+    delta.extend_from_slice(&(page_size as u32).to_le_bytes());
+    delta.extend_from_slice(&since.to_le_bytes());
+    delta.extend_from_slice(&counter.to_le_bytes());
+    delta.extend_from_slice(&(num_pages as u64).to_le_bytes());
+    for (page_num, buf) in pages {
+        delta.extend_from_slice(&(*page_num as u64).to_le_bytes());
+        delta.extend_from_slice(buf);
+    }
+    let checksum = crc32(0, &delta);
+    delta.extend_from_slice(&checksum.to_le_bytes());
+    delta
+}
+
+/// Write the pages of the delta at `delta_path` onto the backup at
+/// `backup_path` and cut it to the page count of the delta.
+/// Returns the number of pages written.
+#[cfg(feature = "fs")]
+pub fn apply_delta(backup_path: &str, delta_path: &str) -> SqlResult<usize> {
+    let delta = std::fs::read(delta_path)
+        .map_err(|e| SqlError::IOError(e, format!("Failed to read delta: {}", delta_path)))?;
+    if delta.len() < DELTA_HEADER_SIZE + DELTA_CHECKSUM_SIZE {
+        return Err(SqlError::CorruptFile);
+    }
+    if &delta[..8] != DELTA_MAGIC {
+        return Err(SqlError::NotADatabase);
+    }
+    let (body, checksum) = delta.split_at(delta.len() - DELTA_CHECKSUM_SIZE);
+    if crc32(0, body) != u32::from_le_bytes(checksum.try_into().unwrap()) {
+        return Err(SqlError::CorruptFile);
+    }
+    let page_size = u32::from_le_bytes(body[8..12].try_into().unwrap()) as usize;
+    let num_pages = u64::from_le_bytes(body[28..36].try_into().unwrap()) as usize;
+    let frames = &body[DELTA_HEADER_SIZE..];
+    let frame_size = PAGE_NUM_SIZE + page_size;
+    if page_size < MIN_PAGE_SIZE || frames.len() % frame_size != 0 {
+        return Err(SqlError::CorruptFile);
+    }
+
+    let file = File::options()
+        .read(true)
+        .write(true)
+        .open(backup_path)
+        .map_err(|e| SqlError::IOError(e, format!("Failed to open backup: {}", backup_path)))?;
+    let mut backup = FileStorage::new(file);
+    let mut head = [0; MIN_PAGE_SIZE];
+    backup.read_page(META_NODE_NUM, &mut head)?;
+    match header_page_size(&head) {
+        Some(size) if size == page_size => {}
+        Some(size) => return Err(SqlError::InvalidPageSize(size)),
+        None => return Err(SqlError::NotADatabase),
+    }
+
+    // The meta page goes last, as with a full backup
+    let mut meta = None;
+    for frame in frames.chunks_exact(frame_size) {
+        let page_num = u64::from_le_bytes(frame[..PAGE_NUM_SIZE].try_into().unwrap()) as usize;
+        if page_num >= num_pages {
+            return Err(SqlError::CorruptFile);
+        }
+        let page = &frame[PAGE_NUM_SIZE..];
+        if page_num == META_NODE_NUM {
+            meta = Some(page);
+        } else {
+            backup.write_page(page_num, page)?;
+        }
+    }
+    backup.set_len(num_pages * page_size)?;
+    backup.sync()?;
+    if let Some(page) = meta {
+        backup.write_page(META_NODE_NUM, page)?;
+        backup.sync()?;
+    }
+    Ok(frames.len() / frame_size)
+}
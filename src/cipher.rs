@@ -0,0 +1,241 @@
+//! Page encryption with ChaCha20-Poly1305.
+//! The page key is derived from the user's key and a random salt with
+//! PBKDF2-HMAC-SHA256. Each page is encrypted in place, and the nonce and tag
+//! take the reserved bytes at its end instead of the checksum. The meta page
+//! keeps its header, the salt and a check value of the key in the clear, so
+//! the file is still recognized and a wrong key is rejected before any page
+//! is decrypted. The page number and the clear bytes are authenticated with
+//! each page, so pages cannot be altered or swapped unnoticed.
+use std::ops::Range;
+
+use chacha20poly1305::{
+    aead::{AeadInPlace, KeyInit},
+    ChaCha20Poly1305, Key, Nonce, Tag,
+};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    meta::{META_HEADER_SIZE, META_NODE_NUM},
+    pager::PAGE_RESERVED_SIZE,
+    sql_error::{SqlError, SqlResult},
+};
+
+const SALT_SIZE: usize = 16;
+const CHECK_SIZE: usize = 16;
+/// Salt and key check, kept just before the reserved bytes of the meta page
+pub const KEY_INFO_SIZE: usize = SALT_SIZE + CHECK_SIZE;
+const NONCE_SIZE: usize = 12;
+const TAG_SIZE: usize = 16;
+const _: () = assert!(NONCE_SIZE + TAG_SIZE <= PAGE_RESERVED_SIZE);
+const KDF_ROUNDS: u32 = 100_000;
+
+pub struct PageCipher {
+    aead: ChaCha20Poly1305,
+    salt: [u8; SALT_SIZE],
+    check: [u8; CHECK_SIZE],
+}
+
+fn random(buf: &mut [u8]) -> SqlResult<()> {
+    getrandom::getrandom(buf).map_err(|e| {
+        let e = std::io::Error::other(e.to_string());
+        SqlError::IOError(e, "Failed to get random bytes".to_string())
+    })
+}
+
+fn key_info_range(page_size: usize) -> Range<usize> {
+    let end = page_size - PAGE_RESERVED_SIZE;
+    end - KEY_INFO_SIZE..end
+}
+
+/// Bytes of a page that are encrypted
+fn encrypted_range(page_num: usize, page_size: usize) -> Range<usize> {
+    if page_num == META_NODE_NUM {
+        META_HEADER_SIZE..key_info_range(page_size).start
+    } else {
+        0..page_size - PAGE_RESERVED_SIZE
+    }
+}
+
+impl PageCipher {
+    fn derive(key: &[u8], salt: [u8; SALT_SIZE]) -> Self {
+        let mut page_key = [0; 32];
+        pbkdf2::pbkdf2_hmac::<Sha256>(key, &salt, KDF_ROUNDS, &mut page_key);
+        let digest = Sha256::new()
+            .chain_update(b"minisql key check")
+            .chain_update(page_key)
+            .finalize();
+        PageCipher {
+            aead: ChaCha20Poly1305::new(Key::from_slice(&page_key)),
+            salt,
+            check: digest[..CHECK_SIZE].try_into().unwrap(),
+        }
+    }
+
+    /// Cipher for a new database, with a fresh salt
+    pub fn create(key: &[u8]) -> SqlResult<Self> {
+        let mut salt = [0; SALT_SIZE];
+        random(&mut salt)?;
+        Ok(Self::derive(key, salt))
+    }
+
+    /// Cipher for the stored meta page of an encrypted database, if `key` is
+    /// the one it was created with
+    pub fn open(key: &[u8], meta: &[u8]) -> SqlResult<Self> {
+        let info = &meta[key_info_range(meta.len())];
+        let cipher = Self::derive(key, info[..SALT_SIZE].try_into().unwrap());
+        if cipher.check[..] != info[SALT_SIZE..] {
+            return Err(SqlError::WrongKey);
+        }
+        Ok(cipher)
+    }
+
+    /// Record the salt and key check in a new meta page
+    pub fn write_key_info(&self, meta: &mut [u8]) {
+        let range = key_info_range(meta.len());
+        let info = &mut meta[range];
+        info[..SALT_SIZE].copy_from_slice(&self.salt);
+        info[SALT_SIZE..].copy_from_slice(&self.check);
+    }
+
+    /// Page number and the bytes left in the clear
+    fn associated_data(page_num: usize, buf: &[u8], range: &Range<usize>) -> Vec<u8> {
+        let tail = buf.len() - PAGE_RESERVED_SIZE;
+        let mut data = (page_num as u64).to_le_bytes().to_vec();
+        data.extend_from_slice(&buf[..range.start]);
+        data.extend_from_slice(&buf[range.end..tail]);
+        data
+    }
+
+    /// Encrypt a page to be stored, with a fresh nonce
+    pub fn encrypt(&self, page_num: usize, buf: &mut [u8]) -> SqlResult<()> {
+        let range = encrypted_range(page_num, buf.len());
+        let aad = Self::associated_data(page_num, buf, &range);
+        let mut nonce = [0; NONCE_SIZE];
+        random(&mut nonce)?;
+        let tag = self
+            .aead
+            .encrypt_in_place_detached(Nonce::from_slice(&nonce), &aad, &mut buf[range])
+            .map_err(|_| SqlError::CorruptPage { page: page_num })?;
+        let tail = buf.len() - PAGE_RESERVED_SIZE;
+        buf[tail..tail + NONCE_SIZE].copy_from_slice(&nonce);
+        buf[tail + NONCE_SIZE..tail + NONCE_SIZE + TAG_SIZE].copy_from_slice(&tag);
+        Ok(())
+    }
+
+    /// Decrypt a stored page, failing if it was altered
+    pub fn decrypt(&self, page_num: usize, buf: &mut [u8]) -> SqlResult<()> {
+        let range = encrypted_range(page_num, buf.len());
+        let aad = Self::associated_data(page_num, buf, &range);
+        let tail = buf.len() - PAGE_RESERVED_SIZE;
+        let nonce = *Nonce::from_slice(&buf[tail..tail + NONCE_SIZE]);
+        let tag = *Tag::from_slice(&buf[tail + NONCE_SIZE..tail + NONCE_SIZE + TAG_SIZE]);
+        self.aead
+            .decrypt_in_place_detached(&nonce, &aad, &mut buf[range], &tag)
+            .map_err(|_| SqlError::ChecksumMismatch { page: page_num })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        commands::prepare_statement,
+        pager::DEFAULT_PAGE_SIZE,
+        table::{Row, Table},
+        test::clean_db,
+    };
+
+    #[test]
+    fn encrypted_table() {
+        let path = clean_db("encrypted_table");
+        let key = b"correct horse";
+        let mut table = Table::open_encrypted(&path, key).unwrap();
+        for i in 0..30 {
+            let row = Row::try_new(i, &format!("name{}", i), "secret@example.com").unwrap();
+            table.insert(row).unwrap();
+        }
+        table.close().unwrap();
+        drop(table);
+
+        // Rows are not in the file in the clear
+        let data = std::fs::read(&path).unwrap();
+        assert!(!data.windows(6).any(|w| w == b"secret"));
+
+        let mut table = Table::open_encrypted(&path, key).unwrap();
+        assert_eq!(table.len().unwrap(), 30);
+        assert_eq!(table.get(7).unwrap().unwrap().name_str(), "name7");
+        table.check().unwrap();
+        let rows = prepare_statement("select")
+            .unwrap()
+            .execute(&mut table)
+            .unwrap();
+        assert_eq!(rows.len(), 30);
+        table.close().unwrap();
+        drop(table);
+
+        assert!(matches!(
+            Table::open_encrypted(&path, b"wrong horse"),
+            Err(SqlError::WrongKey)
+        ));
+        assert!(matches!(Table::open(&path), Err(SqlError::KeyRequired)));
+    }
+
+    #[test]
+    fn encrypted_read_ahead() {
+        let path = clean_db("encrypted_read_ahead");
+        let key = b"correct horse";
+        let mut table = Table::open_encrypted(&path, key).unwrap();
+        for i in 0..200 {
+            let key = i * 37 % 200;
+            let row = Row::try_new(key, &format!("name{}", key), "a@a").unwrap();
+            table.insert(row).unwrap();
+        }
+        table.close().unwrap();
+        drop(table);
+
+        // Pages read ahead of the scan are decrypted like those read one by one
+        let mut table = Table::open_encrypted(&path, key).unwrap();
+        table.pager.read_ahead.set(16);
+        let rows = prepare_statement("select")
+            .unwrap()
+            .execute(&mut table)
+            .unwrap();
+        assert_eq!(rows.len(), 200);
+        for (i, row) in rows.iter().enumerate() {
+            assert_eq!(
+                (row.id, row.name_str()),
+                (i as i64, format!("name{}", i).as_str())
+            );
+        }
+        table.check().unwrap();
+    }
+
+    #[test]
+    fn plain_file_with_key() {
+        let path = clean_db("plain_file_with_key");
+        Table::open(&path).unwrap().close().unwrap();
+        assert!(matches!(
+            Table::open_encrypted(&path, b"key"),
+            Err(SqlError::NotEncrypted)
+        ));
+    }
+
+    #[test]
+    fn tampering_is_detected() {
+        let path = clean_db("tampering_is_detected");
+        let mut table = Table::open_encrypted(&path, b"key").unwrap();
+        table.insert(Row::try_new(1, "a", "a@a").unwrap()).unwrap();
+        table.close().unwrap();
+        drop(table);
+
+        let root = 1;
+        let mut data = std::fs::read(&path).unwrap();
+        data[root * DEFAULT_PAGE_SIZE + 100] ^= 1;
+        std::fs::write(&path, &data).unwrap();
+        let mut table = Table::open_encrypted(&path, b"key").unwrap();
+        assert!(matches!(
+            table.get(1),
+            Err(SqlError::ChecksumMismatch { page }) if page == root
+        ));
+    }
+}
@@ -1,141 +1,328 @@
+use std::ops::Deref;
+
+use crate::cursor::Cursor;
 use crate::sql_error::{SqlError, SqlResult};
-use crate::string_utils::copy_null_terminated;
-use crate::table::{Row, Table};
+use crate::table::{Field, Row, RowRef, Table};
 
 #[derive(Debug)]
 pub enum Statement {
-    Insert(u64, [u8; 32], [u8; 255]),
-    Update(u64, [u8; 32], [u8; 255]),
-    Select(u64),
-    Delete(u64),
+    /// Insert with an explicit id, or the next auto-increment id when None.
+    /// The id of the row is replaced when the statement is executed.
+    Insert(Option<i64>, Row),
+    Update(Row),
+    /// Update of one field of a row, the others left as they are
+    UpdateField(i64, Field, String),
+    Select(i64),
+    /// Row at a position in key order, counting from 0
+    SelectNth(usize),
+    Delete(i64),
     SelectAll(),
+    Count(),
 }
 
-pub fn prepare_statement(buf: &str) -> SqlResult<Statement> {
-    if buf.starts_with("insert") {
-        let cmds = buf.split(" ").collect::<Vec<&str>>();
-        if cmds.len() != 4 {
-            return Err(SqlError::InvalidArgs);
+/// Rows returned by a statement, plus the value of aggregate statements
+#[derive(Debug, Default)]
+pub struct QueryResult {
+    pub rows: Vec<Row>,
+    pub count: Option<usize>,
+}
+impl From<Vec<Row>> for QueryResult {
+    fn from(rows: Vec<Row>) -> Self {
+        Self { rows, count: None }
+    }
+}
+impl Deref for QueryResult {
+    type Target = Vec<Row>;
+    fn deref(&self) -> &Self::Target {
+        &self.rows
+    }
+}
+impl IntoIterator for QueryResult {
+    type Item = Row;
+    type IntoIter = std::vec::IntoIter<Row>;
+    fn into_iter(self) -> Self::IntoIter {
+        self.rows.into_iter()
+    }
+}
+
+/// Rows of a statement, read from the table as the iterator advances.
+/// Statements that are not scans run eagerly and yield their result rows.
+pub struct RowIter<'a> {
+    cursor: Option<Cursor<'a>>,
+    rows: std::vec::IntoIter<Row>,
+    pub count: Option<usize>,
+}
+impl<'a> From<QueryResult> for RowIter<'a> {
+    fn from(result: QueryResult) -> Self {
+        Self {
+            cursor: None,
+            rows: result.rows.into_iter(),
+            count: result.count,
         }
-        let id = cmds[1]
-            .parse::<u64>()
-            .map_err(|_| SqlError::NotNumber(cmds[1].to_string()))?;
-        if cmds[2].len() > 32 - 1 {
-            return Err(SqlError::TooLargeString);
+    }
+}
+impl<'a> Iterator for RowIter<'a> {
+    type Item = SqlResult<Row>;
+    fn next(&mut self) -> Option<Self::Item> {
+        let cursor = match &mut self.cursor {
+            Some(cursor) => cursor,
+            None => return self.rows.next().map(Ok),
+        };
+        if cursor.end_of_table {
+            return None;
         }
-        if cmds[3].len() > 255 - 1 {
-            return Err(SqlError::TooLargeString);
+        let row = match cursor
+            .get()
+            .and_then(|value| Ok(Row::deserialize(&value.get_value()?)))
+        {
+            Ok(row) => row,
+            Err(e) => {
+                self.cursor = None;
+                return Some(Err(e));
+            }
+        };
+        if let Err(e) = cursor.advance() {
+            self.cursor = None;
+            return Some(Err(e));
         }
-        let mut name = [0u8; 32];
-        copy_null_terminated(&mut name, cmds[2]);
-        let mut email = [0u8; 255];
-        copy_null_terminated(&mut email, cmds[3]);
-        return Ok(Statement::Insert(id, name, email));
+        Some(Ok(row))
     }
-    if buf.starts_with("update") {
-        let cmds = buf.split(" ").collect::<Vec<&str>>();
-        if cmds.len() != 4 {
-            return Err(SqlError::InvalidArgs);
+}
+
+/// Words of a statement split at single spaces, each with its byte offset,
+/// so that parse errors can point at the word they are about
+struct Tokens<'a> {
+    input: &'a str,
+    words: Vec<(usize, &'a str)>,
+}
+impl<'a> Tokens<'a> {
+    fn new(input: &'a str) -> Self {
+        let mut offset = 0;
+        let words = input
+            .split(' ')
+            .map(|word| {
+                let start = offset;
+                offset += word.len() + 1;
+                (start, word)
+            })
+            .collect();
+        Self { input, words }
+    }
+    fn len(&self) -> usize {
+        self.words.len()
+    }
+    /// Error at the word `i`, or at the end of the statement past the last word
+    fn error(&self, i: usize, expected: &str) -> SqlError {
+        let (offset, found) = self.words.get(i).copied().unwrap_or((self.input.len(), ""));
+        self.error_at(offset, found, expected)
+    }
+    fn error_at(&self, offset: usize, found: &str, expected: &str) -> SqlError {
+        SqlError::ParseError {
+            input: self.input.to_string(),
+            offset,
+            expected: expected.to_string(),
+            found: found.to_string(),
         }
-        let id = cmds[1]
-            .parse::<u64>()
-            .map_err(|_| SqlError::NotNumber(cmds[1].to_string()))?;
-        if cmds[2].len() > 32 - 1 {
-            return Err(SqlError::TooLargeString);
+    }
+    fn get(&self, i: usize, expected: &str) -> SqlResult<&'a str> {
+        match self.words.get(i) {
+            Some((_, word)) => Ok(word),
+            None => Err(self.error(i, expected)),
         }
-        if cmds[3].len() > 255 - 1 {
-            return Err(SqlError::TooLargeString);
+    }
+    fn number<T: std::str::FromStr>(&self, i: usize, expected: &str) -> SqlResult<T> {
+        self.get(i, expected)?
+            .parse::<T>()
+            .map_err(|_| self.error(i, expected))
+    }
+    /// Fail unless the statement ends before the word `i`
+    fn end(&self, i: usize) -> SqlResult<()> {
+        match i < self.len() {
+            true => Err(self.error(i, "the end of the statement")),
+            false => Ok(()),
         }
-        let mut name = [0u8; 32];
-        copy_null_terminated(&mut name, cmds[2]);
-        let mut email = [0u8; 255];
-        copy_null_terminated(&mut email, cmds[3]);
-        return Ok(Statement::Update(id, name, email));
+    }
+    /// Row of the name and email at the words `i` and `i + 1`
+    fn row(&self, id: i64, i: usize) -> SqlResult<Row> {
+        let name = self.get(i, "a name")?;
+        let email = self.get(i + 1, "an email")?;
+        Row::try_new(id, name, email).map_err(|e| match e {
+            SqlError::TooLargeString(field) if field == "name" => self.error(
+                i,
+                &format!("a name of at most {} bytes", Field::Name.range().len() - 1),
+            ),
+            SqlError::TooLargeString(_) => self.error(
+                i + 1,
+                &format!(
+                    "an email of at most {} bytes",
+                    Field::Email.range().len() - 1
+                ),
+            ),
+            e => e,
+        })
+    }
+}
+
+pub fn prepare_statement(buf: &str) -> SqlResult<Statement> {
+    let tokens = Tokens::new(buf);
+    if buf.starts_with("insert") {
+        // insert [<id>] <name> <email>, the id left out or empty
+        let (id, name_at) = match tokens.len() {
+            3 => (None, 1),
+            _ if tokens.get(1, "an id")?.is_empty() => (None, 2),
+            _ => (Some(tokens.number::<i64>(1, "an id")?), 2),
+        };
+        let row = tokens.row(id.unwrap_or_default(), name_at)?;
+        tokens.end(name_at + 2)?;
+        return Ok(Statement::Insert(id, row));
+    }
+    if buf.starts_with("update") {
+        let id = tokens.number::<i64>(1, "an id")?;
+        let word = tokens.get(2, "a name or <field>=<value>")?;
+        if tokens.len() == 3 && word.contains('=') {
+            // update <id> <field>=<value>
+            let (field, value) = word
+                .split_once('=')
+                .and_then(|(field, value)| Some((Field::parse(field)?, value)))
+                .ok_or_else(|| tokens.error(2, "name=<value> or email=<value>"))?;
+            if value.len() > field.range().len() - 1 {
+                let offset = tokens.words[2].0 + field.name().len() + 1;
+                let expected = format!(
+                    "{} of at most {} bytes",
+                    field.name(),
+                    field.range().len() - 1
+                );
+                return Err(tokens.error_at(offset, value, &expected));
+            }
+            return Ok(Statement::UpdateField(id, field, value.to_string()));
+        }
+        // update <id> <name> <email>
+        let row = tokens.row(id, 2)?;
+        tokens.end(4)?;
+        return Ok(Statement::Update(row));
     }
     if buf.starts_with("select") {
-        let cmds = buf.split(" ").collect::<Vec<&str>>();
-        if cmds.len() == 1 {
+        if tokens.len() == 1 {
             return Ok(Statement::SelectAll());
         }
-        if cmds.len() != 2 {
-            return Err(SqlError::InvalidArgs);
+        if tokens.get(1, "an id")? == "nth" {
+            let k = tokens.number::<usize>(2, "a position")?;
+            tokens.end(3)?;
+            return Ok(Statement::SelectNth(k));
         }
-        let i = cmds[1]
-            .parse::<u64>()
-            .map_err(|_| SqlError::NotNumber(cmds[1].to_string()))?;
-        return Ok(Statement::Select(i as u64));
+        let i = tokens.number::<i64>(1, "an id")?;
+        tokens.end(2)?;
+        return Ok(Statement::Select(i));
+    }
+    if buf.starts_with("count") {
+        tokens.end(1)?;
+        return Ok(Statement::Count());
     }
     if buf.contains("delete") {
-        let cmds = buf.split(" ").collect::<Vec<&str>>();
-        if cmds.len() != 2 {
-            return Err(SqlError::InvalidArgs);
-        }
-        let i = cmds[1]
-            .parse::<u64>()
-            .map_err(|_| SqlError::NotNumber(cmds[1].to_string()))?;
-        return Ok(Statement::Delete(i as u64));
+        let i = tokens.number::<i64>(1, "an id")?;
+        tokens.end(2)?;
+        return Ok(Statement::Delete(i));
     }
     Err(SqlError::UnknownCommand(buf.to_string()))
 }
 
 impl Statement {
-    pub fn execute(&self, table: &mut Table) -> SqlResult<Vec<Row>> {
-        match self {
-            Statement::Insert(id, name, email) => {
-                let row = Row {
-                    id: *id,
-                    name: *name,
-                    email: *email,
-                };
-                let cursor = table.find(*id)?;
+    /// Execute the statement, passing each result row to `f`.
+    /// Scans stop at the first error returned by `f`.
+    /// Scanned rows are read in place from their pages without copying.
+    pub fn execute_streaming<F>(&self, table: &mut Table, f: &mut F) -> SqlResult<QueryResult>
+    where
+        F: FnMut(RowRef) -> SqlResult<()>,
+    {
+        self.execute_streaming_with(table, &mut |row, _| f(row))
+    }
 
-                if cursor.has_cell()? && cursor.get()?.get_key() == *id as u64 {
-                    return Err(SqlError::DuplicateKey);
+    /// Like `execute_streaming`, also lending the table to `f` for each row.
+    /// Scans read the rows as they were when the scan started, so changes
+    /// made by `f` are not seen by the rest of the scan.
+    pub fn execute_streaming_with<F>(&self, table: &mut Table, f: &mut F) -> SqlResult<QueryResult>
+    where
+        F: FnMut(RowRef, &mut Table) -> SqlResult<()>,
+    {
+        match self {
+            Statement::SelectAll() => {
+                let mut scan = table.snapshot_scan()?;
+                while let Some(value) = scan.next_value(table)? {
+                    f(RowRef::new(&value.get_value()?), table)?;
                 }
-                cursor.insert(row.id, row.serialize())?;
-                Ok(vec![row])
+                Ok(QueryResult::default())
             }
-            Statement::Update(id, name, email) => {
-                let cursor = table.find(*id)?;
-                if !cursor.check_key(*id)? {
-                    return Err(SqlError::NoData);
+            _ => {
+                let result = self.run(table)?;
+                for row in &result.rows {
+                    f(RowRef::new(&row.serialize()), table)?;
                 }
-                let row = Row {
-                    id: *id,
-                    name: *name,
-                    email: *email,
+                Ok(QueryResult {
+                    rows: vec![],
+                    count: result.count,
+                })
+            }
+        }
+    }
+
+    /// Execute the statement, reading scanned rows lazily
+    pub fn execute_iter<'a>(&self, table: &'a mut Table) -> SqlResult<RowIter<'a>> {
+        match self {
+            Statement::SelectAll() => Ok(RowIter {
+                cursor: Some(table.start()?),
+                rows: vec![].into_iter(),
+                count: None,
+            }),
+            _ => Ok(self.run(table)?.into()),
+        }
+    }
+
+    pub fn execute(&self, table: &mut Table) -> SqlResult<QueryResult> {
+        let mut iter = self.execute_iter(table)?;
+        let rows = (&mut iter).collect::<SqlResult<Vec<_>>>()?;
+        Ok(QueryResult {
+            rows,
+            count: iter.count,
+        })
+    }
+
+    /// Execute statements that are not scans
+    fn run(&self, table: &mut Table) -> SqlResult<QueryResult> {
+        match self {
+            Statement::Insert(id, row) => {
+                let mut row = row.clone();
+                row.id = match id {
+                    Some(id) => *id,
+                    None => table.get_next_id()?,
                 };
-                cursor.update(row.serialize())?;
-                Ok(vec![row])
+                table.insert(row.clone())?;
+                Ok(vec![row].into())
+            }
+            Statement::Update(row) => {
+                table.update(row.clone())?;
+                Ok(vec![row.clone()].into())
+            }
+            Statement::UpdateField(id, field, value) => {
+                let row = table.update_field(*id, *field, value)?;
+                Ok(vec![row].into())
             }
             Statement::Select(i) => {
-                let cursor = table.find(*i)?;
-                if !cursor.check_key(*i)? {
-                    return Err(SqlError::NoData);
-                }
-                let row = cursor.get()?;
-                let row = Row::deserialize(&row.get_value());
-                Ok(vec![row])
+                let row = table.get(*i)?.ok_or(SqlError::NoData)?;
+                Ok(vec![row].into())
             }
-            Statement::SelectAll() => {
-                let mut cursor = table.start()?;
-                let mut rows = Vec::new();
-                while !cursor.end_of_table {
-                    let row = cursor.get()?;
-                    let row = Row::deserialize(&row.get_value());
-                    rows.push(row);
-                    cursor.advance()?;
-                }
-                Ok(rows)
+            Statement::SelectNth(k) => {
+                let row = table.nth(*k)?.ok_or(SqlError::NoData)?;
+                Ok(vec![row].into())
             }
+            Statement::SelectAll() => unreachable!("scans are executed lazily"),
             Statement::Delete(i) => {
-                let cursor = table.find(*i)?;
-                if !cursor.has_cell()? || cursor.get()?.get_key() != *i as u64 {
-                    return Err(SqlError::NoData);
-                }
-                cursor.remove()?;
-                Ok(vec![])
+                table.delete(*i)?.ok_or(SqlError::NoData)?;
+                Ok(vec![].into())
             }
+            Statement::Count() => Ok(QueryResult {
+                rows: vec![],
+                count: Some(table.len()?),
+            }),
         }
     }
 }
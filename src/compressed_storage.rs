@@ -0,0 +1,302 @@
+//! Storage that keeps pages compressed with LZ4.
+//! Compressed pages vary in length, so they are not found by their number
+//! alone. The data file starts with a header and holds each page as a length
+//! prefix and its compressed bytes, in a slot with some room to grow. A map
+//! file next to it gives the offset and room of each page's slot. A page
+//! written again stays in its slot if it fits, or moves to a new slot at the
+//! end. Slots left behind are not reused. As with plain files, a page torn by
+//! a crash is written again from the write-ahead log.
+use std::{
+    fs::File,
+    io::{self, ErrorKind, Read},
+};
+
+use crate::{
+    meta::COMPRESSED_MAGIC,
+    sql_error::{SqlError, SqlResult},
+    storage::{read_at, write_all_at, Storage},
+};
+
+// HEADER: {MAGIC, PAGE_SIZE}
+// SLOT: {LENGTH, COMPRESSED PAGE, SPARE}
+// MAP ENTRY: {OFFSET, ROOM}, an offset of 0 for a page of zeros
+const PAGE_SIZE_OFFSET: usize = COMPRESSED_MAGIC.len();
+const HEADER_SIZE: usize = PAGE_SIZE_OFFSET + 4;
+const LENGTH_SIZE: usize = 4;
+const ENTRY_SIZE: usize = 8 + 4;
+/// Slots are rounded up to this many bytes, so a page growing a little keeps its slot
+const SLOT_ALIGN: usize = 64;
+
+pub fn map_path(filename: &str) -> String {
+    format!("{}.map", filename)
+}
+
+#[derive(Clone, Copy, Default)]
+struct Slot {
+    offset: u64,
+    room: u32,
+}
+
+pub struct CompressedStorage {
+    data: File,
+    map: File,
+    page_size: usize,
+    slots: Vec<Slot>,
+    /// End of the data file, where new slots go
+    end: u64,
+}
+
+fn io_error(e: io::Error, what: &str) -> SqlError {
+    SqlError::IOError(e, what.to_string())
+}
+
+fn read_exact_at(file: &File, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+    if read_at(file, offset, buf)? < buf.len() {
+        return Err(io::Error::from(ErrorKind::UnexpectedEof));
+    }
+    Ok(())
+}
+
+impl CompressedStorage {
+    /// Open the data file and its map, writing the header of a new file with
+    /// `page_size`. An existing file keeps the page size in its header.
+    pub fn open(filename: &str, page_size: usize) -> SqlResult<Self> {
+        let options = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .clone();
+        let data = options
+            .open(filename)
+            .map_err(|e| io_error(e, "Failed to open file"))?;
+        let data_len = data
+            .metadata()
+            .map_err(|e| io_error(e, "Failed to read metadata"))?
+            .len();
+        let page_size = if data_len == 0 {
+            let mut header = [0; HEADER_SIZE];
+            header[..PAGE_SIZE_OFFSET].copy_from_slice(COMPRESSED_MAGIC);
+            header[PAGE_SIZE_OFFSET..].copy_from_slice(&(page_size as u32).to_le_bytes());
+            write_all_at(&data, 0, &header).map_err(|e| io_error(e, "Failed to write"))?;
+            page_size
+        } else {
+            let mut header = [0; HEADER_SIZE];
+            read_exact_at(&data, 0, &mut header).map_err(|_| SqlError::NotCompressed)?;
+            if &header[..PAGE_SIZE_OFFSET] != COMPRESSED_MAGIC {
+                return Err(SqlError::NotCompressed);
+            }
+            u32::from_le_bytes(header[PAGE_SIZE_OFFSET..].try_into().unwrap()) as usize
+        };
+        let mut map = options
+            .open(map_path(filename))
+            .map_err(|e| io_error(e, "Failed to open page map"))?;
+        if data_len == 0 {
+            // A map left from an earlier file of the same name does not apply
+            map.set_len(0)
+                .map_err(|e| io_error(e, "Failed to truncate page map"))?;
+        }
+
+        let mut entries = Vec::new();
+        map.read_to_end(&mut entries)
+            .map_err(|e| io_error(e, "Failed to read page map"))?;
+        let slots = entries
+            .chunks_exact(ENTRY_SIZE)
+            .map(|entry| Slot {
+                offset: u64::from_le_bytes(entry[..8].try_into().unwrap()),
+                room: u32::from_le_bytes(entry[8..].try_into().unwrap()),
+            })
+            .collect();
+        let end = data_len.max(HEADER_SIZE as u64);
+        Ok(CompressedStorage {
+            data,
+            map,
+            page_size,
+            slots,
+            end,
+        })
+    }
+
+    /// Decompressed bytes of a page, or None for a page of zeros
+    fn load(&self, page_num: usize) -> SqlResult<Option<Vec<u8>>> {
+        let slot = self.slots[page_num];
+        if slot.offset == 0 {
+            return Ok(None);
+        }
+        let corrupt = || SqlError::CorruptPage { page: page_num };
+        let mut length = [0; LENGTH_SIZE];
+        read_exact_at(&self.data, slot.offset, &mut length).map_err(|_| corrupt())?;
+        let length = u32::from_le_bytes(length) as usize;
+        if LENGTH_SIZE + length > slot.room as usize {
+            return Err(corrupt());
+        }
+        let mut compressed = vec![0; length];
+        read_exact_at(
+            &self.data,
+            slot.offset + LENGTH_SIZE as u64,
+            &mut compressed,
+        )
+        .map_err(|_| corrupt())?;
+        let mut page = vec![0; self.page_size];
+        match lz4_flex::decompress_into(&compressed, &mut page) {
+            Ok(n) if n == self.page_size => Ok(Some(page)),
+            _ => Err(corrupt()),
+        }
+    }
+
+    fn write_entry(&mut self, page_num: usize) -> SqlResult<()> {
+        let slot = self.slots[page_num];
+        let mut entry = [0; ENTRY_SIZE];
+        entry[..8].copy_from_slice(&slot.offset.to_le_bytes());
+        entry[8..].copy_from_slice(&slot.room.to_le_bytes());
+        write_all_at(&self.map, (page_num * ENTRY_SIZE) as u64, &entry)
+            .map_err(|e| io_error(e, "Failed to write page map"))
+    }
+}
+
+impl Storage for CompressedStorage {
+    fn read_page(&mut self, page_num: usize, buf: &mut [u8]) -> SqlResult<usize> {
+        if page_num >= self.slots.len() {
+            return Ok(0);
+        }
+        let len = buf.len().min(self.page_size);
+        match self.load(page_num)? {
+            Some(page) => buf[..len].copy_from_slice(&page[..len]),
+            None => buf[..len].fill(0),
+        }
+        Ok(len)
+    }
+    fn write_page(&mut self, page_num: usize, buf: &[u8]) -> SqlResult<()> {
+        if buf.len() != self.page_size {
+            let e = io::Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "page of {} bytes in a file of {}",
+                    buf.len(),
+                    self.page_size
+                ),
+            );
+            return Err(io_error(e, "Failed to write"));
+        }
+        let compressed = lz4_flex::compress(buf);
+        let mut record = Vec::with_capacity(LENGTH_SIZE + compressed.len());
+        record.extend_from_slice(&(compressed.len() as u32).to_le_bytes());
+        record.extend_from_slice(&compressed);
+
+        if page_num >= self.slots.len() {
+            self.slots.resize(page_num + 1, Slot::default());
+        }
+        let slot = self.slots[page_num];
+        if slot.offset != 0 && record.len() <= slot.room as usize {
+            write_all_at(&self.data, slot.offset, &record)
+                .map_err(|e| io_error(e, "Failed to write"))?;
+            return Ok(());
+        }
+        let room = record.len().div_ceil(SLOT_ALIGN) * SLOT_ALIGN;
+        record.resize(room, 0);
+        write_all_at(&self.data, self.end, &record).map_err(|e| io_error(e, "Failed to write"))?;
+        self.slots[page_num] = Slot {
+            offset: self.end,
+            room: room as u32,
+        };
+        self.end += room as u64;
+        self.write_entry(page_num)
+    }
+    /// Length of the pages once decompressed
+    fn len(&self) -> SqlResult<usize> {
+        Ok(self.slots.len() * self.page_size)
+    }
+    fn stored_len(&self) -> SqlResult<usize> {
+        Ok(self.end as usize + self.slots.len() * ENTRY_SIZE)
+    }
+    fn sync(&mut self) -> SqlResult<()> {
+        self.data
+            .sync_data()
+            .map_err(|e| io_error(e, "Failed to sync"))?;
+        self.map
+            .sync_data()
+            .map_err(|e| io_error(e, "Failed to sync page map"))
+    }
+    /// Pages cut off leave their slots behind, and pages added are zeros
+    fn set_len(&mut self, len: usize) -> SqlResult<()> {
+        let num_pages = len.div_ceil(self.page_size);
+        self.slots.resize(num_pages, Slot::default());
+        self.map
+            .set_len((num_pages * ENTRY_SIZE) as u64)
+            .map_err(|e| io_error(e, "Failed to truncate page map"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        pager::DEFAULT_PAGE_SIZE,
+        table::{Row, Table},
+        test::clean_db,
+    };
+
+    #[test]
+    fn compressed_pages() {
+        let path = clean_db("compressed_pages");
+        let mut storage = CompressedStorage::open(&path, DEFAULT_PAGE_SIZE).unwrap();
+        let mut page = vec![0; DEFAULT_PAGE_SIZE];
+        page[..5].copy_from_slice(b"hello");
+        storage.write_page(2, &page).unwrap();
+        assert_eq!(storage.len().unwrap(), 3 * DEFAULT_PAGE_SIZE);
+        assert!(storage.stored_len().unwrap() < DEFAULT_PAGE_SIZE);
+
+        // Skipped pages read as zeros, and a short buffer gets the start
+        let mut buf = vec![7; DEFAULT_PAGE_SIZE];
+        assert_eq!(storage.read_page(1, &mut buf).unwrap(), DEFAULT_PAGE_SIZE);
+        assert!(buf.iter().all(|&b| b == 0));
+        let mut head = [0; 16];
+        storage.read_page(2, &mut head).unwrap();
+        assert_eq!(&head[..5], b"hello");
+        assert_eq!(storage.read_page(3, &mut buf).unwrap(), 0);
+
+        // A page that no longer fits its slot moves, and the map follows
+        for (i, b) in page.iter_mut().enumerate() {
+            *b = (i * 7919 % 251) as u8;
+        }
+        storage.write_page(2, &page).unwrap();
+        drop(storage);
+        let mut storage = CompressedStorage::open(&path, DEFAULT_PAGE_SIZE).unwrap();
+        storage.read_page(2, &mut buf).unwrap();
+        assert_eq!(buf, page);
+
+        storage.set_len(DEFAULT_PAGE_SIZE).unwrap();
+        assert_eq!(storage.read_page(2, &mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn compressed_table() {
+        let path = clean_db("compressed_table");
+        let mut table = Table::open_compressed(&path).unwrap();
+        for i in 0..100 {
+            let row = Row::try_new(i, &format!("name{}", i), "a@example.com").unwrap();
+            table.insert(row).unwrap();
+        }
+        table.close().unwrap();
+        let stats = table.pager.storage_stats().unwrap();
+        assert!(stats.stored * 4 < stats.pages);
+        drop(table);
+
+        let mut table = Table::open_compressed(&path).unwrap();
+        assert_eq!(table.len().unwrap(), 100);
+        assert_eq!(table.get(42).unwrap().unwrap().name_str(), "name42");
+        table.check().unwrap();
+
+        // A plain copy opens without compression, and the formats do not mix
+        let copy = clean_db("compressed_table_copy");
+        table.backup_to(&copy).unwrap();
+        let mut plain = Table::open(&copy).unwrap();
+        assert_eq!(plain.len().unwrap(), 100);
+        plain.close().unwrap();
+        assert!(matches!(Table::open(&path), Err(SqlError::Compressed)));
+        assert!(matches!(
+            Table::open_compressed(&copy),
+            Err(SqlError::NotCompressed)
+        ));
+    }
+}
@@ -1,46 +1,213 @@
 use crate::{
     node::{
-        LeafRef, INTERNAL_NODE_LEFT_SPLIT_COUNT, INTERNAL_NODE_MAX_CELLS,
-        INTERNAL_NODE_RIGHT_SPLIT_COUNT, LEAF_NODE_LEFT_SPLIT_COUNT, LEAF_NODE_MAX_CELLS,
-        LEAF_NODE_RIGHT_SPLIT_COUNT, MISSING_NODE,
+        inline_cell, max_inline_value, overflow_cell, split_counts, LeafRef, LeafValue, NodeRef,
+        LEAF_NODE_MAX_CELLS, LEAF_NODE_OFFSET_SIZE, MISSING_NODE,
     },
+    overflow,
+    pager::{Pager, Snapshot},
     sql_error::{SqlError, SqlResult},
-    table::{Table, ROW_SIZE},
+    table::Table,
+};
+use std::{
+    cell::{Cell, Ref},
+    cmp::Ordering,
+    ops::{Bound, Deref, Range},
 };
-use std::cell::Ref;
 
 pub struct Cursor<'a> {
     pub table: &'a mut Table,
     pub page_num: usize,
     pub cell_num: usize,
     pub end_of_table: bool,
+    /// Set when `retreat` moves before the first cell
+    pub begin_of_table: bool,
+    /// Generation of the table the position was taken at
+    pub generation: Cell<u64>,
+}
+
+/// Keys in order up to an end bound, read without touching row values
+pub struct Keys<'a> {
+    cursor: Cursor<'a>,
+    end: Bound<i64>,
+}
+impl<'a> Keys<'a> {
+    pub fn new(cursor: Cursor<'a>, end: Bound<i64>) -> Self {
+        Keys { cursor, end }
+    }
+}
+impl Iterator for Keys<'_> {
+    type Item = SqlResult<i64>;
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.cursor.end_of_table {
+            return None;
+        }
+        let key = match self.cursor.get_key() {
+            Ok(key) => key,
+            Err(e) => {
+                self.cursor.end_of_table = true;
+                return Some(Err(e));
+            }
+        };
+        let in_range = match self.end {
+            Bound::Included(end) => key <= end,
+            Bound::Excluded(end) => key < end,
+            Bound::Unbounded => true,
+        };
+        if !in_range {
+            self.cursor.end_of_table = true;
+            return None;
+        }
+        if let Err(e) = self.cursor.advance() {
+            self.cursor.end_of_table = true;
+            return Some(Err(e));
+        }
+        Some(Ok(key))
+    }
+}
+
+/// Rows in order as they were when the scan started.
+/// The table is only borrowed for each step, so statements may run in between.
+pub struct SnapshotScan {
+    snapshot: Snapshot,
+    page_num: usize,
+    cell_num: usize,
+}
+
+impl SnapshotScan {
+    pub fn new(table: &Table) -> SqlResult<Self> {
+        let snapshot = table.pager.snapshot();
+        let mut page_num = table.get_root_num()?;
+        while let NodeRef::Internal(internal) =
+            table.snapshot_node(&snapshot, page_num)?.as_typed()?
+        {
+            page_num = table
+                .pager
+                .check_link(page_num, internal.get_child_at(0)?)?;
+        }
+        Ok(SnapshotScan {
+            snapshot,
+            page_num,
+            cell_num: 0,
+        })
+    }
+
+    /// Next cell of the snapshot, or None after the last one
+    pub fn next_value(&mut self, table: &Table) -> SqlResult<Option<CursorValue>> {
+        loop {
+            let leaf = table
+                .snapshot_node(&self.snapshot, self.page_num)?
+                .leaf_node()?;
+            if self.cell_num < leaf.get_num_cells() {
+                self.cell_num += 1;
+                return CursorValue::new(
+                    &table.pager,
+                    Some(&self.snapshot),
+                    leaf,
+                    self.cell_num - 1,
+                )
+                .map(Some);
+            }
+            let next_leaf = leaf.get_next_leaf();
+            if next_leaf == MISSING_NODE {
+                return Ok(None);
+            }
+            let next_leaf = table.pager.check_link(self.page_num, next_leaf)?;
+            table.pager.prefetch(next_leaf)?;
+            self.page_num = next_leaf;
+            self.cell_num = 0;
+        }
+    }
 }
 
 pub struct CursorValue {
     node: LeafRef,
     cell_num: usize,
+    /// Value read from its overflow pages, which are not kept borrowed
+    overflow: Option<Vec<u8>>,
 }
 impl CursorValue {
-    pub fn get_key(&self) -> u64 {
+    fn new(
+        pager: &Pager,
+        snapshot: Option<&Snapshot>,
+        node: LeafRef,
+        cell_num: usize,
+    ) -> SqlResult<Self> {
+        let mut overflow = None;
+        if cell_num < node.get_num_cells() {
+            if let LeafValue::Overflow { first_page, len } = node.get_value(cell_num)? {
+                overflow = Some(overflow::read_chain(pager, snapshot, first_page, len)?);
+            }
+        }
+        Ok(CursorValue {
+            node,
+            cell_num,
+            overflow,
+        })
+    }
+    pub fn get_key(&self) -> SqlResult<i64> {
         self.node.get_key(self.cell_num)
     }
-    pub fn get_value(&self) -> Ref<[u8]> {
-        self.node.get_value(self.cell_num)
+    pub fn get_value(&self) -> SqlResult<Value> {
+        if let Some(value) = &self.overflow {
+            return Ok(Value::Overflow(value));
+        }
+        match self.node.get_value(self.cell_num)? {
+            LeafValue::Inline(value) => Ok(Value::Inline(value)),
+            LeafValue::Overflow { .. } => Err(SqlError::Corrupt {
+                page: self.node.page_num,
+                detail: format!("cell {} moved to overflow pages", self.cell_num),
+            }),
+        }
+    }
+}
+
+/// Value of a row, borrowed from its leaf or read from overflow pages
+pub enum Value<'a> {
+    Inline(Ref<'a, [u8]>),
+    Overflow(&'a [u8]),
+}
+impl Deref for Value<'_> {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        match self {
+            Value::Inline(value) => value,
+            Value::Overflow(value) => value,
+        }
     }
 }
 
 impl<'a> Cursor<'a> {
+    /// Fail if the tree changed since the cursor was placed
+    fn check_generation(&self) -> SqlResult<()> {
+        if self.generation.get() != self.table.generation() {
+            return Err(SqlError::CursorInvalidated);
+        }
+        Ok(())
+    }
+    /// Record a change to the tree. Other cursors no longer know where their
+    /// cells are, and neither does this one unless its cells stayed in place.
+    fn record_change(&self, in_place: bool) {
+        self.table.bump_generation();
+        if in_place {
+            self.generation.set(self.table.generation());
+        }
+    }
+
     /// Get values from the cursorS
     pub fn get(&self) -> SqlResult<CursorValue> {
+        self.check_generation()?;
         let node = self.table.leaf_ref(self.page_num)?;
-        Ok(CursorValue {
-            node,
-            cell_num: self.cell_num,
-        })
+        CursorValue::new(&self.table.pager, None, node, self.cell_num)
+    }
+    /// Key of the cell, without reading its value
+    pub fn get_key(&self) -> SqlResult<i64> {
+        self.check_generation()?;
+        self.table.leaf_ref(self.page_num)?.get_key(self.cell_num)
     }
 
     /// Go to the next cell
     pub fn advance(&mut self) -> SqlResult<()> {
+        self.check_generation()?;
         self.cell_num += 1;
         let leaf = self.table.leaf_ref(self.page_num)?;
         let num_page_cells = leaf.get_num_cells();
@@ -49,6 +216,8 @@ impl<'a> Cursor<'a> {
             if next_leaf == 0 {
                 self.end_of_table = true;
             } else {
+                let next_leaf = self.table.pager.check_link(self.page_num, next_leaf)?;
+                self.table.pager.prefetch(next_leaf)?;
                 self.page_num = next_leaf;
                 self.cell_num = 0;
             }
@@ -56,152 +225,309 @@ impl<'a> Cursor<'a> {
         Ok(())
     }
 
+    /// Go to the previous cell
+    pub fn retreat(&mut self) -> SqlResult<()> {
+        self.check_generation()?;
+        while self.cell_num == 0 {
+            let previous_leaf = match self.previous_leaf(self.page_num)? {
+                Some(previous_leaf) => previous_leaf,
+                None => {
+                    self.begin_of_table = true;
+                    return Ok(());
+                }
+            };
+            self.table.pager.prefetch(previous_leaf)?;
+            self.page_num = previous_leaf;
+            self.cell_num = self.table.leaf_ref(previous_leaf)?.get_num_cells();
+        }
+        self.cell_num -= 1;
+        self.end_of_table = false;
+        Ok(())
+    }
+
+    /// Move past the end of a leaf onto the first cell of the next one
+    pub fn skip_leaf_end(&mut self) -> SqlResult<()> {
+        if self.end_of_table || self.has_cell()? {
+            return Ok(());
+        }
+        let next_leaf = self.table.leaf_ref(self.page_num)?.get_next_leaf();
+        if next_leaf == MISSING_NODE {
+            self.end_of_table = true;
+        } else {
+            let next_leaf = self.table.pager.check_link(self.page_num, next_leaf)?;
+            self.table.pager.prefetch(next_leaf)?;
+            self.page_num = next_leaf;
+            self.cell_num = 0;
+        }
+        Ok(())
+    }
+
+    /// Move to `key` like a new cursor from `Table::find`, also after the
+    /// tree changed. A key within the keys of the current leaf is searched
+    /// for there without going through the root.
+    pub fn seek(&mut self, key: i64) -> SqlResult<()> {
+        let in_leaf = match self.check_generation() {
+            Ok(()) => {
+                let leaf = self.table.leaf_ref(self.page_num)?;
+                let num_cells = leaf.get_num_cells();
+                if num_cells > 0 && leaf.get_key(0)? <= key && key <= leaf.get_key(num_cells - 1)? {
+                    Some(leaf.find_cell(key)?)
+                } else {
+                    None
+                }
+            }
+            Err(_) => None,
+        };
+        let (page_num, cell_num) = match in_leaf {
+            Some(cell_num) => (self.page_num, cell_num),
+            None => {
+                let cursor = self.table.find(key)?;
+                (cursor.page_num, cursor.cell_num)
+            }
+        };
+        self.page_num = page_num;
+        self.cell_num = cell_num;
+        self.end_of_table = false;
+        self.begin_of_table = false;
+        self.generation.set(self.table.generation());
+        Ok(())
+    }
+    /// First and last key of the current leaf, or None if it is empty
+    pub fn key_range(&self) -> SqlResult<Option<(i64, i64)>> {
+        self.check_generation()?;
+        let leaf = self.table.leaf_ref(self.page_num)?;
+        match leaf.get_num_cells() {
+            0 => Ok(None),
+            num_cells => Ok(Some((leaf.get_key(0)?, leaf.get_key(num_cells - 1)?))),
+        }
+    }
+
     /// Check if the cursor has a cell
     pub fn has_cell(&self) -> SqlResult<bool> {
         let node = self.table.leaf_ref(self.page_num)?;
         Ok(self.cell_num < node.get_num_cells())
     }
+    /// Check if the cursor is on the last cell of the table
+    pub fn is_last(&self) -> SqlResult<bool> {
+        if self.end_of_table || !self.has_cell()? {
+            return Ok(false);
+        }
+        let node = self.table.leaf_ref(self.page_num)?;
+        Ok(self.cell_num + 1 == node.get_num_cells() && node.get_next_leaf() == MISSING_NODE)
+    }
     /// Check if the cursor has same key
-    pub fn check_key(&self, key: u64) -> SqlResult<bool> {
+    pub fn check_key(&self, key: i64) -> SqlResult<bool> {
         if !self.has_cell()? {
             return Ok(false);
         }
         let node = self.table.leaf_ref(self.page_num)?;
-        Ok(node.get_key(self.cell_num) == key)
+        Ok(node.get_key(self.cell_num)? == key)
     }
 
     /// Update value
-    pub fn update(&self, value: [u8; ROW_SIZE]) -> SqlResult<()> {
-        println!(
-            "[Update] node {}[{}] key: {}",
-            self.page_num,
-            self.cell_num,
-            self.get()?.get_key(),
-        );
+    pub fn update(&self, value: &[u8]) -> SqlResult<()> {
+        let key = self.get_key()?;
         let node = self.table.leaf_mut(self.page_num)?;
-        node.value(self.cell_num).copy_from_slice(value.as_ref());
-        Ok(())
+        // Fail before taking pages if the row is still borrowed
+        drop(node.borrow_mut_map(|page| &mut page.buf[..])?);
+        let old_chain = match node.get_value(self.cell_num)? {
+            LeafValue::Overflow { first_page, len } => Some((first_page, len)),
+            LeafValue::Inline(_) => None,
+        };
+        let cell = self.new_cell(key, value)?;
+        let node = self.table.leaf_mut(self.page_num)?;
+        node.remove_cell(self.cell_num)?;
+        if self.insert_cell(&cell)? {
+            self.record_change(false);
+        }
+        if let Some((first_page, len)) = old_chain {
+            overflow::free_chain(&self.table.pager, first_page, len)?;
+        }
+        // A longer value may have split the leaf
+        self.table.update_counts_up(self.page_num)
     }
 
-    /// Insert at the position of the cursor
-    pub fn insert(&self, key: u64, value: [u8; ROW_SIZE]) -> SqlResult<()> {
-        println!(
-            "[Insert] node {}[{}] key: {}",
-            self.page_num, self.cell_num, key,
-        );
+    /// Replace the bytes of `range` in the value with `bytes` and zeros
+    /// after them, leaving the other bytes as they are. A value kept in the
+    /// leaf that already spans the new bytes is changed in place.
+    pub fn update_field(&self, range: Range<usize>, bytes: &[u8]) -> SqlResult<()> {
+        if bytes.len() > range.len() {
+            return Err(SqlError::ValueOutOfRange {
+                end: range.start + bytes.len(),
+                len: range.end,
+            });
+        }
+        // Fails on a stale cursor or one past the last cell
+        self.get_key()?;
+        // Missing bytes at the end of a value read as zeros, so only the
+        // bytes it holds are written
+        let used = bytes.iter().rposition(|&b| b != 0).map_or(0, |i| i + 1);
         let node = self.table.leaf_mut(self.page_num)?;
-        let num_cells = node.get_num_cells();
-
-        let key_before = node.get_first_key();
-        if self.cell_num == 0 {
-            self.update_key_rec(self.page_num, key_before, key)?;
+        let in_place = match node.get_value(self.cell_num)? {
+            LeafValue::Inline(value) if range.start + used <= value.len() || used == 0 => {
+                Some(range.end.min(value.len()).saturating_sub(range.start))
+            }
+            _ => None,
+        };
+        if let Some(len) = in_place {
+            let mut field = vec![0; len];
+            field[..used].copy_from_slice(&bytes[..used]);
+            return node.update_value_range(self.cell_num, range.start, &field);
         }
+        let mut value = overflow::read_value(&self.table.pager, None, &node, self.cell_num)?;
+        value.resize(value.len().max(range.end), 0);
+        value[range.clone()].fill(0);
+        value[range.start..range.start + bytes.len()].copy_from_slice(bytes);
+        self.update(&value)
+    }
 
-        if num_cells >= LEAF_NODE_MAX_CELLS {
-            // When the node is full, split it
-            return self.split_and_insert(key, value);
-        }
-        // Shift the cells to the right
-        for i in (self.cell_num..num_cells).rev() {
-            let node = self.table.leaf_mut(self.page_num)?;
-            let cell = node.cell(i).to_owned(); // TODO Slow own
-            node.cell(i + 1).copy_from_slice(&cell);
+    /// Insert at the position of the cursor
+    pub fn insert(&self, key: i64, value: &[u8]) -> SqlResult<()> {
+        self.check_generation()?;
+        let cell = self.new_cell(key, value)?;
+        let split = self.insert_cell(&cell)?;
+        self.record_change(!split);
+
+        if key >= self.table.get_next_id()? {
+            self.table.set_next_id(key.saturating_add(1))?;
         }
-        node.set_key(self.cell_num, key);
-        node.value(self.cell_num).copy_from_slice(value.as_ref());
-        node.set_num_cells(num_cells + 1);
+        // Splits count the nodes they create, leaving the path of this leaf
+        self.table.update_counts_up(self.page_num)?;
+        self.table.set_row_count(self.table.len()? + 1)
+    }
 
-        Ok(())
+    /// Cell of a value, which is written to overflow pages if it is too
+    /// long to keep in the leaf
+    fn new_cell(&self, key: i64, value: &[u8]) -> SqlResult<Vec<u8>> {
+        let pager = &self.table.pager;
+        if value.len() <= max_inline_value(pager.page_size) {
+            return Ok(inline_cell(key, value));
+        }
+        let first_page = overflow::write_chain(pager, value)?;
+        Ok(overflow_cell(key, value.len(), first_page))
     }
 
-    /// Update parents with the first key recursively to root;
-    fn update_key_rec(&self, node_num: usize, key_before: u64, key_after: u64) -> SqlResult<()> {
-        let node = self.table.pager.node(node_num)?;
-        if node.is_root() {
-            return Ok(());
+    /// Put a cell at the position of the cursor, splitting the leaf when it
+    /// has no room for it. Returns whether it split.
+    fn insert_cell(&self, cell: &[u8]) -> SqlResult<bool> {
+        let node = self.table.leaf_mut(self.page_num)?;
+        if node.get_num_cells() >= self.table.node_limits().leaf_max_cells()
+            || !node.fits(cell.len())?
+        {
+            self.split_and_insert(cell)?;
+            Ok(true)
+        } else {
+            node.insert_cell(self.cell_num, cell)?;
+            Ok(false)
         }
-        let parent_num = node.get_parent();
-        let parent = self.table.internal_mut(parent_num)?;
-        let index = parent.find_key(key_before).unwrap();
-        parent.set_key_at(index, key_after);
-        self.update_key_rec(parent_num, key_before, key_after)
     }
 
     /// Insert to full cell
-    fn split_and_insert(&self, key: u64, value: [u8; ROW_SIZE]) -> SqlResult<()> {
+    fn split_and_insert(&self, cell: &[u8]) -> SqlResult<()> {
         // max cursor_page -> old_node
         //                 -> new_node
         let old_num = self.page_num;
+        let old_pin = self.table.pager.pin(old_num)?;
         let old_node = self.table.leaf_mut(old_num)?;
 
         // Create New Leaf Node
-        let new_page_num = self.table.pager.new_page_num();
+        let new_page_num = self.table.pager.alloc_page()?;
+        let new_pin = self.table.pager.pin(new_page_num)?;
         let new_node = self.table.pager.node(new_page_num)?.init_leaf();
 
-        println!("Split Leaf old:{} new:{}", old_num, new_page_num);
-
-        // Move the rows to the old node to the new node
-        for i in (0..=LEAF_NODE_MAX_CELLS).rev() {
-            let n = if i >= LEAF_NODE_LEFT_SPLIT_COUNT {
-                i - LEAF_NODE_LEFT_SPLIT_COUNT
+        // Move the cells after the split point to the new node in one copy,
+        // then put the new cell on its side. Both leaves are packed in order
+        // but for the new cell, which goes before the others.
+        let mut lens = (0..old_node.get_num_cells())
+            .map(|i| old_node.cell_len(i))
+            .collect::<SqlResult<Vec<_>>>()?;
+        lens.insert(self.cell_num, cell.len());
+        // Appending past the last leaf leaves it full, as keys going up are
+        // likely to keep coming after the new one
+        let left_count =
+            if old_node.get_next_leaf() == MISSING_NODE && self.cell_num == lens.len() - 1 {
+                self.cell_num
             } else {
-                i
+                self.split_point(&lens)
             };
-            if i == self.cell_num {
-                if i >= LEAF_NODE_LEFT_SPLIT_COUNT {
-                    new_node.set_key(n, key);
-                    new_node.value(n).copy_from_slice(value.as_ref());
-                } else {
-                    old_node.set_key(i, key);
-                    old_node.value(i).copy_from_slice(value.as_ref());
-                };
-            } else {
-                let g = if i > self.cell_num { i - 1 } else { i };
-                let key = old_node.get_key(g);
-                let value = old_node.get_value(g).to_owned();
-                if i >= LEAF_NODE_LEFT_SPLIT_COUNT {
-                    new_node.set_key(n, key);
-                    new_node.value(n).copy_from_slice(&value);
-                } else {
-                    old_node.set_key(i, key);
-                    old_node.value(i).copy_from_slice(&value);
-                };
-            }
+        if self.cell_num < left_count {
+            old_node.split_off(left_count - 1, &new_node)?;
+            old_node.insert_cell(self.cell_num, cell)?;
+        } else {
+            old_node.split_off(left_count, &new_node)?;
+            new_node.insert_cell(self.cell_num - left_count, cell)?;
         }
 
         // Node properties
         let old_node_next = old_node.get_next_leaf();
         old_node.set_next_leaf(new_page_num);
-        old_node.set_num_cells(LEAF_NODE_LEFT_SPLIT_COUNT);
 
         new_node.set_next_leaf(old_node_next);
-        new_node.set_num_cells(LEAF_NODE_RIGHT_SPLIT_COUNT);
+        new_node.set_prev_leaf(old_num);
+        if old_node_next != MISSING_NODE {
+            self.table
+                .leaf_mut(old_node_next)?
+                .set_prev_leaf(new_page_num);
+        }
         new_node.set_parent(old_node.get_parent());
 
-        // Update parent key
+        // Update parent key, unpinned first so pins do not pile up the tree
         let old_is_root = old_node.is_root();
-        self.update_parent(old_is_root, new_page_num)
+        let key = new_node.get_key(0)?;
+        drop((old_pin, new_pin));
+        self.update_parent(old_num, old_is_root, new_page_num, key)
     }
 
-    /// update parent node after splitting
-    fn update_parent(&self, old_is_root: bool, new_num: usize) -> SqlResult<()> {
+    /// Number of cells left in the old leaf when splitting cells of `lens`
+    /// bytes: the fewest holding half of their bytes. Each side keeps a cell
+    /// or more, so a long value may be left alone in its leaf.
+    fn split_point(&self, lens: &[usize]) -> usize {
+        let size = |len: &usize| LEAF_NODE_OFFSET_SIZE + len;
+        let total = lens.iter().map(size).sum::<usize>();
+        let max_cells = self.table.node_limits().leaf_max_cells();
+        let last = max_cells.min(lens.len() - 1);
+        let mut count = lens.len().saturating_sub(max_cells).max(1);
+        let mut left = lens[..count].iter().map(size).sum::<usize>();
+        while count < last && left * 2 < total {
+            left += size(&lens[count]);
+            count += 1;
+        }
+        count
+    }
+
+    /// update parent node after splitting, adding the new node after the old
+    /// one with the first key under it
+    fn update_parent(
+        &self,
+        old_num: usize,
+        old_is_root: bool,
+        new_num: usize,
+        key: i64,
+    ) -> SqlResult<()> {
         if old_is_root {
-            self.create_new_root(new_num)
+            self.create_new_root(new_num, key)
         } else {
-            self.insert_internal_node(new_num)
+            // The old node gave cells to the new one
+            let parent = self
+                .table
+                .internal_mut(self.table.pager.node(old_num)?.get_parent())?;
+            let index = parent.find_child(old_num)?.ok_or(SqlError::CorruptFile)?;
+            parent.set_count_at(index, self.table.subtree_count(old_num)?)?;
+            self.insert_internal_node(old_num, new_num, key)
         }
     }
 
     /// When root_node is splitted, create new root
-    fn create_new_root(&self, right_child_num: usize) -> SqlResult<()> {
+    fn create_new_root(&self, right_child_num: usize, key: i64) -> SqlResult<()> {
         let old_root_num = self.table.get_root_num()?;
-        let new_root_num = self.table.pager.new_page_num();
-        println!(
-            "Create New Root old root->left: {}, right: {}, new root: {}",
-            old_root_num, right_child_num, new_root_num
-        );
+        let new_root_num = self.table.pager.alloc_page()?;
 
         let left_num = old_root_num;
+        let _pins = [
+            self.table.pager.pin(left_num)?,
+            self.table.pager.pin(right_child_num)?,
+            self.table.pager.pin(new_root_num)?,
+        ];
         let left_child = self.table.pager.node(left_num)?;
         let right_child = self.table.pager.node(right_child_num)?;
         let root = self.table.pager.node(new_root_num)?;
@@ -210,119 +536,120 @@ impl<'a> Cursor<'a> {
         // new root has left and right
         let root = root.init_internal();
         root.set_root(true);
-        root.set_num_keys(2);
-        root.set_key_at(0, left_child.get_first_key());
-        root.set_child_at(0, left_num);
-        root.set_key_at(1, right_child.get_first_key());
-        root.set_child_at(1, right_child_num);
+        root.set_cells(
+            &[
+                (left_num, self.table.subtree_count(left_num)?),
+                (right_child_num, self.table.subtree_count(right_child_num)?),
+            ],
+            &[key],
+        )?;
         self.table.set_root_num(new_root_num)?;
 
-        println!(
-            "root{}: {}\nleft{} [{}]: {}\nright{} j[{}]: {}",
-            self.table.get_root_num()?,
-            root.node_ref.node,
-            left_num,
-            left_child.get_first_key(),
-            left_child,
-            right_child_num,
-            right_child.get_first_key(),
-            right_child
-        );
-
         left_child.set_parent(new_root_num);
         right_child.set_parent(new_root_num);
 
         Ok(())
     }
 
-    /// After node is splitted, insert new node to parent
-    fn insert_internal_node(&self, child_num: usize) -> SqlResult<()> {
+    /// After node is splitted, insert new node to parent next to the node
+    /// it was split from, with the key between them
+    fn insert_internal_node(&self, left_num: usize, child_num: usize, key: i64) -> SqlResult<()> {
         let child = self.table.pager.node(child_num)?;
         let node_num = child.get_parent();
-        println!("Insert internal node {} <- child {}", node_num, child_num);
 
         let node = self.table.internal_mut(node_num)?;
 
-        let num_keys = node.get_num_keys();
-        if num_keys >= INTERNAL_NODE_MAX_CELLS {
-            return self.split_and_insert_internal_node(node_num, child_num);
+        if node.get_num_children() >= self.table.node_limits().internal_max_cells() {
+            return self.split_and_insert_internal_node(node_num, left_num, child_num, key);
         }
 
-        let child_key = child.get_first_key();
-        let index = node.find_key(child_key).unwrap() + 1;
-
-        node.set_num_keys(num_keys + 1);
-        for i in (index..num_keys).rev() {
-            let key = node.get_key_at(i);
-            node.set_key_at(i + 1, key);
-            let child_num = node.get_child_at(i);
-            node.set_child_at(i + 1, child_num);
-        }
-        node.set_key_at(index, child_key);
-        node.set_child_at(index, child_num);
-        Ok(())
+        let index = node.find_child(left_num)?.ok_or(SqlError::CorruptFile)?;
+        node.insert_child(index, child_num, self.table.subtree_count(child_num)?, key)
     }
 
     /// When internal node is overflowed, split to new internal node
-    fn split_and_insert_internal_node(&self, node_num: usize, child_num: usize) -> SqlResult<()> {
+    fn split_and_insert_internal_node(
+        &self,
+        node_num: usize,
+        left_num: usize,
+        child_num: usize,
+        key: i64,
+    ) -> SqlResult<()> {
+        let old_pin = self.table.pager.pin(node_num)?;
         let old_node = self.table.internal_mut(node_num)?;
-        let new_node_num = self.table.pager.new_page_num();
+        let new_node_num = self.table.pager.alloc_page()?;
+        let new_pin = self.table.pager.pin(new_node_num)?;
         let new_node = self.table.pager.node(new_node_num)?.init_internal();
-        let num_keys = old_node.get_num_keys();
-
-        let child = self.table.pager.node(child_num)?;
-        let child_key = child.get_first_key();
-        let child_index = old_node.find_key(child_key).unwrap() + 1;
 
         // old[0] [1] [a]      [2] [3] [4]
-        // old[0] [1] [2]  new [0] [1] [2]
-        println!("Split internal old: {}, new: {}", node_num, new_node_num);
-
-        for i in (0..num_keys + 1).rev() {
-            let (key, num) = if i == child_index as usize {
-                print!("i: {}, child", i);
-                (child_key, child_num)
-            } else if i >= child_index as usize {
-                print!("i: {}, [{}]", i, i - 1);
-                (old_node.get_key_at(i - 1), old_node.get_child_at(i - 1))
-            } else {
-                print!("i: {}, [{}]", i, i);
-                (old_node.get_key_at(i), old_node.get_child_at(i))
-            };
-            print!(" key:{}, page:{} ", key, num);
-            if i < INTERNAL_NODE_LEFT_SPLIT_COUNT {
-                println!(" -> old[{}]", i);
-                old_node.set_key_at(i, key);
-                old_node.set_child_at(i, num);
-            } else if i - INTERNAL_NODE_LEFT_SPLIT_COUNT < INTERNAL_NODE_RIGHT_SPLIT_COUNT {
-                println!(" -> new[{}]", i - INTERNAL_NODE_LEFT_SPLIT_COUNT);
-                new_node.set_key_at(i - INTERNAL_NODE_LEFT_SPLIT_COUNT, key);
-                new_node.set_child_at(i - INTERNAL_NODE_LEFT_SPLIT_COUNT, num);
-            } else {
-                println!("->error");
-                panic!("Invalid index, i: {}", i);
+        // old[0] [1]     [a]  new [2] [3] [4]
+        // The key between the halves moves up to the parent
+        let index = old_node
+            .find_child(left_num)?
+            .ok_or(SqlError::CorruptFile)?;
+        let count = self.table.subtree_count(child_num)?;
+        // The new child goes after the child at `index` once the children
+        // after the split point are moved over in one copy
+        let (left_count, _) = split_counts(old_node.get_num_children() + 1);
+        let middle_key = match (index + 1).cmp(&left_count) {
+            Ordering::Less => {
+                let middle_key = old_node.split_off(left_count - 1, &new_node)?;
+                old_node.insert_child(index, child_num, count, key)?;
+                middle_key
             }
-        }
-
-        old_node.set_num_keys(INTERNAL_NODE_LEFT_SPLIT_COUNT);
-        new_node.set_num_keys(INTERNAL_NODE_RIGHT_SPLIT_COUNT);
+            Ordering::Equal => {
+                let next_key = old_node.split_off(left_count, &new_node)?;
+                new_node.prepend_child(child_num, count, next_key)?;
+                key
+            }
+            Ordering::Greater => {
+                let middle_key = old_node.split_off(left_count, &new_node)?;
+                new_node.insert_child(index - left_count, child_num, count, key)?;
+                middle_key
+            }
+        };
         new_node.set_parent(old_node.get_parent());
 
+        // The new node follows the old one on their level
+        let next_num = old_node.get_next_internal();
+        if next_num != MISSING_NODE {
+            self.table
+                .internal_mut(next_num)?
+                .set_prev_internal(new_node_num);
+        }
+        new_node.set_next_internal(next_num);
+        new_node.set_prev_internal(node_num);
+        old_node.set_next_internal(new_node_num);
+
         // Update right_child's parent;
-        for i in 0..INTERNAL_NODE_RIGHT_SPLIT_COUNT {
-            let child_num = new_node.get_child_at(i);
+        for (child_num, _) in new_node.children()? {
             let child = self.table.pager.node(child_num)?;
             child.set_parent(new_node_num);
         }
 
         let old_is_root = old_node.node.is_root();
-        self.update_parent(old_is_root, new_node_num)
+        drop((old_pin, new_pin));
+        self.update_parent(node_num, old_is_root, new_node_num, middle_key)
     }
 
     /// Remove cell from leaf node
     pub fn remove(&self) -> SqlResult<()> {
-        println!("[Remove] page: {}, cell: {}", self.page_num, self.cell_num);
+        self.check_generation()?;
+        self.remove_cell()
+    }
 
+    /// Store the row counts of children of `parent_num` that gave or took
+    /// cells from a sibling. The parent keeps its total.
+    fn set_counts(&self, parent_num: usize, children: &[usize]) -> SqlResult<()> {
+        let parent = self.table.internal_mut(parent_num)?;
+        for &child_num in children {
+            let index = parent.find_child(child_num)?.ok_or(SqlError::CorruptFile)?;
+            parent.set_count_at(index, self.table.subtree_count(child_num)?)?;
+        }
+        Ok(())
+    }
+
+    fn remove_cell(&self) -> SqlResult<()> {
         if !self.has_cell()? {
             return Err(SqlError::NoData);
         }
@@ -330,363 +657,310 @@ impl<'a> Cursor<'a> {
         let leaf_num = self.page_num;
         let leaf = self.table.leaf_mut(leaf_num)?;
 
-        if self.cell_num == 0 {
-            let before = leaf.get_key(0);
-            let after = leaf.get_key(1); // INFO: LEAF MIN >= 2
-            self.update_key_rec(leaf_num, before, after)?;
-        }
-
         // Remove Element
-        let num_cells = leaf.get_num_cells();
-        for i in self.cell_num..(num_cells - 1) {
-            let cell = leaf.cell(i + 1).to_owned();
-            leaf.cell(i).copy_from_slice(&cell);
+        if let LeafValue::Overflow { first_page, len } = leaf.get_value(self.cell_num)? {
+            overflow::free_chain(&self.table.pager, first_page, len)?;
         }
-        leaf.set_num_cells(num_cells - 1);
+        leaf.remove_cell(self.cell_num)?;
+        self.record_change(true);
         let num_cells = leaf.get_num_cells();
+        self.table.update_counts_up(leaf_num)?;
+        self.table.set_row_count(self.table.len()? - 1)?;
 
         if leaf.node.is_root() {
             // Not need to merge
             return Ok(());
         }
 
-        if num_cells >= LEAF_NODE_RIGHT_SPLIT_COUNT {
+        let limits = self.table.node_limits();
+        if num_cells >= limits.leaf_min_cells() || leaf.used_space()? * 2 >= leaf.cell_space() {
             // No need to balance
             return Ok(());
         }
 
-        println!("Balance leaf node: {}", leaf_num);
-        let next_leaf = leaf.get_next_leaf();
-        if next_leaf == MISSING_NODE {
+        self.record_change(false);
+        let parent = self.table.internal_mut(leaf.get_parent())?;
+        let index = parent.find_child(leaf_num)?.ok_or(SqlError::CorruptFile)?;
+        if index == parent.get_num_keys() {
             // Merge to left node
-            let left_num = self.previous_leaf(leaf_num)?.unwrap();
+            let index = index.checked_sub(1).ok_or(SqlError::CorruptFile)?;
+            let left_num = parent.get_child_at(index)?;
             let left = self.table.leaf_mut(left_num)?;
 
-            if left.get_num_cells() + leaf.get_num_cells() <= LEAF_NODE_MAX_CELLS {
+            if left.get_num_cells() + leaf.get_num_cells() <= limits.leaf_max_cells()
+                && left.used_space()? + leaf.used_space()? <= leaf.cell_space()
+            {
                 // Merge leaves
                 self.merge_and_remove(left_num, leaf_num)?;
             } else {
                 // Shift left --> leaf
-                let num_leaf = leaf.get_num_cells();
+                let _pins = [
+                    self.table.pager.pin(left_num)?,
+                    self.table.pager.pin(leaf_num)?,
+                ];
                 let num_left = left.get_num_cells();
-                for i in (0..num_leaf).rev() {
-                    let cell = leaf.cell(i).to_owned();
-                    leaf.cell(i + 1).copy_from_slice(&cell);
-                }
-                {
-                    let left_last = left.cell(num_left - 1);
-                    leaf.cell(0).copy_from_slice(&left_last);
-                }
-                leaf.set_num_cells(num_leaf + 1);
-                left.set_num_cells(num_left - 1);
-
-                let leaf_after_key = leaf.get_key(0);
-                let leaf_before_key = left.get_key(1);
-                self.update_key_rec(left_num, leaf_before_key, leaf_after_key)?;
+                leaf.copy_cell(0, &left, num_left - 1)?;
+                left.remove_cell(num_left - 1)?;
+                parent.set_key_at(index, leaf.get_key(0)?)?;
+                self.set_counts(parent.node.page_num, &[left_num, leaf_num])?;
             }
 
             return Ok(());
         }
 
         // Pick from right
-        let right_index = next_leaf;
-        let right = self.table.leaf_mut(right_index)?;
+        let right_num = parent.get_child_at(index + 1)?;
+        let right = self.table.leaf_mut(right_num)?;
 
-        if right.get_num_cells() + leaf.get_num_cells() <= LEAF_NODE_MAX_CELLS {
+        if right.get_num_cells() + leaf.get_num_cells() <= limits.leaf_max_cells()
+            && right.used_space()? + leaf.used_space()? <= leaf.cell_space()
+        {
             // Merge leaves
-            self.merge_and_remove(leaf_num, right_index)?;
+            self.merge_and_remove(leaf_num, right_num)?;
         } else {
-            let leaf_num = leaf.get_num_cells();
-            let right_num = right.get_num_cells();
-
-            let right_before = right.get_key(0);
-            let right_after = right.get_key(1);
-            self.update_key_rec(leaf_num, right_before, right_after)?;
-
+            let _pins = [
+                self.table.pager.pin(leaf_num)?,
+                self.table.pager.pin(right_num)?,
+            ];
             // Shift leaf <-- right
-            {
-                let right_0 = right.cell(0);
-                leaf.cell(leaf_num).copy_from_slice(&right_0);
-            }
-            for i in 0..(right.get_num_cells() - 1) {
-                let cell = right.cell(i + 1).to_owned(); // TODO slow owned
-                right.cell(i).copy_from_slice(&cell);
-            }
-            leaf.set_num_cells(leaf_num + 1);
-            right.set_num_cells(right_num - 1);
+            leaf.copy_cell(num_cells, &right, 0)?;
+            right.remove_cell(0)?;
+            parent.set_key_at(index, right.get_key(0)?)?;
+            self.set_counts(parent.node.page_num, &[leaf_num, right_num])?;
         }
         Ok(())
     }
 
     fn previous_leaf(&self, leaf_num: usize) -> SqlResult<Option<usize>> {
-        // Back traverse
-        let leaf = self.table.leaf_ref(leaf_num)?;
-        let leaf_key = leaf.get_first_key();
-        if leaf.is_root() {
-            return Ok(None);
-        }
-        let parent_num = leaf.node.get_parent();
-        let parent = self.table.internal_ref(parent_num)?;
-        let index = parent.find_key(leaf_key).unwrap();
-        if index == 0 {
-            // Recursive upper
-            if parent.is_root() {
-                return Ok(None);
-            }
-            let previous_parent_num = self.prev_internal(parent_num)?;
-            let previous_parent_num = match previous_parent_num {
-                None => return Ok(None),
-                Some(n) => n,
-            };
-            let previous_parent = self.table.internal_ref(previous_parent_num)?;
-            let node_num = previous_parent.get_child_at(previous_parent.get_num_keys() - 1);
-            Ok(Some(node_num))
-        } else {
-            let left_num = parent.get_child_at(index - 1);
-            return Ok(Some(left_num));
+        match self.table.leaf_ref(leaf_num)?.get_prev_leaf() {
+            MISSING_NODE => Ok(None),
+            prev_leaf => Ok(Some(prev_leaf)),
         }
     }
 
-    fn prev_internal(&self, node_num: usize) -> SqlResult<Option<usize>> {
-        let node = self.table.internal_ref(node_num)?;
-        if node.is_root() {
-            return Ok(None);
-        }
-        let parent_num = node.node.get_parent();
-        let parent = self.table.internal_ref(parent_num)?;
-        let node_key = node.get_first_key();
-        let index = parent.find_key(node_key).unwrap();
-        if index == 0 {
-            // Recursive upper
-            if parent.is_root() {
-                return Ok(None);
-            }
-            let previous_parent_num = self.prev_internal(parent_num)?;
-            let previous_parent_num = match previous_parent_num {
-                None => return Ok(None),
-                Some(n) => n,
-            };
-
-            let previous_parent = self.table.internal_ref(previous_parent_num)?;
-            let node_num = previous_parent.get_child_at(previous_parent.get_num_keys() - 1);
-            return Ok(Some(node_num));
-        }
-        let left_num = parent.get_child_at(index - 1);
-        Ok(Some(left_num))
-    }
-
-    fn next_internal(&self, node_num: usize) -> SqlResult<Option<usize>> {
-        let node = self.table.internal_ref(node_num)?;
-        if node.is_root() {
-            return Ok(None);
-        }
-        let parent_num = node.node.get_parent();
-        let parent = self.table.internal_ref(parent_num)?;
-
-        let node_key = node.get_first_key();
-        let index = parent.find_key(node_key).unwrap();
-
-        if index == parent.get_num_keys() {
-            // Recursive upper
-            if parent.is_root() {
-                return Ok(None);
-            }
-            let next_parent_num = self.next_internal(parent_num)?;
-            let next_parent_num = match next_parent_num {
-                None => return Ok(None),
-                Some(n) => n,
-            };
-            let next_parent = self.table.internal_ref(next_parent_num)?;
-            let node_num = next_parent.get_child_at(0);
-            return Ok(Some(node_num));
-        }
-        let right_num = parent.get_child_at(index + 1);
-        Ok(Some(right_num))
-    }
-
     fn merge_and_remove(&self, left_num: usize, right_num: usize) -> SqlResult<()> {
-        println!("Merge Node{} and Node{}", left_num, right_num);
+        let pins = [
+            self.table.pager.pin(left_num)?,
+            self.table.pager.pin(right_num)?,
+        ];
         let left = self.table.leaf_mut(left_num)?;
         let right = self.table.leaf_mut(right_num)?;
-        let right_key = right.get_first_key();
         let parent_num = right.get_parent();
         let left_cells = left.get_num_cells();
         let right_cells = right.get_num_cells();
-        assert!(left_cells + right_cells <= LEAF_NODE_MAX_CELLS);
+        if left_cells + right_cells > LEAF_NODE_MAX_CELLS {
+            return Err(SqlError::Corrupt {
+                page: right_num,
+                detail: format!(
+                    "{} cells do not fit beside the {} of leaf {}",
+                    right_cells, left_cells, left_num
+                ),
+            });
+        }
 
         for i in 0..right_cells {
-            let cell = right.cell(i).to_owned(); // TODO: slow owned
-            left.cell(left_cells + i).copy_from_slice(&cell);
+            left.copy_cell(left_cells + i, &right, i)?;
+        }
+        let next_leaf = right.get_next_leaf();
+        left.set_next_leaf(next_leaf);
+        if next_leaf != MISSING_NODE {
+            self.table.leaf_mut(next_leaf)?.set_prev_leaf(left_num);
         }
-        left.set_next_leaf(right.get_next_leaf());
-        left.set_num_cells(left_cells + right_cells);
-        // TODO: right_cells is already not used.
 
-        self.remove_key_from_internal(parent_num, right_key)
+        drop(pins);
+        self.set_counts(parent_num, &[left_num])?;
+        self.remove_key_from_internal(parent_num, right_num)?;
+        self.table.pager.free_page(right_num)
     }
 
-    fn remove_key_from_internal(&self, parent_num: usize, key: u64) -> SqlResult<()> {
-        println!("remove key {} from Node{}", key, parent_num);
+    /// Remove a child merged into the one before it from their parent, with
+    /// the key between them, balancing the parent
+    fn remove_key_from_internal(&self, parent_num: usize, child_num: usize) -> SqlResult<()> {
         let parent = self.table.internal_mut(parent_num)?;
-        let index = parent.find_key(key).unwrap();
-
+        let index = parent.find_child(child_num)?.ok_or(SqlError::CorruptFile)?;
         if index == 0 {
-            let before = parent.get_key_at(0);
-            let after = parent.get_key_at(1); // INFO: MIN KEYS >= 2
-            self.update_key_rec(parent_num, before, after)?;
+            // The first child has nothing before it to merge into
+            return Err(SqlError::CorruptFile);
         }
 
-        let num_keys = parent.get_num_keys();
-        for i in (index..num_keys - 1).rev() {
-            let key = parent.get_key_at(i + 1);
-            parent.set_key_at(i, key);
-            let child = parent.get_child_at(i + 1);
-            parent.set_child_at(i, child);
-        }
-        parent.set_num_keys(num_keys - 1);
+        parent.remove_child(index)?;
 
         self.balance_internal(parent_num)
     }
 
     fn balance_internal(&self, node_num: usize) -> SqlResult<()> {
-        println!("balance internal node {}", node_num);
-        let node = self.table.internal_mut(node_num).unwrap();
-        let num_keys = node.get_num_keys();
-        if num_keys >= INTERNAL_NODE_RIGHT_SPLIT_COUNT {
+        let node = self.table.internal_mut(node_num)?;
+        let num_children = node.get_num_children();
+        let limits = self.table.node_limits();
+        if num_children >= limits.internal_min_cells() {
             return Ok(());
         }
 
         if node.is_root() {
-            if num_keys == 1 {
-                let single_num = node.get_child_at(0);
+            if num_children == 1 {
+                let single_num = node.get_child_at(0)?;
                 self.table.set_root_num(single_num)?;
                 let single = self.table.pager.node(single_num)?;
                 single.set_parent(MISSING_NODE);
                 single.set_root(true);
-                // TODO: original root is not used anymore
+                self.table.pager.free_page(node_num)?;
             }
             return Ok(());
         }
 
-        let right_num = self.next_internal(node_num)?;
-        if right_num.is_none() {
-            let left_num = self.prev_internal(node_num)?;
-            if left_num.is_none() {
-                panic!("node {} is singleton?", node_num);
+        let parent = self.table.internal_mut(node.get_parent())?;
+        let index = parent.find_child(node_num)?.ok_or(SqlError::CorruptFile)?;
+        let mut children = node.children()?;
+        let mut keys = node.keys()?;
+
+        if index < parent.get_num_keys() {
+            // Not the last child, so the next node on the level is a sibling
+            let right_num = node.get_next_internal();
+            let right = self.table.internal_mut(right_num)?;
+            if num_children + right.get_num_children() <= limits.internal_max_cells() {
+                return self.merge_and_remove_internal(node_num, right_num);
             }
 
-            let left_num = left_num.unwrap();
-            let left = self.table.internal_mut(left_num)?;
-            let left_num_keys = left.get_num_keys();
-
-            if left_num_keys + num_keys <= INTERNAL_NODE_MAX_CELLS {
-                return self.merge_and_remove_internal(left_num, node_num);
-            }
-            // Shift Left ---> Node
-            for i in (1..left_num_keys).rev() {
-                let key = node.get_key_at(i - 1);
-                let child = node.get_child_at(i - 1);
-                node.set_key_at(i, key);
-                node.set_child_at(i, child);
-            }
-            let left_key = left.get_first_key();
-            let left_child = left.get_child_at(left_num_keys - 1);
-            node.set_key_at(0, left_key);
-            node.set_child_at(0, left_child);
-
-            node.set_num_keys(num_keys + 1);
-            left.set_num_keys(left_num_keys - 1);
+            // Shift node <-- right, through the key between them
+            let _pins = [
+                self.table.pager.pin(node_num)?,
+                self.table.pager.pin(right_num)?,
+            ];
+            let mut right_children = right.children()?;
+            let mut right_keys = right.keys()?;
+            let child = right_children.remove(0);
+            children.push(child);
+            keys.push(parent.get_key_at(index)?);
+            parent.set_key_at(index, right_keys.remove(0))?;
+            node.set_cells(&children, &keys)?;
+            right.set_cells(&right_children, &right_keys)?;
+            self.table.pager.node(child.0)?.set_parent(node_num);
+            return self.set_counts(parent.node.page_num, &[node_num, right_num]);
+        }
 
-            let before = node.get_key_at(1);
-            let after = node.get_key_at(0);
-            self.update_key_rec(node_num, before, after)?;
+        let index = index.checked_sub(1).ok_or(SqlError::CorruptFile)?;
+        let left_num = node.get_prev_internal();
+        let left = self.table.internal_mut(left_num)?;
+        if left.get_num_children() + num_children <= limits.internal_max_cells() {
+            return self.merge_and_remove_internal(left_num, node_num);
         }
 
-        let right_num = right_num.unwrap();
-        let right = self.table.internal_mut(right_num)?;
-        let right_num_keys = right.get_num_keys();
-        if num_keys + right_num_keys <= INTERNAL_NODE_MAX_CELLS {
-            return self.merge_and_remove_internal(node_num, right_num);
-        }
-
-        // Shift node <-- right
-        let before = right.get_key_at(0);
-        let after = right.get_key_at(1);
-        self.update_key_rec(right_num, before, after)?;
-
-        node.set_key_at(num_keys, right.get_key_at(0));
-        node.set_child_at(num_keys, right.get_child_at(0));
-        for i in 1..right_num_keys {
-            let key = right.get_key_at(i);
-            let child = right.get_child_at(i);
-            right.set_key_at(i - 1, key);
-            right.set_child_at(i - 1, child);
-        }
-        node.set_num_keys(num_keys + 1);
-        right.set_num_keys(right_num_keys - 1);
-        Ok(())
+        // Shift Left ---> Node, through the key between them
+        let _pins = [
+            self.table.pager.pin(left_num)?,
+            self.table.pager.pin(node_num)?,
+        ];
+        let mut left_children = left.children()?;
+        let mut left_keys = left.keys()?;
+        let child = left_children.pop().ok_or(SqlError::CorruptFile)?;
+        children.insert(0, child);
+        keys.insert(0, parent.get_key_at(index)?);
+        parent.set_key_at(index, left_keys.pop().ok_or(SqlError::CorruptFile)?)?;
+        node.set_cells(&children, &keys)?;
+        left.set_cells(&left_children, &left_keys)?;
+        self.table.pager.node(child.0)?.set_parent(node_num);
+        self.set_counts(parent.node.page_num, &[left_num, node_num])
     }
 
     fn merge_and_remove_internal(&self, left_num: usize, right_num: usize) -> SqlResult<()> {
+        let pins = [
+            self.table.pager.pin(left_num)?,
+            self.table.pager.pin(right_num)?,
+        ];
         let left = self.table.internal_mut(left_num)?;
         let right = self.table.internal_mut(right_num)?;
-        let left_num_keys = left.get_num_keys();
-        let right_num_keys = right.get_num_keys();
 
-        let right_key = right.get_first_key();
         let parent_num = right.get_parent();
-
-        // move right to left
-        left.set_num_keys(left_num_keys + right_num_keys);
-        for i in 0..right_num_keys {
-            let key = right.get_key_at(i);
-            let child = right.get_child_at(i);
-            left.set_key_at(left_num_keys + i, key);
-            left.set_child_at(left_num_keys + i, child);
+        let parent = self.table.internal_ref(parent_num)?;
+        let index = parent.find_child(right_num)?.ok_or(SqlError::CorruptFile)?;
+        let key = parent.get_key_at(index.checked_sub(1).ok_or(SqlError::CorruptFile)?)?;
+
+        // move right to left, through the key between them
+        let mut children = left.children()?;
+        let mut keys = left.keys()?;
+        let right_children = right.children()?;
+        keys.push(key);
+        keys.extend(right.keys()?);
+        children.extend(&right_children);
+        left.set_cells(&children, &keys)?;
+        for &(child, _) in &right_children {
+            self.table.pager.node(child)?.set_parent(left_num);
+        }
+        let next_num = right.get_next_internal();
+        left.set_next_internal(next_num);
+        if next_num != MISSING_NODE {
+            self.table
+                .internal_mut(next_num)?
+                .set_prev_internal(left_num);
         }
-        // TODO: right is not freed
 
-        self.remove_key_from_internal(parent_num, right_key)
+        drop(pins);
+        self.set_counts(parent_num, &[left_num])?;
+        self.remove_key_from_internal(parent_num, right_num)?;
+        self.table.pager.free_page(right_num)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::test::init_test_db;
+    use crate::{
+        node::NodeLimits,
+        pager::CacheStats,
+        table::{Row, ROW_SIZE},
+        test::init_test_db,
+    };
 
     #[test]
     fn test_insert() {
         let db = "test_insert";
         let mut table = init_test_db(db);
         let mut cursor = table.start().unwrap();
-        println!("{}", cursor.table.pager.node(0).unwrap());
-        cursor.insert(1, [1; ROW_SIZE]).unwrap();
-        println!("{}", cursor.table.pager.node(0).unwrap());
-        cursor.insert(2, [2; ROW_SIZE]).unwrap();
-        println!("{}", cursor.table.pager.node(0).unwrap());
+        println!("{}", cursor.table);
+        cursor.insert(1, &[1; ROW_SIZE]).unwrap();
+        println!("{}", cursor.table);
+        cursor.insert(2, &[2; ROW_SIZE]).unwrap();
+        println!("{}", cursor.table);
 
         let cursor = table.start().unwrap();
         let cursor_value = cursor.get().unwrap();
-        assert_eq!(cursor_value.get_key(), 2);
-        assert_eq!(*cursor_value.get_value(), vec![2; ROW_SIZE]);
+        assert_eq!(cursor_value.get_key().unwrap(), 2);
+        assert_eq!(*cursor_value.get_value().unwrap(), vec![2; ROW_SIZE]);
+    }
+    #[test]
+    fn update_while_reading() {
+        let db = "update_while_reading";
+        let mut table = init_test_db(db);
+        table.start().unwrap().insert(1, &[1; ROW_SIZE]).unwrap();
+
+        // Writing a row still borrowed for reading fails instead of panicking
+        let cursor = table.start().unwrap();
+        let value = cursor.get().unwrap();
+        let row = value.get_value().unwrap();
+        assert!(matches!(
+            cursor.update(&[2; ROW_SIZE]),
+            Err(SqlError::PageBusy)
+        ));
+        drop(row);
+        cursor.update(&[2; ROW_SIZE]).unwrap();
+        assert_eq!(*value.get_value().unwrap(), [2; ROW_SIZE]);
     }
     #[test]
     fn small_remove() {
         let db = "small_remove";
         let mut table = init_test_db(db);
         let mut cursor = table.start().unwrap();
-        cursor.insert(0, [1; ROW_SIZE]).unwrap();
+        cursor.insert(0, &[1; ROW_SIZE]).unwrap();
         cursor.advance().unwrap();
-        cursor.insert(1, [1; ROW_SIZE]).unwrap();
+        cursor.insert(1, &[1; ROW_SIZE]).unwrap();
         cursor.advance().unwrap();
-        cursor.insert(2, [1; ROW_SIZE]).unwrap();
+        cursor.insert(2, &[1; ROW_SIZE]).unwrap();
         println!("{}", cursor.table);
         let mut cursor = table.start().unwrap();
         cursor.advance().unwrap();
         cursor.remove().unwrap();
         println!("{}", cursor.table);
-        assert_eq!(cursor.get().unwrap().get_key(), 2);
+        assert_eq!(cursor.get().unwrap().get_key().unwrap(), 2);
         assert_eq!(
             cursor
                 .table
@@ -701,23 +975,665 @@ mod tests {
         println!("{}", cursor.table);
     }
     #[test]
+    fn splits_and_merges_in_tiny_cache() {
+        let db = "splits_and_merges_in_tiny_cache";
+        let mut table = init_test_db(db);
+        table.set_cache_size(Some(4)).unwrap();
+        // Changed pages are evicted too, as without a WAL
+        table.pager.keep_dirty.set(false);
+        for i in 0..60 {
+            table
+                .find(i)
+                .unwrap()
+                .insert(i, &[i as u8; ROW_SIZE])
+                .unwrap();
+        }
+        for i in 2..6 {
+            table.find(i).unwrap().remove().unwrap();
+        }
+        // Pages were evicted in between, but none held by an operation
+        assert!(table.pager.cache_stats().evictions > 0);
+        assert!((0..table.pager.num_pages.get()).all(|n| !table.pager.is_pinned(n)));
+        table.check().unwrap();
+        assert_eq!(table.len().unwrap(), 56);
+        for i in (0..2).chain(6..60) {
+            let cursor = table.find(i).unwrap();
+            assert_eq!(
+                *cursor.get().unwrap().get_value().unwrap(),
+                [i as u8; ROW_SIZE]
+            );
+        }
+    }
+    #[test]
+    fn remove_reads_one_path() {
+        let db = "remove_reads_one_path";
+        let mut table = init_test_db(db);
+        for i in 0..3000 {
+            table.find(i).unwrap().insert(i, &[0; ROW_SIZE]).unwrap();
+        }
+        table.set_cache_size(Some(8)).unwrap();
+        // Deleting must not visit every page to fix the row counts
+        for i in (0..3000).step_by(7) {
+            let before = table.pager.io_stats();
+            table.find(i).unwrap().remove().unwrap();
+            let reads = table.pager.io_stats().reads - before.reads;
+            assert!(reads < 16, "remove of {} read {} pages", i, reads);
+        }
+        table.check().unwrap();
+        assert_eq!(table.len().unwrap(), 3000 - 3000 / 7 - 1);
+    }
+    #[test]
+    fn reverse_scan() {
+        let db = "reverse_scan";
+        let mut table = init_test_db(db);
+        assert!(table.start_rev().unwrap().begin_of_table);
+        for i in 0..60 {
+            let key = i * 37 % 60;
+            table
+                .find(key)
+                .unwrap()
+                .insert(key, &[key as u8; ROW_SIZE])
+                .unwrap();
+        }
+        for i in (0..60).step_by(7) {
+            table.find(i).unwrap().remove().unwrap();
+        }
+
+        let mut forward = Vec::new();
+        let mut cursor = table.start().unwrap();
+        while !cursor.end_of_table {
+            forward.push(cursor.get_key().unwrap());
+            cursor.advance().unwrap();
+        }
+        let mut backward = Vec::new();
+        let mut cursor = table.start_rev().unwrap();
+        while !cursor.begin_of_table {
+            let value = cursor.get().unwrap();
+            assert_eq!(
+                *value.get_value().unwrap(),
+                [value.get_key().unwrap() as u8; ROW_SIZE]
+            );
+            backward.push(value.get_key().unwrap());
+            cursor.retreat().unwrap();
+        }
+        backward.reverse();
+        assert_eq!(forward.len(), 51);
+        assert_eq!(backward, forward);
+    }
+    #[test]
     fn leaf_balance() {
         let db = "leaf_balance";
         let mut table = init_test_db(db);
         let rows = vec![0, 4, 5, 6, 3, 2, 1];
         for i in rows {
             table
-                .find(i as u64)
+                .find(i as i64)
                 .unwrap()
-                .insert(i as u64, [i as u8; ROW_SIZE])
+                .insert(i as i64, &[i as u8; ROW_SIZE])
                 .unwrap();
         }
         println!("{}", table);
 
         let removes = vec![1, 2, 5, 6, 3];
         for i in removes {
-            table.find(i as u64).unwrap().remove().unwrap();
+            table.find(i as i64).unwrap().remove().unwrap();
             println!("### {} ###\n{}", i, table);
         }
     }
+    #[test]
+    fn borrow_from_left() {
+        let db = "borrow_from_left";
+        let mut table = init_test_db(db);
+        // The last key first, so that no split appends to the last leaf
+        for key in [50, 0, 10, 20, 30, 40, 11, 12, 21, 22] {
+            table
+                .find(key)
+                .unwrap()
+                .insert(key, &[1; ROW_SIZE])
+                .unwrap();
+        }
+        // The last leaf is left with one cell, next to a full leaf
+        table.find(50).unwrap().remove().unwrap();
+        table.find(30).unwrap().remove().unwrap();
+
+        let root = table.internal_ref(table.get_root_num().unwrap()).unwrap();
+        assert_eq!(root.keys().unwrap(), [12, 22]);
+        let last = table.leaf_ref(root.get_child_at(2).unwrap()).unwrap();
+        assert_eq!(last.get_key(0).unwrap(), 22);
+        assert_eq!(last.get_num_cells(), 2);
+        table.check().unwrap();
+        for key in [0, 10, 11, 12, 20, 21, 22, 40] {
+            assert!(table.find(key).unwrap().check_key(key).unwrap(), "{}", key);
+        }
+    }
+    #[test]
+    fn borrow_from_right() {
+        let db = "borrow_from_right";
+        let mut table = init_test_db(db);
+        // The last key first, so that no split appends to the last leaf
+        for key in [50, 0, 10, 20, 30, 40, 11, 12, 21, 22] {
+            table
+                .find(key)
+                .unwrap()
+                .insert(key, &[1; ROW_SIZE])
+                .unwrap();
+        }
+        // The first leaf is left with one cell, next to a full leaf
+        table.find(0).unwrap().remove().unwrap();
+        table.find(10).unwrap().remove().unwrap();
+
+        let root = table.internal_ref(table.get_root_num().unwrap()).unwrap();
+        assert_eq!(root.keys().unwrap(), [20, 30]);
+        let right = table.leaf_ref(root.get_child_at(1).unwrap()).unwrap();
+        assert_eq!(right.get_num_cells(), 3);
+        table.check().unwrap();
+        for key in [11, 12, 20, 21, 22, 30, 40, 50] {
+            assert!(table.find(key).unwrap().check_key(key).unwrap(), "{}", key);
+        }
+    }
+    #[test]
+    fn split_under_loose_key() {
+        let db = "split_under_loose_key";
+        let mut table = init_test_db(db);
+        for key in [50, 0, 10, 20, 30, 40, 11, 12] {
+            table
+                .find(key)
+                .unwrap()
+                .insert(key, &[1; ROW_SIZE])
+                .unwrap();
+        }
+        // Any key after the last one of a leaf separates it from the next
+        let root = table.internal_mut(table.get_root_num().unwrap()).unwrap();
+        assert_eq!(root.keys().unwrap(), [12, 30]);
+        root.set_key_at(1, 25).unwrap();
+        for key in [1, 2, 26] {
+            table
+                .find(key)
+                .unwrap()
+                .insert(key, &[1; ROW_SIZE])
+                .unwrap();
+        }
+
+        let root = table.internal_ref(table.get_root_num().unwrap()).unwrap();
+        assert_eq!(root.keys().unwrap(), [10, 12, 25]);
+        table.check().unwrap();
+        for key in [0, 1, 2, 10, 11, 12, 26, 30, 40, 50] {
+            assert!(table.find(key).unwrap().check_key(key).unwrap(), "{}", key);
+        }
+    }
+    #[test]
+    fn rightmost_internal_underflow() {
+        let db = "rightmost_internal_underflow";
+        let mut table = init_test_db(db);
+        let mut keys = (0..32).map(|k| k * 10).collect::<Vec<_>>();
+        keys.push(125);
+        for &key in &keys {
+            table
+                .find(key)
+                .unwrap()
+                .insert(key, &[1; ROW_SIZE])
+                .unwrap();
+        }
+        let root_num = table.get_root_num().unwrap();
+        let internal_keys = |table: &Table| {
+            let root = table.internal_ref(root_num).unwrap();
+            let mut keys = vec![root.keys().unwrap()];
+            for (child, _) in root.children().unwrap() {
+                keys.push(table.internal_ref(child).unwrap().keys().unwrap());
+            }
+            keys
+        };
+        assert_eq!(
+            internal_keys(&table),
+            [vec![120, 240], vec![40, 80], vec![140, 160, 200], vec![280]]
+        );
+
+        // The last internal node takes a child from the one before it
+        for key in [310, 300, 290, 280, 270, 260, 250, 240] {
+            table.find(key).unwrap().remove().unwrap();
+            keys.retain(|&k| k != key);
+            table.check().unwrap();
+            if key == 280 {
+                assert_eq!(
+                    internal_keys(&table),
+                    [vec![120, 200], vec![40, 80], vec![140, 160], vec![240]]
+                );
+            }
+        }
+        // and then merges into it
+        assert_eq!(
+            internal_keys(&table),
+            [vec![120], vec![40, 80], vec![140, 160, 200]]
+        );
+        for &key in &keys {
+            assert!(table.find(key).unwrap().check_key(key).unwrap(), "{}", key);
+        }
+    }
+    #[test]
+    fn single_cell_leaves() {
+        let db = "single_cell_leaves";
+        let mut table = init_test_db(db);
+        table.set_node_limits(NodeLimits::new(3, 3).unwrap());
+        // A long value outweighs the short ones after it, and splits off
+        // into a leaf of its own
+        let long = vec![1; max_inline_value(table.pager.page_size)];
+        let value = |key: i64| if key % 4 == 0 { &long[..] } else { &[2][..] };
+        // The last key first, so that no split appends to the last leaf
+        for key in std::iter::once(23).chain(0..23) {
+            table.find(key).unwrap().insert(key, value(key)).unwrap();
+        }
+        table.check().unwrap();
+        let first = table.start().unwrap().page_num;
+        assert_eq!(table.leaf_ref(first).unwrap().get_num_cells(), 1);
+
+        // Deleting from the left edge empties single-cell leaves
+        for key in 0..24 {
+            table.find(key).unwrap().remove().unwrap();
+            table.check().unwrap();
+            for other in key + 1..24 {
+                let cursor = table.find(other).unwrap();
+                assert_eq!(*cursor.get().unwrap().get_value().unwrap(), *value(other));
+            }
+        }
+        let root = table.get_root_num().unwrap();
+        assert_eq!(table.leaf_ref(root).unwrap().get_num_cells(), 0);
+    }
+    #[test]
+    fn split_every_position() {
+        let value = |key: i64| vec![key as u8; (key * 37).rem_euclid(100) as usize];
+        let cell = |key: i64| inline_cell(key, &value(key));
+        for pos in 0..=6 {
+            let db = format!("split_every_position_{}", pos);
+            let mut table = init_test_db(&db);
+            table.set_node_limits(NodeLimits::new(6, 4).unwrap());
+            for key in (0..6).map(|k| k * 10) {
+                table.find(key).unwrap().insert(key, &value(key)).unwrap();
+            }
+            let new_key = pos * 10 - 5;
+            table
+                .find(new_key)
+                .unwrap()
+                .insert(new_key, &value(new_key))
+                .unwrap();
+            table.check().unwrap();
+
+            // The leaves hold the cells in order, split where the cells
+            // taken one by one would be
+            let mut keys = (0..6).map(|k| k * 10).collect::<Vec<_>>();
+            keys.insert(pos as usize, new_key);
+            let lens = keys.iter().map(|&key| cell(key).len()).collect::<Vec<_>>();
+            // Appending to the last leaf leaves it full
+            let left_count = if pos == 6 {
+                6
+            } else {
+                table.start().unwrap().split_point(&lens)
+            };
+            let root = table.internal_ref(table.get_root_num().unwrap()).unwrap();
+            let leaves = root.children().unwrap();
+            assert_eq!(leaves.len(), 2);
+            let expected = [&keys[..left_count], &keys[left_count..]];
+            for ((page_num, count), keys) in leaves.into_iter().zip(expected) {
+                let leaf = table.leaf_ref(page_num).unwrap();
+                assert_eq!(count, keys.len());
+                let cells = (0..leaf.get_num_cells())
+                    .map(|i| leaf.get_cell(i).unwrap())
+                    .collect::<Vec<_>>();
+                assert_eq!(cells, keys.iter().map(|&key| cell(key)).collect::<Vec<_>>());
+            }
+        }
+
+        // A leaf split at each child of a full internal node splits it too
+        for child in 0..4 {
+            let db = format!("split_every_position_internal_{}", child);
+            let mut table = init_test_db(&db);
+            table.set_node_limits(NodeLimits::new(3, 4).unwrap());
+            let mut key = 0;
+            let root_children = |table: &Table| match table
+                .pager
+                .node(table.get_root_num().unwrap())
+                .unwrap()
+                .as_typed()
+                .unwrap()
+            {
+                NodeRef::Internal(root) => root.children().unwrap(),
+                NodeRef::Leaf(_) => Vec::new(),
+            };
+            while root_children(&table).len() < 4 {
+                table.find(key).unwrap().insert(key, &[1]).unwrap();
+                key += 10;
+            }
+            let leaf_num = root_children(&table)[child].0;
+            let first = table.leaf_ref(leaf_num).unwrap().get_key(0).unwrap();
+            let mut key = first + 1;
+            while root_children(&table).len() == 4 {
+                table.find(key).unwrap().insert(key, &[2]).unwrap();
+                key += 1;
+            }
+            table.check().unwrap();
+            let halves = root_children(&table);
+            assert_eq!(halves.len(), 2);
+            let sizes = halves
+                .iter()
+                .map(|&(page_num, _)| table.internal_ref(page_num).unwrap().get_num_children())
+                .collect::<Vec<_>>();
+            assert_eq!(sizes, [3, 2]);
+            let mut cursor = table.start().unwrap();
+            let mut keys = Vec::new();
+            while !cursor.end_of_table {
+                keys.push(cursor.get_key().unwrap());
+                cursor.advance().unwrap();
+            }
+            assert!(keys.windows(2).all(|w| w[0] < w[1]));
+            assert_eq!(keys.len(), table.len().unwrap());
+        }
+    }
+    #[test]
+    fn keys_of_emptied_nodes() {
+        let db = "keys_of_emptied_nodes";
+        let mut table = init_test_db(db);
+        let internal_keys = |table: &Table| {
+            let mut keys = Vec::new();
+            let mut nodes = vec![table.get_root_num().unwrap()];
+            while let Some(node_num) = nodes.pop() {
+                if let NodeRef::Internal(internal) =
+                    table.pager.node(node_num).unwrap().as_typed().unwrap()
+                {
+                    keys.extend(internal.keys().unwrap());
+                    nodes.extend(internal.children().unwrap().iter().map(|&(child, _)| child));
+                }
+            }
+            keys
+        };
+        // Deleting every row empties leaves and collapses internal nodes down
+        // to an empty root leaf, and no key is made up on the way
+        let keys = (0..40).map(|i| i * 7 % 40 + 1).collect::<Vec<_>>();
+        for round in 0..2 {
+            for &key in &keys {
+                table
+                    .insert(Row::try_new(key, "a", "a@a").unwrap())
+                    .unwrap();
+            }
+            for &key in keys.iter().rev() {
+                table.delete(key).unwrap();
+                table.check().unwrap();
+                assert!(
+                    internal_keys(&table).iter().all(|key| keys.contains(key)),
+                    "round {} after {}: {:?}",
+                    round,
+                    key,
+                    internal_keys(&table)
+                );
+            }
+            let root = table.leaf_ref(table.get_root_num().unwrap()).unwrap();
+            assert_eq!(root.get_first_key().unwrap(), None);
+        }
+    }
+    #[test]
+    fn collapse_levels() {
+        let db = "collapse_levels";
+        let mut table = init_test_db(db);
+        for key in 0..32 {
+            table
+                .find(key)
+                .unwrap()
+                .insert(key, &[1; ROW_SIZE])
+                .unwrap();
+        }
+        let depth = |table: &Table| {
+            let mut depth = 1;
+            let mut page_num = table.get_root_num().unwrap();
+            while let NodeRef::Internal(internal) =
+                table.pager.node(page_num).unwrap().as_typed().unwrap()
+            {
+                page_num = internal.get_child_at(0).unwrap();
+                depth += 1;
+            }
+            depth
+        };
+        // The last internal node starts with a single key
+        let root = table.internal_ref(table.get_root_num().unwrap()).unwrap();
+        let last = root.get_child_at(root.get_num_keys()).unwrap();
+        assert_eq!(table.internal_ref(last).unwrap().get_num_keys(), 1);
+        assert_eq!(depth(&table), 3);
+
+        let mut depths = vec![3];
+        for key in (0..32).rev() {
+            table.find(key).unwrap().remove().unwrap();
+            table.check().unwrap();
+            if depths.last() != Some(&depth(&table)) {
+                depths.push(depth(&table));
+            }
+            for other in 0..key {
+                assert!(table.find(other).unwrap().check_key(other).unwrap());
+            }
+        }
+        assert_eq!(depths, [3, 2, 1]);
+        assert_eq!(table.len().unwrap(), 0);
+    }
+    #[test]
+    fn internal_siblings() {
+        let db = "internal_siblings";
+        let mut table = init_test_db(db);
+        for i in 0..64 {
+            let key = i * 37 % 64 * 100;
+            table.find(key).unwrap().insert(key, &[1]).unwrap();
+        }
+        // The parents of the leaves, following the links from the first one
+        let level = |table: &mut Table| {
+            let leaf_num = table.start().unwrap().page_num;
+            let mut node_num = table.pager.node(leaf_num).unwrap().get_parent();
+            assert_eq!(
+                table.internal_ref(node_num).unwrap().get_prev_internal(),
+                MISSING_NODE
+            );
+            let mut nodes = Vec::new();
+            while node_num != MISSING_NODE {
+                nodes.push(node_num);
+                node_num = table.internal_ref(node_num).unwrap().get_next_internal();
+            }
+            nodes
+        };
+        let first_key = |table: &Table, node_num: usize| {
+            let child = table
+                .internal_ref(node_num)
+                .unwrap()
+                .get_child_at(0)
+                .unwrap();
+            table.leaf_ref(child).unwrap().get_key(0).unwrap()
+        };
+        let picks: [fn(&[usize]) -> usize; 3] = [
+            |nodes| nodes[0],
+            |nodes| nodes[nodes.len() / 2],
+            |nodes| nodes[nodes.len() - 1],
+        ];
+        assert!(level(&mut table).len() >= 3);
+
+        // Rows added under the first, a middle and the last node split it
+        for pick in picks {
+            let nodes = level(&mut table);
+            let mut key = first_key(&table, pick(&nodes)) + 1;
+            while level(&mut table).len() == nodes.len() {
+                table.find(key).unwrap().insert(key, &[2]).unwrap();
+                key += 1;
+            }
+            table.check().unwrap();
+            assert_eq!(level(&mut table).len(), nodes.len() + 1);
+        }
+
+        // Rows taken from under them merge them with a neighbour
+        for pick in picks {
+            let nodes = level(&mut table);
+            while level(&mut table).len() == nodes.len() {
+                let node_num = pick(&level(&mut table));
+                let key = first_key(&table, node_num);
+                table.find(key).unwrap().remove().unwrap();
+                table.check().unwrap();
+            }
+            assert_eq!(level(&mut table).len(), nodes.len() - 1);
+        }
+    }
+    #[test]
+    fn seek_matches_find() {
+        let db = "seek_matches_find";
+        let mut table = init_test_db(db);
+        for i in 0..1000 {
+            let key = i * 389 % 1000 * 3;
+            table.find(key).unwrap().insert(key, &[1]).unwrap();
+        }
+        table.set_cache_size(Some(8)).unwrap();
+        // Keys on and between the rows, and past both ends
+        let probes = (0..3000)
+            .map(|i: i64| (i * 7919 % 3100) - 50)
+            .collect::<Vec<_>>();
+        let expected = probes
+            .iter()
+            .map(|&key| {
+                let cursor = table.find(key).unwrap();
+                (cursor.page_num, cursor.cell_num)
+            })
+            .collect::<Vec<_>>();
+        let mut cursor = table.start().unwrap();
+        for (&key, &expected) in probes.iter().zip(&expected) {
+            cursor.seek(key).unwrap();
+            assert_eq!((cursor.page_num, cursor.cell_num), expected, "{}", key);
+        }
+
+        // Keys of the same leaf only read the leaf
+        let accesses = |cursor: &Cursor, before: &CacheStats| {
+            let stats = cursor.table.pager.cache_stats().since(before);
+            (stats.hits + stats.misses, stats.misses)
+        };
+        cursor.seek(1500).unwrap();
+        let (first, last) = cursor.key_range().unwrap().unwrap();
+        assert!(first <= 1500 && 1500 <= last);
+        for key in first..=last {
+            let before = cursor.table.pager.cache_stats();
+            cursor.seek(key).unwrap();
+            assert_eq!(accesses(&cursor, &before), (1, 0));
+            assert_eq!(cursor.check_key(key).unwrap(), key % 3 == 0);
+        }
+        let before = cursor.table.pager.cache_stats();
+        cursor.seek(last + 100).unwrap();
+        assert!(accesses(&cursor, &before).0 > 1);
+    }
+    #[test]
+    fn stale_cursor() {
+        let db = "stale_cursor";
+        let mut table = init_test_db(db);
+        for key in (0..8).map(|k| k * 10) {
+            table.find(key).unwrap().insert(key, &[1]).unwrap();
+        }
+        let mut cursor = table.find(20).unwrap();
+        let leaf_num = cursor.page_num;
+        let next_leaf = |cursor: &Cursor| cursor.table.leaf_ref(leaf_num).unwrap().get_next_leaf();
+        let first_next = next_leaf(&cursor);
+        // Rows inserted through the table split the leaf of the cursor
+        let mut key = 21;
+        while next_leaf(&cursor) == first_next {
+            let row = Row::try_new(key, "a", "b").unwrap();
+            cursor.table.insert(row).unwrap();
+            key += 1;
+        }
+        let stale = |result: SqlResult<()>| matches!(result, Err(SqlError::CursorInvalidated));
+        assert!(matches!(cursor.get(), Err(SqlError::CursorInvalidated)));
+        assert!(stale(cursor.get_key().map(|_| ())));
+        assert!(stale(cursor.advance()));
+        assert!(stale(cursor.insert(25, &[2])));
+        assert!(stale(cursor.update(&[2])));
+        assert!(stale(cursor.remove()));
+        assert_eq!(cursor.table.len().unwrap(), 8 + (key - 21) as usize);
+
+        // Seeking places it again
+        cursor.seek(20).unwrap();
+        assert_eq!(cursor.get_key().unwrap(), 20);
+        cursor.advance().unwrap();
+        assert_eq!(cursor.get_key().unwrap(), 21);
+
+        // Its own change keeps it placed while the cells stay in the leaf
+        cursor.seek(0).unwrap();
+        cursor.remove().unwrap();
+        assert_eq!(cursor.get_key().unwrap(), 10);
+        let leaf = cursor.table.leaf_ref(cursor.page_num).unwrap();
+        assert!(leaf.get_num_cells() < NodeLimits::TINY.leaf_max_cells());
+        drop(leaf);
+        cursor.insert(5, &[1]).unwrap();
+        assert_eq!(cursor.get_key().unwrap(), 5);
+        cursor.advance().unwrap();
+        assert_eq!(cursor.get_key().unwrap(), 10);
+        table.check().unwrap();
+    }
+    #[test]
+    fn split_after_internal_merge() {
+        let db = "split_after_internal_merge";
+        let mut table = init_test_db(db);
+        for i in 0..64 {
+            let key = i * 37 % 64 * 10;
+            table.find(key).unwrap().insert(key, &[1]).unwrap();
+        }
+        let num_internals = |table: &Table| {
+            let mut count = 0;
+            let mut nodes = vec![table.get_root_num().unwrap()];
+            while let Some(node_num) = nodes.pop() {
+                if let NodeRef::Internal(internal) =
+                    table.pager.node(node_num).unwrap().as_typed().unwrap()
+                {
+                    count += 1;
+                    nodes.extend(internal.children().unwrap().iter().map(|&(child, _)| child));
+                }
+            }
+            count
+        };
+        // Rows taken from the middle until two internal nodes merge
+        let before = num_internals(&table);
+        let mut key = 300;
+        while num_internals(&table) == before {
+            table.find(key).unwrap().remove().unwrap();
+            key += 10;
+        }
+        table.check().unwrap();
+
+        // Rows added to every leaf split it, under whichever node it moved to
+        let mut firsts = Vec::new();
+        let mut leaf_num = table.start().unwrap().page_num;
+        while leaf_num != MISSING_NODE {
+            let leaf = table.leaf_ref(leaf_num).unwrap();
+            firsts.push(leaf.get_key(0).unwrap());
+            leaf_num = leaf.get_next_leaf();
+        }
+        for first in firsts {
+            let leaves = table.tree_stats().unwrap().leaves;
+            let mut key = first + 1;
+            while table.tree_stats().unwrap().leaves == leaves {
+                table.find(key).unwrap().insert(key, &[2]).unwrap();
+                key += 1;
+            }
+            table.check().unwrap();
+        }
+
+        // A child pointing at another node fails the check
+        let root = table.internal_ref(table.get_root_num().unwrap()).unwrap();
+        let child = root.get_child_at(1).unwrap();
+        let other = root.get_child_at(0).unwrap();
+        table.pager.node(child).unwrap().set_parent(other);
+        assert!(matches!(table.check(), Err(SqlError::CheckFailed(_))));
+    }
+    #[test]
+    fn merge_overfull_leaves() {
+        let db = "merge_overfull_leaves";
+        let mut table = init_test_db(db);
+        for key in 0..16 {
+            table.find(key).unwrap().insert(key, &[1]).unwrap();
+        }
+        let left_num = table.start().unwrap().page_num;
+        let right_num = table.leaf_ref(left_num).unwrap().get_next_leaf();
+        assert_ne!(right_num, MISSING_NODE);
+        // A cell count past what the page holds is reported, not asserted
+        table
+            .leaf_mut(right_num)
+            .unwrap()
+            .set_num_cells(LEAF_NODE_MAX_CELLS);
+        let cursor = table.start().unwrap();
+        let result = cursor.merge_and_remove(left_num, right_num);
+        assert!(matches!(result, Err(SqlError::Corrupt { page, .. }) if page == right_num));
+    }
 }
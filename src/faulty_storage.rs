@@ -0,0 +1,415 @@
+//! Storage wrapper that injects failures, for durability tests.
+//! Clones share the wrapped storage and the programmed faults, so a test can
+//! keep a handle while the table owns the other.
+use std::{cell::RefCell, io, rc::Rc};
+
+use crate::{
+    pager::DEFAULT_PAGE_SIZE,
+    sql_error::{SqlError, SqlResult},
+    storage::Storage,
+};
+
+#[derive(Default)]
+struct Faults {
+    writes: usize,
+    syncs: usize,
+    fail_write: Option<usize>,
+    full_after: Option<usize>,
+    crash_after: Option<usize>,
+    tear: Option<(usize, usize)>,
+    short_read: Option<usize>,
+}
+
+pub struct FaultyStorage<S> {
+    inner: Rc<RefCell<S>>,
+    faults: Rc<RefCell<Faults>>,
+}
+
+impl<S> Clone for FaultyStorage<S> {
+    fn clone(&self) -> Self {
+        FaultyStorage {
+            inner: self.inner.clone(),
+            faults: self.faults.clone(),
+        }
+    }
+}
+
+impl<S: Storage> FaultyStorage<S> {
+    pub fn new(inner: S) -> Self {
+        FaultyStorage {
+            inner: Rc::new(RefCell::new(inner)),
+            faults: Rc::new(RefCell::new(Faults::default())),
+        }
+    }
+
+    /// Fail the `n`th write from now, counting from 1
+    pub fn fail_nth_write(&self, n: usize) {
+        let mut faults = self.faults.borrow_mut();
+        faults.fail_write = Some(faults.writes + n);
+    }
+
+    /// Run out of space after `n` more writes: every write from then on fails,
+    /// as does growing the storage, until `free_space` is called
+    pub fn fill_disk_after(&self, n: usize) {
+        let mut faults = self.faults.borrow_mut();
+        faults.full_after = Some(faults.writes + n);
+    }
+
+    /// Make room again after `fill_disk_after`
+    pub fn free_space(&self) {
+        self.faults.borrow_mut().full_after = None;
+    }
+
+    /// Pretend the process crashed after `n` more writes: later writes report
+    /// success but never reach the wrapped storage
+    pub fn crash_after_writes(&self, n: usize) {
+        let mut faults = self.faults.borrow_mut();
+        faults.crash_after = Some(faults.writes + n);
+    }
+
+    /// Pretend the process crashed partway through the `n`th write from now:
+    /// only its first `len` bytes reach the wrapped storage, and no later write
+    pub fn tear_nth_write(&self, n: usize, len: usize) {
+        let mut faults = self.faults.borrow_mut();
+        faults.tear = Some((faults.writes + n, len));
+        faults.crash_after = Some(faults.writes + n);
+    }
+
+    /// Read only the first `len` bytes of each page, as if the file ended
+    /// there, or whole pages with None
+    pub fn short_reads(&self, len: Option<usize>) {
+        self.faults.borrow_mut().short_read = len;
+    }
+
+    /// Number of writes so far, including failed ones
+    pub fn writes(&self) -> usize {
+        self.faults.borrow().writes
+    }
+
+    /// Number of syncs so far
+    pub fn syncs(&self) -> usize {
+        self.faults.borrow().syncs
+    }
+
+    /// Bytes that actually reached the wrapped storage
+    pub fn snapshot(&self) -> SqlResult<Vec<u8>> {
+        let mut inner = self.inner.borrow_mut();
+        let mut data = vec![0; inner.len()?];
+        inner.read_page(0, &mut data)?;
+        Ok(data)
+    }
+}
+
+impl<S: Storage> Storage for FaultyStorage<S> {
+    fn read_page(&mut self, page_num: usize, buf: &mut [u8]) -> SqlResult<usize> {
+        let short_read = self.faults.borrow().short_read;
+        match short_read {
+            Some(len) => {
+                let mut full = vec![0; buf.len()];
+                let read = self.inner.borrow_mut().read_page(page_num, &mut full)?;
+                let len = len.min(read);
+                buf[0..len].copy_from_slice(&full[0..len]);
+                Ok(len)
+            }
+            None => self.inner.borrow_mut().read_page(page_num, buf),
+        }
+    }
+    fn write_page(&mut self, page_num: usize, buf: &[u8]) -> SqlResult<()> {
+        let mut faults = self.faults.borrow_mut();
+        faults.writes += 1;
+        if faults.fail_write == Some(faults.writes) {
+            let e = io::Error::other(format!("injected failure writing page {}", page_num));
+            return Err(SqlError::IOError(e, "Failed to write".to_string()));
+        }
+        if faults.full_after.is_some_and(|n| faults.writes > n) {
+            let e = io::Error::from(io::ErrorKind::StorageFull);
+            return Err(SqlError::IOError(e, "Failed to write".to_string()));
+        }
+        if faults.crash_after.is_some_and(|n| faults.writes > n) {
+            return Ok(());
+        }
+        if let Some((_, len)) = faults.tear.filter(|&(n, _)| n == faults.writes) {
+            let mut inner = self.inner.borrow_mut();
+            let mut torn = vec![0; buf.len()];
+            inner.read_page(page_num, &mut torn)?;
+            torn[..len].copy_from_slice(&buf[..len]);
+            return inner.write_page(page_num, &torn);
+        }
+        self.inner.borrow_mut().write_page(page_num, buf)
+    }
+    fn len(&self) -> SqlResult<usize> {
+        self.inner.borrow().len()
+    }
+    fn sync(&mut self) -> SqlResult<()> {
+        self.faults.borrow_mut().syncs += 1;
+        self.inner.borrow_mut().sync()
+    }
+    fn set_len(&mut self, len: usize) -> SqlResult<()> {
+        let faults = self.faults.borrow();
+        let full = faults.full_after.is_some_and(|n| faults.writes >= n);
+        if full && len > self.inner.borrow().len()? {
+            let e = io::Error::from(io::ErrorKind::StorageFull);
+            return Err(SqlError::IOError(e, "Failed to truncate".to_string()));
+        }
+        self.inner.borrow_mut().set_len(len)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        commands::prepare_statement,
+        meta::{META_HEADER_SIZE, META_NODE_NUM},
+        storage::MemoryStorage,
+        table::{Durability, Row, Table},
+    };
+
+    fn insert_rows(table: &mut Table, count: i64) {
+        for i in 0..count {
+            let row = Row::try_new(i, &format!("name{}", i), &format!("{}@a", i)).unwrap();
+            table.insert(row).unwrap();
+        }
+    }
+
+    #[test]
+    fn close_reports_unwritten_pages() {
+        let storage = FaultyStorage::new(MemoryStorage::new());
+        let mut table = Table::open_with(storage.clone()).unwrap();
+        insert_rows(&mut table, 10);
+        table.close().unwrap();
+        drop(table);
+
+        // Deleting a row changes the meta page, the root and one leaf
+        let mut table = Table::open_with(storage.clone()).unwrap();
+        table.delete(3).unwrap();
+        let dirty = (0..table.pager.num_pages.get())
+            .filter(|&n| table.pager.is_dirty(n))
+            .collect::<Vec<_>>();
+        assert_eq!(dirty.len(), 3);
+        assert_eq!(dirty[0], META_NODE_NUM);
+        let before = storage.snapshot().unwrap();
+        // Consecutive pages go in one write and fail together
+        let first_write = dirty[1..]
+            .chunk_by(|a, b| a + 1 == *b)
+            .next()
+            .unwrap()
+            .to_vec();
+
+        storage.fail_nth_write(1);
+        match table.close() {
+            Err(SqlError::CloseIncomplete(failures)) => {
+                let pages = failures.iter().map(|(page, _)| *page).collect::<Vec<_>>();
+                assert_eq!(&pages[..first_write.len()], first_write);
+                // The meta page is held back, written last once the rest are
+                assert_eq!(pages.last(), Some(&META_NODE_NUM));
+            }
+            _ => panic!("expected CloseIncomplete"),
+        }
+        let written = storage.snapshot().unwrap();
+        assert_eq!(&written[..DEFAULT_PAGE_SIZE], &before[..DEFAULT_PAGE_SIZE]);
+
+        // Only the failed pages stay dirty, so closing again writes them
+        assert!(table.pager.is_dirty(META_NODE_NUM));
+        assert_eq!(table.pager.resident_pages(), first_write.len() + 1);
+        table.close().unwrap();
+        drop(table);
+        let mut table = Table::open_with(storage).unwrap();
+        assert_eq!(table.len().unwrap(), 9);
+        table.check().unwrap();
+    }
+
+    #[test]
+    fn disk_full_then_retry() {
+        let storage = FaultyStorage::new(MemoryStorage::new());
+        let mut table = Table::open_with(storage.clone()).unwrap();
+        insert_rows(&mut table, 10);
+        table.close().unwrap();
+        drop(table);
+
+        // The disk fills up partway through writing the new pages
+        let mut table = Table::open_with(storage.clone()).unwrap();
+        for i in 10..60 {
+            let row = Row::try_new(i, &format!("name{}", i), &format!("{}@a", i)).unwrap();
+            table.insert(row).unwrap();
+        }
+        storage.fill_disk_after(2);
+        let failures = match table.close() {
+            Err(SqlError::CloseIncomplete(failures)) => failures,
+            _ => panic!("expected CloseIncomplete"),
+        };
+        assert!(matches!(
+            &failures[0].1,
+            SqlError::IOError(e, _) if e.kind() == io::ErrorKind::StorageFull
+        ));
+        for (page_num, _) in &failures {
+            assert!(table.pager.is_dirty(*page_num));
+        }
+        assert_eq!(failures.last().unwrap().0, META_NODE_NUM);
+        // What was written so far is not taken for the whole database
+        let partial = MemoryStorage::from_bytes(storage.snapshot().unwrap());
+        assert_eq!(Table::open_with(partial).unwrap().len().unwrap(), 10);
+
+        // Nothing is lost, and once there is room again the pages go out
+        assert_eq!(table.len().unwrap(), 60);
+        assert!(table.sync().is_err());
+        storage.free_space();
+        table.sync().unwrap();
+        table.close().unwrap();
+        drop(table);
+
+        let mut table = Table::open_with(storage).unwrap();
+        assert_eq!(table.len().unwrap(), 60);
+        assert_eq!(table.get(42).unwrap().unwrap().name_str(), "name42");
+        table.check().unwrap();
+    }
+
+    #[test]
+    fn only_changed_pages_are_written() {
+        let storage = FaultyStorage::new(MemoryStorage::new());
+        let mut table = Table::open_with(storage.clone()).unwrap();
+        insert_rows(&mut table, 50);
+        table.delete(0).unwrap();
+        table.close().unwrap();
+        drop(table);
+
+        // Reading every row writes nothing back
+        let writes = storage.writes();
+        let mut table = Table::open_with(storage.clone()).unwrap();
+        let rows = prepare_statement("select")
+            .unwrap()
+            .execute(&mut table)
+            .unwrap();
+        assert_eq!(rows.len(), 49);
+        table.close().unwrap();
+        drop(table);
+        assert_eq!(storage.writes(), writes);
+
+        // Inserting into a leaf with room writes the leaf, the internal nodes
+        // above it counting its rows and the meta page counting every row
+        let mut table = Table::open_with(storage.clone()).unwrap();
+        let row = Row::try_new(0, "name0", "0@a").unwrap();
+        table.insert(row).unwrap();
+        let leaf = table.find(0).unwrap().page_num;
+        let dirty = (0..table.pager.num_pages.get())
+            .filter(|&n| table.pager.is_dirty(n))
+            .collect::<Vec<_>>();
+        assert!(dirty.contains(&META_NODE_NUM) && dirty.contains(&leaf));
+        assert!(dirty.contains(&table.get_root_num().unwrap()));
+        for &page_num in &dirty {
+            if page_num != META_NODE_NUM && page_num != leaf {
+                assert!(!table.pager.node(page_num).unwrap().is_leaf());
+            }
+        }
+        assert!(dirty.len() * 4 < table.pager.num_pages.get());
+        table.close().unwrap();
+        assert_eq!(storage.writes(), writes + dirty.len());
+    }
+
+    #[test]
+    fn durability_syncs() {
+        for (durability, on_flush, on_close) in [
+            (Durability::Off, 0, 0),
+            (Durability::OnClose, 0, 1),
+            (Durability::OnCommit, 1, 1),
+        ] {
+            let storage = FaultyStorage::new(MemoryStorage::new());
+            let mut table = Table::open_with(storage.clone()).unwrap();
+            table.durability = durability;
+            insert_rows(&mut table, 10);
+            assert_eq!(storage.syncs(), 0);
+            table.flush_all().unwrap();
+            assert_eq!(storage.syncs(), on_flush);
+            table.close().unwrap();
+            assert_eq!(storage.syncs(), on_flush + on_close);
+        }
+    }
+
+    #[test]
+    fn crash_is_detected() {
+        let storage = FaultyStorage::new(MemoryStorage::new());
+        let mut table = Table::open_with(storage.clone()).unwrap();
+        insert_rows(&mut table, 10);
+        table.close().unwrap();
+        drop(table);
+
+        let mut table = Table::open_with(storage.clone()).unwrap();
+        let row = Row::try_new(10, "name10", "10@a").unwrap();
+        table.insert(row).unwrap();
+        // Only the first changed page reaches the storage before the crash,
+        // not the meta page written last
+        storage.crash_after_writes(1);
+        table.close().unwrap();
+        drop(table);
+
+        let crashed = MemoryStorage::from_bytes(storage.snapshot().unwrap());
+        let mut table = Table::open_with(crashed).unwrap();
+        assert_eq!(table.len().unwrap(), 10);
+        assert!(matches!(table.check(), Err(SqlError::CheckFailed(_))));
+    }
+
+    #[test]
+    fn torn_meta_write() {
+        let storage = FaultyStorage::new(MemoryStorage::new());
+        let mut table = Table::open_with(storage.clone()).unwrap();
+        insert_rows(&mut table, 10);
+        table.close().unwrap();
+        drop(table);
+        let base = storage.snapshot().unwrap();
+
+        // The write stops either inside the live fields, before the new slot
+        // is reached, or just short of the page checksum
+        for (len, next_id) in [(META_HEADER_SIZE + 32, 10), (DEFAULT_PAGE_SIZE - 4, 500)] {
+            let storage = FaultyStorage::new(MemoryStorage::from_bytes(base.clone()));
+            let mut table = Table::open_with(storage.clone()).unwrap();
+            table.set_next_id(500).unwrap();
+            storage.tear_nth_write(1, len);
+            table.close().unwrap();
+            drop(table);
+
+            let torn = storage.snapshot().unwrap();
+            assert!(torn[..DEFAULT_PAGE_SIZE] != base[..DEFAULT_PAGE_SIZE]);
+            let mut table = Table::open_with(MemoryStorage::from_bytes(torn)).unwrap();
+            assert_eq!(table.get_next_id().unwrap(), next_id);
+            assert_eq!(table.len().unwrap(), 10);
+            table.check().unwrap();
+            // The next write goes to the slot that was torn
+            table.set_next_id(600).unwrap();
+            table.close().unwrap();
+        }
+    }
+
+    #[test]
+    fn short_read() {
+        let mut storage = FaultyStorage::new(MemoryStorage::new());
+        storage.write_page(0, &[7; DEFAULT_PAGE_SIZE]).unwrap();
+        storage.short_reads(Some(10));
+        let mut buf = [0; DEFAULT_PAGE_SIZE];
+        assert_eq!(storage.read_page(0, &mut buf).unwrap(), 10);
+        assert_eq!(buf[9], 7);
+        assert_eq!(buf[10], 0);
+        storage.short_reads(None);
+        assert_eq!(storage.read_page(0, &mut buf).unwrap(), DEFAULT_PAGE_SIZE);
+        assert_eq!(buf[DEFAULT_PAGE_SIZE - 1], 7);
+    }
+
+    #[test]
+    fn short_read_is_reported() {
+        let storage = FaultyStorage::new(MemoryStorage::new());
+        let mut table = Table::open_with(storage.clone()).unwrap();
+        insert_rows(&mut table, 10);
+        table.close().unwrap();
+        drop(table);
+
+        // Only the meta page is cached, so the root is read first
+        let mut table = Table::open_with(storage.clone()).unwrap();
+        let root = table.get_root_num().unwrap();
+        storage.short_reads(Some(100));
+        assert!(matches!(
+            table.get(3),
+            Err(SqlError::CorruptPage { page }) if page == root
+        ));
+        storage.short_reads(None);
+        assert_eq!(table.get(3).unwrap().unwrap().id, 3);
+    }
+}
@@ -0,0 +1,293 @@
+//! C interface, declared in `include/minisql.h`.
+//! Every function catches panics and reports them as `MINISQL_PANIC`.
+//! The message of the last failure on the calling thread is kept for `minisql_errmsg`,
+//! and the code of the database error behind it for `minisql_errcode`.
+use std::{
+    cell::RefCell,
+    ffi::{c_char, c_int, c_void, CStr, CString},
+    panic::{catch_unwind, AssertUnwindSafe},
+    ptr,
+};
+
+use crate::{
+    commands::prepare_statement,
+    sql_error::{SqlError, SqlResult},
+    table::Table,
+};
+
+pub const MINISQL_OK: c_int = 0;
+pub const MINISQL_ERROR: c_int = 1;
+pub const MINISQL_MISUSE: c_int = 2;
+pub const MINISQL_PANIC: c_int = 3;
+pub const MINISQL_ABORT: c_int = 4;
+
+/// Called for each result row. A nonzero return stops the statement.
+pub type MinisqlRowCallback = Option<
+    extern "C" fn(ctx: *mut c_void, id: i64, name: *const c_char, email: *const c_char) -> c_int,
+>;
+
+/// Opaque database handle
+pub struct MinisqlDb {
+    table: Table,
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<CString> = RefCell::new(CString::default());
+    static LAST_CODE: RefCell<CString> = RefCell::new(CString::default());
+}
+
+fn set_error(msg: String) {
+    let msg = CString::new(msg.replace('\0', " ")).unwrap();
+    LAST_ERROR.with(|e| *e.borrow_mut() = msg);
+}
+
+fn set_code(code: &str) {
+    let code = CString::new(code).unwrap();
+    LAST_CODE.with(|c| *c.borrow_mut() = code);
+}
+
+/// Run `f`, turning errors and panics into status codes
+fn guard<F>(f: F) -> c_int
+where
+    F: FnOnce() -> Result<(), (c_int, String)>,
+{
+    set_code("");
+    match catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(())) => {
+            set_error(String::new());
+            MINISQL_OK
+        }
+        Ok(Err((code, msg))) => {
+            set_error(msg);
+            code
+        }
+        Err(_) => {
+            set_error("panic in minisql".to_string());
+            MINISQL_PANIC
+        }
+    }
+}
+
+fn sql_error(e: SqlError) -> (c_int, String) {
+    set_code(e.code());
+    (MINISQL_ERROR, e.to_string())
+}
+
+/// Borrow a NUL-terminated UTF-8 argument
+unsafe fn arg_str<'a>(s: *const c_char, name: &str) -> Result<&'a str, (c_int, String)> {
+    if s.is_null() {
+        return Err((MINISQL_MISUSE, format!("{} is null", name)));
+    }
+    CStr::from_ptr(s)
+        .to_str()
+        .map_err(|_| (MINISQL_MISUSE, format!("{} is not valid UTF-8", name)))
+}
+
+/// Open a database and store its handle in `out`.
+///
+/// # Safety
+/// `path` must be a NUL-terminated string and `out` a valid pointer.
+#[no_mangle]
+pub unsafe extern "C" fn minisql_open(path: *const c_char, out: *mut *mut MinisqlDb) -> c_int {
+    guard(|| {
+        if out.is_null() {
+            return Err((MINISQL_MISUSE, "out is null".to_string()));
+        }
+        *out = ptr::null_mut();
+        let path = arg_str(path, "path")?;
+        let table = Table::open(path).map_err(sql_error)?;
+        *out = Box::into_raw(Box::new(MinisqlDb { table }));
+        Ok(())
+    })
+}
+
+/// Flush and free the handle. The handle is freed even when flushing fails.
+///
+/// # Safety
+/// `db` must come from `minisql_open` and not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn minisql_close(db: *mut MinisqlDb) -> c_int {
+    guard(|| {
+        if db.is_null() {
+            return Err((MINISQL_MISUSE, "db is null".to_string()));
+        }
+        let mut db = Box::from_raw(db);
+        db.table.close().map_err(sql_error)
+    })
+}
+
+/// Execute one statement, passing each result row to `callback`.
+/// `count` receives the value of a count statement, or -1 for other statements.
+/// Strings given to the callback are valid only during the call.
+///
+/// # Safety
+/// `db` must be a live handle and `statement` a NUL-terminated string.
+/// `count` may be null.
+#[no_mangle]
+pub unsafe extern "C" fn minisql_exec(
+    db: *mut MinisqlDb,
+    statement: *const c_char,
+    callback: MinisqlRowCallback,
+    ctx: *mut c_void,
+    count: *mut i64,
+) -> c_int {
+    guard(|| {
+        if db.is_null() {
+            return Err((MINISQL_MISUSE, "db is null".to_string()));
+        }
+        let table = &mut (*db).table;
+        let statement = arg_str(statement, "statement")?;
+        let mut aborted = false;
+        let result: SqlResult<_> = (|| {
+            let statement = prepare_statement(statement)?;
+            statement.execute_streaming(table, &mut |row| {
+                let callback = match callback {
+                    Some(callback) => callback,
+                    None => return Ok(()),
+                };
+                let name = CString::new(row.name_str()).unwrap();
+                let email = CString::new(row.email_str()).unwrap();
+                if callback(ctx, row.id(), name.as_ptr(), email.as_ptr()) != 0 {
+                    aborted = true;
+                    return Err(SqlError::NoData);
+                }
+                Ok(())
+            })
+        })();
+        if aborted {
+            return Err((MINISQL_ABORT, "aborted by callback".to_string()));
+        }
+        let result = result.map_err(sql_error)?;
+        if !count.is_null() {
+            *count = result.count.map_or(-1, |c| c as i64);
+        }
+        Ok(())
+    })
+}
+
+/// Message of the last failed call on this thread, empty after a success.
+/// The string is valid until the next call on this thread.
+#[no_mangle]
+pub extern "C" fn minisql_errmsg() -> *const c_char {
+    LAST_ERROR.with(|e| e.borrow().as_ptr())
+}
+
+/// Code of the database error behind the last failed call on this thread,
+/// such as `MINISQL_DUP_KEY`. Empty after a success and for `MINISQL_MISUSE`,
+/// `MINISQL_PANIC` and `MINISQL_ABORT`.
+/// The string is valid until the next call on this thread.
+#[no_mangle]
+pub extern "C" fn minisql_errcode() -> *const c_char {
+    LAST_CODE.with(|c| c.borrow().as_ptr())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::clean_db;
+
+    extern "C" fn collect(
+        ctx: *mut c_void,
+        id: i64,
+        name: *const c_char,
+        email: *const c_char,
+    ) -> c_int {
+        let rows = unsafe { &mut *(ctx as *mut Vec<String>) };
+        let (name, email) = unsafe {
+            (
+                CStr::from_ptr(name).to_str().unwrap(),
+                CStr::from_ptr(email).to_str().unwrap(),
+            )
+        };
+        rows.push(format!("{} {} {}", id, name, email));
+        (rows.len() >= 2) as c_int
+    }
+
+    fn errmsg() -> String {
+        unsafe { CStr::from_ptr(minisql_errmsg()) }
+            .to_str()
+            .unwrap()
+            .to_string()
+    }
+
+    fn errcode() -> String {
+        unsafe { CStr::from_ptr(minisql_errcode()) }
+            .to_str()
+            .unwrap()
+            .to_string()
+    }
+
+    fn exec(db: *mut MinisqlDb, statement: &str, rows: &mut Vec<String>, count: &mut i64) -> c_int {
+        let statement = CString::new(statement).unwrap();
+        unsafe {
+            minisql_exec(
+                db,
+                statement.as_ptr(),
+                Some(collect),
+                rows as *mut Vec<String> as *mut c_void,
+                count,
+            )
+        }
+    }
+
+    #[test]
+    fn ffi_exec() {
+        let path = clean_db("ffi_exec");
+        let c_path = CString::new(path).unwrap();
+        let mut db = ptr::null_mut();
+        assert_eq!(
+            unsafe { minisql_open(c_path.as_ptr(), &mut db) },
+            MINISQL_OK
+        );
+
+        let mut rows = vec![];
+        let mut count = 0;
+        for i in 1..=3 {
+            let statement = format!("insert {} name{} {}@a", i, i, i);
+            assert_eq!(exec(db, &statement, &mut vec![], &mut count), MINISQL_OK);
+            assert_eq!(count, -1);
+        }
+        assert_eq!(exec(db, "select 2", &mut rows, &mut count), MINISQL_OK);
+        assert_eq!(rows, vec!["2 name2 2@a"]);
+        assert_eq!(exec(db, "count", &mut vec![], &mut count), MINISQL_OK);
+        assert_eq!(count, 3);
+
+        // The callback stops the scan after two rows
+        let mut rows = vec![];
+        assert_eq!(exec(db, "select", &mut rows, &mut count), MINISQL_ABORT);
+        assert_eq!(rows, vec!["1 name1 1@a", "2 name2 2@a"]);
+
+        assert_eq!(
+            exec(db, "insert 1 a b", &mut vec![], &mut count),
+            MINISQL_ERROR
+        );
+        assert_eq!(errmsg(), "a row with the key already exists");
+        assert_eq!(errcode(), "MINISQL_DUP_KEY");
+        let null = unsafe { minisql_exec(db, ptr::null(), None, ptr::null_mut(), ptr::null_mut()) };
+        assert_eq!(null, MINISQL_MISUSE);
+        assert_eq!(errmsg(), "statement is null");
+        assert_eq!(errcode(), "");
+
+        assert_eq!(unsafe { minisql_close(db) }, MINISQL_OK);
+        assert_eq!(errmsg(), "");
+    }
+
+    #[test]
+    fn ffi_open_error() {
+        let path = CString::new("./forTest").unwrap();
+        let mut db = ptr::null_mut();
+        assert_eq!(
+            unsafe { minisql_open(path.as_ptr(), &mut db) },
+            MINISQL_ERROR
+        );
+        assert!(db.is_null());
+        assert!(errmsg().contains("is a directory"));
+        assert_eq!(errcode(), "MINISQL_IO");
+    }
+
+    #[test]
+    fn ffi_panic() {
+        assert_eq!(guard(|| panic!("boom")), MINISQL_PANIC);
+        assert_eq!(errmsg(), "panic in minisql");
+    }
+}
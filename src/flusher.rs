@@ -0,0 +1,218 @@
+//! Background writer for pages flushed from the cache.
+//! Writes are queued with a copy of the page and done in queue order by a
+//! worker thread owning its own handle on the storage. Until its write is
+//! done a page is read from the queued copy, so the cache may drop it at once.
+//! `drain` waits for every queued write and returns the ones that failed.
+use std::{
+    collections::HashMap,
+    sync::{mpsc, Arc, Mutex},
+    thread,
+};
+
+use crate::{
+    sql_error::{SqlError, SqlResult},
+    storage::Storage,
+};
+
+type Failures = Vec<(usize, SqlError)>;
+type Pending = Arc<Mutex<HashMap<usize, Arc<Vec<u8>>>>>;
+
+enum Job {
+    Write(usize),
+    /// Reply with the failures since the last drain once the writes before are done
+    Drain(mpsc::Sender<Failures>),
+}
+
+pub struct Flusher {
+    jobs: Option<mpsc::Sender<Job>>,
+    /// Latest queued copy of each page not yet written
+    pending: Pending,
+    worker: Option<thread::JoinHandle<()>>,
+}
+
+fn stopped() -> SqlError {
+    let e = std::io::Error::other("background flusher stopped");
+    SqlError::IOError(e, "Failed to write".to_string())
+}
+
+impl Flusher {
+    /// Start a worker writing to `storage`, which must hold the same pages as
+    /// the storage the pager reads
+    pub fn new<S: Storage + Send + 'static>(mut storage: S) -> Self {
+        let (jobs, queue) = mpsc::channel();
+        let pending: Pending = Arc::default();
+        let shared = pending.clone();
+        let worker = thread::spawn(move || {
+            let mut failures = Vec::new();
+            for job in queue {
+                match job {
+                    Job::Write(page_num) => {
+                        // A page queued twice is written once, with its latest copy
+                        let buf = match shared.lock().unwrap().get(&page_num) {
+                            Some(buf) => buf.clone(),
+                            None => continue,
+                        };
+                        match storage.write_page(page_num, &buf) {
+                            Ok(()) => {
+                                let mut pending = shared.lock().unwrap();
+                                if pending.get(&page_num).is_some_and(|p| Arc::ptr_eq(p, &buf)) {
+                                    pending.remove(&page_num);
+                                }
+                            }
+                            // The copy stays pending for the drain to hand back
+                            Err(e) => failures.push((page_num, e)),
+                        }
+                    }
+                    Job::Drain(done) => {
+                        let _ = done.send(std::mem::take(&mut failures));
+                    }
+                }
+            }
+        });
+        Flusher {
+            jobs: Some(jobs),
+            pending,
+            worker: Some(worker),
+        }
+    }
+
+    fn send(&self, job: Job) -> SqlResult<()> {
+        match &self.jobs {
+            Some(jobs) => jobs.send(job).map_err(|_| stopped()),
+            None => Err(stopped()),
+        }
+    }
+
+    /// Queue a write of a page, replacing an earlier copy still queued
+    pub fn queue(&self, page_num: usize, buf: Vec<u8>) -> SqlResult<()> {
+        self.pending.lock().unwrap().insert(page_num, Arc::new(buf));
+        self.send(Job::Write(page_num))
+    }
+
+    /// Copy of a page whose write is not done yet
+    pub fn pending(&self, page_num: usize) -> Option<Vec<u8>> {
+        let pending = self.pending.lock().unwrap();
+        pending.get(&page_num).map(|buf| buf.to_vec())
+    }
+
+    /// Stop tracking a page, returning its copy if it is still pending
+    pub fn take(&self, page_num: usize) -> Option<Vec<u8>> {
+        let buf = self.pending.lock().unwrap().remove(&page_num)?;
+        Some(Arc::try_unwrap(buf).unwrap_or_else(|buf| buf.to_vec()))
+    }
+
+    /// Wait until every queued write is done and return the failed ones
+    pub fn drain(&self) -> SqlResult<Failures> {
+        let (done, failures) = mpsc::channel();
+        self.send(Job::Drain(done))?;
+        failures.recv().map_err(|_| stopped())
+    }
+}
+
+impl Drop for Flusher {
+    /// Let the worker finish the queued writes
+    fn drop(&mut self) {
+        self.jobs = None;
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::table::{Row, Table};
+    use std::sync::Condvar;
+
+    /// Pages in memory shared between threads. Writes through a gated clone
+    /// wait until the gate opens.
+    #[derive(Clone, Default)]
+    struct SharedStorage {
+        data: Arc<Mutex<Vec<u8>>>,
+        gate: Option<Arc<(Mutex<bool>, Condvar)>>,
+    }
+
+    impl SharedStorage {
+        fn gated(&self) -> Self {
+            SharedStorage {
+                data: self.data.clone(),
+                gate: Some(Arc::default()),
+            }
+        }
+        fn open(&self) {
+            let (open, changed) = &**self.gate.as_ref().unwrap();
+            *open.lock().unwrap() = true;
+            changed.notify_all();
+        }
+    }
+
+    impl Storage for SharedStorage {
+        fn read_page(&mut self, page_num: usize, buf: &mut [u8]) -> SqlResult<usize> {
+            let data = self.data.lock().unwrap();
+            let start = (page_num * buf.len()).min(data.len());
+            let end = (start + buf.len()).min(data.len());
+            buf[0..end - start].copy_from_slice(&data[start..end]);
+            Ok(end - start)
+        }
+        fn write_page(&mut self, page_num: usize, buf: &[u8]) -> SqlResult<()> {
+            if let Some(gate) = &self.gate {
+                let (open, changed) = &**gate;
+                let _open = changed
+                    .wait_while(open.lock().unwrap(), |open| !*open)
+                    .unwrap();
+            }
+            let mut data = self.data.lock().unwrap();
+            let start = page_num * buf.len();
+            if data.len() < start + buf.len() {
+                data.resize(start + buf.len(), 0);
+            }
+            data[start..start + buf.len()].copy_from_slice(buf);
+            Ok(())
+        }
+        fn len(&self) -> SqlResult<usize> {
+            Ok(self.data.lock().unwrap().len())
+        }
+        fn sync(&mut self) -> SqlResult<()> {
+            Ok(())
+        }
+        fn set_len(&mut self, len: usize) -> SqlResult<()> {
+            self.data.lock().unwrap().resize(len, 0);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn writes_in_background() {
+        let storage = SharedStorage::default();
+        let mut table = Table::open_with(storage.clone()).unwrap();
+        table.close().unwrap();
+        drop(table);
+        let written = storage.data.lock().unwrap().clone();
+
+        // Statements evicting pages return while no write can finish
+        let mut table = Table::open_with(storage.clone()).unwrap();
+        let gated = storage.gated();
+        table.start_flusher(gated.clone()).unwrap();
+        table.set_cache_size(Some(4)).unwrap();
+        for i in 0..100 {
+            let key = i * 37 % 100;
+            let row = Row::try_new(key, &format!("name{}", key), "a@a").unwrap();
+            table.insert(row).unwrap();
+        }
+        assert!(table.pager.cache_stats().evictions > 0);
+        assert_eq!(*storage.data.lock().unwrap(), written);
+        // Evicted pages are read back from their queued copies
+        for key in [0, 42, 99] {
+            assert_eq!(table.get(key).unwrap().unwrap().id, key);
+        }
+
+        // Closing waits for the writes
+        gated.open();
+        table.close().unwrap();
+        drop(table);
+        let mut table = Table::open_with(storage).unwrap();
+        assert_eq!(table.len().unwrap(), 100);
+        table.check().unwrap();
+    }
+}
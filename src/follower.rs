@@ -0,0 +1,171 @@
+//! Follower mode: keep a replica up to date by applying the statements another
+//! process appends to its journal.
+//! The replica's meta page records how many journal bytes were applied and
+//! their checksum, so a restart resumes where it stopped. The primary empties
+//! its journal when it flushes, after which the replica has to be copied again.
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+};
+
+use crate::{
+    commands::prepare_statement,
+    crc32::crc32,
+    journal::parse_entry,
+    pager::{open_error, Pager},
+    sql_error::{SqlError, SqlResult},
+    table::Table,
+};
+
+pub struct Follower {
+    pub table: Table,
+    journal: File,
+}
+
+impl Follower {
+    /// Open the replica and check it still matches the journal.
+    /// The replica has no journal of its own: its pages and applied offset
+    /// reach the file together when it is flushed.
+    pub fn open(replica: &str, journal: &str) -> SqlResult<Self> {
+        let table = Table::with_pager(Pager::open(replica)?, None)?;
+        let journal = File::open(journal).map_err(|e| open_error(e, journal))?;
+        let mut follower = Follower { table, journal };
+        follower.verify()?;
+        Ok(follower)
+    }
+
+    /// Journal bytes from `offset` to the end
+    fn read_from(&mut self, offset: u64) -> SqlResult<Vec<u8>> {
+        let mut buf = Vec::new();
+        self.journal
+            .seek(SeekFrom::Start(offset))
+            .map_err(|e| SqlError::IOError(e, "Failed to seek journal".to_string()))?;
+        self.journal
+            .read_to_end(&mut buf)
+            .map_err(|e| SqlError::IOError(e, "Failed to read journal".to_string()))?;
+        Ok(buf)
+    }
+
+    /// Check that the journal still starts with the bytes already applied
+    fn verify(&mut self) -> SqlResult<()> {
+        let meta = self.table.meta_ref()?;
+        let offset = meta.get_follow_offset() as usize;
+        let checksum = meta.get_follow_checksum();
+        let applied = self.read_from(0)?;
+        if applied.len() < offset || crc32(0, &applied[..offset]) != checksum {
+            return Err(SqlError::Diverged);
+        }
+        Ok(())
+    }
+
+    /// Apply the complete statements appended since the last call.
+    /// Returns how many were applied.
+    pub fn poll(&mut self) -> SqlResult<usize> {
+        let meta = self.table.meta_ref()?;
+        let mut offset = meta.get_follow_offset();
+        let mut checksum = meta.get_follow_checksum();
+        if self.journal.metadata().map_or(0, |m| m.len()) < offset {
+            return Err(SqlError::Diverged);
+        }
+        let buf = self.read_from(offset)?;
+        let complete = match buf.iter().rposition(|&b| b == b'\n') {
+            Some(end) => &buf[..=end],
+            None => return Ok(0),
+        };
+        let mut applied = 0;
+        for line in complete.split_inclusive(|&b| b == b'\n') {
+            let text = std::str::from_utf8(line).map_err(|_| SqlError::CorruptFile)?;
+            let (_, statement) = parse_entry(text.trim_end_matches('\n'))?;
+            prepare_statement(&statement)?.execute(&mut self.table)?;
+
+            offset += line.len() as u64;
+            checksum = crc32(checksum, line);
+            let meta = self.table.meta_mut()?;
+            meta.set_follow_offset(offset);
+            meta.set_follow_checksum(checksum);
+            applied += 1;
+        }
+        Ok(applied)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        journal::journal_path,
+        test::{clean_db, db_name},
+    };
+
+    fn select_all(table: &mut Table) -> Vec<String> {
+        let rows = prepare_statement("select").unwrap().execute(table).unwrap();
+        rows.iter().map(|row| row.to_string()).collect()
+    }
+
+    #[test]
+    fn follow_journal() {
+        let (primary_path, replica_path) = (clean_db("follow_primary"), clean_db("follow_replica"));
+        let journal = journal_path(&primary_path);
+
+        let mut primary = Table::open(&primary_path).unwrap();
+        for i in 0..10 {
+            let statement = format!("insert {} name{} {}@a", i, i, i);
+            prepare_statement(&statement)
+                .unwrap()
+                .execute(&mut primary)
+                .unwrap();
+        }
+        let mut follower = Follower::open(&replica_path, &journal).unwrap();
+        assert_eq!(follower.poll().unwrap(), 10);
+        assert_eq!(select_all(&mut follower.table), select_all(&mut primary));
+        assert_eq!(follower.poll().unwrap(), 0);
+
+        for statement in ["delete 3", "update 4 four 4@b", "insert 20 x x@a"] {
+            prepare_statement(statement)
+                .unwrap()
+                .execute(&mut primary)
+                .unwrap();
+        }
+        assert_eq!(follower.poll().unwrap(), 3);
+        assert_eq!(select_all(&mut follower.table), select_all(&mut primary));
+        follower.table.close().unwrap();
+        drop(follower);
+
+        // A restart resumes after the statements already applied
+        prepare_statement("insert 21 y y@a")
+            .unwrap()
+            .execute(&mut primary)
+            .unwrap();
+        let mut follower = Follower::open(&replica_path, &journal).unwrap();
+        assert_eq!(follower.poll().unwrap(), 1);
+        assert_eq!(select_all(&mut follower.table), select_all(&mut primary));
+        follower.table.close().unwrap();
+        drop(follower);
+
+        // Flushing the primary empties its journal
+        primary.close().unwrap();
+        assert!(matches!(
+            Follower::open(&replica_path, &journal),
+            Err(SqlError::Diverged)
+        ));
+    }
+
+    #[test]
+    fn follow_rewritten_journal() {
+        let replica_path = clean_db("follow_rewritten");
+        let journal = db_name("follow_rewritten.source");
+        std::fs::write(&journal, "1 insert 1 a a@a\n2 insert 2 b b@b\n").unwrap();
+
+        let mut follower = Follower::open(&replica_path, &journal).unwrap();
+        assert_eq!(follower.poll().unwrap(), 2);
+        follower.table.close().unwrap();
+        drop(follower);
+
+        // Same length, different statements
+        std::fs::write(&journal, "1 insert 1 a a@a\n2 insert 3 b b@b\n").unwrap();
+        assert!(matches!(
+            Follower::open(&replica_path, &journal),
+            Err(SqlError::Diverged)
+        ));
+    }
+}
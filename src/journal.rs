@@ -0,0 +1,79 @@
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom, Write},
+};
+
+use crate::sql_error::{SqlError, SqlResult};
+
+/// Append-only log of mutating statements, one `<seq> <statement>` per line.
+/// It is truncated once every page has been flushed.
+pub struct Journal {
+    file: File,
+}
+
+pub fn journal_path(filename: &str) -> String {
+    format!("{}.journal", filename)
+}
+
+/// Split a journal line, without its newline, into sequence number and statement
+pub fn parse_entry(line: &str) -> SqlResult<(u64, String)> {
+    let (seq, statement) = line.split_once(' ').ok_or(SqlError::CorruptFile)?;
+    let seq = seq.parse::<u64>().map_err(|_| SqlError::CorruptFile)?;
+    Ok((seq, statement.to_string()))
+}
+
+impl Journal {
+    #[cfg(feature = "fs")]
+    pub fn open(path: &str) -> SqlResult<Self> {
+        let file = File::options()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)
+            .map_err(|e| SqlError::IOError(e, "Failed to open journal".to_string()))?;
+        Ok(Journal { file })
+    }
+
+    /// Read all complete entries. A torn last line is ignored.
+    pub fn entries(&mut self) -> SqlResult<Vec<(u64, String)>> {
+        let mut buf = String::new();
+        self.file
+            .seek(SeekFrom::Start(0))
+            .map_err(|e| SqlError::IOError(e, "Failed to seek journal".to_string()))?;
+        self.file
+            .read_to_string(&mut buf)
+            .map_err(|e| SqlError::IOError(e, "Failed to read journal".to_string()))?;
+        let complete = match buf.rfind('\n') {
+            Some(end) => &buf[..end],
+            None => return Ok(vec![]),
+        };
+        complete.split('\n').map(parse_entry).collect()
+    }
+
+    /// Append an entry, and make it durable with `sync`
+    pub fn append(&mut self, seq: u64, statement: &str, sync: bool) -> SqlResult<()> {
+        self.file
+            .write_all(format!("{} {}\n", seq, statement).as_bytes())
+            .map_err(|e| SqlError::IOError(e, "Failed to write journal".to_string()))?;
+        if sync {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    pub fn truncate(&mut self, sync: bool) -> SqlResult<()> {
+        self.file
+            .set_len(0)
+            .map_err(|e| SqlError::IOError(e, "Failed to truncate journal".to_string()))?;
+        if sync {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    fn sync(&mut self) -> SqlResult<()> {
+        self.file
+            .sync_data()
+            .map_err(|e| SqlError::IOError(e, "Failed to sync journal".to_string()))
+    }
+}
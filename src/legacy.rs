@@ -0,0 +1,681 @@
+//! Conversion of files written before format version 9, which stored page
+//! numbers and cell counts in the 8 bytes of usize on the machines that
+//! wrote them. Opening such a file converts every page into the cache with
+//! 4-byte fields and the layout of its version otherwise kept, so the usual
+//! upgrade steps apply after it, and the upgrade writes the pages back.
+//! A value that does not fit in 4 bytes fails the conversion.
+use std::collections::HashSet;
+
+use crate::{
+    meta::{
+        ENCRYPTED_MAGIC, HEADER_VERSION, MAGIC, META_HEADER_SIZE, META_NODE_NUM,
+        PAGE_COUNT_VERSION, PORTABLE_VERSION,
+    },
+    node::POINTER_SIZE,
+    pager::PAGE_RESERVED_SIZE,
+    sql_error::{SqlError, SqlResult},
+    table::ROW_SIZE,
+};
+
+/// Width of page numbers and counts before format version 9
+pub const WIDE_POINTER_SIZE: usize = 8;
+
+// Widths that did not change
+const KEY_SIZE: usize = 8;
+const ROW_COUNT_SIZE: usize = 8;
+const LEAF_CELL_SIZE: usize = KEY_SIZE + ROW_SIZE;
+// Header of every version with one: {MAGIC, PAGE_SIZE, VERSION}
+const VERSION_OFFSET: usize = 12;
+// Before the header, VERSION followed ROOT, ROW_COUNT and NEXT_ID
+const WIDE_LEGACY_VERSION_OFFSET: usize = WIDE_POINTER_SIZE + ROW_COUNT_SIZE + KEY_SIZE;
+
+const INTERNAL: u8 = 0;
+const LEAF: u8 = 1;
+/// First format version with row counts in internal node cells
+const INTERNAL_COUNT_VERSION: u32 = 3;
+/// First format version with a free list
+const FREE_LIST_VERSION: u32 = 5;
+
+fn has_header(meta: &[u8]) -> bool {
+    let magic = &meta[..MAGIC.len()];
+    magic == MAGIC || magic == ENCRYPTED_MAGIC
+}
+
+/// Format version of a stored meta page with 8-byte page numbers, or None
+/// if it is in the current layout or not a meta page at all
+pub fn wide_version(meta: &[u8]) -> Option<u32> {
+    let version_at =
+        |offset: usize| u32::from_le_bytes(meta[offset..offset + 4].try_into().unwrap());
+    if has_header(meta) {
+        let version = version_at(VERSION_OFFSET);
+        return (version < PORTABLE_VERSION).then_some(version);
+    }
+    // The root page number, whose high bytes are zero, comes first
+    let version = version_at(WIDE_LEGACY_VERSION_OFFSET);
+    let high_clear = meta[POINTER_SIZE..WIDE_POINTER_SIZE]
+        .iter()
+        .all(|&b| b == 0);
+    (version < HEADER_VERSION && high_clear).then_some(version)
+}
+
+/// Copies fields from a page in one width of page numbers to another
+struct Converter<'a> {
+    page_num: usize,
+    src: &'a [u8],
+    dst: &'a mut [u8],
+    read: usize,
+    written: usize,
+    from: usize,
+    to: usize,
+}
+
+impl<'a> Converter<'a> {
+    fn corrupt(&self, detail: String) -> SqlError {
+        SqlError::Corrupt {
+            page: self.page_num,
+            detail,
+        }
+    }
+    /// Copy a page number or count, returning it
+    fn pointer(&mut self) -> SqlResult<usize> {
+        let src = self
+            .src
+            .get(self.read..self.read + self.from)
+            .ok_or_else(|| self.corrupt("field past the end of the page".to_string()))?;
+        let mut value = [0; 8];
+        value[..self.from].copy_from_slice(src);
+        let value = u64::from_le_bytes(value);
+        if self.to < 8 && value >> (self.to * 8) != 0 {
+            return Err(self.corrupt(format!("{} does not fit in {} bytes", value, self.to)));
+        }
+        self.dst[self.written..self.written + self.to]
+            .copy_from_slice(&value.to_le_bytes()[..self.to]);
+        self.read += self.from;
+        self.written += self.to;
+        Ok(value as usize)
+    }
+    /// Copy bytes whose width does not change
+    fn bytes(&mut self, len: usize) -> SqlResult<()> {
+        let end = self.written + len;
+        if self.read + len > self.src.len() || end > self.dst.len() {
+            return Err(self.corrupt("field past the end of the page".to_string()));
+        }
+        self.dst[self.written..end].copy_from_slice(&self.src[self.read..self.read + len]);
+        self.read += len;
+        self.written = end;
+        Ok(())
+    }
+}
+
+/// Convert the pages of a database of format `version` from page numbers of
+/// `from` bytes to `to` bytes. The free list is followed to tell free pages
+/// from nodes. Checksums are left stale.
+pub fn convert_pages(pages: &mut [Vec<u8>], version: u32, from: usize, to: usize) -> SqlResult<()> {
+    let Some(meta) = pages.get(META_NODE_NUM) else {
+        return Ok(());
+    };
+    let mut free_head = 0;
+    let converted = convert_meta(meta, version, from, to, &mut free_head)?;
+    pages[META_NODE_NUM] = converted;
+
+    let mut free = HashSet::new();
+    let mut page_num = free_head;
+    while version >= FREE_LIST_VERSION && page_num != 0 {
+        // A list longer than the file has a cycle
+        if page_num >= pages.len() || !free.insert(page_num) {
+            return Err(SqlError::CorruptFile);
+        }
+        let mut next = [0; 8];
+        next[..from].copy_from_slice(&pages[page_num][..from]);
+        page_num = u64::from_le_bytes(next) as usize;
+    }
+
+    for (page_num, page) in pages.iter_mut().enumerate().skip(1) {
+        let mut dst = vec![0; page.len()];
+        let mut converter = Converter {
+            page_num,
+            src: page,
+            dst: &mut dst,
+            read: 0,
+            written: 0,
+            from,
+            to,
+        };
+        if free.contains(&page_num) {
+            converter.pointer()?;
+        } else {
+            convert_node(&mut converter, version)?;
+        }
+        *page = dst;
+    }
+    Ok(())
+}
+
+fn convert_meta(
+    src: &[u8],
+    version: u32,
+    from: usize,
+    to: usize,
+    free_head: &mut usize,
+) -> SqlResult<Vec<u8>> {
+    // Whatever follows the fields, like the key check of an encrypted file,
+    // stays where it is
+    let mut dst = src.to_vec();
+    let header = has_header(src);
+    let mut converter = Converter {
+        page_num: META_NODE_NUM,
+        src,
+        dst: &mut dst,
+        read: 0,
+        written: 0,
+        from,
+        to,
+    };
+    if header {
+        converter.bytes(META_HEADER_SIZE)?;
+    }
+    // ROOT, ROW_COUNT, NEXT_ID, VERSION before the header
+    converter.pointer()?;
+    converter.bytes(ROW_COUNT_SIZE + KEY_SIZE)?;
+    if !header {
+        converter.bytes(4)?;
+    }
+    // JOURNAL_SEQ, FOLLOW_OFFSET, FOLLOW_CHECKSUM, FREE_HEAD, PAGE_COUNT
+    converter.bytes(8 + 8 + 4)?;
+    *free_head = converter.pointer()?;
+    if header && version >= PAGE_COUNT_VERSION {
+        converter.pointer()?;
+    }
+    let (read, written) = (converter.read, converter.written);
+    if written < read {
+        dst[written..read].fill(0);
+    }
+    Ok(dst)
+}
+
+fn convert_node(converter: &mut Converter, version: u32) -> SqlResult<()> {
+    // NODE_TYPE, IS_ROOT, PARENT_POINTER
+    let node_type = converter.src[0];
+    converter.bytes(2)?;
+    converter.pointer()?;
+    match node_type {
+        LEAF => {
+            // NUM_CELLS, NEXT_LEAF, then the cells, which keep their width
+            let num_cells = converter.pointer()?;
+            converter.pointer()?;
+            let usable = converter.src.len() - PAGE_RESERVED_SIZE;
+            if converter.read + num_cells * LEAF_CELL_SIZE > usable {
+                return Err(converter.corrupt(format!("{} cells in a leaf", num_cells)));
+            }
+            converter.bytes(num_cells * LEAF_CELL_SIZE)
+        }
+        INTERNAL => {
+            // NUM_KEYS, then {CHILD, KEY, COUNT from version 3} cells
+            let num_keys = converter.pointer()?;
+            for _ in 0..num_keys {
+                converter.pointer()?;
+                converter.bytes(KEY_SIZE)?;
+                if version >= INTERNAL_COUNT_VERSION {
+                    converter.bytes(ROW_COUNT_SIZE)?;
+                }
+            }
+            Ok(())
+        }
+        other => Err(converter.corrupt(format!("unknown node type {}", other))),
+    }
+}
+
+/// 16 rows inserted and rows 3 to 6 deleted by format version 8, leaving a
+/// page on the free list
+#[cfg(test)]
+pub const FORMAT8_FIXTURE: &[u8] = include_bytes!("../tests/fixtures/format8.db");
+
+/// Rewrite a closed database of format `version` with 8-byte page numbers,
+/// as it was stored before format version 9
+#[cfg(test)]
+pub fn widen_file(path: &str, version: u32) {
+    use crate::pager::{seal, DEFAULT_PAGE_SIZE};
+
+    let data = std::fs::read(path).unwrap();
+    let mut pages = data
+        .chunks(DEFAULT_PAGE_SIZE)
+        .map(|page| page.to_vec())
+        .collect::<Vec<_>>();
+    convert_pages(&mut pages, version, POINTER_SIZE, WIDE_POINTER_SIZE).unwrap();
+    for page in &mut pages {
+        seal(page);
+    }
+    std::fs::write(path, pages.concat()).unwrap();
+}
+
+/// Rewrite the internal nodes under a node in the layout before format
+/// version 14, and the leaves with `to_leaf`, returning the first key under
+/// the node
+#[cfg(test)]
+fn to_keyed_tree(
+    table: &crate::table::Table,
+    node_num: usize,
+    to_leaf: &dyn Fn(&crate::node::LeafRef),
+) -> Option<i64> {
+    use crate::node::NodeRef;
+
+    match table.pager.node(node_num).unwrap().as_typed().unwrap() {
+        NodeRef::Internal(internal) => {
+            let keys = internal.keys().unwrap();
+            let mut cells = Vec::new();
+            for (i, (child, count)) in internal.children().unwrap().into_iter().enumerate() {
+                let first_key = to_keyed_tree(table, child, to_leaf);
+                // Only the root leaf is ever empty
+                let key = first_key.unwrap_or_else(|| keys[i.saturating_sub(1)]);
+                cells.push((child, key, count));
+            }
+            internal
+                .node
+                .internal_node_mut()
+                .unwrap()
+                .set_keyed_cells(&cells);
+            cells.first().map(|&(_, key, _)| key)
+        }
+        NodeRef::Leaf(leaf) => {
+            let first_key = leaf.get_first_key().unwrap();
+            to_leaf(&leaf);
+            first_key
+        }
+    }
+}
+
+/// Rewrite the tree under a node in the layout of format version 13
+#[cfg(test)]
+pub fn to_keyed_internals(table: &crate::table::Table, node_num: usize) {
+    to_keyed_tree(table, node_num, &to_linked_leaf);
+}
+
+/// Rewrite the internal nodes under a node in the layout of format versions
+/// 14 and 15, and the leaves with `to_leaf`
+#[cfg(test)]
+fn to_separator_tree(
+    table: &crate::table::Table,
+    node_num: usize,
+    to_leaf: &dyn Fn(&crate::node::LeafRef),
+) {
+    use crate::node::NodeRef;
+
+    match table.pager.node(node_num).unwrap().as_typed().unwrap() {
+        NodeRef::Internal(internal) => {
+            let children = internal.children().unwrap();
+            let keys = internal.keys().unwrap();
+            for &(child, _) in &children {
+                to_separator_tree(table, child, to_leaf);
+            }
+            internal
+                .node
+                .internal_node_mut()
+                .unwrap()
+                .set_separator_cells(&children, &keys);
+        }
+        NodeRef::Leaf(leaf) => to_leaf(&leaf),
+    }
+}
+
+/// Rewrite the internal nodes under a node in the layout of format version 15
+#[cfg(test)]
+pub fn to_separator_internals(table: &crate::table::Table, node_num: usize) {
+    to_separator_tree(table, node_num, &|_| {});
+}
+
+/// Rewrite the tree under a node in the layout of format version 14
+#[cfg(test)]
+pub fn to_linked_leaves(table: &crate::table::Table, node_num: usize) {
+    to_separator_tree(table, node_num, &to_linked_leaf);
+}
+
+#[cfg(test)]
+fn to_linked_leaf(leaf: &crate::node::LeafRef) {
+    let cells = (0..leaf.get_num_cells())
+        .map(|i| leaf.get_cell(i).unwrap())
+        .collect::<Vec<_>>();
+    leaf.node.leaf_node_mut().unwrap().set_linked_cells(&cells);
+}
+
+/// Rewrite the tree under a node in the layout before format version 11
+#[cfg(test)]
+pub fn to_legacy_leaves(table: &crate::table::Table, node_num: usize) {
+    use crate::overflow::read_value;
+
+    to_keyed_tree(table, node_num, &|leaf| {
+        let cells = (0..leaf.get_num_cells())
+            .map(|i| {
+                let row = read_value(&table.pager, None, leaf, i).unwrap();
+                (leaf.get_key(i).unwrap(), row)
+            })
+            .collect::<Vec<_>>();
+        leaf.node.leaf_node_mut().unwrap().set_legacy_cells(&cells);
+    });
+}
+
+/// Rewrite the tree under a node in the layout before format version 13
+#[cfg(test)]
+pub fn to_unlinked_leaves(table: &crate::table::Table, node_num: usize) {
+    to_keyed_tree(table, node_num, &|leaf| {
+        let cells = (0..leaf.get_num_cells())
+            .map(|i| leaf.get_cell(i).unwrap())
+            .collect::<Vec<_>>();
+        leaf.node
+            .leaf_node_mut()
+            .unwrap()
+            .set_unlinked_cells(&cells);
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        meta::FORMAT_VERSION,
+        node::{
+            inline_cell, max_inline_value, INTERNAL_NODE_MAX_CELLS, MISSING_NODE,
+            UNLINKED_LEAF_NODE_MAX_CELLS,
+        },
+        pager::DEFAULT_PAGE_SIZE,
+        table::Row,
+        test::{db_name, init_test_db, reopen_test_db},
+    };
+
+    #[test]
+    fn open_format8_fixture() {
+        let db = "open_format8_fixture";
+        init_test_db(db).close().unwrap();
+        std::fs::write(db_name(db), FORMAT8_FIXTURE).unwrap();
+        assert_eq!(wide_version(FORMAT8_FIXTURE), Some(8));
+
+        let mut table = reopen_test_db(db);
+        assert_eq!(table.meta_ref().unwrap().get_version(), FORMAT_VERSION);
+        table.check().unwrap();
+        assert!(!table.pager.free_pages().unwrap().is_empty());
+        let ids = table
+            .keys()
+            .unwrap()
+            .collect::<SqlResult<Vec<_>>>()
+            .unwrap();
+        assert_eq!(ids, [1, 2, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16]);
+        assert_eq!(table.get(9).unwrap().unwrap().name_str(), "user9");
+        table
+            .insert(Row::try_new(3, "user3", "a@a").unwrap())
+            .unwrap();
+        table.close().unwrap();
+        drop(table);
+
+        // The file was written back in the current layout
+        let data = std::fs::read(db_name(db)).unwrap();
+        assert_eq!(wide_version(&data), None);
+        let mut table = reopen_test_db(db);
+        assert_eq!(table.len().unwrap(), 13);
+        table.check().unwrap();
+    }
+
+    #[test]
+    fn widths_round_trip() {
+        let db = "widths_round_trip";
+        let mut table = init_test_db(db);
+        for i in 0..30 {
+            table
+                .insert(Row::try_new(i, "name", "a@a").unwrap())
+                .unwrap();
+        }
+        for i in 2..8 {
+            table.delete(i).unwrap();
+        }
+        let free = table.pager.free_pages().unwrap();
+        assert!(!free.is_empty());
+        to_legacy_leaves(&table, table.get_root_num().unwrap());
+        table.meta_mut().unwrap().set_version(8);
+        table.close().unwrap();
+        drop(table);
+
+        widen_file(&db_name(db), 8);
+        let mut table = reopen_test_db(db);
+        assert_eq!(table.meta_ref().unwrap().get_version(), FORMAT_VERSION);
+        table.check().unwrap();
+        // Rebuilding the internal nodes may free more
+        let now_free = table.pager.free_pages().unwrap();
+        assert!(free.iter().all(|page_num| now_free.contains(page_num)));
+        let ids = table
+            .keys()
+            .unwrap()
+            .collect::<SqlResult<Vec<_>>>()
+            .unwrap();
+        assert_eq!(ids, (0..2).chain(8..30).collect::<Vec<_>>());
+
+        // A page number too large for 4 bytes is not cut short
+        let mut pages = vec![vec![0; DEFAULT_PAGE_SIZE]; 2];
+        pages[0][..MAGIC.len()].copy_from_slice(MAGIC);
+        pages[1][0] = LEAF;
+        pages[1][2 + 4] = 1;
+        assert!(matches!(
+            convert_pages(&mut pages, 8, WIDE_POINTER_SIZE, POINTER_SIZE),
+            Err(SqlError::Corrupt { page: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn unlinked_leaves_upgrade() {
+        let db = "unlinked_leaves_upgrade";
+        let mut table = init_test_db(db);
+        for i in 0..30 {
+            table
+                .insert(Row::try_new(i, "name", "a@a").unwrap())
+                .unwrap();
+        }
+        for i in 2..6 {
+            table.delete(i).unwrap();
+        }
+        to_unlinked_leaves(&table, table.get_root_num().unwrap());
+        table.meta_mut().unwrap().set_version(12);
+        table.close().unwrap();
+        drop(table);
+
+        let mut table = reopen_test_db(db);
+        assert_eq!(table.meta_ref().unwrap().get_version(), FORMAT_VERSION);
+        table.check().unwrap();
+        let mut keys = Vec::new();
+        let mut cursor = table.start_rev().unwrap();
+        while !cursor.begin_of_table {
+            keys.push(cursor.get_key().unwrap());
+            cursor.retreat().unwrap();
+        }
+        assert_eq!(keys, (0..2).chain(6..30).rev().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn keyed_internals_upgrade() {
+        let db = "keyed_internals_upgrade";
+        let mut table = init_test_db(db);
+        for i in 0..60 {
+            let key = i * 37 % 60;
+            table
+                .insert(Row::try_new(key, "name", "a@a").unwrap())
+                .unwrap();
+        }
+        // Removed first keys leave the keys of their children behind
+        for i in (0..60).step_by(4) {
+            table.delete(i).unwrap();
+        }
+        let root = table.get_root_num().unwrap();
+        assert!(table.internal_ref(root).unwrap().get_num_keys() > 0);
+        to_keyed_internals(&table, root);
+        table.meta_mut().unwrap().set_version(13);
+        table.close().unwrap();
+        drop(table);
+
+        let mut table = reopen_test_db(db);
+        assert_eq!(table.meta_ref().unwrap().get_version(), FORMAT_VERSION);
+        table.check().unwrap();
+        let expected = (0..60).filter(|i| i % 4 != 0).collect::<Vec<_>>();
+        for &key in &expected {
+            assert_eq!(table.get(key).unwrap().unwrap().id, key);
+        }
+        assert!(table.get(4).unwrap().is_none());
+        for key in (0..60).step_by(4) {
+            table
+                .insert(Row::try_new(key, "name", "a@a").unwrap())
+                .unwrap();
+        }
+        table.check().unwrap();
+        assert_eq!(table.len().unwrap(), 60);
+    }
+
+    #[test]
+    fn linked_leaves_upgrade() {
+        let db = "linked_leaves_upgrade";
+        let mut table = init_test_db(db);
+        for i in 0..30 {
+            let key = i * 7 % 30;
+            table
+                .insert(Row::try_new(key, "name", "a@a").unwrap())
+                .unwrap();
+        }
+        let long = vec![3; max_inline_value(DEFAULT_PAGE_SIZE) + 1];
+        table.find(40).unwrap().insert(40, &long).unwrap();
+        to_linked_leaves(&table, table.get_root_num().unwrap());
+        table.meta_mut().unwrap().set_version(14);
+        table.close().unwrap();
+        drop(table);
+
+        let mut table = reopen_test_db(db);
+        assert_eq!(table.meta_ref().unwrap().get_version(), FORMAT_VERSION);
+        table.check().unwrap();
+        assert_eq!(table.len().unwrap(), 31);
+        for key in 0..30 {
+            assert_eq!(table.get(key).unwrap().unwrap().id, key);
+        }
+        let cursor = table.find(40).unwrap();
+        assert_eq!(*cursor.get().unwrap().get_value().unwrap(), long[..]);
+        for key in (0..30).step_by(3) {
+            table.delete(key).unwrap();
+        }
+        table.check().unwrap();
+        assert_eq!(table.len().unwrap(), 21);
+    }
+
+    #[test]
+    fn separator_internals_upgrade() {
+        let db = "separator_internals_upgrade";
+        let mut table = init_test_db(db);
+        for i in 0..60 {
+            let key = i * 37 % 60;
+            table
+                .insert(Row::try_new(key, "name", "a@a").unwrap())
+                .unwrap();
+        }
+        to_separator_internals(&table, table.get_root_num().unwrap());
+        table.meta_mut().unwrap().set_version(15);
+        table.close().unwrap();
+        drop(table);
+
+        let mut table = reopen_test_db(db);
+        assert_eq!(table.meta_ref().unwrap().get_version(), FORMAT_VERSION);
+        table.check().unwrap();
+        assert_eq!(table.len().unwrap(), 60);
+        for key in (0..60).step_by(3) {
+            table.delete(key).unwrap();
+        }
+        for key in 60..80 {
+            table
+                .insert(Row::try_new(key, "name", "a@a").unwrap())
+                .unwrap();
+        }
+        table.check().unwrap();
+        assert_eq!(table.len().unwrap(), 60);
+    }
+
+    #[test]
+    fn overfull_separator_internal() {
+        // A root over a child more than an internal node now holds
+        let db = "overfull_separator_internal";
+        let mut table = init_test_db(db);
+        let num_leaves = INTERNAL_NODE_MAX_CELLS + 1;
+        let rows = (0..num_leaves as i64 * 4).map(|i| Row::try_new(i, "name", "a@a").unwrap());
+        table.bulk_load(rows).unwrap();
+        let mut leaves = Vec::new();
+        let mut leaf_num = table.start().unwrap().page_num;
+        while leaf_num != MISSING_NODE {
+            leaves.push(leaf_num);
+            leaf_num = table.leaf_ref(leaf_num).unwrap().get_next_leaf();
+        }
+        assert_eq!(leaves.len(), num_leaves);
+        let internals = (1..table.pager.num_pages.get())
+            .filter(|&page_num| table.pager.node(page_num).unwrap().is_internal())
+            .collect::<Vec<_>>();
+        let root_num = internals[0];
+        for &page_num in &internals[1..] {
+            table.pager.free_page(page_num).unwrap();
+        }
+        let root = table.pager.node(root_num).unwrap().init_internal();
+        root.set_root(true);
+        let children = leaves.iter().map(|&leaf| (leaf, 4)).collect::<Vec<_>>();
+        let keys = leaves[1..]
+            .iter()
+            .map(|&leaf| table.leaf_ref(leaf).unwrap().get_key(0).unwrap())
+            .collect::<Vec<_>>();
+        root.set_separator_cells(&children, &keys);
+        for &leaf in &leaves {
+            table.pager.node(leaf).unwrap().set_parent(root_num);
+        }
+        table.set_root_num(root_num).unwrap();
+        table.meta_mut().unwrap().set_version(15);
+        table.close().unwrap();
+        drop(table);
+
+        let mut table = reopen_test_db(db);
+        table.check().unwrap();
+        assert_eq!(table.len().unwrap(), num_leaves * 4);
+        let keys = table
+            .keys()
+            .unwrap()
+            .collect::<SqlResult<Vec<_>>>()
+            .unwrap();
+        assert_eq!(keys, (0..num_leaves as i64 * 4).collect::<Vec<_>>());
+        assert!(table.tree_stats().unwrap().depth > 2);
+        table.delete(0).unwrap();
+        table.check().unwrap();
+    }
+
+    #[test]
+    fn unlinked_full_leaves() {
+        // Leaves the longer header leaves too little room for, and a value
+        // kept in place that is now too long for its cell
+        let long = vec![7; max_inline_value(DEFAULT_PAGE_SIZE) + 1];
+        let full = (0..UNLINKED_LEAF_NODE_MAX_CELLS as i64)
+            .map(|key| inline_cell(key, &[]))
+            .collect::<Vec<_>>();
+        let with_long = std::iter::once(inline_cell(0, &long))
+            .chain((1..190).map(|key| inline_cell(key, &[])))
+            .collect::<Vec<_>>();
+        for (i, cells) in [full, with_long].iter().enumerate() {
+            let db = &format!("unlinked_full_leaves{}", i);
+            let mut table = init_test_db(db);
+            let root = table.get_root_num().unwrap();
+            table.leaf_mut(root).unwrap().set_unlinked_cells(cells);
+            table.set_row_count(cells.len()).unwrap();
+            table.set_next_id(cells.len() as i64).unwrap();
+            table.meta_mut().unwrap().set_version(12);
+            table.close().unwrap();
+            drop(table);
+
+            let mut table = reopen_test_db(db);
+            table.check().unwrap();
+            assert_ne!(table.get_root_num().unwrap(), root);
+            assert_eq!(table.len().unwrap(), cells.len());
+            let keys = table
+                .keys()
+                .unwrap()
+                .collect::<SqlResult<Vec<_>>>()
+                .unwrap();
+            assert_eq!(keys, (0..cells.len() as i64).collect::<Vec<_>>());
+            if i == 1 {
+                let cursor = table.find(0).unwrap();
+                assert_eq!(*cursor.get().unwrap().get_value().unwrap(), long[..]);
+            }
+        }
+    }
+}
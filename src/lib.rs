@@ -0,0 +1,65 @@
+//! Single table database in a B+ tree file. The REPL in main.rs runs
+//! statements through `repl::exec_buf`.
+#[cfg(feature = "async")]
+pub mod async_table;
+pub mod changes;
+#[cfg(feature = "encryption")]
+pub mod cipher;
+pub mod commands;
+#[cfg(feature = "compression")]
+pub mod compressed_storage;
+mod crc32;
+pub mod cursor;
+#[cfg(any(test, feature = "testing"))]
+pub mod faulty_storage;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+mod flusher;
+#[cfg(feature = "fs")]
+pub mod follower;
+pub mod journal;
+mod legacy;
+pub mod meta;
+pub mod node;
+mod overflow;
+pub mod pager;
+pub mod repl;
+pub mod sql_error;
+pub mod storage;
+mod string_utils;
+pub mod table;
+pub mod wal;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+
+#[cfg(test)]
+mod test {
+    use crate::{changes, journal, table::Table, wal};
+
+    pub fn db_name(prefix: &str) -> String {
+        format!("./forTest/{}.db", prefix)
+    }
+    /// Remove the test database and every file kept beside it, returning
+    /// its path
+    pub fn clean_db(prefix: &str) -> String {
+        let path = db_name(prefix);
+        let mut files = vec![
+            journal::journal_path(&path),
+            wal::wal_path(&path),
+            changes::changes_path(&path),
+        ];
+        #[cfg(feature = "compression")]
+        files.push(crate::compressed_storage::map_path(&path));
+        files.push(path.clone());
+        for file in files {
+            let _ = std::fs::remove_file(file);
+        }
+        path
+    }
+    pub fn init_test_db(prefix: &str) -> Table {
+        Table::open(&clean_db(prefix)).unwrap()
+    }
+    pub fn reopen_test_db(prefix: &str) -> Table {
+        Table::open(&db_name(prefix)).unwrap()
+    }
+}
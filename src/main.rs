@@ -1,22 +1,64 @@
-mod commands;
-mod cursor;
-mod meta;
-mod node;
-mod pager;
-mod sql_error;
-mod string_utils;
-mod table;
-
 use std::io::stdout;
 use std::io::Write;
 
-use commands::*;
-use sql_error::{SqlError, SqlResult};
-use table::Table;
+#[cfg(feature = "compression")]
+use minisql::sql_error::SqlError;
+use minisql::{
+    commands::prepare_statement,
+    follower, journal, node, pager,
+    repl::{exec_buf, report_error, OutputMode, OUTPUT_MODE},
+    sql_error::SqlResult,
+    storage,
+    table::{self, Table},
+};
 
 fn main() {
     let filename = std::env::args().nth(1).expect("minisql <db filename>");
-    let mut table = Table::open(&filename).unwrap();
+    if filename == "bench" {
+        let filename = std::env::args()
+            .nth(2)
+            .expect("minisql bench <db filename>");
+        bench(&filename).unwrap();
+        return;
+    }
+    if filename == "follow" {
+        let usage = "minisql follow <replica db> --journal <journal>";
+        let args = std::env::args().skip(2).collect::<Vec<_>>();
+        if args.len() != 3 || args[1] != "--journal" {
+            panic!("{}", usage);
+        }
+        follow(&args[0], &args[2]).unwrap();
+        return;
+    }
+    if filename == "migrate" {
+        let usage = "minisql migrate <old db> <new db>";
+        let args = std::env::args().skip(2).collect::<Vec<_>>();
+        if args.len() != 2 {
+            panic!("{}", usage);
+        }
+        let stats = Table::migrate(&args[0], &args[1]).unwrap();
+        println!("Migrated {} rows into {} pages", stats.rows, stats.pages);
+        return;
+    }
+    if std::env::args().skip(2).any(|arg| arg == "--json-errors") {
+        OUTPUT_MODE.set(OutputMode::Json);
+    }
+    let opened = match Table::open(&filename) {
+        #[cfg(feature = "compression")]
+        Err(SqlError::Compressed) => Table::open_compressed(&filename),
+        result => result,
+    };
+    let mut table = match opened {
+        Ok(table) => table,
+        Err(e) => {
+            // No statement ran yet, so the error has none to name
+            report_error(&e, "", &mut stdout(), &mut std::io::stderr()).unwrap();
+            std::process::exit(1);
+        }
+    };
+    if let Some(mismatch) = table.pager.length_mismatch {
+        println!("Warning: {}", mismatch);
+    }
     loop {
         let mut buf = String::new();
         print!("> ");
@@ -26,223 +68,224 @@ fn main() {
             continue;
         }
         let buf = buf.trim();
-        match exec_buf(buf, &mut table) {
-            Ok(_) => {}
-            Err(e) => {
-                println!("Error: {:?}", e);
-                continue;
-            }
+        if let Err(e) = exec_buf(buf, &mut table) {
+            report_error(&e, buf, &mut stdout(), &mut std::io::stderr()).unwrap();
         }
     }
 }
 
-fn exec_buf(buf: &str, table: &mut Table) -> SqlResult<()> {
-    if buf.starts_with(".") {
-        return meta_command(buf, table);
-    }
-    let statement = prepare_statement(buf)?;
-    let rows = statement.execute(table)?;
-    for row in rows {
-        println!("{}", row);
-    }
-    Ok(())
-}
-
-fn meta_command(buf: &str, table: &mut Table) -> SqlResult<()> {
-    match buf {
-        ".exit" => {
-            table.close()?;
-            std::process::exit(0);
-        }
-        ".btree" => {
-            println!("{}", table);
-            return Ok(());
+/// Apply statements appended to a journal until killed, flushing every second
+fn follow(replica: &str, journal: &str) -> SqlResult<()> {
+    let mut follower = follower::Follower::open(replica, journal)?;
+    let mut flushed = std::time::Instant::now();
+    loop {
+        if follower.poll()? > 0 {
+            println!(
+                "applied up to {}",
+                follower.table.meta_ref()?.get_follow_offset()
+            );
         }
-        _ => {
-            return Err(SqlError::UnknownCommand(buf.to_string()));
+        if flushed.elapsed() >= std::time::Duration::from_secs(1) {
+            follower.table.flush_all()?;
+            flushed = std::time::Instant::now();
         }
+        std::thread::sleep(std::time::Duration::from_millis(100));
     }
 }
-#[cfg(test)]
-mod test {
-    use std::assert_eq;
-
-    use super::*;
-    #[test]
-    fn insert_select() {
-        let db = "insert_select";
-        let mut table = init_test_db(db);
-
-        let statement = prepare_statement("insert 1 wass wass@example.com").unwrap();
-        let row = &statement.execute(&mut table).unwrap()[0];
-        assert_eq!(row.id, 1);
-
-        let statement = prepare_statement("insert 2 nnna nnna@example.com").unwrap();
-        let row = &statement.execute(&mut table).unwrap()[0];
-        assert_eq!(row.id, 2);
-
-        let statement = prepare_statement("select 1").unwrap();
-        let row = &statement.execute(&mut table).unwrap()[0];
-        assert_eq!(row.id, 1);
-        assert_eq!(string_utils::to_string_null_terminated(&row.name), "wass");
-        assert_eq!(
-            string_utils::to_string_null_terminated(&row.email),
-            "wass@example.com"
-        );
-    }
-    #[test]
-    fn close_db() {
-        let db = "close_db";
-        let mut table = init_test_db(db);
-
-        let statement = prepare_statement("insert 1 wass wass@example.com").unwrap();
-        let row = &statement.execute(&mut table).unwrap()[0];
-        assert_eq!(row.id, 1);
-
-        table.close().unwrap();
 
-        let mut table = reopen_test_db(db);
-        let statement = prepare_statement("select 0").unwrap();
-        let row = &statement.execute(&mut table).unwrap()[0];
-        assert_eq!(row.id, 1);
-        assert_eq!(string_utils::to_string_null_terminated(&row.name), "wass");
-        assert_eq!(
-            string_utils::to_string_null_terminated(&row.email),
-            "wass@example.com"
-        );
+/// Time point lookups on a scratch database
+fn bench(filename: &str) -> SqlResult<()> {
+    let _ = std::fs::remove_file(filename);
+    let _ = std::fs::remove_file(journal::journal_path(filename));
+    let mut table = Table::open(filename)?;
+    let rows = 60;
+    for i in 0..rows {
+        table.insert(table::Row::try_new(i, "bench", "bench@example.com")?)?;
     }
-    #[test]
-    fn tough_insert() {
-        let db = "tough_insert";
-        let mut table = init_test_db(db);
-
-        let rows = 60;
-        for i in 0..rows {
-            let statement = prepare_statement(&format!("insert {} name{} {}@a", i, i, i)).unwrap();
-            statement.execute(&mut table).unwrap();
-            println!("\n##### {} #####\n{}", i, table);
+    let lookups = 100_000;
+    let time = |label: &str, lookup: &mut dyn FnMut(i64) -> SqlResult<()>| -> SqlResult<()> {
+        let start = std::time::Instant::now();
+        for i in 0..lookups {
+            lookup(i % rows)?;
         }
-        table.close().unwrap();
+        let per_lookup = start.elapsed().as_nanos() / lookups as u128;
+        println!("{}: {} ns/lookup", label, per_lookup);
+        Ok(())
+    };
+    time("find, cached root", &mut |key| {
+        table.find(key)?;
+        Ok(())
+    })?;
+    // What every lookup paid before the root number was cached
+    time("find, root from meta page", &mut |key| {
+        table.meta_ref()?.get_root_num();
+        table.find(key)?;
+        Ok(())
+    })?;
 
-        let mut table = reopen_test_db(db);
-        for i in 0..rows {
-            println!("\n##### {} #####\n{}", i, table);
-            let statement = prepare_statement(&format!("select {}", i)).unwrap();
-            let row = &statement.execute(&mut table).unwrap()[0];
-            println!("{}", row);
-            assert_eq!(row.id, i);
+    let scans = 10_000;
+    let select_all = prepare_statement("select")?;
+    let start = std::time::Instant::now();
+    for _ in 0..scans {
+        select_all.execute_streaming(&mut table, &mut |row| {
+            std::hint::black_box(row.email_str());
+            Ok(())
+        })?;
+    }
+    let per_scan = start.elapsed().as_nanos() / scans;
+    println!("select all, rows in place: {} ns/scan", per_scan);
+    let start = std::time::Instant::now();
+    for _ in 0..scans {
+        for row in select_all.execute(&mut table)?.iter() {
+            std::hint::black_box(row.email_str());
         }
     }
-
-    #[test]
-    fn select_all() {
-        let db = "select_all";
-        let mut table = init_test_db(db);
-
-        let num_rows = 12;
-        for i in 0..num_rows {
-            let statement = prepare_statement(&format!("insert {} name{} {}@a", i, i, i)).unwrap();
-            statement.execute(&mut table).unwrap();
+    let per_scan = start.elapsed().as_nanos() / scans;
+    println!("select all, copied rows: {} ns/scan", per_scan);
+    let start = std::time::Instant::now();
+    for _ in 0..scans {
+        for key in table.keys()? {
+            std::hint::black_box(key?);
         }
-        table.close().unwrap();
+    }
+    let per_scan = start.elapsed().as_nanos() / scans;
+    println!("keys only: {} ns/scan", per_scan);
+    table.close()?;
 
-        let mut table = reopen_test_db(db);
-        println!("{}", table);
-        let statement = prepare_statement("select").unwrap();
-        let rows = statement.execute(&mut table).unwrap();
-        assert_eq!(rows.len(), num_rows);
-        for i in 0..num_rows {
-            let row = &rows[i];
-            println!("{}", row);
-            assert_eq!(row.id, i as u64);
+    // Scans starting from a cache holding only the meta page
+    let cold_scans = 1_000;
+    for read_ahead in [0, 16] {
+        let options = table::TableOptions {
+            read_ahead,
+            ..Default::default()
+        };
+        let mut elapsed = std::time::Duration::ZERO;
+        for _ in 0..cold_scans {
+            let mut table = Table::open_with_options(filename, &options)?;
+            let start = std::time::Instant::now();
+            for key in table.keys()? {
+                std::hint::black_box(key?);
+            }
+            elapsed += start.elapsed();
+            // Nothing changed, so skip writing the pages back
+            table.release();
         }
+        let per_scan = elapsed.as_nanos() / cold_scans;
+        println!(
+            "keys only, cold cache, read-ahead {}: {} ns/scan",
+            read_ahead, per_scan
+        );
     }
 
-    #[test]
-    fn random_insert() {
-        let db = "random_insert";
-        let mut table = init_test_db(db);
-        let order = vec![9, 17, 5, 4, 6, 8, 11, 2, 1, 0, 7, 21, 15, 12, 14, 20, 13];
-        for i in &order {
-            let statement = prepare_statement(&format!("insert {} name{} {}@a", i, i, i)).unwrap();
-            println!("##### {} #####", i);
-            statement.execute(&mut table).unwrap();
-            println!("{}", table);
+    // Bulk insert into a new file, then close, which writes every page.
+    // Storage without write_pages writes one page at a time.
+    struct PageAtATime(storage::FileStorage);
+    impl storage::Storage for PageAtATime {
+        fn read_page(&mut self, page_num: usize, buf: &mut [u8]) -> SqlResult<usize> {
+            self.0.read_page(page_num, buf)
         }
-
-        for i in &order {
-            let statement = prepare_statement(&format!("select {}", i)).unwrap();
-            let row = &statement.execute(&mut table).unwrap()[0];
-            assert_eq!(row.id, *i);
-        }
-    }
-    #[test]
-    fn remove_single() {
-        let db = "random_insert";
-        let mut table = init_test_db(db);
-        let order = vec![9, 17, 3, 2, 6];
-        for i in &order {
-            let statement = prepare_statement(&format!("insert {} name{} {}@a", i, i, i)).unwrap();
-            println!("##### ins {} #####", i);
-            statement.execute(&mut table).unwrap();
-            println!("{}", table);
+        fn write_page(&mut self, page_num: usize, buf: &[u8]) -> SqlResult<()> {
+            self.0.write_page(page_num, buf)
         }
-
-        for i in &order {
-            let statement = prepare_statement(&format!("delete {}", i)).unwrap();
-            println!("##### del {} #####", i);
-            statement.execute(&mut table).unwrap();
-            println!("{}", table);
-        }
-    }
-    #[test]
-    fn update() {
-        let db = "update";
-        let mut table = init_test_db(db);
-        let order = vec![9, 17, 3, 2, 6];
-        for i in &order {
-            let statement = prepare_statement(&format!("insert {} name{} {}@a", i, i, i)).unwrap();
-            println!("##### ins {} #####", i);
-            statement.execute(&mut table).unwrap();
-            println!("{}", table);
+        fn len(&self) -> SqlResult<usize> {
+            self.0.len()
         }
-
-        for i in &order {
-            let statement = prepare_statement(&format!("update {} name{} {}@b", i, i, i)).unwrap();
-            println!("##### upd {} #####", i);
-            statement.execute(&mut table).unwrap();
-            println!("{}", table);
+        fn sync(&mut self) -> SqlResult<()> {
+            self.0.sync()
         }
-
-        fn null_term_buf_to_str(buf: &[u8]) -> String {
-            let mut s = String::new();
-            for i in buf {
-                if *i == 0 {
-                    break;
-                }
-                s.push(*i as char);
-            }
-            s
-        }
-
-        for i in &order {
-            let statement = prepare_statement(&format!("select {}", i)).unwrap();
-            let row = &statement.execute(&mut table).unwrap()[0];
-            assert_eq!(row.id, *i);
-            assert_eq!(null_term_buf_to_str(&row.email), format!("{}@b", i));
+        fn set_len(&mut self, len: usize) -> SqlResult<()> {
+            self.0.set_len(len)
         }
     }
-    fn db_name(prefix: &str) -> String {
-        format!("./forTest/{}.db", prefix)
+    let bulk_rows = 20_000;
+    let bulk_path = format!("{}.bulk", filename);
+    for combined in [false, true] {
+        let file = std::fs::File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&bulk_path)
+            .map_err(|e| pager::open_error(e, &bulk_path))?;
+        let file = storage::FileStorage::new(file);
+        let mut table = if combined {
+            Table::open_with(file)?
+        } else {
+            Table::open_with(PageAtATime(file))?
+        };
+        for i in 0..bulk_rows {
+            table.insert(table::Row::try_new(i, "bench", "bench@example.com")?)?;
+        }
+        let start = std::time::Instant::now();
+        table.close()?;
+        let label = if combined {
+            "consecutive pages combined"
+        } else {
+            "one write per page"
+        };
+        println!(
+            "close after bulk insert, {}: {} us",
+            label,
+            start.elapsed().as_micros()
+        );
     }
-    pub fn init_test_db(prefix: &str) -> Table {
-        match std::fs::remove_file(db_name(prefix)) {
-            Ok(_) => {}
-            Err(_) => {}
+    let _ = std::fs::remove_file(&bulk_path);
+
+    // Appending rows in key order at the last leaf the previous insert
+    // left, and descending from the root for each row as before
+    for hinted in [false, true] {
+        let mut table = Table::open_with(storage::MemoryStorage::new())?;
+        table.set_node_limits(node::NodeLimits::FULL);
+        let before = table.pager.cache_stats();
+        let start = std::time::Instant::now();
+        for i in 0..bulk_rows {
+            if !hinted {
+                // Any change leaves the next insert to descend
+                table.bump_generation();
+            }
+            table.insert(table::Row::try_new(i, "bench", "bench@example.com")?)?;
         }
-        Table::open(&db_name(prefix)).unwrap()
+        let stats = table.pager.cache_stats().since(&before);
+        println!(
+            "{} rows appended {}: {} us, {:.1} pages read per row",
+            bulk_rows,
+            if hinted {
+                "at the last leaf"
+            } else {
+                "from the root"
+            },
+            start.elapsed().as_micros(),
+            (stats.hits + stats.misses) as f64 / bulk_rows as f64
+        );
     }
-    pub fn reopen_test_db(prefix: &str) -> Table {
-        Table::open(&db_name(prefix)).unwrap()
+
+    // Building a file row by row, and from sorted rows in bulk
+    for bulk in [false, true] {
+        let _ = std::fs::remove_file(&bulk_path);
+        let _ = std::fs::remove_file(journal::journal_path(&bulk_path));
+        let mut table = Table::open(&bulk_path)?;
+        table.set_node_limits(node::NodeLimits::FULL);
+        let rows = (0..bulk_rows)
+            .map(|i| table::Row::try_new(i, "bench", "bench@example.com"))
+            .collect::<SqlResult<Vec<_>>>()?;
+        let start = std::time::Instant::now();
+        if bulk {
+            table.bulk_load(rows.into_iter())?;
+        } else {
+            for row in rows {
+                table.insert(row)?;
+            }
+        }
+        table.close()?;
+        println!(
+            "{} rows {}: {} us, {} pages",
+            bulk_rows,
+            if bulk { "loaded in bulk" } else { "inserted" },
+            start.elapsed().as_micros(),
+            table.pager.num_pages.get()
+        );
     }
+    let _ = std::fs::remove_file(&bulk_path);
+    let _ = std::fs::remove_file(journal::journal_path(&bulk_path));
+    Ok(())
 }
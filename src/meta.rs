@@ -1,4 +1,11 @@
-use crate::node::{Node, POINTER_SIZE};
+use std::ops::Range;
+
+use crate::{
+    crc32::crc32,
+    node::{read_pointer, write_pointer, Node, POINTER_SIZE},
+    pager::{CHECKSUM_SIZE, MIN_PAGE_SIZE},
+    sql_error::{SqlError, SqlResult},
+};
 
 pub struct MetaRef {
     pub node: Node,
@@ -9,20 +16,400 @@ pub struct MetaMut {
 
 pub const META_NODE_NUM: usize = 0;
 pub const DEFAULT_ROOT_NUM: usize = 1;
+
+// HEADER:
+//   MAGIC, PAGE_SIZE, VERSION
+pub const MAGIC: &[u8; 8] = b"MINISQL1";
+/// Magic string of a file whose pages are encrypted
+pub const ENCRYPTED_MAGIC: &[u8; 8] = b"MINISQLE";
+/// Magic string of a file of compressed pages, whose meta page is inside
+pub const COMPRESSED_MAGIC: &[u8; 8] = b"MINISQLZ";
+const META_MAGIC_SIZE: usize = MAGIC.len();
+const META_MAGIC_OFFSET: usize = 0;
+const META_PAGE_SIZE_SIZE: usize = 4;
+const META_PAGE_SIZE_OFFSET: usize = META_MAGIC_OFFSET + META_MAGIC_SIZE;
+const META_VERSION_SIZE: usize = 4;
+const META_VERSION_OFFSET: usize = META_PAGE_SIZE_OFFSET + META_PAGE_SIZE_SIZE;
+pub const META_HEADER_SIZE: usize = META_MAGIC_SIZE + META_PAGE_SIZE_SIZE + META_VERSION_SIZE;
+
+// FIELDS:
+//   ROOT, ROW_COUNT, NEXT_ID, JOURNAL_SEQ, FOLLOW_OFFSET, FOLLOW_CHECKSUM, FREE_HEAD,
+//   PAGE_COUNT, CREATED, MODIFIED, WRITE_COUNT
+// CREATED and MODIFIED are seconds since the Unix epoch, 0 when unknown.
 const META_ROOT_NODE_SIZE: usize = POINTER_SIZE;
-const MEAT_ROOT_OFFSET: usize = 0;
+const MEAT_ROOT_OFFSET: usize = META_HEADER_SIZE;
+const META_ROW_COUNT_SIZE: usize = 8;
+const META_ROW_COUNT_OFFSET: usize = MEAT_ROOT_OFFSET + META_ROOT_NODE_SIZE;
+const META_NEXT_ID_SIZE: usize = 8;
+const META_NEXT_ID_OFFSET: usize = META_ROW_COUNT_OFFSET + META_ROW_COUNT_SIZE;
+const META_JOURNAL_SEQ_SIZE: usize = 8;
+const META_JOURNAL_SEQ_OFFSET: usize = META_NEXT_ID_OFFSET + META_NEXT_ID_SIZE;
+const META_FOLLOW_OFFSET_SIZE: usize = 8;
+const META_FOLLOW_OFFSET_OFFSET: usize = META_JOURNAL_SEQ_OFFSET + META_JOURNAL_SEQ_SIZE;
+const META_FOLLOW_CHECKSUM_SIZE: usize = 4;
+const META_FOLLOW_CHECKSUM_OFFSET: usize = META_FOLLOW_OFFSET_OFFSET + META_FOLLOW_OFFSET_SIZE;
+const META_FREE_HEAD_SIZE: usize = POINTER_SIZE;
+const META_FREE_HEAD_OFFSET: usize = META_FOLLOW_CHECKSUM_OFFSET + META_FOLLOW_CHECKSUM_SIZE;
+const META_PAGE_COUNT_SIZE: usize = POINTER_SIZE;
+const META_PAGE_COUNT_OFFSET: usize = META_FREE_HEAD_OFFSET + META_FREE_HEAD_SIZE;
+const META_CREATED_SIZE: usize = 8;
+const META_CREATED_OFFSET: usize = META_PAGE_COUNT_OFFSET + META_PAGE_COUNT_SIZE;
+const META_MODIFIED_SIZE: usize = 8;
+const META_MODIFIED_OFFSET: usize = META_CREATED_OFFSET + META_CREATED_SIZE;
+const META_WRITE_COUNT_SIZE: usize = 8;
+const META_WRITE_COUNT_OFFSET: usize = META_MODIFIED_OFFSET + META_MODIFIED_SIZE;
+const META_FIELDS_END: usize = META_WRITE_COUNT_OFFSET + META_WRITE_COUNT_SIZE;
+/// End of the fields before format version 17
+const INFOLESS_FIELDS_END: usize = META_CREATED_OFFSET;
+
+// SLOTS: two copies of FIELDS after them, each {SEQ, FIELDS, CHECKSUM}.
+// Each write of the meta page fills the slot of the next sequence number,
+// picked by its parity, and writes the other back as it was stored, so a
+// torn write leaves at least one of them whole. The fields are read from the
+// newest whole slot. Before format version 17 the slots followed the shorter
+// fields, and they are found from the version in the header.
+const META_SLOT_SEQ_SIZE: usize = 8;
+const META_SLOT_CHECKSUM_SIZE: usize = 4;
+
+// USER ENTRIES: entries set by `Table::set_meta` after the slots, each
+//   {KEY_LEN, VALUE_LEN, KEY, VALUE}
+// and ended by a KEY_LEN of 0 when they do not fill USER_META_SIZE bytes.
+// They are not kept in the slots, so a torn write of the meta page may lose
+// the entries changed since the last write.
+const USER_META_OFFSET: usize = META_FIELDS_END + 2 * slot_size(META_FIELDS_END);
+/// Bytes kept for the entries set by `Table::set_meta`, with their lengths
+pub const USER_META_SIZE: usize = 1024;
+const USER_KEY_LEN_SIZE: usize = 1;
+const USER_VALUE_LEN_SIZE: usize = 2;
+const USER_ENTRY_HEADER_SIZE: usize = USER_KEY_LEN_SIZE + USER_VALUE_LEN_SIZE;
+// Well clear of the key of an encrypted file at the end of the page
+const _: () = assert!(USER_META_OFFSET + USER_META_SIZE <= MIN_PAGE_SIZE / 2);
+
+// Before format version 7 there was no header. The fields up to FREE_HEAD
+// started at 0 with VERSION after NEXT_ID.
+const LEGACY_VERSION_OFFSET: usize = META_NEXT_ID_OFFSET - META_HEADER_SIZE + META_NEXT_ID_SIZE;
+const LEGACY_FIELDS_END: usize = META_PAGE_COUNT_OFFSET - META_HEADER_SIZE + META_VERSION_SIZE;
+/// First id assigned by auto-increment
+pub const DEFAULT_NEXT_ID: i64 = 1;
+/// On-disk format version. Bump this on every change to the file layout.
+///   0: written before the version field existed
+///   1: row count, next id and version in the meta page
+///   2: journal sequence number in the meta page
+///   3: row counts in internal node cells
+///   4: followed journal offset and checksum in the meta page
+///   5: free page list head in the meta page
+///   6: CRC-32 of each page in its last bytes
+///   7: header with a magic string and the page size in the meta page
+///   8: page count in the meta page
+///   9: page numbers and cell counts in 4 bytes instead of the width of usize
+///  10: two checksummed copies of the meta fields, written in turn
+///  11: leaf cells of any length behind an array of offsets, with long
+///      values in overflow pages
+///  12: the key of each internal cell is the first key under its child
+///  13: previous leaf in the leaf header
+///  14: internal nodes keep a key between each two children, with the last
+///      child in the header
+///  15: leaf keys in the slots beside the cell offsets
+///  16: next and previous internal node of the same level in the header
+///  17: creation and modification times and a write count in the meta fields
+///  18: entries of the application after the meta slots
+pub const FORMAT_VERSION: u32 = 18;
+/// First format version whose pages carry a checksum
+pub const CHECKSUM_VERSION: u32 = 6;
+/// First format version with the header
+pub const HEADER_VERSION: u32 = 7;
+/// First format version recording the page count, which the file length
+/// used to give
+pub const PAGE_COUNT_VERSION: u32 = 8;
+/// First format version whose integers have the same width on every platform
+pub const PORTABLE_VERSION: u32 = 9;
+/// First format version with copies of the meta fields in slots
+pub const SLOT_VERSION: u32 = 10;
+/// First format version whose leaf cells vary in length
+pub const VARIABLE_CELL_VERSION: u32 = 11;
+/// First format version whose internal keys are kept in step with the first
+/// key under each child, which removes used to leave stale
+pub const FIRST_KEY_VERSION: u32 = 12;
+/// First format version whose leaves point back to the previous leaf
+pub const LINKED_LEAF_VERSION: u32 = 13;
+/// First format version whose internal keys separate the children
+pub const SEPARATOR_VERSION: u32 = 14;
+/// First format version whose leaf keys are kept apart from the values
+pub const KEY_SLOT_VERSION: u32 = 15;
+/// First format version whose internal nodes link to their neighbours
+pub const SIBLING_VERSION: u32 = 16;
+/// First format version recording when the file was created and changed
+pub const INFO_VERSION: u32 = 17;
+/// First format version keeping entries of the application in the meta page
+pub const USER_META_VERSION: u32 = 18;
+
+/// Seconds since the Unix epoch, as the meta page records times
+#[cfg(not(all(feature = "wasm", target_arch = "wasm32")))]
+pub fn unix_time() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs())
+}
+/// The browser's clock, as there is no system time on wasm32
+#[cfg(all(feature = "wasm", target_arch = "wasm32"))]
+pub fn unix_time() -> u64 {
+    (js_sys::Date::now() / 1000.0) as u64
+}
+
+fn has_magic(buf: &[u8]) -> bool {
+    let magic = &buf[META_MAGIC_OFFSET..META_MAGIC_OFFSET + META_MAGIC_SIZE];
+    magic == MAGIC || magic == ENCRYPTED_MAGIC
+}
+
+/// Whether the header at the start of a file marks its pages as encrypted
+pub fn header_encrypted(buf: &[u8]) -> bool {
+    &buf[META_MAGIC_OFFSET..META_MAGIC_OFFSET + META_MAGIC_SIZE] == ENCRYPTED_MAGIC
+}
+
+/// Whether a file starts with the header of compressed pages
+pub fn header_compressed(buf: &[u8]) -> bool {
+    &buf[META_MAGIC_OFFSET..META_MAGIC_OFFSET + META_MAGIC_SIZE] == COMPRESSED_MAGIC
+}
+
+/// Page size recorded in the header at the start of a file, if it has one
+pub fn header_page_size(buf: &[u8]) -> Option<usize> {
+    if !has_magic(buf) {
+        return None;
+    }
+    let page_size = u32::from_le_bytes(
+        buf[META_PAGE_SIZE_OFFSET..META_PAGE_SIZE_OFFSET + META_PAGE_SIZE_SIZE]
+            .try_into()
+            .unwrap(),
+    );
+    Some(page_size as usize)
+}
+
+fn header_version(buf: &[u8]) -> u32 {
+    u32::from_le_bytes(
+        buf[META_VERSION_OFFSET..META_VERSION_OFFSET + META_VERSION_SIZE]
+            .try_into()
+            .unwrap(),
+    )
+}
+
+/// End of the fields of a meta page, where its slots start
+fn fields_end(buf: &[u8]) -> usize {
+    if header_version(buf) >= INFO_VERSION {
+        META_FIELDS_END
+    } else {
+        INFOLESS_FIELDS_END
+    }
+}
+
+const fn slot_size(fields_end: usize) -> usize {
+    META_SLOT_SEQ_SIZE + fields_end - MEAT_ROOT_OFFSET + META_SLOT_CHECKSUM_SIZE
+}
+
+fn slot_range(fields_end: usize, seq: u64) -> Range<usize> {
+    let start = fields_end + (seq % 2) as usize * slot_size(fields_end);
+    start..start + slot_size(fields_end)
+}
+
+/// Whether a meta page keeps its fields in slots
+pub fn has_slots(buf: &[u8]) -> bool {
+    has_magic(buf) && header_version(buf) >= SLOT_VERSION
+}
+
+/// Whether the slot of sequence number `seq` is whole
+fn slot_is_whole(buf: &[u8], seq: u64) -> bool {
+    let slot = &buf[slot_range(fields_end(buf), seq)];
+    let (body, checksum) = slot.split_at(slot.len() - META_SLOT_CHECKSUM_SIZE);
+    crc32(0, body).to_le_bytes() == checksum && body[..META_SLOT_SEQ_SIZE] == seq.to_le_bytes()
+}
+
+/// Copy the fields of a meta page into the slot after the one of sequence
+/// number `seq`, unless that one holds them already.
+/// Returns the sequence number of the slot holding them.
+pub fn fill_slot(buf: &mut [u8], seq: u64) -> u64 {
+    let end = fields_end(buf);
+    let current = slot_range(end, seq).start + META_SLOT_SEQ_SIZE;
+    let fields = MEAT_ROOT_OFFSET..end;
+    if slot_is_whole(buf, seq) && buf[fields.clone()] == buf[current..current + fields.len()] {
+        return seq;
+    }
+    let seq = seq + 1;
+    let range = slot_range(end, seq);
+    let body_end = range.end - META_SLOT_CHECKSUM_SIZE;
+    buf.copy_within(fields, range.start + META_SLOT_SEQ_SIZE);
+    buf[range.start..range.start + META_SLOT_SEQ_SIZE].copy_from_slice(&seq.to_le_bytes());
+    let checksum = crc32(0, &buf[range.start..body_end]);
+    buf[body_end..range.end].copy_from_slice(&checksum.to_le_bytes());
+    seq
+}
+
+/// Sequence number of the newest whole slot of a meta page
+fn newest_slot(buf: &[u8]) -> Option<u64> {
+    let end = fields_end(buf);
+    (0..2)
+        .map(|i| {
+            let start = end + i * slot_size(end);
+            u64::from_le_bytes(buf[start..start + META_SLOT_SEQ_SIZE].try_into().unwrap())
+        })
+        .filter(|&seq| slot_is_whole(buf, seq))
+        .max()
+}
 
 impl MetaRef {
     pub fn new(node: Node) -> Self {
         Self { node }
     }
+    pub fn has_header(&self) -> bool {
+        has_magic(&self.node.page.borrow().buf)
+    }
+    /// Format version of a meta page read from a file of `num_pages` pages.
+    /// Pages written before the header are recognized by a root page inside
+    /// the file and nothing after their fields.
+    pub fn stored_version(&self, num_pages: usize) -> SqlResult<u32> {
+        if self.has_header() {
+            return Ok(self.get_version());
+        }
+        let buf = &self.node.page.borrow().buf;
+        let root_num = read_pointer(buf);
+        let version = u32::from_le_bytes(
+            buf[LEGACY_VERSION_OFFSET..LEGACY_VERSION_OFFSET + META_VERSION_SIZE]
+                .try_into()
+                .unwrap(),
+        );
+        let rest_clear = buf[LEGACY_FIELDS_END..buf.len() - CHECKSUM_SIZE]
+            .iter()
+            .all(|&b| b == 0);
+        if version < HEADER_VERSION && (1..num_pages).contains(&root_num) && rest_clear {
+            Ok(version)
+        } else {
+            Err(SqlError::NotADatabase)
+        }
+    }
     pub fn get_root_num(&self) -> usize {
-        usize::from_le_bytes(
-            self.node.page.borrow().buf[MEAT_ROOT_OFFSET..MEAT_ROOT_OFFSET + META_ROOT_NODE_SIZE]
+        read_pointer(
+            &self.node.page.borrow().buf[MEAT_ROOT_OFFSET..MEAT_ROOT_OFFSET + META_ROOT_NODE_SIZE],
+        )
+    }
+    pub fn get_row_count(&self) -> usize {
+        u64::from_le_bytes(
+            self.node.page.borrow().buf
+                [META_ROW_COUNT_OFFSET..META_ROW_COUNT_OFFSET + META_ROW_COUNT_SIZE]
+                .try_into()
+                .unwrap(),
+        ) as usize
+    }
+    pub fn get_next_id(&self) -> i64 {
+        i64::from_le_bytes(
+            self.node.page.borrow().buf
+                [META_NEXT_ID_OFFSET..META_NEXT_ID_OFFSET + META_NEXT_ID_SIZE]
+                .try_into()
+                .unwrap(),
+        )
+    }
+    pub fn get_version(&self) -> u32 {
+        u32::from_le_bytes(
+            self.node.page.borrow().buf
+                [META_VERSION_OFFSET..META_VERSION_OFFSET + META_VERSION_SIZE]
+                .try_into()
+                .unwrap(),
+        )
+    }
+    /// Sequence number of the last journaled statement applied to the pages
+    pub fn get_journal_seq(&self) -> u64 {
+        u64::from_le_bytes(
+            self.node.page.borrow().buf
+                [META_JOURNAL_SEQ_OFFSET..META_JOURNAL_SEQ_OFFSET + META_JOURNAL_SEQ_SIZE]
+                .try_into()
+                .unwrap(),
+        )
+    }
+    /// Bytes of a followed journal applied to this replica
+    pub fn get_follow_offset(&self) -> u64 {
+        u64::from_le_bytes(
+            self.node.page.borrow().buf
+                [META_FOLLOW_OFFSET_OFFSET..META_FOLLOW_OFFSET_OFFSET + META_FOLLOW_OFFSET_SIZE]
+                .try_into()
+                .unwrap(),
+        )
+    }
+    /// CRC-32 of the applied bytes of a followed journal
+    pub fn get_follow_checksum(&self) -> u32 {
+        u32::from_le_bytes(
+            self.node.page.borrow().buf[META_FOLLOW_CHECKSUM_OFFSET
+                ..META_FOLLOW_CHECKSUM_OFFSET + META_FOLLOW_CHECKSUM_SIZE]
+                .try_into()
+                .unwrap(),
+        )
+    }
+    /// First page of the free list, or 0 when it is empty
+    pub fn get_free_head(&self) -> usize {
+        read_pointer(
+            &self.node.page.borrow().buf
+                [META_FREE_HEAD_OFFSET..META_FREE_HEAD_OFFSET + META_FREE_HEAD_SIZE],
+        )
+    }
+    /// Pages in the database, meta page included
+    pub fn get_page_count(&self) -> usize {
+        read_pointer(
+            &self.node.page.borrow().buf
+                [META_PAGE_COUNT_OFFSET..META_PAGE_COUNT_OFFSET + META_PAGE_COUNT_SIZE],
+        )
+    }
+    /// When the file was created, 0 for files made before it was recorded
+    pub fn get_created(&self) -> u64 {
+        u64::from_le_bytes(
+            self.node.page.borrow().buf
+                [META_CREATED_OFFSET..META_CREATED_OFFSET + META_CREATED_SIZE]
+                .try_into()
+                .unwrap(),
+        )
+    }
+    /// When the last write statement was applied, 0 before the first one
+    pub fn get_modified(&self) -> u64 {
+        u64::from_le_bytes(
+            self.node.page.borrow().buf
+                [META_MODIFIED_OFFSET..META_MODIFIED_OFFSET + META_MODIFIED_SIZE]
+                .try_into()
+                .unwrap(),
+        )
+    }
+    /// Write statements applied to the file
+    pub fn get_write_count(&self) -> u64 {
+        u64::from_le_bytes(
+            self.node.page.borrow().buf
+                [META_WRITE_COUNT_OFFSET..META_WRITE_COUNT_OFFSET + META_WRITE_COUNT_SIZE]
                 .try_into()
                 .unwrap(),
         )
     }
+    /// Entries of the application, in the order they were first set
+    pub fn user_entries(&self) -> SqlResult<Vec<(String, Vec<u8>)>> {
+        let page = self.node.page.borrow();
+        let buf = &page.buf[USER_META_OFFSET..USER_META_OFFSET + USER_META_SIZE];
+        let corrupt = |detail: &str| SqlError::Corrupt {
+            page: META_NODE_NUM,
+            detail: detail.to_string(),
+        };
+        let mut entries = Vec::new();
+        let mut offset = 0;
+        while offset + USER_ENTRY_HEADER_SIZE <= buf.len() && buf[offset] != 0 {
+            let key_len = buf[offset] as usize;
+            let value_len = u16::from_le_bytes(
+                buf[offset + USER_KEY_LEN_SIZE..offset + USER_ENTRY_HEADER_SIZE]
+                    .try_into()
+                    .unwrap(),
+            ) as usize;
+            let key_start = offset + USER_ENTRY_HEADER_SIZE;
+            let value_start = key_start + key_len;
+            offset = value_start + value_len;
+            if offset > buf.len() {
+                return Err(corrupt("meta entry runs past its space"));
+            }
+            let key = std::str::from_utf8(&buf[key_start..value_start])
+                .map_err(|_| corrupt("meta entry key is not UTF-8"))?;
+            entries.push((key.to_string(), buf[value_start..offset].to_vec()));
+        }
+        Ok(entries)
+    }
 }
 impl MetaMut {
     pub fn new(node: Node) -> Self {
@@ -31,12 +418,165 @@ impl MetaMut {
         }
     }
     pub fn init(&self) {
+        self.set_header();
         self.set_root_num(DEFAULT_ROOT_NUM);
+        self.set_row_count(0);
+        self.set_next_id(DEFAULT_NEXT_ID);
+        self.set_version(FORMAT_VERSION);
+    }
+    fn set_header(&self) {
+        let mut page = self.node_erf.node.page_mut();
+        let page_size = page.buf.len() as u32;
+        page.buf[META_MAGIC_OFFSET..META_MAGIC_OFFSET + META_MAGIC_SIZE].copy_from_slice(MAGIC);
+        page.buf[META_PAGE_SIZE_OFFSET..META_PAGE_SIZE_OFFSET + META_PAGE_SIZE_SIZE]
+            .copy_from_slice(&page_size.to_le_bytes());
+    }
+    /// Mark the file as encrypted
+    #[cfg(feature = "encryption")]
+    pub fn set_encrypted(&self) {
+        self.node_erf.node.page_mut().buf[META_MAGIC_OFFSET..META_MAGIC_OFFSET + META_MAGIC_SIZE]
+            .copy_from_slice(ENCRYPTED_MAGIC);
+    }
+    /// Take the fields from the newest whole slot, which a torn write of the
+    /// meta page leaves in place. Returns its sequence number, or None if
+    /// neither slot is whole.
+    pub fn load_newest_slot(&self) -> Option<u64> {
+        let mut page = self.node_erf.node.page.borrow_mut();
+        let seq = newest_slot(&page.buf)?;
+        let end = fields_end(&page.buf);
+        let start = slot_range(end, seq).start + META_SLOT_SEQ_SIZE;
+        page.buf
+            .copy_within(start..start + end - MEAT_ROOT_OFFSET, MEAT_ROOT_OFFSET);
+        Some(seq)
+    }
+    /// Move the fields of a page written before the header after it.
+    /// The old version is kept, so the table still upgrades from it.
+    pub fn add_header(&self) {
+        let old = self.node_erf.node.page.borrow().buf.clone();
+        let version = u32::from_le_bytes(
+            old[LEGACY_VERSION_OFFSET..LEGACY_VERSION_OFFSET + META_VERSION_SIZE]
+                .try_into()
+                .unwrap(),
+        );
+        {
+            let mut page = self.node_erf.node.page_mut();
+            page.buf[..LEGACY_FIELDS_END].fill(0);
+            page.buf[MEAT_ROOT_OFFSET..META_JOURNAL_SEQ_OFFSET]
+                .copy_from_slice(&old[..LEGACY_VERSION_OFFSET]);
+            page.buf[META_JOURNAL_SEQ_OFFSET..META_PAGE_COUNT_OFFSET].copy_from_slice(
+                &old[LEGACY_VERSION_OFFSET + META_VERSION_SIZE..LEGACY_FIELDS_END],
+            );
+        }
+        self.set_header();
+        self.set_version(version);
     }
     pub fn set_root_num(&self, root_num: usize) {
-        self.node_erf.node.page.borrow_mut().buf
-            [MEAT_ROOT_OFFSET..MEAT_ROOT_OFFSET + META_ROOT_NODE_SIZE]
-            .copy_from_slice(&root_num.to_le_bytes());
+        write_pointer(
+            &mut self.node_erf.node.page_mut().buf
+                [MEAT_ROOT_OFFSET..MEAT_ROOT_OFFSET + META_ROOT_NODE_SIZE],
+            root_num,
+        );
+    }
+    pub fn set_row_count(&self, row_count: usize) {
+        self.node_erf.node.page_mut().buf
+            [META_ROW_COUNT_OFFSET..META_ROW_COUNT_OFFSET + META_ROW_COUNT_SIZE]
+            .copy_from_slice(&(row_count as u64).to_le_bytes());
+    }
+    pub fn set_next_id(&self, next_id: i64) {
+        self.node_erf.node.page_mut().buf
+            [META_NEXT_ID_OFFSET..META_NEXT_ID_OFFSET + META_NEXT_ID_SIZE]
+            .copy_from_slice(&next_id.to_le_bytes());
+    }
+    pub fn set_version(&self, version: u32) {
+        self.node_erf.node.page_mut().buf
+            [META_VERSION_OFFSET..META_VERSION_OFFSET + META_VERSION_SIZE]
+            .copy_from_slice(&version.to_le_bytes());
+    }
+    pub fn set_journal_seq(&self, seq: u64) {
+        self.node_erf.node.page_mut().buf
+            [META_JOURNAL_SEQ_OFFSET..META_JOURNAL_SEQ_OFFSET + META_JOURNAL_SEQ_SIZE]
+            .copy_from_slice(&seq.to_le_bytes());
+    }
+    pub fn set_follow_offset(&self, offset: u64) {
+        self.node_erf.node.page_mut().buf
+            [META_FOLLOW_OFFSET_OFFSET..META_FOLLOW_OFFSET_OFFSET + META_FOLLOW_OFFSET_SIZE]
+            .copy_from_slice(&offset.to_le_bytes());
+    }
+    pub fn set_follow_checksum(&self, checksum: u32) {
+        self.node_erf.node.page_mut().buf
+            [META_FOLLOW_CHECKSUM_OFFSET..META_FOLLOW_CHECKSUM_OFFSET + META_FOLLOW_CHECKSUM_SIZE]
+            .copy_from_slice(&checksum.to_le_bytes());
+    }
+    pub fn set_free_head(&self, page_num: usize) {
+        write_pointer(
+            &mut self.node_erf.node.page_mut().buf
+                [META_FREE_HEAD_OFFSET..META_FREE_HEAD_OFFSET + META_FREE_HEAD_SIZE],
+            page_num,
+        );
+    }
+    pub fn set_page_count(&self, page_count: usize) {
+        write_pointer(
+            &mut self.node_erf.node.page_mut().buf
+                [META_PAGE_COUNT_OFFSET..META_PAGE_COUNT_OFFSET + META_PAGE_COUNT_SIZE],
+            page_count,
+        );
+    }
+    pub fn set_created(&self, created: u64) {
+        self.node_erf.node.page_mut().buf
+            [META_CREATED_OFFSET..META_CREATED_OFFSET + META_CREATED_SIZE]
+            .copy_from_slice(&created.to_le_bytes());
+    }
+    pub fn set_modified(&self, modified: u64) {
+        self.node_erf.node.page_mut().buf
+            [META_MODIFIED_OFFSET..META_MODIFIED_OFFSET + META_MODIFIED_SIZE]
+            .copy_from_slice(&modified.to_le_bytes());
+    }
+    pub fn set_write_count(&self, count: u64) {
+        self.node_erf.node.page_mut().buf
+            [META_WRITE_COUNT_OFFSET..META_WRITE_COUNT_OFFSET + META_WRITE_COUNT_SIZE]
+            .copy_from_slice(&count.to_le_bytes());
+    }
+    /// Count a write statement applied at `now`
+    pub fn record_write(&self, now: u64) {
+        self.record_writes(1, now);
+    }
+    /// `record_write` for `count` writes at once
+    pub fn record_writes(&self, count: usize, now: u64) {
+        self.set_write_count(self.node_erf.get_write_count() + count as u64);
+        self.set_modified(now);
+    }
+    /// Replace the entries of the application, failing with MetaFull if
+    /// they do not fit in USER_META_SIZE bytes
+    pub fn set_user_entries(&self, entries: &[(String, Vec<u8>)]) -> SqlResult<()> {
+        let mut encoded = Vec::new();
+        for (key, value) in entries {
+            if key.is_empty() || key.len() > u8::MAX as usize || value.len() > u16::MAX as usize {
+                return Err(SqlError::InvalidArgs);
+            }
+            encoded.push(key.len() as u8);
+            encoded.extend_from_slice(&(value.len() as u16).to_le_bytes());
+            encoded.extend_from_slice(key.as_bytes());
+            encoded.extend_from_slice(value);
+        }
+        if encoded.len() > USER_META_SIZE {
+            return Err(SqlError::MetaFull);
+        }
+        let mut page = self.node_erf.node.page_mut();
+        let buf = &mut page.buf[USER_META_OFFSET..USER_META_OFFSET + USER_META_SIZE];
+        buf[..encoded.len()].copy_from_slice(&encoded);
+        buf[encoded.len()..].fill(0);
+        Ok(())
+    }
+    /// Clear the space of the entries in a file before format version 18
+    pub fn clear_user_entries(&self) {
+        self.node_erf.node.page_mut().buf[USER_META_OFFSET..USER_META_OFFSET + USER_META_SIZE]
+            .fill(0);
+    }
+    /// Clear the slots of a file before format version 17, which lie where
+    /// the fields added in it go, before the version is raised
+    pub fn clear_infoless_slots(&self) {
+        let end = INFOLESS_FIELDS_END + 2 * slot_size(INFOLESS_FIELDS_END);
+        self.node_erf.node.page_mut().buf[INFOLESS_FIELDS_END..end].fill(0);
     }
 }
 
@@ -47,10 +587,72 @@ mod test {
     use super::*;
     #[test]
     fn test_meta() {
-        let node = Node::new(new_page());
+        let node = Node::new(0, new_page());
         let meta = node.init_meta();
+        assert_eq!(meta.node_erf.get_version(), FORMAT_VERSION);
+        assert_eq!(meta.node_erf.get_journal_seq(), 0);
+        meta.set_journal_seq(9);
+        assert_eq!(meta.node_erf.get_journal_seq(), 9);
+        meta.set_follow_offset(40);
+        meta.set_follow_checksum(7);
+        assert_eq!(meta.node_erf.get_follow_offset(), 40);
+        assert_eq!(meta.node_erf.get_follow_checksum(), 7);
+        assert_eq!(meta.node_erf.get_free_head(), 0);
+        meta.set_free_head(3);
+        assert_eq!(meta.node_erf.get_free_head(), 3);
+        meta.set_page_count(12);
+        assert_eq!(meta.node_erf.get_page_count(), 12);
+        assert_eq!(meta.node_erf.get_free_head(), 3);
+        assert_eq!(meta.node_erf.get_follow_checksum(), 7);
+        assert_eq!(meta.node_erf.get_journal_seq(), 9);
         assert_eq!(meta.node_erf.get_root_num(), DEFAULT_ROOT_NUM);
         meta.set_root_num(2);
         assert_eq!(meta.node_erf.get_root_num(), 2);
+        assert_eq!(meta.node_erf.get_row_count(), 0);
+        meta.set_row_count(5);
+        assert_eq!(meta.node_erf.get_row_count(), 5);
+        assert_eq!(meta.node_erf.get_root_num(), 2);
+        assert_eq!(meta.node_erf.get_next_id(), DEFAULT_NEXT_ID);
+        meta.set_next_id(-3);
+        assert_eq!(meta.node_erf.get_next_id(), -3);
+        assert_eq!(meta.node_erf.get_row_count(), 5);
+        assert_eq!(meta.node_erf.stored_version(3).unwrap(), FORMAT_VERSION);
+        assert_eq!(meta.node_erf.get_write_count(), 0);
+        meta.set_created(100);
+        meta.record_write(200);
+        meta.record_write(300);
+        assert_eq!(meta.node_erf.get_created(), 100);
+        assert_eq!(meta.node_erf.get_modified(), 300);
+        assert_eq!(meta.node_erf.get_write_count(), 2);
+        assert_eq!(meta.node_erf.get_page_count(), 12);
+    }
+
+    #[test]
+    fn legacy_meta() {
+        // Fields of a version 5 page, which started at 0 without a header,
+        // once its page numbers are narrowed
+        let node = Node::new(0, new_page());
+        {
+            let mut page = node.page_mut();
+            page.buf[0..4].copy_from_slice(&2u32.to_le_bytes());
+            page.buf[4..12].copy_from_slice(&7u64.to_le_bytes());
+            page.buf[12..20].copy_from_slice(&8i64.to_le_bytes());
+            page.buf[20..24].copy_from_slice(&5u32.to_le_bytes());
+            page.buf[24..32].copy_from_slice(&9u64.to_le_bytes());
+            page.buf[44..48].copy_from_slice(&3u32.to_le_bytes());
+        }
+        let meta = node.meta_node_mut();
+        assert!(matches!(
+            meta.node_erf.stored_version(2),
+            Err(SqlError::NotADatabase)
+        ));
+        assert_eq!(meta.node_erf.stored_version(4).unwrap(), 5);
+        meta.add_header();
+        assert_eq!(meta.node_erf.stored_version(4).unwrap(), 5);
+        assert_eq!(meta.node_erf.get_root_num(), 2);
+        assert_eq!(meta.node_erf.get_row_count(), 7);
+        assert_eq!(meta.node_erf.get_next_id(), 8);
+        assert_eq!(meta.node_erf.get_journal_seq(), 9);
+        assert_eq!(meta.node_erf.get_free_head(), 3);
     }
 }
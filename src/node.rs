@@ -1,12 +1,14 @@
 use std::{
     cell::{Ref, RefMut},
+    cmp::Ordering,
     fmt::Display,
-    ops::Deref,
+    ops::{Deref, Range},
 };
 
 use crate::{
     meta::{MetaMut, MetaRef},
-    pager::{Page, PageBuffer, PAGE_SIZE},
+    pager::{Page, PageBuffer, CHECKSUM_SIZE, MIN_PAGE_SIZE, PAGE_RESERVED_SIZE},
+    sql_error::{SqlError, SqlResult},
     table::{Row, ROW_SIZE},
 };
 
@@ -16,7 +18,19 @@ pub enum NodeType {
     Leaf,
 }
 
-pub const POINTER_SIZE: usize = std::mem::size_of::<usize>();
+/// Page numbers and cell counts are stored as u32, whatever the width of
+/// usize on the machine writing them. Keys and row counts are 8 bytes.
+pub const POINTER_SIZE: usize = 4;
+
+/// Read a page number or count stored at the start of `buf`
+pub fn read_pointer(buf: &[u8]) -> usize {
+    u32::from_le_bytes(buf[..POINTER_SIZE].try_into().unwrap()) as usize
+}
+/// Store a page number or count at the start of `buf`
+pub fn write_pointer(buf: &mut [u8], value: usize) {
+    debug_assert!(u32::try_from(value).is_ok());
+    buf[..POINTER_SIZE].copy_from_slice(&(value as u32).to_le_bytes());
+}
 
 // COMMON_NODE_HEADER:
 //   NODE_TYPE, IS_ROOT, PARENT_POINTER
@@ -29,53 +43,214 @@ const PARENT_POINTER_OFFSET: usize = IS_ROOT_OFFSET + IS_ROOT_SIZE;
 const COMMON_NODE_HEADER_SIZE: usize = NODE_TYPE_SIZE + IS_ROOT_SIZE + PARENT_POINTER_SIZE;
 
 // LEAF NODE HEADER
-//   COMMON_NODE_HEADER, NUM_CELLS
+//   COMMON_NODE_HEADER, NUM_CELLS, NEXT_LEAF, CONTENT_START, PREV_LEAF
 const LEAF_NODE_NUM_CELLS_SIZE: usize = POINTER_SIZE;
 const LEAF_NODE_NUM_CELLS_OFFSET: usize = COMMON_NODE_HEADER_SIZE;
 const LEAF_NODE_NEXT_LEAF_OFFSET: usize = LEAF_NODE_NUM_CELLS_OFFSET + LEAF_NODE_NUM_CELLS_SIZE;
 const LEAF_NODE_NEXT_LEAF_SIZE: usize = POINTER_SIZE;
-const LEAF_NODE_HEADER_SIZE: usize =
-    COMMON_NODE_HEADER_SIZE + LEAF_NODE_NUM_CELLS_SIZE + LEAF_NODE_NEXT_LEAF_SIZE;
+const LEAF_NODE_CONTENT_START_OFFSET: usize = LEAF_NODE_NEXT_LEAF_OFFSET + LEAF_NODE_NEXT_LEAF_SIZE;
+const LEAF_NODE_CONTENT_START_SIZE: usize = POINTER_SIZE;
+const LEAF_NODE_PREV_LEAF_OFFSET: usize =
+    LEAF_NODE_CONTENT_START_OFFSET + LEAF_NODE_CONTENT_START_SIZE;
+const LEAF_NODE_PREV_LEAF_SIZE: usize = POINTER_SIZE;
+const LEAF_NODE_HEADER_SIZE: usize = COMMON_NODE_HEADER_SIZE
+    + LEAF_NODE_NUM_CELLS_SIZE
+    + LEAF_NODE_NEXT_LEAF_SIZE
+    + LEAF_NODE_CONTENT_START_SIZE
+    + LEAF_NODE_PREV_LEAF_SIZE;
 
 // LEAF NODE BODY
-//  {NODE_KEY, NODE_VALUE}...
+//   {KEY, CELL_OFFSET}... slots growing from the header, free space, then
+//   {VALUE_LENGTH, VALUE or FIRST_OVERFLOW_PAGE}... packed at the end of the
+//   page from CONTENT_START, in any order.
+// Searching a leaf reads the keys side by side in the slots, and no values.
+// The cells handed in and out of a leaf are a key followed by the rest, as
+// every cell was stored before format version 15, so a cell takes an offset
+// more than its bytes.
+// A value longer than `max_inline_value` is kept in a chain of overflow pages.
+// Keys are i64 stored as little-endian two's complement and compared as i64,
+// so non-negative keys keep the same bytes as files written with u64 keys.
+pub const LEAF_NODE_OFFSET_SIZE: usize = POINTER_SIZE;
 const LEAF_NODE_KEY_SIZE: usize = 8;
-#[allow(dead_code)]
-const LEAF_NODE_KEY_OFFSET: usize = 0;
-const LEAF_NODE_VALUE_SIZE: usize = ROW_SIZE;
-#[allow(dead_code)]
-const LEAF_NODE_VALUE_OFFSET: usize = LEAF_NODE_KEY_OFFSET + LEAF_NODE_KEY_SIZE;
-const LEAF_NODE_CELL_SIZE: usize = LEAF_NODE_KEY_SIZE + LEAF_NODE_VALUE_SIZE;
-#[allow(dead_code)]
-const LEAF_NODE_SPACE_FOR_CELLS: usize = PAGE_SIZE - LEAF_NODE_HEADER_SIZE;
-// pub const LEAF_NODE_MAX_CELLS: usize = LEAF_NODE_SPACE_FOR_CELLS / LEAF_NODE_CELL_SIZE;
-pub const LEAF_NODE_MAX_CELLS: usize = 4; // DEBUG: 4 for testing
+const LEAF_NODE_SLOT_SIZE: usize = LEAF_NODE_KEY_SIZE + LEAF_NODE_OFFSET_SIZE;
+const LEAF_NODE_VALUE_LEN_SIZE: usize = POINTER_SIZE;
+const LEAF_NODE_CELL_HEADER_SIZE: usize = LEAF_NODE_KEY_SIZE + LEAF_NODE_VALUE_LEN_SIZE;
+// Cell counts do not depend on the page size, so they must fit the smallest page
+const LEAF_NODE_SPACE_FOR_CELLS: usize = MIN_PAGE_SIZE - PAGE_RESERVED_SIZE - LEAF_NODE_HEADER_SIZE;
+/// Most cells of empty values the smallest page has room for
+pub const LEAF_NODE_MAX_CELLS: usize =
+    LEAF_NODE_SPACE_FOR_CELLS / (LEAF_NODE_OFFSET_SIZE + LEAF_NODE_CELL_HEADER_SIZE);
+// Cells before format version 11 all held a key and a row
+const LEGACY_LEAF_NODE_HEADER_SIZE: usize = LEAF_NODE_CONTENT_START_OFFSET;
+const LEGACY_LEAF_NODE_CELL_SIZE: usize = LEAF_NODE_KEY_SIZE + ROW_SIZE;
+// Headers before format version 13 had no previous leaf
+const UNLINKED_LEAF_NODE_HEADER_SIZE: usize = LEAF_NODE_PREV_LEAF_OFFSET;
+/// Most cells of empty values a leaf held before format version 13
+pub const UNLINKED_LEAF_NODE_MAX_CELLS: usize =
+    (MIN_PAGE_SIZE - PAGE_RESERVED_SIZE - UNLINKED_LEAF_NODE_HEADER_SIZE)
+        / (LEAF_NODE_OFFSET_SIZE + LEAF_NODE_CELL_HEADER_SIZE);
+
+/// Bytes for cells and their offsets in a leaf of a page of `page_size`
+pub fn leaf_space(page_size: usize) -> usize {
+    page_size - PAGE_RESERVED_SIZE - LEAF_NODE_HEADER_SIZE
+}
+/// Longest value kept in its cell. Any four cells fit in a leaf, so a leaf
+/// split in two always has room for one more.
+pub fn max_inline_value(page_size: usize) -> usize {
+    inline_limit(leaf_space(page_size))
+}
+/// Longest value of which four cells fit in `space`
+fn inline_limit(space: usize) -> usize {
+    space / 4 - LEAF_NODE_OFFSET_SIZE - LEAF_NODE_CELL_HEADER_SIZE
+}
+/// Cell holding a value in place
+pub fn inline_cell(key: i64, value: &[u8]) -> Vec<u8> {
+    let mut cell = Vec::with_capacity(LEAF_NODE_CELL_HEADER_SIZE + value.len());
+    cell.extend_from_slice(&key.to_le_bytes());
+    cell.extend_from_slice(&(value.len() as u32).to_le_bytes());
+    cell.extend_from_slice(value);
+    cell
+}
+/// Cell of a value of `len` bytes kept in overflow pages from `first_page`
+pub fn overflow_cell(key: i64, len: usize, first_page: usize) -> Vec<u8> {
+    let mut cell = vec![0; LEAF_NODE_CELL_HEADER_SIZE + POINTER_SIZE];
+    cell[..LEAF_NODE_KEY_SIZE].copy_from_slice(&key.to_le_bytes());
+    write_pointer(&mut cell[LEAF_NODE_KEY_SIZE..], len);
+    write_pointer(&mut cell[LEAF_NODE_CELL_HEADER_SIZE..], first_page);
+    cell
+}
+/// Start of the slot of a cell, its key followed by its offset
+fn slot_at(cell: usize) -> usize {
+    LEAF_NODE_HEADER_SIZE + cell * LEAF_NODE_SLOT_SIZE
+}
+
+/// Value of a leaf cell: its bytes in the page, or where its overflow pages start
+pub enum LeafValue<'a> {
+    Inline(Ref<'a, [u8]>),
+    Overflow { first_page: usize, len: usize },
+}
+/// Value of a cell copied out of its page
+pub enum CellValue {
+    Inline(Vec<u8>),
+    Overflow { first_page: usize, len: usize },
+}
 
 // INTERNAL NODE HEADER
+//   COMMON_NODE_HEADER, NUM_KEYS, RIGHT_CHILD, RIGHT_COUNT, NEXT_INTERNAL,
+//   PREV_INTERNAL
+// The right child holds the rows from the last key on. The next and previous
+// internal nodes are the neighbours on the same level, under any parent.
 const INTERNAL_NODE_NUM_KEYS_SIZE: usize = POINTER_SIZE;
 const INTERNAL_NODE_NUM_KEYS_OFFSET: usize = COMMON_NODE_HEADER_SIZE;
-const INTERNAL_NODE_HEADER_SIZE: usize = COMMON_NODE_HEADER_SIZE + INTERNAL_NODE_NUM_KEYS_SIZE;
+const INTERNAL_NODE_RIGHT_CHILD_OFFSET: usize =
+    INTERNAL_NODE_NUM_KEYS_OFFSET + INTERNAL_NODE_NUM_KEYS_SIZE;
+const INTERNAL_NODE_RIGHT_COUNT_OFFSET: usize =
+    INTERNAL_NODE_RIGHT_CHILD_OFFSET + INTERNAL_NODE_CHILD_SIZE;
+const INTERNAL_NODE_NEXT_INTERNAL_OFFSET: usize =
+    INTERNAL_NODE_RIGHT_COUNT_OFFSET + INTERNAL_NODE_COUNT_SIZE;
+const INTERNAL_NODE_PREV_INTERNAL_OFFSET: usize = INTERNAL_NODE_NEXT_INTERNAL_OFFSET + POINTER_SIZE;
+const INTERNAL_NODE_HEADER_SIZE: usize = INTERNAL_NODE_PREV_INTERNAL_OFFSET + POINTER_SIZE;
+/// Header of format versions 14 and 15, which had no neighbours
+const SEPARATOR_INTERNAL_NODE_HEADER_SIZE: usize = INTERNAL_NODE_NEXT_INTERNAL_OFFSET;
+/// Header before format version 14, which had no right child
+const KEYED_INTERNAL_NODE_HEADER_SIZE: usize = INTERNAL_NODE_RIGHT_CHILD_OFFSET;
 
 // INTERNAL NODE BODY
-//   {INTERNAL_NODE_CHILD, INTERNAL_NODE_KEY}...
+//   {INTERNAL_NODE_CHILD, INTERNAL_NODE_KEY, INTERNAL_NODE_COUNT}...
+// The child of a cell holds the rows from the key before it up to its key.
+// The count is the number of rows under the child. Before format version
+// 14 every child had a cell keyed by the first key under it, and before
+// version 3 cells had no count.
 const INTERNAL_NODE_CHILD_SIZE: usize = POINTER_SIZE;
 const INTERNAL_NODE_KEY_SIZE: usize = 8;
-const INTERNAL_NODE_CELL_SIZE: usize = INTERNAL_NODE_CHILD_SIZE + INTERNAL_NODE_KEY_SIZE;
-pub const INTERNAL_NODE_MAX_CELLS: usize = 4; // DEBUG: 4 for testing
+const INTERNAL_NODE_COUNT_SIZE: usize = 8;
+const INTERNAL_NODE_COUNT_OFFSET: usize = INTERNAL_NODE_CHILD_SIZE + INTERNAL_NODE_KEY_SIZE;
+const INTERNAL_NODE_CELL_SIZE: usize =
+    INTERNAL_NODE_CHILD_SIZE + INTERNAL_NODE_KEY_SIZE + INTERNAL_NODE_COUNT_SIZE;
+const LEGACY_INTERNAL_NODE_CELL_SIZE: usize = INTERNAL_NODE_CHILD_SIZE + INTERNAL_NODE_KEY_SIZE;
+const INTERNAL_NODE_SPACE_FOR_CELLS: usize =
+    MIN_PAGE_SIZE - PAGE_RESERVED_SIZE - INTERNAL_NODE_HEADER_SIZE;
+const INTERNAL_NODE_MAX_KEYS: usize = INTERNAL_NODE_SPACE_FOR_CELLS / INTERNAL_NODE_CELL_SIZE;
+/// Most children of an internal node, one more than its keys
+pub const INTERNAL_NODE_MAX_CELLS: usize = INTERNAL_NODE_MAX_KEYS + 1;
 
 // Node Splitting
-pub const LEAF_NODE_LEFT_SPLIT_COUNT: usize = (LEAF_NODE_MAX_CELLS + 2) / 2;
-pub const LEAF_NODE_RIGHT_SPLIT_COUNT: usize = LEAF_NODE_MAX_CELLS + 1 - LEAF_NODE_LEFT_SPLIT_COUNT;
+/// Cells left in the old node and moved to the new one when `cells` are split
+pub fn split_counts(cells: usize) -> (usize, usize) {
+    let left = cells.div_ceil(2);
+    (left, cells - left)
+}
+
+/// Most cells a node holds before it splits, up to as many as fit in a page.
+/// Fewer cells make deep trees out of few rows, which tests rely on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NodeLimits {
+    leaf_max_cells: usize,
+    internal_max_cells: usize,
+}
 
-pub const INTERNAL_NODE_LEFT_SPLIT_COUNT: usize = (INTERNAL_NODE_MAX_CELLS + 2) / 2;
-pub const INTERNAL_NODE_RIGHT_SPLIT_COUNT: usize =
-    INTERNAL_NODE_MAX_CELLS + 1 - INTERNAL_NODE_LEFT_SPLIT_COUNT;
+impl NodeLimits {
+    /// Nodes filling their pages
+    pub const FULL: NodeLimits = NodeLimits {
+        leaf_max_cells: LEAF_NODE_MAX_CELLS,
+        internal_max_cells: INTERNAL_NODE_MAX_CELLS,
+    };
+    /// Nodes of four cells
+    pub const TINY: NodeLimits = NodeLimits {
+        leaf_max_cells: 4,
+        internal_max_cells: 4,
+    };
+    /// Fewest cells a node may split at
+    pub const MIN_MAX_CELLS: usize = 3;
+
+    pub fn new(leaf_max_cells: usize, internal_max_cells: usize) -> SqlResult<Self> {
+        for (max_cells, capacity) in [
+            (leaf_max_cells, LEAF_NODE_MAX_CELLS),
+            (internal_max_cells, INTERNAL_NODE_MAX_CELLS),
+        ] {
+            if !(Self::MIN_MAX_CELLS..=capacity).contains(&max_cells) {
+                return Err(SqlError::InvalidMaxCells(max_cells));
+            }
+        }
+        Ok(NodeLimits {
+            leaf_max_cells,
+            internal_max_cells,
+        })
+    }
+    pub fn leaf_max_cells(&self) -> usize {
+        self.leaf_max_cells
+    }
+    pub fn internal_max_cells(&self) -> usize {
+        self.internal_max_cells
+    }
+    /// Leaves with fewer cells take cells from a sibling or merge with it
+    pub fn leaf_min_cells(&self) -> usize {
+        split_counts(self.leaf_max_cells + 1).1
+    }
+    /// Internal nodes with fewer children take children from a sibling or merge
+    /// with it
+    pub fn internal_min_cells(&self) -> usize {
+        split_counts(self.internal_max_cells + 1).1
+    }
+}
+
+impl Default for NodeLimits {
+    fn default() -> Self {
+        if cfg!(test) {
+            Self::TINY
+        } else {
+            Self::FULL
+        }
+    }
+}
 
 pub const MISSING_NODE: usize = 0;
 
 #[derive(Debug, Clone)]
 pub struct Node {
     pub page: Page,
+    /// Page number, for errors
+    pub page_num: usize,
 }
 
 #[derive(Debug, Clone)]
@@ -108,66 +283,93 @@ pub enum NodeMut {
 }
 
 impl Node {
-    pub fn new(page: Page) -> Self {
-        Self { page }
+    pub fn new(page_num: usize, page: Page) -> Self {
+        Self { page, page_num }
+    }
+    fn corrupt(&self, detail: String) -> SqlError {
+        SqlError::Corrupt {
+            page: self.page_num,
+            detail,
+        }
+    }
+    /// Borrow the page for writing, marking it to be flushed
+    pub fn page_mut(&self) -> RefMut<'_, Box<PageBuffer>> {
+        let mut page = self.page.borrow_mut();
+        page.dirty = true;
+        page
     }
     pub fn raw_buf(&self) -> RefMut<[u8]> {
-        RefMut::map(self.page.borrow_mut(), |page| &mut page.buf[..])
+        RefMut::map(self.page_mut(), |page| &mut page.buf[..])
     }
     // Leaf Node
     pub fn init_leaf(&self) -> LeafMut {
         self.set_type(NodeType::Leaf);
         self.set_root(false);
-        let leaf = self.leaf_node_mut();
-        leaf.set_num_cells(0);
+        let leaf = LeafMut {
+            node_ref: LeafRef { node: self.clone() },
+        };
+        leaf.clear();
         leaf.set_next_leaf(MISSING_NODE);
+        leaf.set_prev_leaf(MISSING_NODE);
         leaf
     }
-    pub fn leaf_node_mut(&self) -> LeafMut {
-        assert!(self.is_leaf());
-        LeafMut {
-            node_ref: self.leaf_node(),
-        }
+    pub fn leaf_node_mut(&self) -> SqlResult<LeafMut> {
+        Ok(LeafMut {
+            node_ref: self.leaf_node()?,
+        })
     }
-    pub fn leaf_node(&self) -> LeafRef {
-        assert!(self.is_leaf());
-        LeafRef { node: self.clone() }
+    /// The node as a leaf, failing if it is not one
+    pub fn leaf_node(&self) -> SqlResult<LeafRef> {
+        match self.get_type()? {
+            NodeType::Leaf => Ok(LeafRef { node: self.clone() }),
+            NodeType::Internal => {
+                Err(self.corrupt("internal node where a leaf was expected".to_string()))
+            }
+        }
     }
 
     // Internal Node
     pub fn init_internal(&self) -> InternalMut {
         self.set_type(NodeType::Internal);
         self.set_root(false);
-        let internal = self.internal_node_mut();
+        let internal = InternalMut {
+            node_ref: InternalRef { node: self.clone() },
+        };
         internal.set_num_keys(0);
+        internal.set_next_internal(MISSING_NODE);
+        internal.set_prev_internal(MISSING_NODE);
         internal
     }
-    pub fn internal_node_mut(&self) -> InternalMut {
-        assert!(self.is_internal());
-        InternalMut {
-            node_ref: self.internal_node(),
-        }
+    pub fn internal_node_mut(&self) -> SqlResult<InternalMut> {
+        Ok(InternalMut {
+            node_ref: self.internal_node()?,
+        })
     }
-    pub fn internal_node(&self) -> InternalRef {
-        assert!(self.is_internal());
-        InternalRef { node: self.clone() }
+    /// The node as an internal node, failing if it is not one
+    pub fn internal_node(&self) -> SqlResult<InternalRef> {
+        match self.get_type()? {
+            NodeType::Internal => Ok(InternalRef { node: self.clone() }),
+            NodeType::Leaf => {
+                Err(self.corrupt("leaf where an internal node was expected".to_string()))
+            }
+        }
     }
 
     // Common Node
     pub fn set_root(&self, is_root: bool) {
-        self.page.borrow_mut().buf[IS_ROOT_OFFSET] = is_root as u8;
+        self.page_mut().buf[IS_ROOT_OFFSET] = is_root as u8;
     }
     pub fn is_root(&self) -> bool {
         self.page.borrow().buf[IS_ROOT_OFFSET] == 1
     }
     pub fn set_type(&self, node_type: NodeType) {
-        self.page.borrow_mut().buf[NODE_TYPE_OFFSET] = node_type as u8;
+        self.page_mut().buf[NODE_TYPE_OFFSET] = node_type as u8;
     }
-    pub fn get_type(&self) -> NodeType {
+    pub fn get_type(&self) -> SqlResult<NodeType> {
         match self.page.borrow().buf[NODE_TYPE_OFFSET] {
-            0 => NodeType::Internal,
-            1 => NodeType::Leaf,
-            _ => panic!("Unknown node type"),
+            0 => Ok(NodeType::Internal),
+            1 => Ok(NodeType::Leaf),
+            node_type => Err(self.corrupt(format!("unknown node type {}", node_type))),
         }
     }
     pub fn is_leaf(&self) -> bool {
@@ -176,58 +378,93 @@ impl Node {
     pub fn is_internal(&self) -> bool {
         self.page.borrow().buf[NODE_TYPE_OFFSET] == NodeType::Internal as u8
     }
-    pub fn as_typed(&self) -> NodeRef {
-        if self.is_leaf() {
-            NodeRef::Leaf(self.leaf_node())
-        } else {
-            NodeRef::Internal(self.internal_node())
-        }
+    pub fn as_typed(&self) -> SqlResult<NodeRef> {
+        Ok(match self.get_type()? {
+            NodeType::Leaf => NodeRef::Leaf(LeafRef { node: self.clone() }),
+            NodeType::Internal => NodeRef::Internal(InternalRef { node: self.clone() }),
+        })
     }
-    pub fn as_typed_mut(&mut self) -> NodeMut {
-        if self.is_leaf() {
-            NodeMut::Leaf(self.leaf_node_mut())
-        } else {
-            NodeMut::Internal(self.internal_node_mut())
-        }
+    pub fn as_typed_mut(&mut self) -> SqlResult<NodeMut> {
+        Ok(match self.as_typed()? {
+            NodeRef::Leaf(node_ref) => NodeMut::Leaf(LeafMut { node_ref }),
+            NodeRef::Internal(node_ref) => NodeMut::Internal(InternalMut { node_ref }),
+        })
     }
 
     // Parent Node
     pub fn set_parent(&self, parent: usize) {
-        self.page.borrow_mut().buf
-            [PARENT_POINTER_OFFSET..PARENT_POINTER_OFFSET + PARENT_POINTER_SIZE]
-            .copy_from_slice(&parent.to_le_bytes())
+        write_pointer(&mut self.page_mut().buf[PARENT_POINTER_OFFSET..], parent)
     }
     pub fn get_parent(&self) -> usize {
-        usize::from_le_bytes(
-            self.page.borrow().buf
-                [PARENT_POINTER_OFFSET..PARENT_POINTER_OFFSET + PARENT_POINTER_SIZE]
-                .try_into()
-                .unwrap(),
-        )
+        read_pointer(&self.page.borrow().buf[PARENT_POINTER_OFFSET..])
     }
 
-    // Max Key (internal and leaf)
-    pub fn get_first_key(&self) -> u64 {
-        match self.as_typed() {
-            NodeRef::Internal(internal) => internal.get_key_at(0),
-            NodeRef::Leaf(leaf) => leaf.get_key(0),
+    /// Check the header of a page read from a file of `num_pages` pages:
+    /// its type and root flag, a cell count the page has room for, and
+    /// links to pages of the file or MISSING_NODE
+    pub fn check_header(&self, num_pages: usize) -> SqlResult<()> {
+        let node_type = self.get_type()?;
+        let buf = self.borrow_map(|page| &page.buf[..])?;
+        let is_root = buf[IS_ROOT_OFFSET];
+        if is_root > 1 {
+            return Err(self.corrupt(format!("is_root is {}", is_root)));
+        }
+        let (count, max_count, links) = match node_type {
+            NodeType::Leaf => (
+                ("num_cells", LEAF_NODE_NUM_CELLS_OFFSET),
+                LEAF_NODE_MAX_CELLS,
+                [
+                    ("parent", PARENT_POINTER_OFFSET),
+                    ("next_leaf", LEAF_NODE_NEXT_LEAF_OFFSET),
+                    ("prev_leaf", LEAF_NODE_PREV_LEAF_OFFSET),
+                ],
+            ),
+            NodeType::Internal => (
+                ("num_keys", INTERNAL_NODE_NUM_KEYS_OFFSET),
+                INTERNAL_NODE_MAX_KEYS,
+                [
+                    ("parent", PARENT_POINTER_OFFSET),
+                    ("next_internal", INTERNAL_NODE_NEXT_INTERNAL_OFFSET),
+                    ("prev_internal", INTERNAL_NODE_PREV_INTERNAL_OFFSET),
+                ],
+            ),
+        };
+        let (name, offset) = count;
+        let value = read_pointer(&buf[offset..]);
+        if value > max_count {
+            return Err(self.corrupt(format!("{} is {} of at most {}", name, value, max_count)));
         }
+        for (name, offset) in links {
+            let page_num = read_pointer(&buf[offset..]);
+            if page_num >= num_pages {
+                return Err(self.corrupt(format!(
+                    "{} is {} past the end of the file of {} pages",
+                    name, page_num, num_pages
+                )));
+            }
+        }
+        Ok(())
     }
 
     // Borrow Map
-    pub fn borrow_map<T, F>(&self, f: F) -> Ref<T>
+    // Borrows handed out of a node fail with PageBusy instead of panicking
+    // while the page is borrowed mutably, or at all for `borrow_mut_map`.
+    pub fn borrow_map<T, F>(&self, f: F) -> SqlResult<Ref<T>>
     where
         F: FnOnce(&Box<PageBuffer>) -> &T,
         T: ?Sized,
     {
-        Ref::map(self.page.borrow(), f)
+        let page = self.page.try_borrow().map_err(|_| SqlError::PageBusy)?;
+        Ok(Ref::map(page, f))
     }
-    pub fn borrow_mut_map<T, F>(&self, f: F) -> RefMut<T>
+    pub fn borrow_mut_map<T, F>(&self, f: F) -> SqlResult<RefMut<T>>
     where
         F: FnOnce(&mut Box<PageBuffer>) -> &mut T,
         T: ?Sized,
     {
-        RefMut::map(self.page.borrow_mut(), f)
+        let mut page = self.page.try_borrow_mut().map_err(|_| SqlError::PageBusy)?;
+        page.dirty = true;
+        Ok(RefMut::map(page, f))
     }
 
     // Meta
@@ -245,133 +482,834 @@ impl Node {
 }
 
 impl LeafRef {
-    pub fn get_cell(&self, cell: usize) -> Ref<[u8]> {
-        let start = LEAF_NODE_HEADER_SIZE + cell * LEAF_NODE_CELL_SIZE;
-        self.node
-            .borrow_map(|page| &page.buf[start..start + LEAF_NODE_CELL_SIZE])
+    /// Number of cells and start of their content, which must fit the page
+    fn checked_header(&self) -> SqlResult<(usize, usize)> {
+        // Read the header without panicking while the page is written
+        let buf = self.node.borrow_map(|page| &page.buf[..])?;
+        let num_cells = read_pointer(&buf[LEAF_NODE_NUM_CELLS_OFFSET..]);
+        if num_cells > LEAF_NODE_MAX_CELLS {
+            return Err(self.corrupt(format!(
+                "{} cells in a leaf of {}",
+                num_cells, LEAF_NODE_MAX_CELLS
+            )));
+        }
+        let content_start = read_pointer(&buf[LEAF_NODE_CONTENT_START_OFFSET..]);
+        if content_start < slot_at(num_cells) || content_start > buf.len() - PAGE_RESERVED_SIZE {
+            return Err(self.corrupt(format!(
+                "cells start at {} in a leaf of {} cells",
+                content_start, num_cells
+            )));
+        }
+        Ok((num_cells, content_start))
+    }
+    /// Slot of a cell there is
+    fn checked_slot(&self, cell: usize) -> SqlResult<usize> {
+        let (num_cells, _) = self.checked_header()?;
+        if cell >= num_cells {
+            return Err(self.corrupt(format!("cell {} of {} cells", cell, num_cells)));
+        }
+        Ok(slot_at(cell))
+    }
+    /// Bytes of a cell kept after the free space, from its value length
+    fn cell_range(&self, cell: usize) -> SqlResult<Range<usize>> {
+        let slot = self.checked_slot(cell)?;
+        let (_, content_start) = self.checked_header()?;
+        let buf = self.node.borrow_map(|page| &page.buf[..])?;
+        let end = buf.len() - PAGE_RESERVED_SIZE;
+        let start = read_pointer(&buf[slot + LEAF_NODE_KEY_SIZE..]);
+        if start < content_start || start + LEAF_NODE_VALUE_LEN_SIZE > end {
+            return Err(self.corrupt(format!("cell {} at {} is not in a cell", cell, start)));
+        }
+        let value_len = read_pointer(&buf[start..]);
+        let stored = if value_len > max_inline_value(buf.len()) {
+            POINTER_SIZE
+        } else {
+            value_len
+        };
+        if start + LEAF_NODE_VALUE_LEN_SIZE + stored > end {
+            return Err(self.corrupt(format!("cell {} runs past the end of the page", cell)));
+        }
+        Ok(start..start + LEAF_NODE_VALUE_LEN_SIZE + stored)
+    }
+    /// Key and bytes of a cell, as taken by `LeafMut::insert_cell`
+    pub fn get_cell(&self, cell: usize) -> SqlResult<Vec<u8>> {
+        let slot = self.checked_slot(cell)?;
+        let range = self.cell_range(cell)?;
+        let buf = self.node.borrow_map(|page| &page.buf[..])?;
+        let mut bytes = buf[slot..slot + LEAF_NODE_KEY_SIZE].to_vec();
+        bytes.extend_from_slice(&buf[range]);
+        Ok(bytes)
     }
     pub fn get_num_cells(&self) -> usize {
-        let start = LEAF_NODE_NUM_CELLS_OFFSET;
-        usize::from_le_bytes(
-            self.node.page.borrow().buf[start..start + LEAF_NODE_NUM_CELLS_SIZE]
-                .try_into()
-                .unwrap(),
-        )
+        read_pointer(&self.node.page.borrow().buf[LEAF_NODE_NUM_CELLS_OFFSET..])
     }
-    pub fn get_key(&self, cell: usize) -> u64 {
-        let start = LEAF_NODE_HEADER_SIZE + cell * LEAF_NODE_CELL_SIZE;
-        u64::from_le_bytes(
-            self.node.page.borrow().buf[start..start + LEAF_NODE_KEY_SIZE]
-                .try_into()
-                .unwrap(),
-        )
+    /// Length of a cell as `get_cell` returns it
+    pub fn cell_len(&self, cell: usize) -> SqlResult<usize> {
+        Ok(LEAF_NODE_KEY_SIZE + self.cell_range(cell)?.len())
     }
-    pub fn get_value(&self, cell: usize) -> Ref<[u8]> {
-        let start = LEAF_NODE_HEADER_SIZE + cell * LEAF_NODE_CELL_SIZE + LEAF_NODE_KEY_SIZE;
-        self.node
-            .borrow_map(|page| &page.buf[start..start + LEAF_NODE_VALUE_SIZE])
+    pub fn get_key(&self, cell: usize) -> SqlResult<i64> {
+        let slot = self.checked_slot(cell)?;
+        let buf = self.node.borrow_map(|page| &page.buf[..])?;
+        Ok(i64::from_le_bytes(
+            buf[slot..slot + LEAF_NODE_KEY_SIZE].try_into().unwrap(),
+        ))
+    }
+    /// Key of the first cell, or None in an empty leaf, which only the root
+    /// ever is
+    pub fn get_first_key(&self) -> SqlResult<Option<i64>> {
+        match self.get_num_cells() {
+            0 => Ok(None),
+            _ => Ok(Some(self.get_key(0)?)),
+        }
+    }
+    /// First cell whose key is not below `key`, or the number of cells
+    pub fn find_cell(&self, key: i64) -> SqlResult<usize> {
+        let mut min_cell = 0;
+        let mut max_cell = self.get_num_cells();
+        while min_cell < max_cell {
+            let mid_cell = (min_cell + max_cell) / 2;
+            if self.get_key(mid_cell)? >= key {
+                max_cell = mid_cell;
+            } else {
+                min_cell = mid_cell + 1;
+            }
+        }
+        Ok(max_cell)
+    }
+    /// Length of the value of a cell, wherever it is kept
+    pub fn get_value_len(&self, cell: usize) -> SqlResult<usize> {
+        let range = self.cell_range(cell)?;
+        Ok(read_pointer(
+            &self.node.borrow_map(|page| &page.buf[..])?[range.start..],
+        ))
+    }
+    pub fn get_value(&self, cell: usize) -> SqlResult<LeafValue> {
+        let len = self.get_value_len(cell)?;
+        let range = self.cell_range(cell)?;
+        let value = self
+            .node
+            .borrow_map(|page| &page.buf[range.start + LEAF_NODE_VALUE_LEN_SIZE..range.end])?;
+        if len > max_inline_value(self.page_size()) {
+            return Ok(LeafValue::Overflow {
+                first_page: read_pointer(&value),
+                len,
+            });
+        }
+        Ok(LeafValue::Inline(value))
     }
     pub fn get_next_leaf(&self) -> usize {
-        usize::from_le_bytes(
-            self.node.page.borrow().buf
-                [LEAF_NODE_NEXT_LEAF_OFFSET..LEAF_NODE_NEXT_LEAF_OFFSET + LEAF_NODE_NEXT_LEAF_SIZE]
-                .try_into()
-                .unwrap(),
+        read_pointer(&self.node.page.borrow().buf[LEAF_NODE_NEXT_LEAF_OFFSET..])
+    }
+    pub fn get_prev_leaf(&self) -> usize {
+        read_pointer(&self.node.page.borrow().buf[LEAF_NODE_PREV_LEAF_OFFSET..])
+    }
+    fn page_size(&self) -> usize {
+        self.node.page.borrow().buf.len()
+    }
+    /// Bytes for cells and their offsets
+    pub fn cell_space(&self) -> usize {
+        leaf_space(self.page_size())
+    }
+    /// Bytes taken by the cells and their offsets
+    pub fn used_space(&self) -> SqlResult<usize> {
+        let (num_cells, content_start) = self.checked_header()?;
+        let end = self.page_size() - PAGE_RESERVED_SIZE;
+        Ok(num_cells * LEAF_NODE_SLOT_SIZE + end - content_start)
+    }
+    /// Whether a cell of `len` bytes fits in the free space, its key in a
+    /// new slot
+    pub fn fits(&self, len: usize) -> SqlResult<bool> {
+        let (num_cells, content_start) = self.checked_header()?;
+        Ok(slot_at(num_cells + 1) + len.saturating_sub(LEAF_NODE_KEY_SIZE) <= content_start)
+    }
+    /// Keys and rows of a leaf written before format version 11
+    pub fn legacy_cells(&self) -> SqlResult<Vec<(i64, Vec<u8>)>> {
+        let num_cells = self.get_num_cells();
+        let end = LEGACY_LEAF_NODE_HEADER_SIZE + num_cells * LEGACY_LEAF_NODE_CELL_SIZE;
+        if end > MIN_PAGE_SIZE - PAGE_RESERVED_SIZE {
+            return Err(self.corrupt(format!("{} cells in a legacy leaf", num_cells)));
+        }
+        let buf = &self.node.page.borrow().buf;
+        Ok((0..num_cells)
+            .map(|i| {
+                let start = LEGACY_LEAF_NODE_HEADER_SIZE + i * LEGACY_LEAF_NODE_CELL_SIZE;
+                let key = &buf[start..start + LEAF_NODE_KEY_SIZE];
+                (
+                    i64::from_le_bytes(key.try_into().unwrap()),
+                    buf[start + LEAF_NODE_KEY_SIZE..start + LEGACY_LEAF_NODE_CELL_SIZE].to_vec(),
+                )
+            })
+            .collect())
+    }
+    /// Keys and values of a leaf written before format version 13, whose
+    /// offsets started where the previous leaf is now
+    pub fn unlinked_cells(&self) -> SqlResult<Vec<(i64, CellValue)>> {
+        let end = self.page_size() - PAGE_RESERVED_SIZE;
+        // The shorter header left a little more room for values in the leaf
+        self.offset_cells(
+            UNLINKED_LEAF_NODE_HEADER_SIZE,
+            UNLINKED_LEAF_NODE_MAX_CELLS,
+            inline_limit(end - UNLINKED_LEAF_NODE_HEADER_SIZE),
+        )
+    }
+    /// Keys and values of a leaf written by format versions 13 and 14, whose
+    /// cells held their keys
+    pub fn linked_cells(&self) -> SqlResult<Vec<(i64, CellValue)>> {
+        self.offset_cells(
+            LEAF_NODE_HEADER_SIZE,
+            LEAF_NODE_MAX_CELLS,
+            max_inline_value(self.page_size()),
         )
     }
+    /// Cells of a leaf written from format version 11 to 14, holding their
+    /// keys behind an array of offsets from `header_size`
+    fn offset_cells(
+        &self,
+        header_size: usize,
+        max_cells: usize,
+        max_inline: usize,
+    ) -> SqlResult<Vec<(i64, CellValue)>> {
+        let buf = &self.node.page.borrow().buf;
+        let num_cells = read_pointer(&buf[LEAF_NODE_NUM_CELLS_OFFSET..]);
+        let end = buf.len() - PAGE_RESERVED_SIZE;
+        if num_cells > max_cells {
+            return Err(self.corrupt(format!("{} cells in an offset leaf", num_cells)));
+        }
+        (0..num_cells)
+            .map(|i| {
+                let offset = header_size + i * LEAF_NODE_OFFSET_SIZE;
+                let start = read_pointer(&buf[offset..]);
+                let value_start = start + LEAF_NODE_CELL_HEADER_SIZE;
+                if value_start > end {
+                    return Err(self.corrupt(format!("cell {} at {} is not in a cell", i, start)));
+                }
+                let key =
+                    i64::from_le_bytes(buf[start..start + LEAF_NODE_KEY_SIZE].try_into().unwrap());
+                let len = read_pointer(&buf[start + LEAF_NODE_KEY_SIZE..]);
+                let stored = if len > max_inline { POINTER_SIZE } else { len };
+                if value_start + stored > end {
+                    return Err(self.corrupt(format!("cell {} runs past the end of the page", i)));
+                }
+                let value = if len > max_inline {
+                    CellValue::Overflow {
+                        first_page: read_pointer(&buf[value_start..]),
+                        len,
+                    }
+                } else {
+                    CellValue::Inline(buf[value_start..value_start + len].to_vec())
+                };
+                Ok((key, value))
+            })
+            .collect()
+    }
 }
 
+// Cells are only moved within the page as a whole, so the cells in front of
+// the content always fill it without gaps
 impl LeafMut {
+    #[cfg(test)]
     pub fn set_num_cells(&self, num_cells: usize) {
-        let start = LEAF_NODE_NUM_CELLS_OFFSET;
-        self.node.page.borrow_mut().buf[start..start + LEAF_NODE_NUM_CELLS_SIZE]
-            .copy_from_slice(&num_cells.to_le_bytes())
+        write_pointer(
+            &mut self.node.page_mut().buf[LEAF_NODE_NUM_CELLS_OFFSET..],
+            num_cells,
+        )
     }
     pub fn set_next_leaf(&self, next_leaf: usize) {
-        self.node.page.borrow_mut().buf
-            [LEAF_NODE_NEXT_LEAF_OFFSET..LEAF_NODE_NEXT_LEAF_OFFSET + LEAF_NODE_NEXT_LEAF_SIZE]
-            .copy_from_slice(&next_leaf.to_le_bytes())
+        write_pointer(
+            &mut self.node.page_mut().buf[LEAF_NODE_NEXT_LEAF_OFFSET..],
+            next_leaf,
+        )
     }
-    pub fn set_key(&self, cell: usize, key: u64) {
-        let start = LEAF_NODE_HEADER_SIZE + cell * LEAF_NODE_CELL_SIZE;
-        self.node.page.borrow_mut().buf[start..start + LEAF_NODE_KEY_SIZE]
-            .copy_from_slice(&key.to_le_bytes())
+    pub fn set_prev_leaf(&self, prev_leaf: usize) {
+        write_pointer(
+            &mut self.node.page_mut().buf[LEAF_NODE_PREV_LEAF_OFFSET..],
+            prev_leaf,
+        )
     }
-    pub fn cell(&self, cell: usize) -> RefMut<[u8]> {
-        let start = LEAF_NODE_HEADER_SIZE + cell * LEAF_NODE_CELL_SIZE;
-        self.node
-            .borrow_mut_map(|page| &mut page.buf[start..start + LEAF_NODE_CELL_SIZE])
+    /// Remove every cell, keeping the header otherwise
+    pub fn clear(&self) {
+        let mut page = self.node.page_mut();
+        let end = page.buf.len() - PAGE_RESERVED_SIZE;
+        page.buf[LEAF_NODE_HEADER_SIZE..end].fill(0);
+        write_pointer(&mut page.buf[LEAF_NODE_NUM_CELLS_OFFSET..], 0);
+        write_pointer(&mut page.buf[LEAF_NODE_CONTENT_START_OFFSET..], end);
+    }
+    /// Put a cell before the cell now at `cell`, failing if it does not fit
+    pub fn insert_cell(&self, cell: usize, bytes: &[u8]) -> SqlResult<()> {
+        let (num_cells, content_start) = self.checked_header()?;
+        if cell > num_cells {
+            return Err(self.corrupt(format!("cell {} past {} cells", cell, num_cells)));
+        }
+        if bytes.len() < LEAF_NODE_CELL_HEADER_SIZE || !self.fits(bytes.len())? {
+            return Err(self.corrupt(format!("no room for a cell of {} bytes", bytes.len())));
+        }
+        self.shift_cells(cell, cell + 1, num_cells - cell)?;
+        let (key, content) = bytes.split_at(LEAF_NODE_KEY_SIZE);
+        let start = content_start - content.len();
+        let mut buf = self.node.borrow_mut_map(|page| &mut page.buf[..])?;
+        buf[start..content_start].copy_from_slice(content);
+        buf[slot_at(cell)..slot_at(cell) + LEAF_NODE_KEY_SIZE].copy_from_slice(key);
+        write_pointer(&mut buf[slot_at(cell) + LEAF_NODE_KEY_SIZE..], start);
+        write_pointer(&mut buf[LEAF_NODE_NUM_CELLS_OFFSET..], num_cells + 1);
+        write_pointer(&mut buf[LEAF_NODE_CONTENT_START_OFFSET..], start);
+        Ok(())
+    }
+    /// Take a cell out, moving the cells stored before it over its bytes
+    pub fn remove_cell(&self, cell: usize) -> SqlResult<()> {
+        let (num_cells, content_start) = self.checked_header()?;
+        let range = self.cell_range(cell)?;
+        let len = range.len();
+        let mut buf = self.node.borrow_mut_map(|page| &mut page.buf[..])?;
+        buf.copy_within(content_start..range.start, content_start + len);
+        buf[content_start..content_start + len].fill(0);
+        for i in 0..num_cells {
+            let offset = slot_at(i) + LEAF_NODE_KEY_SIZE;
+            let start = read_pointer(&buf[offset..]);
+            if start < range.start {
+                write_pointer(&mut buf[offset..], start + len);
+            }
+        }
+        drop(buf);
+        self.shift_cells(cell + 1, cell, num_cells - cell - 1)?;
+        let mut buf = self.node.borrow_mut_map(|page| &mut page.buf[..])?;
+        buf[slot_at(num_cells - 1)..slot_at(num_cells)].fill(0);
+        write_pointer(&mut buf[LEAF_NODE_NUM_CELLS_OFFSET..], num_cells - 1);
+        write_pointer(
+            &mut buf[LEAF_NODE_CONTENT_START_OFFSET..],
+            content_start + len,
+        );
+        Ok(())
+    }
+    /// Move the cells from `at` on to the empty leaf `dest`, copying the
+    /// cells and slots over in one go and packing both leaves after
+    pub fn split_off(&self, at: usize, dest: &LeafMut) -> SqlResult<()> {
+        let (num_cells, content_start) = self.checked_header()?;
+        if at > num_cells {
+            return Err(self.corrupt(format!("split at {} of {} cells", at, num_cells)));
+        }
+        {
+            let src = self.node.borrow_map(|page| &page.buf[..])?;
+            let mut buf = dest.node.borrow_mut_map(|page| &mut page.buf[..])?;
+            let end = src.len() - PAGE_RESERVED_SIZE;
+            buf[content_start..end].copy_from_slice(&src[content_start..end]);
+            buf[slot_at(0)..slot_at(num_cells - at)]
+                .copy_from_slice(&src[slot_at(at)..slot_at(num_cells)]);
+            write_pointer(&mut buf[LEAF_NODE_NUM_CELLS_OFFSET..], num_cells - at);
+            write_pointer(&mut buf[LEAF_NODE_CONTENT_START_OFFSET..], content_start);
+        }
+        {
+            let mut buf = self.node.borrow_mut_map(|page| &mut page.buf[..])?;
+            buf[slot_at(at)..slot_at(num_cells)].fill(0);
+            write_pointer(&mut buf[LEAF_NODE_NUM_CELLS_OFFSET..], at);
+        }
+        self.compact()?;
+        dest.compact()
+    }
+    /// Pack the cells at the end of the page in their order, the first one
+    /// last, freeing the bytes no slot points to
+    pub fn compact(&self) -> SqlResult<()> {
+        let (num_cells, content_start) = self.checked_header()?;
+        let ranges = (0..num_cells)
+            .map(|i| self.cell_range(i))
+            .collect::<SqlResult<Vec<_>>>()?;
+        let mut buf = self.node.borrow_mut_map(|page| &mut page.buf[..])?;
+        let end = buf.len() - PAGE_RESERVED_SIZE;
+        let start = end - ranges.iter().map(|range| range.len()).sum::<usize>();
+        let mut packed = Vec::with_capacity(end - start);
+        for (i, range) in ranges.into_iter().enumerate().rev() {
+            write_pointer(
+                &mut buf[slot_at(i) + LEAF_NODE_KEY_SIZE..],
+                start + packed.len(),
+            );
+            packed.extend_from_slice(&buf[range]);
+        }
+        buf[content_start..start].fill(0);
+        buf[start..end].copy_from_slice(&packed);
+        write_pointer(&mut buf[LEAF_NODE_CONTENT_START_OFFSET..], start);
+        Ok(())
     }
-    pub fn value(&self, cell: usize) -> RefMut<[u8]> {
-        let start = LEAF_NODE_HEADER_SIZE + cell * LEAF_NODE_CELL_SIZE + LEAF_NODE_KEY_SIZE;
+    /// Move the slots of `count` cells from `from` to `to` in one copy, the
+    /// ranges overlapping or not. The slots left behind keep their bytes.
+    pub fn shift_cells(&self, from: usize, to: usize, count: usize) -> SqlResult<()> {
+        let (_, content_start) = self.checked_header()?;
+        if slot_at(from.max(to) + count) > content_start {
+            return Err(self.corrupt(format!(
+                "{} cells from {} to {} run into the cells",
+                count, from, to
+            )));
+        }
+        let mut buf = self.node.borrow_mut_map(|page| &mut page.buf[..])?;
+        buf.copy_within(slot_at(from)..slot_at(from + count), slot_at(to));
+        Ok(())
+    }
+    /// Value of a cell kept in place, to be overwritten with as many bytes
+    pub fn value(&self, cell: usize) -> SqlResult<RefMut<[u8]>> {
+        if let LeafValue::Overflow { .. } = self.get_value(cell)? {
+            return Err(self.corrupt(format!("cell {} is kept in overflow pages", cell)));
+        }
+        let range = self.cell_range(cell)?;
         self.node
-            .borrow_mut_map(|page| &mut page.buf[start..start + LEAF_NODE_VALUE_SIZE])
+            .borrow_mut_map(|page| &mut page.buf[range.start + LEAF_NODE_VALUE_LEN_SIZE..range.end])
+    }
+    /// Overwrite the bytes of a value kept in place from `offset` on,
+    /// failing if they run past the end of the value
+    pub fn update_value_range(&self, cell: usize, offset: usize, bytes: &[u8]) -> SqlResult<()> {
+        let mut value = self.value(cell)?;
+        let end = offset.saturating_add(bytes.len());
+        if end > value.len() {
+            return Err(SqlError::ValueOutOfRange {
+                end,
+                len: value.len(),
+            });
+        }
+        value[offset..end].copy_from_slice(bytes);
+        Ok(())
+    }
+    /// Write cells in the layout before format version 11, rows padded with zeros
+    #[cfg(test)]
+    pub fn set_legacy_cells(&self, cells: &[(i64, Vec<u8>)]) {
+        self.clear();
+        self.set_num_cells(cells.len());
+        let buf = &mut self.node.page_mut().buf;
+        buf[LEGACY_LEAF_NODE_HEADER_SIZE..LEAF_NODE_HEADER_SIZE].fill(0);
+        for (i, (key, row)) in cells.iter().enumerate() {
+            let start = LEGACY_LEAF_NODE_HEADER_SIZE + i * LEGACY_LEAF_NODE_CELL_SIZE;
+            buf[start..start + LEAF_NODE_KEY_SIZE].copy_from_slice(&key.to_le_bytes());
+            let row_start = start + LEAF_NODE_KEY_SIZE;
+            buf[row_start..row_start + row.len()].copy_from_slice(row);
+        }
+    }
+    /// Write cells in the layout before format version 13, their offsets
+    /// starting where the previous leaf is now
+    #[cfg(test)]
+    pub fn set_unlinked_cells(&self, cells: &[Vec<u8>]) {
+        self.set_offset_cells(UNLINKED_LEAF_NODE_HEADER_SIZE, cells);
+    }
+    /// Write cells in the layout of format versions 13 and 14, keys and all
+    /// behind their offsets
+    #[cfg(test)]
+    pub fn set_linked_cells(&self, cells: &[Vec<u8>]) {
+        self.set_offset_cells(LEAF_NODE_HEADER_SIZE, cells);
+    }
+    #[cfg(test)]
+    fn set_offset_cells(&self, header_size: usize, cells: &[Vec<u8>]) {
+        self.clear();
+        let buf = &mut self.node.page_mut().buf;
+        let mut start = buf.len() - PAGE_RESERVED_SIZE;
+        for (i, cell) in cells.iter().enumerate() {
+            start -= cell.len();
+            buf[start..start + cell.len()].copy_from_slice(cell);
+            let offset = header_size + i * LEAF_NODE_OFFSET_SIZE;
+            write_pointer(&mut buf[offset..], start);
+        }
+        write_pointer(&mut buf[LEAF_NODE_NUM_CELLS_OFFSET..], cells.len());
+        write_pointer(&mut buf[LEAF_NODE_CONTENT_START_OFFSET..], start);
+    }
+    /// Insert a copy of a cell of `src`, which may be this same page, at `cell`
+    pub fn copy_cell(&self, cell: usize, src: &LeafRef, src_cell: usize) -> SqlResult<()> {
+        let bytes = src.get_cell(src_cell)?;
+        self.insert_cell(cell, &bytes)
     }
 }
 
 impl InternalRef {
     pub fn get_num_keys(&self) -> usize {
-        usize::from_le_bytes(
-            self.node.page.borrow().buf
-                [INTERNAL_NODE_NUM_KEYS_OFFSET..INTERNAL_NODE_NUM_KEYS_OFFSET + 8]
-                .try_into()
-                .unwrap(),
-        )
+        read_pointer(&self.node.page.borrow().buf[INTERNAL_NODE_NUM_KEYS_OFFSET..])
+    }
+    /// Number of children, one more than the keys
+    pub fn get_num_children(&self) -> usize {
+        self.get_num_keys() + 1
+    }
+    pub fn get_next_internal(&self) -> usize {
+        read_pointer(&self.node.page.borrow().buf[INTERNAL_NODE_NEXT_INTERNAL_OFFSET..])
     }
-    pub fn get_key_at(&self, cell: usize) -> u64 {
-        let start =
-            INTERNAL_NODE_HEADER_SIZE + cell * INTERNAL_NODE_CELL_SIZE + INTERNAL_NODE_CHILD_SIZE;
-        u64::from_le_bytes(
+    pub fn get_prev_internal(&self) -> usize {
+        read_pointer(&self.node.page.borrow().buf[INTERNAL_NODE_PREV_INTERNAL_OFFSET..])
+    }
+    /// Start of a cell the page has room for
+    fn slot_start(&self, cell: usize) -> SqlResult<usize> {
+        if cell >= INTERNAL_NODE_MAX_KEYS {
+            return Err(self.corrupt(format!(
+                "cell {} past the {} cells of an internal node",
+                cell, INTERNAL_NODE_MAX_KEYS
+            )));
+        }
+        Ok(INTERNAL_NODE_HEADER_SIZE + cell * INTERNAL_NODE_CELL_SIZE)
+    }
+    /// Start of a cell holding a key
+    fn cell_start(&self, cell: usize) -> SqlResult<usize> {
+        let num_keys = self.checked_num_keys()?;
+        if cell >= num_keys {
+            return Err(self.corrupt(format!("cell {} of {} keys", cell, num_keys)));
+        }
+        self.slot_start(cell)
+    }
+    /// Start of the child pointer and count of a child, in its cell or in
+    /// the header for the right child
+    fn child_start(&self, child: usize) -> SqlResult<(usize, usize)> {
+        let num_keys = self.checked_num_keys()?;
+        match child.cmp(&num_keys) {
+            Ordering::Less => {
+                let start = self.slot_start(child)?;
+                Ok((start, start + INTERNAL_NODE_COUNT_OFFSET))
+            }
+            Ordering::Equal => Ok((
+                INTERNAL_NODE_RIGHT_CHILD_OFFSET,
+                INTERNAL_NODE_RIGHT_COUNT_OFFSET,
+            )),
+            Ordering::Greater => {
+                Err(self.corrupt(format!("child {} of {} children", child, num_keys + 1)))
+            }
+        }
+    }
+    /// Number of keys, which must fit the page
+    fn checked_num_keys(&self) -> SqlResult<usize> {
+        let num_keys = self.get_num_keys();
+        if num_keys > INTERNAL_NODE_MAX_KEYS {
+            return Err(self.corrupt(format!(
+                "{} keys in an internal node of {}",
+                num_keys, INTERNAL_NODE_MAX_KEYS
+            )));
+        }
+        Ok(num_keys)
+    }
+    pub fn get_key_at(&self, cell: usize) -> SqlResult<i64> {
+        let start = self.cell_start(cell)? + INTERNAL_NODE_CHILD_SIZE;
+        Ok(i64::from_le_bytes(
             self.node.page.borrow().buf[start..start + INTERNAL_NODE_KEY_SIZE]
                 .try_into()
                 .unwrap(),
-        )
+        ))
     }
-    pub fn get_child_at(&self, cell: usize) -> usize {
-        let start = INTERNAL_NODE_HEADER_SIZE + cell * INTERNAL_NODE_CELL_SIZE;
-        usize::from_le_bytes(
-            self.node.page.borrow().buf[start..start + INTERNAL_NODE_CHILD_SIZE]
+    /// Page of a child, the right child being the one after the last key
+    pub fn get_child_at(&self, child: usize) -> SqlResult<usize> {
+        let (start, _) = self.child_start(child)?;
+        Ok(read_pointer(&self.node.page.borrow().buf[start..]))
+    }
+    /// Number of rows under a child
+    pub fn get_count_at(&self, child: usize) -> SqlResult<usize> {
+        let (_, start) = self.child_start(child)?;
+        Ok(u64::from_le_bytes(
+            self.node.page.borrow().buf[start..start + INTERNAL_NODE_COUNT_SIZE]
                 .try_into()
                 .unwrap(),
-        )
+        ) as usize)
+    }
+    /// Number of rows under this node
+    pub fn total_count(&self) -> SqlResult<usize> {
+        (0..self.get_num_children())
+            .map(|i| self.get_count_at(i))
+            .sum()
+    }
+    /// Index of the child at the page
+    pub fn find_child(&self, child: usize) -> SqlResult<Option<usize>> {
+        for i in 0..self.get_num_children() {
+            if self.get_child_at(i)? == child {
+                return Ok(Some(i));
+            }
+        }
+        Ok(None)
+    }
+    /// Pages of the children with the number of rows under each
+    pub fn children(&self) -> SqlResult<Vec<(usize, usize)>> {
+        (0..self.get_num_children())
+            .map(|i| Ok((self.get_child_at(i)?, self.get_count_at(i)?)))
+            .collect()
+    }
+    pub fn keys(&self) -> SqlResult<Vec<i64>> {
+        (0..self.get_num_keys())
+            .map(|i| self.get_key_at(i))
+            .collect()
+    }
+    /// Children, first keys and counts of a node written from format
+    /// version 3 to 13
+    pub fn keyed_cells(&self) -> SqlResult<Vec<(usize, i64, usize)>> {
+        let num_keys = self.get_num_keys();
+        let end = KEYED_INTERNAL_NODE_HEADER_SIZE + num_keys * INTERNAL_NODE_CELL_SIZE;
+        if end > MIN_PAGE_SIZE - PAGE_RESERVED_SIZE {
+            return Err(self.corrupt(format!("{} keys in a keyed internal node", num_keys)));
+        }
+        let buf = &self.node.page.borrow().buf;
+        Ok((0..num_keys)
+            .map(|i| {
+                let start = KEYED_INTERNAL_NODE_HEADER_SIZE + i * INTERNAL_NODE_CELL_SIZE;
+                let key =
+                    &buf[start + INTERNAL_NODE_CHILD_SIZE..start + INTERNAL_NODE_COUNT_OFFSET];
+                let count =
+                    &buf[start + INTERNAL_NODE_COUNT_OFFSET..start + INTERNAL_NODE_CELL_SIZE];
+                (
+                    read_pointer(&buf[start..]),
+                    i64::from_le_bytes(key.try_into().unwrap()),
+                    u64::from_le_bytes(count.try_into().unwrap()) as usize,
+                )
+            })
+            .collect())
+    }
+    /// Children of a node written by format versions 14 and 15
+    pub fn separator_children(&self) -> SqlResult<Vec<usize>> {
+        let num_keys = self.get_num_keys();
+        let end = SEPARATOR_INTERNAL_NODE_HEADER_SIZE + num_keys * INTERNAL_NODE_CELL_SIZE;
+        if end > MIN_PAGE_SIZE - PAGE_RESERVED_SIZE {
+            return Err(self.corrupt(format!("{} keys in a separator internal node", num_keys)));
+        }
+        let buf = &self.node.page.borrow().buf;
+        Ok((0..num_keys)
+            .map(|i| {
+                read_pointer(
+                    &buf[SEPARATOR_INTERNAL_NODE_HEADER_SIZE + i * INTERNAL_NODE_CELL_SIZE..],
+                )
+            })
+            .chain([read_pointer(&buf[INTERNAL_NODE_RIGHT_CHILD_OFFSET..])])
+            .collect())
+    }
+    /// Children and keys of a node written before format version 3
+    pub fn legacy_cells(&self) -> SqlResult<Vec<(usize, i64)>> {
+        let num_keys = self.get_num_keys();
+        let end = KEYED_INTERNAL_NODE_HEADER_SIZE + num_keys * LEGACY_INTERNAL_NODE_CELL_SIZE;
+        if end > MIN_PAGE_SIZE - CHECKSUM_SIZE {
+            return Err(self.corrupt(format!("{} keys in a legacy internal node", num_keys)));
+        }
+        let buf = &self.node.page.borrow().buf;
+        Ok((0..num_keys)
+            .map(|i| {
+                let start = KEYED_INTERNAL_NODE_HEADER_SIZE + i * LEGACY_INTERNAL_NODE_CELL_SIZE;
+                let key =
+                    &buf[start + INTERNAL_NODE_CHILD_SIZE..start + LEGACY_INTERNAL_NODE_CELL_SIZE];
+                (
+                    read_pointer(&buf[start..]),
+                    i64::from_le_bytes(key.try_into().unwrap()),
+                )
+            })
+            .collect())
     }
     // Find key
-    pub fn find_key(&self, key: u64) -> Option<usize> {
+    /// Index of the child whose rows may hold the key, the number of keys
+    /// up to it
+    pub fn find_key(&self, key: i64) -> SqlResult<usize> {
         let mut min_index = 0;
-        let mut max_index = self.get_num_keys();
+        let mut max_index = self.checked_num_keys()?;
         while min_index < max_index {
             let index = (min_index + max_index) / 2;
-            let key_at_index = self.get_key_at(index);
+            let key_at_index = self.get_key_at(index)?;
             if key_at_index > key {
                 max_index = index;
             } else {
                 min_index = index + 1;
             }
         }
-        if min_index == 0 {
-            return None;
-        }
-        Some(min_index - 1 as usize)
+        Ok(min_index)
     }
 }
 
 impl InternalMut {
     pub fn set_num_keys(&self, num_keys: usize) {
-        self.node.page.borrow_mut().buf
-            [INTERNAL_NODE_NUM_KEYS_OFFSET..INTERNAL_NODE_NUM_KEYS_OFFSET + 8]
-            .copy_from_slice(&num_keys.to_le_bytes())
+        write_pointer(
+            &mut self.node.page_mut().buf[INTERNAL_NODE_NUM_KEYS_OFFSET..],
+            num_keys,
+        )
+    }
+    pub fn set_next_internal(&self, next_internal: usize) {
+        write_pointer(
+            &mut self.node.page_mut().buf[INTERNAL_NODE_NEXT_INTERNAL_OFFSET..],
+            next_internal,
+        )
     }
-    pub fn set_key_at(&self, cell: usize, key: u64) {
-        let start =
-            INTERNAL_NODE_HEADER_SIZE + cell * INTERNAL_NODE_CELL_SIZE + INTERNAL_NODE_CHILD_SIZE;
-        self.node.page.borrow_mut().buf[start..start + INTERNAL_NODE_KEY_SIZE]
-            .copy_from_slice(&key.to_le_bytes())
+    pub fn set_prev_internal(&self, prev_internal: usize) {
+        write_pointer(
+            &mut self.node.page_mut().buf[INTERNAL_NODE_PREV_INTERNAL_OFFSET..],
+            prev_internal,
+        )
+    }
+    pub fn set_key_at(&self, cell: usize, key: i64) -> SqlResult<()> {
+        let start = self.slot_start(cell)? + INTERNAL_NODE_CHILD_SIZE;
+        self.node.page_mut().buf[start..start + INTERNAL_NODE_KEY_SIZE]
+            .copy_from_slice(&key.to_le_bytes());
+        Ok(())
+    }
+    pub fn set_child_at(&self, child: usize, page_num: usize) -> SqlResult<()> {
+        let (start, _) = self.child_start(child)?;
+        write_pointer(&mut self.node.page_mut().buf[start..], page_num);
+        Ok(())
     }
+    pub fn set_count_at(&self, child: usize, count: usize) -> SqlResult<()> {
+        let (_, start) = self.child_start(child)?;
+        self.node.page_mut().buf[start..start + INTERNAL_NODE_COUNT_SIZE]
+            .copy_from_slice(&(count as u64).to_le_bytes());
+        Ok(())
+    }
+    /// Move `count` cells from `from` to `to` in one copy, the ranges
+    /// overlapping or not. The cells left behind keep their bytes.
+    pub fn shift_cells(&self, from: usize, to: usize, count: usize) -> SqlResult<()> {
+        if count == 0 {
+            return Ok(());
+        }
+        let end = self.slot_start(from.max(to) + count - 1)? + INTERNAL_NODE_CELL_SIZE;
+        let start = self.slot_start(from)?;
+        let dest = self.slot_start(to)?;
+        let mut buf = self.node.borrow_mut_map(|page| &mut page.buf[..end])?;
+        buf.copy_within(start..start + count * INTERNAL_NODE_CELL_SIZE, dest);
+        Ok(())
+    }
+    /// Put a child after the child at `index`, with `key` between them
+    pub fn insert_child(
+        &self,
+        index: usize,
+        child: usize,
+        count: usize,
+        key: i64,
+    ) -> SqlResult<()> {
+        let num_keys = self.checked_num_keys()?;
+        if index > num_keys || num_keys == INTERNAL_NODE_MAX_KEYS {
+            return Err(self.corrupt(format!(
+                "child after {} of {} children",
+                index,
+                num_keys + 1
+            )));
+        }
+        if index == num_keys {
+            // The right child moves into the new last cell
+            let right = (self.get_child_at(index)?, self.get_count_at(index)?);
+            self.set_num_keys(num_keys + 1);
+            self.set_child_at(index, right.0)?;
+            self.set_count_at(index, right.1)?;
+        } else {
+            let next_key = self.get_key_at(index)?;
+            self.shift_cells(index + 1, index + 2, num_keys - index - 1)?;
+            self.set_num_keys(num_keys + 1);
+            self.set_key_at(index + 1, next_key)?;
+        }
+        self.set_key_at(index, key)?;
+        self.set_child_at(index + 1, child)?;
+        self.set_count_at(index + 1, count)
+    }
+    /// Put a child before the first one, with `key` between them
+    pub fn prepend_child(&self, child: usize, count: usize, key: i64) -> SqlResult<()> {
+        let num_keys = self.checked_num_keys()?;
+        if num_keys == INTERNAL_NODE_MAX_KEYS {
+            return Err(self.corrupt(format!("child before {} children", num_keys + 1)));
+        }
+        self.shift_cells(0, 1, num_keys)?;
+        self.set_num_keys(num_keys + 1);
+        self.set_child_at(0, child)?;
+        self.set_count_at(0, count)?;
+        self.set_key_at(0, key)
+    }
+    /// Move the children from `at` on to the empty node `dest` in one copy,
+    /// returning the key before them, which neither node keeps
+    pub fn split_off(&self, at: usize, dest: &InternalMut) -> SqlResult<i64> {
+        let num_keys = self.checked_num_keys()?;
+        if at == 0 || at > num_keys {
+            return Err(self.corrupt(format!("split at {} of {} children", at, num_keys + 1)));
+        }
+        let middle_key = self.get_key_at(at - 1)?;
+        let right = (self.get_child_at(num_keys)?, self.get_count_at(num_keys)?);
+        let left = (self.get_child_at(at - 1)?, self.get_count_at(at - 1)?);
+        {
+            let start = self.slot_start(at)?;
+            let len = (num_keys - at) * INTERNAL_NODE_CELL_SIZE;
+            let src = self.node.borrow_map(|page| &page.buf[start..start + len])?;
+            let mut buf = dest.node.borrow_mut_map(|page| &mut page.buf[..])?;
+            buf[INTERNAL_NODE_HEADER_SIZE..INTERNAL_NODE_HEADER_SIZE + len].copy_from_slice(&src);
+        }
+        dest.set_num_keys(num_keys - at);
+        dest.set_child_at(num_keys - at, right.0)?;
+        dest.set_count_at(num_keys - at, right.1)?;
 
-    pub fn set_child_at(&self, cell: usize, child: usize) {
-        let start = INTERNAL_NODE_HEADER_SIZE + cell * INTERNAL_NODE_CELL_SIZE;
-        self.node.page.borrow_mut().buf[start..start + INTERNAL_NODE_CHILD_SIZE]
-            .copy_from_slice(&child.to_le_bytes())
+        let start = self.slot_start(at - 1)?;
+        let end = INTERNAL_NODE_HEADER_SIZE + num_keys * INTERNAL_NODE_CELL_SIZE;
+        self.node.page_mut().buf[start..end].fill(0);
+        self.set_num_keys(at - 1);
+        self.set_child_at(at - 1, left.0)?;
+        self.set_count_at(at - 1, left.1)?;
+        Ok(middle_key)
+    }
+    /// Take out the child at `index` and the key before it
+    pub fn remove_child(&self, index: usize) -> SqlResult<()> {
+        let num_keys = self.checked_num_keys()?;
+        if index == 0 || index > num_keys {
+            return Err(self.corrupt(format!(
+                "key before child {} of {} children",
+                index,
+                num_keys + 1
+            )));
+        }
+        if index == num_keys {
+            // The child before it becomes the right child
+            let left = (self.get_child_at(index - 1)?, self.get_count_at(index - 1)?);
+            self.set_num_keys(num_keys - 1);
+            self.set_child_at(index - 1, left.0)?;
+            self.set_count_at(index - 1, left.1)?;
+        } else {
+            self.set_key_at(index - 1, self.get_key_at(index)?)?;
+            self.shift_cells(index + 1, index, num_keys - index - 1)?;
+            self.set_num_keys(num_keys - 1);
+        }
+        let start = self.slot_start(num_keys - 1)?;
+        self.node.page_mut().buf[start..start + INTERNAL_NODE_CELL_SIZE].fill(0);
+        Ok(())
+    }
+    /// Replace the children, with their counts, and the keys between them
+    pub fn set_cells(&self, children: &[(usize, usize)], keys: &[i64]) -> SqlResult<()> {
+        if children.len() != keys.len() + 1 || keys.len() > INTERNAL_NODE_MAX_KEYS {
+            return Err(self.corrupt(format!(
+                "{} children with {} keys in an internal node",
+                children.len(),
+                keys.len()
+            )));
+        }
+        self.set_num_keys(keys.len());
+        for (i, &(child, count)) in children.iter().enumerate() {
+            self.set_child_at(i, child)?;
+            self.set_count_at(i, count)?;
+        }
+        for (i, &key) in keys.iter().enumerate() {
+            self.set_key_at(i, key)?;
+        }
+        Ok(())
+    }
+    /// Write children and keys in the layout of format versions 14 and 15
+    #[cfg(test)]
+    pub fn set_separator_cells(&self, children: &[(usize, usize)], keys: &[i64]) {
+        self.set_num_keys(keys.len());
+        let buf = &mut self.node.page_mut().buf;
+        let (right_child, right_count) = children[keys.len()];
+        write_pointer(&mut buf[INTERNAL_NODE_RIGHT_CHILD_OFFSET..], right_child);
+        buf[INTERNAL_NODE_RIGHT_COUNT_OFFSET..INTERNAL_NODE_NEXT_INTERNAL_OFFSET]
+            .copy_from_slice(&(right_count as u64).to_le_bytes());
+        for (i, (&(child, count), key)) in children.iter().zip(keys).enumerate() {
+            let start = SEPARATOR_INTERNAL_NODE_HEADER_SIZE + i * INTERNAL_NODE_CELL_SIZE;
+            write_pointer(&mut buf[start..], child);
+            buf[start + INTERNAL_NODE_CHILD_SIZE..start + INTERNAL_NODE_COUNT_OFFSET]
+                .copy_from_slice(&key.to_le_bytes());
+            buf[start + INTERNAL_NODE_COUNT_OFFSET..start + INTERNAL_NODE_CELL_SIZE]
+                .copy_from_slice(&(count as u64).to_le_bytes());
+        }
+    }
+    /// Write cells in the layout from format version 3 to 13
+    #[cfg(test)]
+    pub fn set_keyed_cells(&self, cells: &[(usize, i64, usize)]) {
+        self.set_num_keys(cells.len());
+        let buf = &mut self.node.page_mut().buf;
+        for (i, (child, key, count)) in cells.iter().enumerate() {
+            let start = KEYED_INTERNAL_NODE_HEADER_SIZE + i * INTERNAL_NODE_CELL_SIZE;
+            write_pointer(&mut buf[start..], *child);
+            buf[start + INTERNAL_NODE_CHILD_SIZE..start + INTERNAL_NODE_COUNT_OFFSET]
+                .copy_from_slice(&key.to_le_bytes());
+            buf[start + INTERNAL_NODE_COUNT_OFFSET..start + INTERNAL_NODE_CELL_SIZE]
+                .copy_from_slice(&(*count as u64).to_le_bytes());
+        }
+    }
+    /// Write cells in the layout before format version 3
+    #[cfg(test)]
+    pub fn set_legacy_cells(&self, cells: &[(usize, i64)]) {
+        self.set_num_keys(cells.len());
+        let buf = &mut self.node.page_mut().buf;
+        for (i, (child, key)) in cells.iter().enumerate() {
+            let start = KEYED_INTERNAL_NODE_HEADER_SIZE + i * LEGACY_INTERNAL_NODE_CELL_SIZE;
+            write_pointer(&mut buf[start..], *child);
+            buf[start + INTERNAL_NODE_CHILD_SIZE..start + LEGACY_INTERNAL_NODE_CELL_SIZE]
+                .copy_from_slice(&key.to_le_bytes());
+        }
     }
 }
 
@@ -400,46 +1338,59 @@ impl Deref for InternalRef {
     }
 }
 
-impl Display for Node {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let node_type = match self.get_type() {
+impl Node {
+    /// Header and cells of the node, failing on a corrupt page
+    pub fn describe(&self) -> SqlResult<String> {
+        let node_type = match self.get_type()? {
             NodeType::Internal => "Internal",
             NodeType::Leaf => "Leaf",
         };
         let is_root = if self.is_root() { "Yes" } else { "No" };
-        let parent_page = self.get_parent();
-        write!(
-            f,
+        let mut out = format!(
             "NodeType: {}, IsRoot: {}, Parent: {}",
-            node_type, is_root, parent_page
-        )?;
-        match self.as_typed() {
+            node_type,
+            is_root,
+            self.get_parent()
+        );
+        match self.as_typed()? {
             NodeRef::Leaf(leaf) => {
                 let num_cells = leaf.get_num_cells();
-                writeln!(
-                    f,
-                    " ( NumCells: {}, NextLeaf {} ) ",
+                out += &format!(
+                    " ( NumCells: {}, NextLeaf {} ) \n",
                     num_cells,
                     leaf.get_next_leaf()
-                )?;
-                for i in 0..num_cells as usize {
-                    let key = leaf.get_key(i);
-                    let value = leaf.get_value(i);
-                    let row = Row::deserialize(&value);
-                    writeln!(f, "[{}] {}", key, row)?;
+                );
+                for i in 0..num_cells {
+                    let key = leaf.get_key(i)?;
+                    out += &match leaf.get_value(i)? {
+                        LeafValue::Inline(value) => {
+                            format!("[{}] {}\n", key, Row::deserialize(&value))
+                        }
+                        LeafValue::Overflow { first_page, len } => format!(
+                            "[{}] {} bytes in overflow pages from {}\n",
+                            key, len, first_page
+                        ),
+                    };
                 }
             }
             NodeRef::Internal(internal) => {
                 let num_keys = internal.get_num_keys();
-                writeln!(f, " ( NumKeys: {} )", num_keys)?;
-                for i in 0..num_keys as usize {
-                    let child = internal.get_child_at(i);
-                    let key = internal.get_key_at(i);
-                    write!(f, "[{}] {} ", key, child)?;
+                out += &format!(" ( NumKeys: {} )\n", num_keys);
+                for i in 0..num_keys {
+                    let child = internal.get_child_at(i)?;
+                    let key = internal.get_key_at(i)?;
+                    out += &format!("{} [{}] ", child, key);
                 }
+                out += &format!("{} ", internal.get_child_at(num_keys)?);
             }
         }
-        Ok(())
+        Ok(out)
+    }
+}
+
+impl Display for Node {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.describe().map_err(|_| std::fmt::Error)?)
     }
 }
 
@@ -447,30 +1398,106 @@ impl Display for Node {
 mod tests {
     use std::assert_eq;
 
-    use crate::pager::new_page;
+    use crate::{
+        meta::META_NODE_NUM,
+        pager::{new_page, DEFAULT_PAGE_SIZE},
+        storage::MemoryStorage,
+        table::Table,
+    };
 
     use super::*;
 
     #[test]
     fn test_leaf() {
-        let node = Node::new(new_page());
+        let node = Node::new(0, new_page());
         let leaf = node.init_leaf();
         assert_eq!(leaf.node.is_leaf(), true);
         assert_eq!(leaf.node.is_internal(), false);
         assert_eq!(leaf.get_num_cells(), 0);
-        leaf.set_num_cells(1);
-        assert_eq!(leaf.get_num_cells(), 1);
-        leaf.set_key(0, 1);
-        assert_eq!(leaf.get_key(0), 1);
         let row = [2u8; ROW_SIZE];
-        leaf.value(0).copy_from_slice(&row);
-        assert_eq!(*leaf.get_value(0), row);
+        leaf.insert_cell(0, &inline_cell(1, &row)).unwrap();
+        assert_eq!(leaf.get_num_cells(), 1);
+        assert_eq!(leaf.get_key(0).unwrap(), 1);
+        assert!(matches!(leaf.get_value(0).unwrap(), LeafValue::Inline(value) if *value == row));
         leaf.set_next_leaf(1);
         assert_eq!(leaf.get_next_leaf(), 1);
+
+        let cell = overflow_cell(4, 5000, 7);
+        leaf.insert_cell(1, &cell).unwrap();
+        assert_eq!(leaf.get_value_len(1).unwrap(), 5000);
+        assert!(matches!(
+            leaf.get_value(1).unwrap(),
+            LeafValue::Overflow {
+                first_page: 7,
+                len: 5000
+            }
+        ));
+        assert!(matches!(leaf.value(1), Err(SqlError::Corrupt { .. })));
+    }
+    #[test]
+    fn variable_cells() {
+        let node = Node::new(0, new_page());
+        let leaf = node.init_leaf();
+        let values = [vec![1u8; 10], vec![], vec![3u8; 300], vec![4u8; 1]];
+        for (i, value) in values.iter().enumerate() {
+            leaf.insert_cell(i, &inline_cell(i as i64, value)).unwrap();
+        }
+        let used = leaf.used_space().unwrap();
+        assert_eq!(used, 4 * 16 + 311);
+
+        // Removing a cell moves the cells stored before it into its place
+        leaf.remove_cell(1).unwrap();
+        leaf.remove_cell(0).unwrap();
+        assert_eq!(leaf.used_space().unwrap(), 2 * 16 + 301);
+        for (i, value) in [(0, &values[2]), (1, &values[3])] {
+            assert!(matches!(leaf.get_value(i).unwrap(), LeafValue::Inline(v) if *v == **value));
+        }
+
+        // Cells of the longest inline value fill a page four at a time
+        leaf.clear();
+        let value = vec![5u8; max_inline_value(DEFAULT_PAGE_SIZE)];
+        let cell = inline_cell(0, &value);
+        for i in 0..4 {
+            assert!(leaf.fits(cell.len()).unwrap());
+            leaf.insert_cell(i, &cell).unwrap();
+        }
+        assert!(!leaf.fits(cell.len()).unwrap());
+        assert!(matches!(
+            leaf.insert_cell(4, &cell),
+            Err(SqlError::Corrupt { .. })
+        ));
+        assert!(leaf.used_space().unwrap() <= leaf.cell_space());
+    }
+    #[test]
+    fn page_busy() {
+        let node = Node::new(0, new_page());
+        let leaf = node.init_leaf();
+        leaf.insert_cell(0, &inline_cell(1, &[3u8; ROW_SIZE]))
+            .unwrap();
+        leaf.insert_cell(1, &inline_cell(2, &[4u8; ROW_SIZE]))
+            .unwrap();
+
+        // A value borrowed for writing makes other borrows of the page fail
+        let value = leaf.value(1).unwrap();
+        assert!(matches!(leaf.get_value(0), Err(SqlError::PageBusy)));
+        assert!(matches!(leaf.get_cell(0), Err(SqlError::PageBusy)));
+        drop(value);
+        let value = leaf.get_value(0).unwrap();
+        assert!(matches!(leaf.value(1), Err(SqlError::PageBusy)));
+        assert!(matches!(leaf.remove_cell(1), Err(SqlError::PageBusy)));
+        drop(value);
+
+        // Two handles onto one page copy between its cells
+        let other = node.leaf_node_mut().unwrap();
+        leaf.copy_cell(1, &other, 0).unwrap();
+        assert_eq!(other.get_key(1).unwrap(), 1);
+        assert!(
+            matches!(other.get_value(1).unwrap(), LeafValue::Inline(v) if *v == [3u8; ROW_SIZE])
+        );
     }
     #[test]
     fn test_internal() {
-        let node = Node::new(new_page());
+        let node = Node::new(0, new_page());
         let internal = node.init_internal();
         internal.node.set_root(true);
         assert_eq!(internal.node.is_root(), true);
@@ -479,24 +1506,258 @@ mod tests {
         assert_eq!(internal.get_num_keys(), 0);
         internal.set_num_keys(1);
         assert_eq!(internal.get_num_keys(), 1);
-        internal.set_key_at(0, 1);
-        assert_eq!(internal.get_key_at(0), 1);
-        internal.set_child_at(0, 2);
-        assert_eq!(internal.get_child_at(0), 2);
+        assert_eq!(internal.get_num_children(), 2);
+        internal.set_key_at(0, 1).unwrap();
+        assert_eq!(internal.get_key_at(0).unwrap(), 1);
+        internal.set_child_at(0, 2).unwrap();
+        assert_eq!(internal.get_child_at(0).unwrap(), 2);
+        internal.set_child_at(1, 3).unwrap();
+        internal.set_count_at(1, 7).unwrap();
+        assert_eq!(internal.get_child_at(1).unwrap(), 3);
+        assert_eq!(internal.get_count_at(1).unwrap(), 7);
+        assert!(matches!(
+            internal.get_child_at(2),
+            Err(SqlError::Corrupt { .. })
+        ));
+
+        // The right child stays in the header when keys are added
+        internal
+            .set_cells(&[(2, 1), (4, 2), (3, 7)], &[1, 5])
+            .unwrap();
+        assert_eq!(internal.children().unwrap(), [(2, 1), (4, 2), (3, 7)]);
+        assert_eq!(internal.keys().unwrap(), [1, 5]);
+        assert_eq!(internal.total_count().unwrap(), 10);
+        assert_eq!(internal.find_child(3).unwrap(), Some(2));
+        assert!(internal.set_cells(&[(2, 1)], &[1]).is_err());
     }
+    #[test]
+    fn shift_leaf_cells() {
+        let node = Node::new(0, new_page());
+        let leaf = node.init_leaf();
+        for key in 0..5 {
+            leaf.insert_cell(key as usize, &inline_cell(key, &[key as u8; 3]))
+                .unwrap();
+        }
+        // Overlapping both ways, the slots left behind keeping their bytes
+        leaf.shift_cells(1, 2, 3).unwrap();
+        let keys = |leaf: &LeafMut| (0..5).map(|i| leaf.get_key(i).unwrap()).collect::<Vec<_>>();
+        assert_eq!(keys(&leaf), [0, 1, 1, 2, 3]);
+        leaf.shift_cells(2, 0, 3).unwrap();
+        assert_eq!(keys(&leaf), [1, 2, 3, 2, 3]);
+        assert!(matches!(leaf.get_value(2).unwrap(), LeafValue::Inline(v) if *v == [3; 3]));
+        leaf.shift_cells(0, 0, 0).unwrap();
+        // Slots may not run into the cells
+        assert!(matches!(
+            leaf.shift_cells(0, DEFAULT_PAGE_SIZE / LEAF_NODE_SLOT_SIZE, 1),
+            Err(SqlError::Corrupt { .. })
+        ));
+    }
+
+    #[test]
+    fn insert_remove_children() {
+        let node = Node::new(0, new_page());
+        let internal = node.init_internal();
+        internal.set_cells(&[(1, 10)], &[]).unwrap();
+        internal.insert_child(0, 3, 30, 30).unwrap();
+        internal.insert_child(0, 2, 20, 20).unwrap();
+        internal.insert_child(2, 4, 40, 40).unwrap();
+        assert_eq!(
+            internal.children().unwrap(),
+            [(1, 10), (2, 20), (3, 30), (4, 40)]
+        );
+        assert_eq!(internal.keys().unwrap(), [20, 30, 40]);
+
+        internal.remove_child(1).unwrap();
+        assert_eq!(internal.children().unwrap(), [(1, 10), (3, 30), (4, 40)]);
+        assert_eq!(internal.keys().unwrap(), [30, 40]);
+        internal.remove_child(2).unwrap();
+        assert_eq!(internal.children().unwrap(), [(1, 10), (3, 30)]);
+        assert_eq!(internal.keys().unwrap(), [30]);
+        assert!(matches!(
+            internal.remove_child(0),
+            Err(SqlError::Corrupt { .. })
+        ));
+        assert!(matches!(
+            internal.insert_child(2, 5, 0, 50),
+            Err(SqlError::Corrupt { .. })
+        ));
+    }
+
+    #[test]
+    fn split_leaf_off() {
+        let node = Node::new(0, new_page());
+        let leaf = node.init_leaf();
+        let dest_node = Node::new(1, new_page());
+        let dest = dest_node.init_leaf();
+        let cells = (0..6)
+            .map(|key| inline_cell(key, &vec![key as u8; 10 * key as usize]))
+            .collect::<Vec<_>>();
+        for (i, cell) in cells.iter().enumerate() {
+            leaf.insert_cell(i, cell).unwrap();
+        }
+        leaf.remove_cell(1).unwrap();
+        leaf.insert_cell(1, &cells[1]).unwrap();
+        leaf.split_off(4, &dest).unwrap();
+        let contents = |leaf: &LeafMut| {
+            (0..leaf.get_num_cells())
+                .map(|i| leaf.get_cell(i).unwrap())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(contents(&leaf), cells[..4]);
+        assert_eq!(contents(&dest), cells[4..]);
+        // Both leaves are packed as if their cells were inserted in order
+        for (leaf, cells) in [(&leaf, &cells[..4]), (&dest, &cells[4..])] {
+            let used = cells.iter().map(|cell| cell.len()).sum::<usize>();
+            assert_eq!(
+                leaf.used_space().unwrap(),
+                used + cells.len() * LEAF_NODE_OFFSET_SIZE
+            );
+            let packed = Node::new(2, new_page()).init_leaf();
+            for (i, cell) in cells.iter().enumerate() {
+                packed.insert_cell(i, cell).unwrap();
+            }
+            assert_eq!(
+                leaf.node.page.borrow().buf[..],
+                packed.node.page.borrow().buf[..]
+            );
+        }
+    }
+
+    #[test]
+    fn split_internal_off() {
+        let node = Node::new(0, new_page());
+        let internal = node.init_internal();
+        let dest_node = Node::new(1, new_page());
+        let dest = dest_node.init_internal();
+        internal
+            .set_cells(
+                &[(1, 10), (2, 20), (3, 30), (4, 40), (5, 50)],
+                &[2, 3, 4, 5],
+            )
+            .unwrap();
+        assert_eq!(internal.split_off(2, &dest).unwrap(), 3);
+        assert_eq!(internal.children().unwrap(), [(1, 10), (2, 20)]);
+        assert_eq!(internal.keys().unwrap(), [2]);
+        assert_eq!(dest.children().unwrap(), [(3, 30), (4, 40), (5, 50)]);
+        assert_eq!(dest.keys().unwrap(), [4, 5]);
+        dest.prepend_child(6, 60, 3).unwrap();
+        assert_eq!(
+            dest.children().unwrap(),
+            [(6, 60), (3, 30), (4, 40), (5, 50)]
+        );
+        assert_eq!(dest.keys().unwrap(), [3, 4, 5]);
+        assert!(matches!(
+            internal.split_off(0, &dest),
+            Err(SqlError::Corrupt { .. })
+        ));
+    }
+
     #[test]
     fn find_key() {
-        let node = Node::new(new_page());
+        let node = Node::new(0, new_page());
         let internal = node.init_internal();
-        internal.set_num_keys(3);
-        internal.set_key_at(0, 1);
-        internal.set_key_at(1, 3);
-        internal.set_key_at(2, 5);
-        assert_eq!(internal.find_key(0), None);
-        assert_eq!(internal.find_key(1), Some(0));
-        assert_eq!(internal.find_key(2), Some(0));
-        assert_eq!(internal.find_key(3), Some(1));
-        assert_eq!(internal.find_key(4), Some(1));
-        assert_eq!(internal.find_key(5), Some(2));
+        internal
+            .set_cells(&[(1, 0), (2, 0), (3, 0), (4, 0)], &[1, 3, 5])
+            .unwrap();
+        assert_eq!(internal.find_key(0).unwrap(), 0);
+        assert_eq!(internal.find_key(1).unwrap(), 1);
+        assert_eq!(internal.find_key(2).unwrap(), 1);
+        assert_eq!(internal.find_key(3).unwrap(), 2);
+        assert_eq!(internal.find_key(4).unwrap(), 2);
+        assert_eq!(internal.find_key(5).unwrap(), 3);
+        assert_eq!(internal.find_key(i64::MAX).unwrap(), 3);
+    }
+
+    #[test]
+    fn header_checks() {
+        type Damage<'a> = &'a dyn Fn(&mut Table) -> usize;
+        // Each image is a file of two levels with one field damaged in a
+        // page, closed so that the damage is written out
+        let image = |corrupt: Damage| {
+            let storage = MemoryStorage::new();
+            let mut table = Table::open_with(storage.clone()).unwrap();
+            for i in 0..50 {
+                table.insert(Row::try_new(i, "a", "a@a").unwrap()).unwrap();
+            }
+            let page_num = corrupt(&mut table);
+            table.close().unwrap();
+            drop(table);
+            (storage, page_num)
+        };
+        let leaf_num = |table: &mut Table| table.find(0).unwrap().page_num;
+        let write = |table: &Table, page_num: usize, offset: usize, value: usize| {
+            let node = table.pager.node(page_num).unwrap();
+            write_pointer(&mut node.page_mut().buf[offset..], value);
+        };
+        let detail = |storage: MemoryStorage| {
+            let mut table = Table::open_with(storage)?;
+            table.find(0).map(|cursor| cursor.page_num)
+        };
+        let cases: [(&str, Damage); 7] = [
+            ("unknown node type 7", &|table| {
+                let page_num = leaf_num(table);
+                table.pager.node(page_num).unwrap().page_mut().buf[NODE_TYPE_OFFSET] = 7;
+                page_num
+            }),
+            ("is_root is 5", &|table| {
+                let page_num = leaf_num(table);
+                table.pager.node(page_num).unwrap().page_mut().buf[IS_ROOT_OFFSET] = 5;
+                page_num
+            }),
+            ("num_cells is 10000", &|table| {
+                let page_num = leaf_num(table);
+                write(table, page_num, LEAF_NODE_NUM_CELLS_OFFSET, 10_000);
+                page_num
+            }),
+            ("num_keys is 10000", &|table| {
+                let page_num = table.get_root_num().unwrap();
+                write(table, page_num, INTERNAL_NODE_NUM_KEYS_OFFSET, 10_000);
+                page_num
+            }),
+            ("parent is 1000", &|table| {
+                let page_num = leaf_num(table);
+                write(table, page_num, PARENT_POINTER_OFFSET, 1000);
+                page_num
+            }),
+            ("next_leaf is 1000", &|table| {
+                let page_num = leaf_num(table);
+                write(table, page_num, LEAF_NODE_NEXT_LEAF_OFFSET, 1000);
+                page_num
+            }),
+            ("prev_leaf is 1000", &|table| {
+                let page_num = leaf_num(table);
+                write(table, page_num, LEAF_NODE_PREV_LEAF_OFFSET, 1000);
+                page_num
+            }),
+        ];
+        for (expected, corrupt) in cases {
+            let (storage, bad_num) = image(corrupt);
+            match detail(storage) {
+                Err(SqlError::Corrupt { page, detail }) => {
+                    assert_eq!(page, bad_num, "{}", expected);
+                    assert!(detail.starts_with(expected), "{}: {}", expected, detail);
+                }
+                other => panic!("{}: expected Corrupt, got {:?}", expected, other),
+            }
+        }
+
+        // The meta page is checked as the file is opened
+        let (storage, _) = image(&|table| {
+            table.set_root_num(1000).unwrap();
+            META_NODE_NUM
+        });
+        assert!(matches!(
+            Table::open_with(storage),
+            Err(SqlError::Corrupt { page: META_NODE_NUM, detail })
+                if detail.starts_with("root_num is 1000")
+        ));
+
+        // Pages made in memory are trusted as they are
+        let (storage, _) = image(&|_| 0);
+        let table = Table::open_with(storage).unwrap();
+        let page_num = table.pager.alloc_page().unwrap();
+        let leaf = table.pager.node(page_num).unwrap().init_leaf();
+        leaf.set_next_leaf(1000);
+        assert!(table.leaf_ref(page_num).is_ok());
     }
 }
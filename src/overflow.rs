@@ -0,0 +1,212 @@
+//! Chains of overflow pages holding the values too long for a leaf cell.
+//! Each page starts with the number of the next page of the chain, or
+//! MISSING_NODE on the last one, and is filled with the value after it. The
+//! cell keeps the length of the value, which tells how many pages it takes.
+use crate::{
+    node::{read_pointer, write_pointer, LeafRef, LeafValue, MISSING_NODE, POINTER_SIZE},
+    pager::{Pager, Snapshot, PAGE_RESERVED_SIZE},
+    sql_error::{SqlError, SqlResult},
+};
+
+// OVERFLOW PAGE
+//   NEXT_PAGE, DATA
+const NEXT_PAGE_OFFSET: usize = 0;
+const DATA_OFFSET: usize = NEXT_PAGE_OFFSET + POINTER_SIZE;
+
+/// Bytes of a value each page of a chain holds
+pub fn page_capacity(page_size: usize) -> usize {
+    page_size - PAGE_RESERVED_SIZE - DATA_OFFSET
+}
+
+fn corrupt(page: usize, detail: &str) -> SqlError {
+    SqlError::Corrupt {
+        page,
+        detail: detail.to_string(),
+    }
+}
+
+/// Store a value in new pages, returning the first one. The pages taken
+/// are freed again if the value does not fit.
+pub fn write_chain(pager: &Pager, value: &[u8]) -> SqlResult<usize> {
+    let mut first = MISSING_NODE;
+    let mut written = 0;
+    // Written from the end, so that each page knows the one after it
+    for data in value.chunks(page_capacity(pager.page_size)).rev() {
+        let page_num = match pager.alloc_page() {
+            Ok(page_num) => page_num,
+            Err(e) => {
+                free_chain(pager, first, written)?;
+                return Err(e);
+            }
+        };
+        let node = pager.node(page_num)?;
+        let mut page = node.page_mut();
+        write_pointer(&mut page.buf[NEXT_PAGE_OFFSET..], first);
+        page.buf[DATA_OFFSET..DATA_OFFSET + data.len()].copy_from_slice(data);
+        first = page_num;
+        written += data.len();
+    }
+    Ok(first)
+}
+
+/// Pages of the chain of a value of `len` bytes
+pub fn chain_pages(pager: &Pager, first_page: usize, len: usize) -> SqlResult<Vec<usize>> {
+    let num_pages = len.div_ceil(page_capacity(pager.page_size));
+    let mut pages = Vec::new();
+    let mut page_num = first_page;
+    for _ in 0..num_pages {
+        if page_num == MISSING_NODE {
+            return Err(corrupt(first_page, "overflow chain ends early"));
+        }
+        pages.push(page_num);
+        page_num = read_pointer(&pager.node(page_num)?.page.borrow().buf[NEXT_PAGE_OFFSET..]);
+    }
+    Ok(pages)
+}
+
+/// Value of `len` bytes kept in a chain, as it was when the snapshot was
+/// taken if there is one
+pub fn read_chain(
+    pager: &Pager,
+    snapshot: Option<&Snapshot>,
+    first_page: usize,
+    len: usize,
+) -> SqlResult<Vec<u8>> {
+    let capacity = page_capacity(pager.page_size);
+    let mut value = Vec::new();
+    value
+        .try_reserve_exact(len)
+        .map_err(|_| SqlError::OutOfMemory)?;
+    let mut page_num = first_page;
+    while value.len() < len {
+        if page_num == MISSING_NODE {
+            return Err(corrupt(first_page, "overflow chain ends early"));
+        }
+        let node = match snapshot {
+            Some(snapshot) => pager.snapshot_node(snapshot, page_num)?,
+            None => pager.node(page_num)?,
+        };
+        let page = node.page.borrow();
+        let take = capacity.min(len - value.len());
+        value.extend_from_slice(&page.buf[DATA_OFFSET..DATA_OFFSET + take]);
+        page_num = read_pointer(&page.buf[NEXT_PAGE_OFFSET..]);
+    }
+    Ok(value)
+}
+
+/// Put the pages of a chain on the free list
+pub fn free_chain(pager: &Pager, first_page: usize, len: usize) -> SqlResult<()> {
+    for page_num in chain_pages(pager, first_page, len)? {
+        pager.free_page(page_num)?;
+    }
+    Ok(())
+}
+
+/// Copy of the value of a leaf cell, read from its chain if it has one
+pub fn read_value(
+    pager: &Pager,
+    snapshot: Option<&Snapshot>,
+    leaf: &LeafRef,
+    cell: usize,
+) -> SqlResult<Vec<u8>> {
+    match leaf.get_value(cell)? {
+        LeafValue::Inline(value) => Ok(value.to_vec()),
+        LeafValue::Overflow { first_page, len } => read_chain(pager, snapshot, first_page, len),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        node::NodeLimits,
+        table::Row,
+        test::{init_test_db, reopen_test_db},
+    };
+
+    #[test]
+    fn long_values() {
+        let db = "long_values";
+        let mut table = init_test_db(db);
+        for i in 0..10 {
+            table.insert(Row::try_new(i, "a", "b").unwrap()).unwrap();
+        }
+        // A value over three pages, the last one partly filled
+        let capacity = page_capacity(table.pager.page_size);
+        let long = (0..capacity * 2 + 100)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<_>>();
+        table.find(20).unwrap().insert(20, &long).unwrap();
+        table.close().unwrap();
+        drop(table);
+
+        let mut table = reopen_test_db(db);
+        table.check().unwrap();
+        let cursor = table.find(20).unwrap();
+        assert_eq!(*cursor.get().unwrap().get_value().unwrap(), long[..]);
+
+        // Changing the value frees its pages, and a scan started before
+        // still reads it
+        let mut scan = table.snapshot_scan().unwrap();
+        let free = table.pager.free_pages().unwrap().len();
+        table.find(20).unwrap().update(&[1; 10]).unwrap();
+        assert_eq!(table.pager.free_pages().unwrap().len(), free + 3);
+        table.check().unwrap();
+        let mut scanned = None;
+        while let Some(value) = scan.next_value(&table).unwrap() {
+            if value.get_key().unwrap() == 20 {
+                scanned = Some(value.get_value().unwrap().to_vec());
+            }
+        }
+        assert_eq!(scanned, Some(long.clone()));
+
+        table.find(20).unwrap().update(&long).unwrap();
+        assert_eq!(table.pager.free_pages().unwrap().len(), free);
+        let cursor = table.find(20).unwrap();
+        let leaf = cursor.table.leaf_ref(cursor.page_num).unwrap();
+        let chain = match leaf.get_value(cursor.cell_num).unwrap() {
+            LeafValue::Overflow { first_page, len } => {
+                chain_pages(&cursor.table.pager, first_page, len).unwrap()
+            }
+            LeafValue::Inline(_) => panic!("value kept in the leaf"),
+        };
+        assert_eq!(chain.len(), 3);
+        cursor.remove().unwrap();
+        let free = table.pager.free_pages().unwrap();
+        assert!(chain.iter().all(|page_num| free.contains(page_num)));
+        table.check().unwrap();
+        assert_eq!(table.len().unwrap(), 10);
+    }
+
+    #[test]
+    fn mixed_lengths() {
+        let db = "mixed_lengths";
+        let mut table = init_test_db(db);
+        table.set_node_limits(NodeLimits::FULL);
+        let value = |i: i64| vec![i as u8; (i as usize * 397) % 1500];
+        let keys = (0..60).map(|i| i * 37 % 60).collect::<Vec<_>>();
+        for &i in &keys {
+            table.find(i).unwrap().insert(i, &value(i)).unwrap();
+        }
+        table.check().unwrap();
+        for &i in keys.iter().step_by(3) {
+            table.find(i).unwrap().update(&value(i + 1)).unwrap();
+        }
+        for i in 10..12 {
+            table.find(i).unwrap().remove().unwrap();
+        }
+        table.check().unwrap();
+        table.close().unwrap();
+        drop(table);
+
+        let mut table = reopen_test_db(db);
+        table.check().unwrap();
+        assert_eq!(table.len().unwrap(), 58);
+        for &i in keys.iter().filter(|&&i| !(10..12).contains(&i)) {
+            let updated = keys.iter().step_by(3).any(|&k| k == i);
+            let expected = if updated { value(i + 1) } else { value(i) };
+            let cursor = table.find(i).unwrap();
+            assert_eq!(*cursor.get().unwrap().get_value().unwrap(), expected[..]);
+        }
+    }
+}
@@ -1,32 +1,97 @@
-use array_macro::array;
 use std::{
     cell::{Cell, RefCell},
-    fs::File,
-    io::{Read, Seek, SeekFrom, Write},
-    rc::Rc,
+    collections::{HashMap, HashSet},
+    io::ErrorKind,
+    rc::{Rc, Weak},
 };
+#[cfg(feature = "fs")]
+use std::{fs::File, path::Path};
 
+#[cfg(feature = "encryption")]
+use crate::cipher::PageCipher;
+#[cfg(feature = "fs")]
+use crate::storage::FileStorage;
 use crate::{
-    meta::{DEFAULT_ROOT_NUM, META_NODE_NUM},
-    node::Node,
+    changes::ChangeMap,
+    crc32::crc32,
+    flusher::Flusher,
+    legacy::{self, WIDE_POINTER_SIZE},
+    meta::{
+        fill_slot, has_slots, header_compressed, header_encrypted, header_page_size, unix_time,
+        CHECKSUM_VERSION, DEFAULT_ROOT_NUM, META_NODE_NUM, PAGE_COUNT_VERSION, SLOT_VERSION,
+    },
+    node::{read_pointer, write_pointer, Node, NodeLimits, MISSING_NODE, POINTER_SIZE},
     sql_error::{SqlError, SqlResult},
+    storage::Storage,
 };
 
-pub const PAGE_SIZE: usize = 4096;
-pub const MAX_PAGES: usize = 100;
+/// Page size of new databases, and of every file written before the header
+pub const DEFAULT_PAGE_SIZE: usize = 4096;
+pub const MIN_PAGE_SIZE: usize = 4096;
+pub const MAX_PAGE_SIZE: usize = 65536;
+/// Bytes at the end of each page holding a CRC-32 of the rest
+pub const CHECKSUM_SIZE: usize = 4;
+/// Bytes at the end of each page kept out of nodes: room for the nonce and
+/// tag of an encrypted page, whose last bytes are the checksum otherwise
+pub const PAGE_RESERVED_SIZE: usize = 28;
+
+/// Page sizes a new database may use: powers of two within the limits
+pub fn check_page_size(page_size: usize) -> SqlResult<()> {
+    if !page_size.is_power_of_two() || !(MIN_PAGE_SIZE..=MAX_PAGE_SIZE).contains(&page_size) {
+        return Err(SqlError::InvalidPageSize(page_size));
+    }
+    Ok(())
+}
+
+/// Store the checksum of a page in its last bytes
+pub fn seal(buf: &mut [u8]) {
+    let usable = buf.len() - CHECKSUM_SIZE;
+    let checksum = crc32(0, &buf[..usable]);
+    buf[usable..].copy_from_slice(&checksum.to_le_bytes());
+}
+
+/// Whether a page read from storage is a hole, left for a page of zeros
+/// that was never written
+fn is_hole(buf: &[u8]) -> bool {
+    buf.iter().all(|&b| b == 0)
+}
+
+fn verify(page_num: usize, buf: &[u8]) -> SqlResult<()> {
+    if is_hole(buf) {
+        return Ok(());
+    }
+    let usable = buf.len() - CHECKSUM_SIZE;
+    let stored = u32::from_le_bytes(buf[usable..].try_into().unwrap());
+    if crc32(0, &buf[..usable]) != stored {
+        return Err(SqlError::ChecksumMismatch { page: page_num });
+    }
+    Ok(())
+}
 
 #[derive(Debug, Clone)]
 pub struct PageBuffer {
-    pub buf: [u8; PAGE_SIZE],
+    pub buf: Vec<u8>,
+    /// Changed since it was read or last flushed
+    pub dirty: bool,
+    /// Node header found sound, or made in memory rather than read
+    pub checked: bool,
 }
 impl PageBuffer {
-    fn new() -> Self {
+    /// A zeroed page, not yet in storage
+    fn new(page_size: usize) -> Self {
         Self {
-            buf: [0; PAGE_SIZE],
+            buf: vec![0; page_size],
+            dirty: true,
+            checked: true,
         }
     }
-    fn from_buf(buf: [u8; PAGE_SIZE]) -> Self {
-        Self { buf }
+    /// A page as read from storage
+    fn from_buf(buf: Vec<u8>) -> Self {
+        Self {
+            buf,
+            dirty: false,
+            checked: false,
+        }
     }
     fn to_page(&self) -> Page {
         Rc::new(RefCell::new(Box::new(self.clone())))
@@ -34,100 +99,1159 @@ impl PageBuffer {
 }
 pub type Page = Rc<RefCell<Box<PageBuffer>>>;
 
-#[allow(dead_code)]
 pub fn new_page() -> Page {
-    PageBuffer::new().to_page()
+    PageBuffer::new(DEFAULT_PAGE_SIZE).to_page()
 }
 
-type PageContainer = RefCell<Box<[Option<Page>; MAX_PAGES]>>;
-pub struct Pager {
-    pub file: RefCell<File>,
+/// Cache activity since the pager was opened or the counters were reset
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Pages found in the cache
+    pub hits: u64,
+    /// Pages read from storage
+    pub misses: u64,
+    /// Pages written to storage
+    pub writes: u64,
+    /// Pages dropped to keep the cache within its size
+    pub evictions: u64,
+}
+
+impl CacheStats {
+    /// Activity between `earlier` and these counters
+    pub fn since(&self, earlier: &CacheStats) -> CacheStats {
+        CacheStats {
+            hits: self.hits - earlier.hits,
+            misses: self.misses - earlier.misses,
+            writes: self.writes - earlier.writes,
+            evictions: self.evictions - earlier.evictions,
+        }
+    }
+}
+
+impl std::fmt::Display for CacheStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "hits {}, misses {}, writes {}, evictions {}",
+            self.hits, self.misses, self.writes, self.evictions
+        )
+    }
+}
+
+/// Storage activity since the pager was opened or the counters were reset
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct IoStats {
+    /// Pages read from storage
+    pub reads: u64,
+    /// Pages written to storage, or queued for the background flusher
+    pub writes: u64,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    pub syncs: u64,
+}
+
+impl std::fmt::Display for IoStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "reads {} ({} bytes), writes {} ({} bytes), syncs {}",
+            self.reads, self.bytes_read, self.writes, self.bytes_written, self.syncs
+        )
+    }
+}
+
+#[derive(Default)]
+struct IoCounters {
+    reads: Cell<u64>,
+    writes: Cell<u64>,
+    bytes_read: Cell<u64>,
+    bytes_written: Cell<u64>,
+    syncs: Cell<u64>,
+}
+
+/// Size of the pages against the bytes the storage takes for them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StorageStats {
+    pub pages: usize,
+    pub stored: usize,
+}
+
+impl std::fmt::Display for StorageStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "pages {} bytes, stored in {} bytes",
+            self.pages, self.stored
+        )?;
+        if self.pages > 0 && self.stored < self.pages {
+            let saved = (self.pages - self.stored) * 100 / self.pages;
+            write!(f, " ({}% smaller)", saved)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct CacheCounters {
+    hits: Cell<u64>,
+    misses: Cell<u64>,
+    writes: Cell<u64>,
+    evictions: Cell<u64>,
+}
+
+fn bump(counter: &Cell<u64>, n: u64) {
+    counter.set(counter.get() + n);
+}
+
+/// Pages recorded in the meta page against the bytes found in the file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LengthMismatch {
+    pub recorded_pages: usize,
     pub file_length: usize,
+}
+
+impl std::fmt::Display for LengthMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "the meta page records {} pages but the file holds {} bytes",
+            self.recorded_pages, self.file_length
+        )
+    }
+}
+
+type PageContainer = RefCell<Vec<Option<Page>>>;
+type SnapshotPages = RefCell<HashMap<usize, Page>>;
+pub struct Pager {
+    pub storage: RefCell<Box<dyn Storage>>,
+    /// Bytes in the file, including pages queued to be written.
+    /// Pages another handle appends are only seen after `refresh`.
+    pub file_length: Cell<usize>,
+    /// Set when the file length disagrees with the page count in the meta
+    /// page, which is trusted instead
+    pub length_mismatch: Option<LengthMismatch>,
+    /// Bytes in each page, fixed when the file is created
+    pub page_size: usize,
     pub num_pages: Cell<usize>,
     pub pages: PageContainer,
+    /// Most pages the file may grow to, or None for no limit
+    pub max_pages: Cell<Option<usize>>,
+    /// Most pages kept in memory, or None to keep every page read
+    pub cache_size: Cell<Option<usize>>,
+    /// Pages read at once when a scan moves to a leaf not in the cache,
+    /// or 0 to read one page at a time
+    pub read_ahead: Cell<usize>,
+    /// Keep changed pages cached past `cache_size` rather than write them on
+    /// eviction, for a write-ahead log that must hold them first
+    pub keep_dirty: Cell<bool>,
+    /// Most cells a node holds before it splits
+    pub node_limits: Cell<NodeLimits>,
+    /// Clock value of the last access to each page, for LRU eviction
+    last_used: RefCell<Vec<u64>>,
+    clock: Cell<u64>,
+    counters: CacheCounters,
+    io: IoCounters,
+    pub closed: Cell<bool>,
+    /// Whether pages read from storage are verified. Files older than page
+    /// checksums are read without it until they are upgraded.
+    pub checksums: Cell<bool>,
+    snapshots: RefCell<Vec<Weak<SnapshotPages>>>,
+    /// Sequence number of the meta slot last written
+    meta_seq: Cell<u64>,
+    /// Number of pins on each page, shared with the `PinnedPage` guards
+    pins: Rc<RefCell<Vec<usize>>>,
+    /// Worker writing flushed pages in the background, if started
+    flusher: Option<Flusher>,
+    /// When each page was last written, if tracked
+    changes: RefCell<Option<ChangeMap>>,
+    /// Key of an encrypted database
+    #[cfg(feature = "encryption")]
+    cipher: Option<PageCipher>,
+}
+
+/// Pages as they were when the snapshot was taken.
+/// A page is copied the first time anyone touches it afterwards, and the
+/// snapshot stops collecting copies once dropped.
+pub struct Snapshot {
+    pages: Rc<SnapshotPages>,
+}
+
+/// Keeps a page in the cache until dropped, for operations that move cells
+/// between several pages and come back to each of them
+pub struct PinnedPage {
+    page_num: usize,
+    pins: Rc<RefCell<Vec<usize>>>,
+}
+
+impl PinnedPage {
+    pub fn page_num(&self) -> usize {
+        self.page_num
+    }
+}
+
+impl Drop for PinnedPage {
+    fn drop(&mut self) {
+        self.pins.borrow_mut()[self.page_num] -= 1;
+    }
+}
+
+/// Describe why a database file could not be opened
+pub fn open_error(e: std::io::Error, filename: &str) -> SqlError {
+    let reason = match e.kind() {
+        ErrorKind::NotFound => "Database not found",
+        ErrorKind::PermissionDenied => "Permission denied",
+        ErrorKind::IsADirectory => "Database path is a directory",
+        ErrorKind::AlreadyExists => "Database already exists",
+        _ => "Failed to open file",
+    };
+    SqlError::IOError(e, format!("{}: {}", reason, filename))
 }
 
 impl Pager {
+    #[cfg(feature = "fs")]
     pub fn open(filename: &str) -> SqlResult<Self> {
-        let file = File::options()
-            .read(true)
-            .write(true)
-            .create(true)
+        Self::open_file(filename, false, DEFAULT_PAGE_SIZE)
+    }
+    /// Open a database. `page_size` is used only when the file is new.
+    /// With `create_new`, fail if the file already exists.
+    #[cfg(feature = "fs")]
+    pub fn open_file(filename: &str, create_new: bool, page_size: usize) -> SqlResult<Self> {
+        Self::open_file_keyed(filename, create_new, page_size, None)
+    }
+    /// Open a database whose pages are encrypted with `key`, creating it
+    /// when the file is new
+    #[cfg(feature = "encryption")]
+    pub fn open_encrypted(filename: &str, key: &[u8]) -> SqlResult<Self> {
+        Self::open_file_keyed(filename, false, DEFAULT_PAGE_SIZE, Some(key))
+    }
+    #[cfg(feature = "fs")]
+    fn open_file_keyed(
+        filename: &str,
+        create_new: bool,
+        page_size: usize,
+        key: Option<&[u8]>,
+    ) -> SqlResult<Self> {
+        check_page_size(page_size)?;
+        if let Some(parent) = Path::new(filename).parent() {
+            if !parent.as_os_str().is_empty() && !parent.exists() {
+                std::fs::create_dir_all(parent).map_err(|e| open_error(e, filename))?;
+            }
+        }
+        let mut options = File::options();
+        options.read(true).write(true);
+        if create_new {
+            options.create_new(true);
+        } else {
+            options.create(true);
+        }
+        let file = options
             .open(filename)
-            .map_err(|e| SqlError::IOError(e, "Failed to open file".to_string()))?;
-
-        let file_length = file.metadata().unwrap().len() as usize;
-        let num_pages = file_length / PAGE_SIZE;
-        if file_length % PAGE_SIZE != 0 {
-            return Err(SqlError::CorruptFile);
+            .map_err(|e| open_error(e, filename))?;
+        let storage = Box::new(FileStorage::new(file));
+        Self::from_storage_keyed(storage, page_size, key)
+    }
+    /// Wrap an opened database file, initializing it when empty
+    #[cfg(feature = "fs")]
+    pub fn from_file(file: File) -> SqlResult<Self> {
+        Self::from_storage(Box::new(FileStorage::new(file)))
+    }
+    pub fn from_storage(storage: Box<dyn Storage>) -> SqlResult<Self> {
+        Self::from_storage_with(storage, DEFAULT_PAGE_SIZE)
+    }
+    /// Wrap a storage backend. An empty one is initialized with pages of
+    /// `page_size` bytes; otherwise the size recorded in its header is used.
+    pub fn from_storage_with(storage: Box<dyn Storage>, page_size: usize) -> SqlResult<Self> {
+        Self::from_storage_keyed(storage, page_size, None)
+    }
+    /// Wrap a storage backend, encrypted with `key` if given
+    fn from_storage_keyed(
+        mut storage: Box<dyn Storage>,
+        page_size: usize,
+        key: Option<&[u8]>,
+    ) -> SqlResult<Self> {
+        let file_length = storage.len()?;
+        let (page_size, encrypted, wide_version) = if file_length == 0 {
+            check_page_size(page_size)?;
+            (page_size, key.is_some(), None)
+        } else {
+            let mut head = [0; MIN_PAGE_SIZE];
+            storage.read_page(META_NODE_NUM, &mut head)?;
+            if header_compressed(&head) {
+                return Err(SqlError::Compressed);
+            }
+            let page_size = header_page_size(&head).unwrap_or(DEFAULT_PAGE_SIZE);
+            check_page_size(page_size).map_err(|_| SqlError::CorruptFile)?;
+            (
+                page_size,
+                header_encrypted(&head),
+                legacy::wide_version(&head),
+            )
+        };
+        match (encrypted, key.is_some()) {
+            (true, false) => return Err(SqlError::KeyRequired),
+            (false, true) => return Err(SqlError::NotEncrypted),
+            _ => {}
         }
-        let pages = array![None; MAX_PAGES];
-        let pager = Pager {
-            file: RefCell::new(file),
-            file_length,
+        // Until the meta page is read, a partial last page counts as a page
+        let num_pages = file_length.div_ceil(page_size);
+        let mut pager = Pager {
+            storage: RefCell::new(storage),
+            file_length: Cell::new(file_length),
+            length_mismatch: None,
+            page_size,
             num_pages: Cell::new(num_pages),
-            pages: RefCell::new(Box::new(pages)),
+            pages: RefCell::new(vec![None; num_pages]),
+            max_pages: Cell::new(None),
+            cache_size: Cell::new(None),
+            read_ahead: Cell::new(0),
+            keep_dirty: Cell::new(false),
+            node_limits: Cell::new(NodeLimits::default()),
+            last_used: RefCell::new(vec![0; num_pages]),
+            clock: Cell::new(0),
+            counters: CacheCounters::default(),
+            io: IoCounters::default(),
+            closed: Cell::new(false),
+            checksums: Cell::new(false),
+            snapshots: RefCell::new(vec![]),
+            meta_seq: Cell::new(0),
+            pins: Rc::new(RefCell::new(vec![])),
+            flusher: None,
+            changes: RefCell::new(None),
+            #[cfg(feature = "encryption")]
+            cipher: None,
         };
+        #[cfg(feature = "encryption")]
+        if let Some(key) = key {
+            pager.use_key(key)?;
+        }
         if pager.num_pages.get() == 0 {
-            pager.init_db()?
+            pager.init_db()?;
+            pager.checksums.set(true);
+            return Ok(pager);
+        }
+        // Pages with 8-byte page numbers are converted before anything reads them
+        if let Some(version) = wide_version {
+            pager.convert_wide_pages(version)?;
+        }
+        // The meta page is read unchecked to learn its format
+        let node = pager.node(META_NODE_NUM)?;
+        let meta = node.meta_node_mut();
+        let version = meta.node_erf.stored_version(num_pages)?;
+        // A whole slot holds the fields even if the rest of the page is torn
+        let slot = (version >= SLOT_VERSION)
+            .then(|| meta.load_newest_slot())
+            .flatten();
+        if let Some(seq) = slot {
+            pager.meta_seq.set(seq);
+        }
+        if version >= CHECKSUM_VERSION {
+            pager.checksums.set(true);
+            // An encrypted meta page was authenticated when read
+            if !pager.encrypted() && slot.is_none() {
+                verify(META_NODE_NUM, &node.page.borrow().buf)?;
+            }
+        }
+        if !meta.node_erf.has_header() {
+            meta.add_header();
+        }
+        let num_pages = if version >= PAGE_COUNT_VERSION {
+            let recorded_pages = meta.node_erf.get_page_count();
+            if recorded_pages * page_size != file_length {
+                pager.length_mismatch = Some(LengthMismatch {
+                    recorded_pages,
+                    file_length,
+                });
+            }
+            recorded_pages
+        } else if file_length % page_size != 0 {
+            return Err(SqlError::CorruptFile);
+        } else {
+            num_pages
+        };
+        pager.num_pages.set(num_pages);
+        let root_num = meta.node_erf.get_root_num();
+        if root_num == META_NODE_NUM || root_num >= num_pages {
+            return Err(SqlError::Corrupt {
+                page: META_NODE_NUM,
+                detail: format!(
+                    "root_num is {} outside the file of {} pages",
+                    root_num, num_pages
+                ),
+            });
         }
         Ok(pager)
     }
     fn init_db(&self) -> SqlResult<()> {
-        let page = self.node(META_NODE_NUM)?;
-        page.init_meta();
+        self.extend()?;
+        let meta = self.node(META_NODE_NUM)?.init_meta();
+        meta.set_created(unix_time());
+        #[cfg(feature = "encryption")]
+        if let Some(cipher) = &self.cipher {
+            meta.set_encrypted();
+            cipher.write_key_info(&mut meta.node_erf.node.page_mut().buf);
+        }
+        self.extend()?;
         let page = self.node(DEFAULT_ROOT_NUM)?;
         page.init_leaf();
         page.set_root(true);
         Ok(())
     }
+    /// Change the number of pages, recording it in the meta page as well
+    fn set_num_pages(&self, num_pages: usize) {
+        self.num_pages.set(num_pages);
+        let meta = match self.pages.borrow().get(META_NODE_NUM) {
+            Some(Some(meta)) => meta.clone(),
+            _ => return,
+        };
+        self.preserve(META_NODE_NUM, &meta);
+        Node::new(META_NODE_NUM, meta)
+            .meta_node_mut()
+            .set_page_count(num_pages);
+    }
+    /// `page_num` as a link stored in the page `from`, which is corrupt if
+    /// the link points past the end of the file
+    pub fn check_link(&self, from: usize, page_num: usize) -> SqlResult<usize> {
+        if page_num >= self.num_pages.get() {
+            return Err(SqlError::Corrupt {
+                page: from,
+                detail: format!("link to page {} past the end of the file", page_num),
+            });
+        }
+        Ok(page_num)
+    }
+    /// Page of the database, which must be below the page count.
+    /// New pages come from `alloc_page`.
     pub fn node(&self, page_num: usize) -> SqlResult<Node> {
-        if page_num >= MAX_PAGES {
-            return Err(SqlError::TableFull);
+        if self.closed.get() {
+            return Err(SqlError::Closed);
+        }
+        if page_num >= self.num_pages.get() {
+            return Err(SqlError::PageOutOfRange { page: page_num });
         }
         let mut pages = self.pages.borrow_mut();
-        let page = &pages[page_num];
-        if page.is_none() {
-            let mut buf = [0u8; PAGE_SIZE];
-            let num_pages: usize = (self.file_length + PAGE_SIZE - 1) / PAGE_SIZE;
-            if page_num < num_pages {
-                self.file
-                    .borrow_mut()
-                    .seek(SeekFrom::Start((page_num * PAGE_SIZE) as u64))
-                    .map_err(|e| SqlError::IOError(e, "Failed to seek to read".to_string()))?;
-                self.file
-                    .borrow_mut()
-                    .read(&mut buf)
-                    .map_err(|e| SqlError::IOError(e, "Failed to read".to_string()))?;
-            }
+        if page_num >= pages.len() {
+            let additional = page_num + 1 - pages.len();
+            pages
+                .try_reserve(additional)
+                .map_err(|_| SqlError::OutOfMemory)?;
+            pages.resize(page_num + 1, None);
+        }
+        let loaded = pages[page_num].is_none();
+        if loaded {
+            // Pages were in the file or written when evicted
+            let mut buf = vec![0u8; self.page_size];
+            self.read_stored(page_num, &mut buf)?;
             pages[page_num] = Some(PageBuffer::from_buf(buf).to_page());
-            if page_num >= self.num_pages.get() {
-                self.num_pages.set(page_num + 1);
+        } else {
+            bump(&self.counters.hits, 1);
+        }
+        let page = pages[page_num].as_ref().unwrap().to_owned();
+        drop(pages);
+        self.touch(page_num);
+        self.preserve(page_num, &page);
+        if loaded {
+            if let Err(e) = self.evict() {
+                // The page read finds no room, and was not changed yet
+                if matches!(e, SqlError::CachePinned) {
+                    self.pages.borrow_mut()[page_num] = None;
+                }
+                return Err(e);
             }
         }
-        Ok(Node::new(pages[page_num].as_ref().unwrap().to_owned()))
+        Ok(Node::new(page_num, page))
     }
-    pub fn flush(&self, page_num: usize) -> SqlResult<()> {
-        if self.pages.borrow()[page_num].is_none() {
+    fn touch(&self, page_num: usize) {
+        let mut last_used = self.last_used.borrow_mut();
+        if page_num >= last_used.len() {
+            last_used.resize(page_num + 1, 0);
+        }
+        self.clock.set(self.clock.get() + 1);
+        last_used[page_num] = self.clock.get();
+    }
+    /// Number of pages in memory
+    pub fn resident_pages(&self) -> usize {
+        self.pages.borrow().iter().flatten().count()
+    }
+    /// Cache a page and keep it there until the guard is dropped
+    pub fn pin(&self, page_num: usize) -> SqlResult<PinnedPage> {
+        self.node(page_num)?;
+        let mut pins = self.pins.borrow_mut();
+        if page_num >= pins.len() {
+            pins.resize(page_num + 1, 0);
+        }
+        pins[page_num] += 1;
+        Ok(PinnedPage {
+            page_num,
+            pins: self.pins.clone(),
+        })
+    }
+    pub fn is_pinned(&self, page_num: usize) -> bool {
+        self.pins.borrow().get(page_num).is_some_and(|&n| n > 0)
+    }
+    /// Write back and drop the least recently used pages until the cache fits.
+    /// The meta page, pinned pages, pages still referenced outside the cache
+    /// and, with `keep_dirty`, changed pages are kept. Fails if the pinned
+    /// pages leave no room in the cache.
+    pub fn evict(&self) -> SqlResult<()> {
+        let cache_size = match self.cache_size.get() {
+            Some(cache_size) => cache_size,
+            None => return Ok(()),
+        };
+        let mut resident = self.resident_pages();
+        while resident > cache_size {
+            let victim = {
+                let pages = self.pages.borrow();
+                let last_used = self.last_used.borrow();
+                (0..pages.len())
+                    .filter(|&n| n != META_NODE_NUM && !self.is_pinned(n))
+                    .filter(|&n| !(self.keep_dirty.get() && self.is_dirty(n)))
+                    .filter(|&n| matches!(&pages[n], Some(page) if Rc::strong_count(page) == 1))
+                    .min_by_key(|&n| last_used.get(n).copied().unwrap_or(0))
+            };
+            let victim = match victim {
+                Some(victim) => victim,
+                None => {
+                    // Pages only referenced go when they are let go of, but
+                    // pins hold on for a whole operation
+                    let pinned = self.pins.borrow().iter().filter(|&&n| n > 0).count();
+                    if pinned + 1 >= cache_size {
+                        return Err(SqlError::CachePinned);
+                    }
+                    return Ok(());
+                }
+            };
+            self.flush(victim)?;
+            self.pages.borrow_mut()[victim] = None;
+            bump(&self.counters.evictions, 1);
+            resident -= 1;
+        }
+        Ok(())
+    }
+    /// Cache up to `read_ahead` pages of the file from `page_num` with one read.
+    /// Cached pages are kept as they are, and the cache keeps room for the
+    /// meta page and the page being scanned.
+    pub fn prefetch(&self, page_num: usize) -> SqlResult<()> {
+        let mut count = self
+            .read_ahead
+            .get()
+            .min(self.num_pages.get().saturating_sub(page_num));
+        if let Some(cache_size) = self.cache_size.get() {
+            count = count.min(cache_size.saturating_sub(2));
+        }
+        if count == 0 || self.closed.get() || self.is_cached(page_num) {
             return Ok(());
         }
-        self.file
+        let mut buf = vec![0; count * self.page_size];
+        let read = self
+            .storage
             .borrow_mut()
-            .seek(SeekFrom::Start((page_num * PAGE_SIZE) as u64))
-            .map_err(|e| SqlError::IOError(e, "Failed to seek to write".to_string()))?;
+            .read_pages(page_num, self.page_size, &mut buf)?;
+        // Pages past the end of the file are new ones, which are cached
+        let whole = read / self.page_size;
+        bump(&self.counters.misses, whole as u64);
+        bump(&self.io.reads, whole as u64);
+        bump(&self.io.bytes_read, read as u64);
+        let flusher = self.flusher.as_ref();
+        {
+            let mut pages = self.pages.borrow_mut();
+            if pages.len() < page_num + whole {
+                pages.resize(page_num + whole, None);
+            }
+            for (i, chunk) in buf.chunks_exact_mut(self.page_size).take(whole).enumerate() {
+                let n = page_num + i;
+                if pages[n].is_some() {
+                    continue;
+                }
+                // Checked as `read_stored` checks a page read on its own
+                let buf = match flusher.and_then(|f| f.pending(n)) {
+                    Some(queued) => queued,
+                    None => {
+                        self.check_page(n, chunk)?;
+                        chunk.to_vec()
+                    }
+                };
+                pages[n] = Some(PageBuffer::from_buf(buf).to_page());
+                self.touch(n);
+            }
+        }
+        self.evict()
+    }
+    /// Start keeping the current contents of pages
+    pub fn snapshot(&self) -> Snapshot {
+        let pages = Rc::new(RefCell::new(HashMap::new()));
+        self.snapshots.borrow_mut().push(Rc::downgrade(&pages));
+        Snapshot { pages }
+    }
+    /// Node of a page as it was when the snapshot was taken
+    pub fn snapshot_node(&self, snapshot: &Snapshot, page_num: usize) -> SqlResult<Node> {
+        // Touching the page makes sure the snapshot has a copy
+        self.node(page_num)?;
+        Ok(Node::new(
+            page_num,
+            snapshot.pages.borrow()[&page_num].clone(),
+        ))
+    }
+    /// Copy a page into the live snapshots that have not kept it yet
+    fn preserve(&self, page_num: usize, page: &Page) {
+        let mut snapshots = self.snapshots.borrow_mut();
+        if snapshots.is_empty() {
+            return;
+        }
+        snapshots.retain(|pages| pages.strong_count() > 0);
+        for pages in snapshots.iter().filter_map(Weak::upgrade) {
+            pages
+                .borrow_mut()
+                .entry(page_num)
+                .or_insert_with(|| page.borrow().to_page());
+        }
+    }
+    /// Copy of a page, taken from the cache if present without caching it otherwise
+    pub fn read_page(&self, page_num: usize) -> SqlResult<Vec<u8>> {
+        if self.closed.get() {
+            return Err(SqlError::Closed);
+        }
+        if let Some(Some(page)) = self.pages.borrow().get(page_num) {
+            return Ok(page.borrow().buf.clone());
+        }
+        let mut buf = vec![0u8; self.page_size];
+        if page_num < self.num_pages.get() {
+            self.read_stored(page_num, &mut buf)?;
+        }
+        Ok(buf)
+    }
+    /// Read a page inside the file, which must be there whole
+    fn read_stored(&self, page_num: usize, buf: &mut [u8]) -> SqlResult<()> {
+        if let Some(queued) = self.flusher.as_ref().and_then(|f| f.pending(page_num)) {
+            buf.copy_from_slice(&queued);
+            return Ok(());
+        }
+        let read = self.storage.borrow_mut().read_page(page_num, buf)?;
+        bump(&self.counters.misses, 1);
+        bump(&self.io.reads, 1);
+        bump(&self.io.bytes_read, read as u64);
+        if read < buf.len() {
+            return Err(SqlError::CorruptPage { page: page_num });
+        }
+        self.check_page(page_num, buf)
+    }
+    /// Whether pages are encrypted
+    pub fn encrypted(&self) -> bool {
+        #[cfg(feature = "encryption")]
+        return self.cipher.is_some();
+        #[cfg(not(feature = "encryption"))]
+        false
+    }
+    /// Take the key of an encrypted database, checking it against the stored
+    /// meta page unless the database is new
+    #[cfg(feature = "encryption")]
+    fn use_key(&mut self, key: &[u8]) -> SqlResult<()> {
+        let cipher = if self.num_pages.get() == 0 {
+            PageCipher::create(key)?
+        } else {
+            let mut meta = vec![0; self.page_size];
+            self.storage
+                .borrow_mut()
+                .read_page(META_NODE_NUM, &mut meta)?;
+            PageCipher::open(key, &meta)?
+        };
+        self.cipher = Some(cipher);
+        Ok(())
+    }
+    /// Prepare a copy of a page for storage: encrypt it, or store its checksum.
+    /// Changed fields of the meta page go into the slot of the next write.
+    pub fn seal_page(&self, page_num: usize, buf: &mut [u8]) -> SqlResult<()> {
+        if page_num == META_NODE_NUM && has_slots(buf) {
+            fill_slot(buf, self.meta_seq.get());
+        }
+        #[cfg(feature = "encryption")]
+        if let Some(cipher) = &self.cipher {
+            return cipher.encrypt(page_num, buf);
+        }
+        seal(buf);
+        Ok(())
+    }
+    /// Check a page read from storage: decrypt it, or verify its checksum.
+    /// A hole reads as a page of zeros either way.
+    fn check_page(&self, page_num: usize, buf: &mut [u8]) -> SqlResult<()> {
+        #[cfg(feature = "encryption")]
+        if let Some(cipher) = self.cipher.as_ref().filter(|_| !is_hole(buf)) {
+            return cipher.decrypt(page_num, buf);
+        }
+        if self.checksums.get() {
+            verify(page_num, buf)?;
+        }
+        Ok(())
+    }
+    /// Cache every page of a file written before format version 9, with page
+    /// numbers and counts narrowed to the current width. The pages are dirty,
+    /// so the upgrade that follows writes them back.
+    fn convert_wide_pages(&self, version: u32) -> SqlResult<()> {
+        let whole = self.file_length.get() / self.page_size;
+        let mut pages = Vec::with_capacity(whole);
+        for page_num in 0..whole {
+            let mut buf = vec![0; self.page_size];
+            self.read_stored(page_num, &mut buf)?;
+            if version >= CHECKSUM_VERSION && !self.encrypted() {
+                verify(page_num, &buf)?;
+            }
+            pages.push(buf);
+        }
+        legacy::convert_pages(&mut pages, version, WIDE_POINTER_SIZE, POINTER_SIZE)?;
+        let mut cache = self.pages.borrow_mut();
+        for (page_num, mut buf) in pages.into_iter().enumerate() {
+            // Sealed again, so the meta page still verifies as it is read
+            seal(&mut buf);
+            let mut page = PageBuffer::from_buf(buf);
+            page.dirty = true;
+            cache[page_num] = Some(page.to_page());
+        }
+        Ok(())
+    }
+    /// Cache pages from the file contents read elsewhere.
+    /// Pages already in the cache are kept.
+    #[cfg(feature = "fs")]
+    pub(crate) fn load_pages(&self, data: &[u8]) -> SqlResult<()> {
+        let mut pages = self.pages.borrow_mut();
+        let num_pages = data.len() / self.page_size;
+        if pages.len() < num_pages {
+            pages.resize(num_pages, None);
+        }
+        for (page_num, chunk) in data.chunks_exact(self.page_size).enumerate() {
+            if pages[page_num].is_none() {
+                let mut buf = chunk.to_vec();
+                self.check_page(page_num, &mut buf)?;
+                pages[page_num] = Some(PageBuffer::from_buf(buf).to_page());
+            }
+        }
+        Ok(())
+    }
+    /// Write a cached page if it changed since it was read or last flushed
+    pub fn flush(&self, page_num: usize) -> SqlResult<()> {
+        if !self.is_dirty(page_num) {
+            return Ok(());
+        }
+        // A hole the storage could not be extended over is written instead
+        if self.skip_holes(&mut vec![page_num]).unwrap_or(0) == 0 {
+            self.flush_run(page_num, 1)?;
+        }
+        Ok(())
+    }
+    /// Take the pages of zeros past the end of storage out of `dirty` and
+    /// mark them clean, extending the storage over them instead of writing
+    /// them, so that they read back as holes. Returns the pages taken.
+    fn skip_holes(&self, dirty: &mut Vec<usize>) -> SqlResult<usize> {
+        // Queued pages may already reach past the end
+        if self.flusher.is_some() {
+            return Ok(0);
+        }
+        let first_new = self.file_length.get().div_ceil(self.page_size);
+        let holes = dirty
+            .iter()
+            .copied()
+            .filter(|&n| n >= first_new)
+            .filter(|&n| is_hole(&self.pages.borrow()[n].as_ref().unwrap().borrow().buf))
+            .collect::<Vec<_>>();
+        let Some(&last) = holes.last() else {
+            return Ok(0);
+        };
+        for &page_num in &holes {
+            self.record_write(page_num)?;
+        }
+        let end = (last + 1) * self.page_size;
+        self.storage.borrow_mut().set_len(end)?;
+        self.file_length.set(end);
+        for &page_num in &holes {
+            if let Some(Some(page)) = self.pages.borrow().get(page_num) {
+                page.borrow_mut().dirty = false;
+            }
+        }
+        dirty.retain(|n| !holes.contains(n));
+        Ok(holes.len())
+    }
+    /// Write every changed page in ascending order, with the meta page last
+    /// so that it never points at pages not written yet. Consecutive changed
+    /// pages go to storage in one write, and new pages still all zeros are
+    /// left as holes. Returns the pages that failed, each with its failure;
+    /// every page of a failed write stays dirty, and so does the meta page,
+    /// which is held back until they are written.
+    pub fn flush_dirty(&self) -> Vec<(usize, SqlError)> {
+        let mut failures = Vec::new();
+        let unwritten = |page_num: usize, failed: usize, why: &str| {
+            let e = std::io::Error::other(format!("{} page {}", why, failed));
+            (
+                page_num,
+                SqlError::IOError(e, "Failed to write".to_string()),
+            )
+        };
+        for (page_num, count) in self.dirty_runs() {
+            if page_num == META_NODE_NUM {
+                if let Some(&(failed, _)) = failures.first() {
+                    failures.push(unwritten(META_NODE_NUM, failed, "held back for"));
+                    continue;
+                }
+            }
+            if let Err(e) = self.flush_run(page_num, count) {
+                // Which pages of the write reached storage is unknown
+                failures.push((page_num, e));
+                for other in page_num + 1..page_num + count {
+                    failures.push(unwritten(other, page_num, "written with"));
+                }
+            }
+        }
+        failures
+    }
+    /// Changed pages in the order `flush_dirty` writes them, as runs of
+    /// consecutive pages given by their first page and length, with the meta
+    /// page last on its own. New pages still all zeros are left out, the
+    /// storage extended over them instead.
+    pub fn dirty_runs(&self) -> Vec<(usize, usize)> {
+        let mut dirty = (1..self.num_pages.get())
+            .filter(|&n| self.is_dirty(n))
+            .collect::<Vec<_>>();
+        // Holes the storage could not be extended over are written instead
+        let _ = self.skip_holes(&mut dirty);
+        let mut runs = dirty
+            .chunk_by(|a, b| a + 1 == *b)
+            .map(|run| (run[0], run.len()))
+            .collect::<Vec<_>>();
+        if self.is_dirty(META_NODE_NUM) {
+            runs.push((META_NODE_NUM, 1));
+        }
+        runs
+    }
+    /// Write `count` cached pages from `page_num`, which are all dirty
+    fn flush_run(&self, page_num: usize, count: usize) -> SqlResult<()> {
+        let buf = self.seal_run(page_num, count)?;
+        match &self.flusher {
+            Some(flusher) => {
+                for (i, page) in buf.chunks(self.page_size).enumerate() {
+                    flusher.queue(page_num + i, page.to_vec())?;
+                }
+            }
+            None => self
+                .storage
+                .borrow_mut()
+                .write_pages(page_num, self.page_size, &buf)?,
+        }
+        self.finish_run(page_num, count);
+        Ok(())
+    }
+    /// Sealed copies of `count` cached pages from `page_num`, to store with
+    /// one write
+    pub fn seal_run(&self, page_num: usize, count: usize) -> SqlResult<Vec<u8>> {
         let pages = self.pages.borrow();
-        let buf = &pages[page_num].as_ref().unwrap().borrow().buf;
-
-        self.file
-            .borrow_mut()
-            .write_all(buf.as_slice())
-            .map_err(|e| SqlError::IOError(e, "Failed to write".to_string()))?;
+        let mut buf = Vec::with_capacity(count * self.page_size);
+        for (i, page) in pages[page_num..page_num + count].iter().enumerate() {
+            let mut page = page.as_ref().unwrap().borrow().buf.clone();
+            self.seal_page(page_num + i, &mut page)?;
+            self.record_write(page_num + i)?;
+            buf.extend_from_slice(&page);
+        }
+        Ok(buf)
+    }
+    /// Take the pages of a run from `seal_run` as stored: mark them clean,
+    /// move the meta slot on and count the write
+    pub fn finish_run(&self, page_num: usize, count: usize) {
+        let pages = self.pages.borrow();
+        let run = &pages[page_num..page_num + count];
+        for page in run.iter().flatten() {
+            page.borrow_mut().dirty = false;
+        }
+        if page_num == META_NODE_NUM {
+            // The cached page keeps the slots as stored, for the next write
+            let mut meta = run[0].as_ref().unwrap().borrow_mut();
+            if has_slots(&meta.buf) {
+                self.meta_seq
+                    .set(fill_slot(&mut meta.buf, self.meta_seq.get()));
+            }
+        }
+        bump(&self.counters.writes, count as u64);
+        bump(&self.io.writes, count as u64);
+        bump(&self.io.bytes_written, (count * self.page_size) as u64);
+        let end = (page_num + count) * self.page_size;
+        if end > self.file_length.get() {
+            self.file_length.set(end);
+        }
+    }
+    /// Read the file length again, so that pages appended through another
+    /// handle can be read. Cached pages are kept as they are.
+    pub fn refresh(&self) -> SqlResult<()> {
+        let file_length = self.storage.borrow().len()?;
+        self.file_length.set(file_length);
+        let whole = file_length / self.page_size;
+        if whole > self.num_pages.get() {
+            self.set_num_pages(whole);
+        }
+        Ok(())
+    }
+    /// Write pages from now on with a worker thread writing to `storage`,
+    /// after the writes of a previous worker are done
+    pub fn start_flusher<S: Storage + Send + 'static>(&mut self, storage: S) -> SqlResult<()> {
+        self.stop_flusher()?;
+        self.flusher = Some(Flusher::new(storage));
+        Ok(())
+    }
+    /// Wait for the background writes and write from this thread again
+    pub fn stop_flusher(&mut self) -> SqlResult<()> {
+        let failures = self.drain()?;
+        if !failures.is_empty() {
+            return Err(SqlError::CloseIncomplete(failures));
+        }
+        self.flusher = None;
         Ok(())
     }
+    pub fn has_flusher(&self) -> bool {
+        self.flusher.is_some()
+    }
+    /// Wait for the background writes to finish. Pages whose write failed are
+    /// cached again, so that the next flush retries them.
+    pub fn drain(&self) -> SqlResult<Vec<(usize, SqlError)>> {
+        let flusher = match &self.flusher {
+            Some(flusher) => flusher,
+            None => return Ok(vec![]),
+        };
+        let mut failures = flusher.drain()?;
+        // Pages written successfully after failing are not reported
+        failures.retain(|&(page_num, _)| match flusher.take(page_num) {
+            Some(buf) => {
+                let mut pages = self.pages.borrow_mut();
+                match &pages[page_num] {
+                    Some(page) => page.borrow_mut().dirty = true,
+                    None => {
+                        let mut page = PageBuffer::from_buf(buf);
+                        page.dirty = true;
+                        pages[page_num] = Some(page.to_page());
+                    }
+                }
+                true
+            }
+            None => false,
+        });
+        Ok(failures)
+    }
+    pub fn cache_stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.counters.hits.get(),
+            misses: self.counters.misses.get(),
+            writes: self.counters.writes.get(),
+            evictions: self.counters.evictions.get(),
+        }
+    }
+    pub fn reset_cache_stats(&self) {
+        for counter in [
+            &self.counters.hits,
+            &self.counters.misses,
+            &self.counters.writes,
+            &self.counters.evictions,
+        ] {
+            counter.set(0);
+        }
+    }
+    pub fn storage_stats(&self) -> SqlResult<StorageStats> {
+        let storage = self.storage.borrow();
+        Ok(StorageStats {
+            pages: storage.len()?,
+            stored: storage.stored_len()?,
+        })
+    }
+    pub fn io_stats(&self) -> IoStats {
+        IoStats {
+            reads: self.io.reads.get(),
+            writes: self.io.writes.get(),
+            bytes_read: self.io.bytes_read.get(),
+            bytes_written: self.io.bytes_written.get(),
+            syncs: self.io.syncs.get(),
+        }
+    }
+    pub fn reset_io_stats(&self) {
+        for counter in [
+            &self.io.reads,
+            &self.io.writes,
+            &self.io.bytes_read,
+            &self.io.bytes_written,
+            &self.io.syncs,
+        ] {
+            counter.set(0);
+        }
+    }
+    /// Pages counted in the meta page that the file does not hold whole,
+    /// and that are not in memory waiting to be written
+    pub fn missing_pages(&self) -> SqlResult<Vec<usize>> {
+        let whole = self.storage.borrow().len()? / self.page_size;
+        let flusher = self.flusher.as_ref();
+        Ok((whole..self.num_pages.get())
+            .filter(|&n| !self.is_cached(n))
+            .filter(|&n| flusher.and_then(|f| f.pending(n)).is_none())
+            .collect())
+    }
+    pub fn is_cached(&self, page_num: usize) -> bool {
+        matches!(self.pages.borrow().get(page_num), Some(Some(_)))
+    }
+    /// Whether a page is cached with changes not flushed yet
+    pub fn is_dirty(&self, page_num: usize) -> bool {
+        matches!(self.pages.borrow().get(page_num), Some(Some(page)) if page.borrow().dirty)
+    }
+    pub fn sync(&self) -> SqlResult<()> {
+        if let Some(changes) = self.changes.borrow_mut().as_mut() {
+            changes.sync()?;
+        }
+        bump(&self.io.syncs, 1);
+        self.storage.borrow_mut().sync()
+    }
+    /// Mark each page written from now on in `changes`
+    pub fn track_changes(&mut self, changes: ChangeMap) {
+        *self.changes.get_mut() = Some(changes);
+    }
+    /// Mark a page about to be written, if changes are tracked
+    pub fn record_write(&self, page_num: usize) -> SqlResult<()> {
+        match self.changes.borrow_mut().as_mut() {
+            Some(changes) => changes.mark(page_num),
+            None => Ok(()),
+        }
+    }
+    /// Move the change counter on, returning it as it was, or None if
+    /// changes are not tracked
+    pub fn bump_changes(&self) -> SqlResult<Option<u64>> {
+        self.changes
+            .borrow_mut()
+            .as_mut()
+            .map(|changes| changes.bump())
+            .transpose()
+    }
+    /// Pages written after the change counter was `since`, or None if that
+    /// is not known
+    pub fn changed_since(&self, since: u64) -> Option<Vec<usize>> {
+        let changes = self.changes.borrow();
+        let changes = changes.as_ref()?;
+        if since >= changes.counter() {
+            return None;
+        }
+        Some(changes.changed_since(since, self.num_pages.get()))
+    }
+    /// Drop a cached page unless it is still referenced outside the cache
+    pub fn uncache(&self, page_num: usize) {
+        if let Some(slot) = self.pages.borrow_mut().get_mut(page_num) {
+            if matches!(slot, Some(page) if Rc::strong_count(page) == 1) {
+                *slot = None;
+            }
+        }
+    }
     pub fn drop(&mut self, page_num: usize) {
-        self.pages.borrow_mut()[page_num] = None;
+        if let Some(page) = self.pages.borrow_mut().get_mut(page_num) {
+            *page = None;
+        }
+    }
+    /// Reserve a zeroed page for a new node, taken from the free list before
+    /// extending the file
+    pub fn alloc_page(&self) -> SqlResult<usize> {
+        let meta = self.node(META_NODE_NUM)?.meta_node_mut();
+        let head = meta.node_erf.get_free_head();
+        if head == MISSING_NODE {
+            return self.extend();
+        }
+        let node = self.node(head)?;
+        let mut page = node.page_mut();
+        let next = read_pointer(&page.buf);
+        page.buf.fill(0);
+        meta.set_free_head(next);
+        Ok(head)
     }
-    pub fn new_page_num(&self) -> usize {
-        self.num_pages.get()
+    /// Add a zeroed page after the last one, cached until it is flushed
+    fn extend(&self) -> SqlResult<usize> {
+        let page_num = self.num_pages.get();
+        if self.max_pages.get().is_some_and(|max| page_num >= max) {
+            return Err(SqlError::TableFull);
+        }
+        {
+            let mut pages = self.pages.borrow_mut();
+            if page_num >= pages.len() {
+                let additional = page_num + 1 - pages.len();
+                pages
+                    .try_reserve(additional)
+                    .map_err(|_| SqlError::OutOfMemory)?;
+                pages.resize(page_num + 1, None);
+            }
+            pages[page_num] = Some(PageBuffer::new(self.page_size).to_page());
+        }
+        self.set_num_pages(page_num + 1);
+        self.touch(page_num);
+        self.evict()?;
+        Ok(page_num)
+    }
+    /// Put a page that is no longer in the tree on the free list.
+    /// The page is cleared except for the number of the next free page.
+    pub fn free_page(&self, page_num: usize) -> SqlResult<()> {
+        let meta = self.node(META_NODE_NUM)?.meta_node_mut();
+        let node = self.node(page_num)?;
+        let mut page = node.page_mut();
+        page.buf.fill(0);
+        write_pointer(&mut page.buf, meta.node_erf.get_free_head());
+        meta.set_free_head(page_num);
+        Ok(())
+    }
+    /// Pages on the free list, from its head
+    pub fn free_pages(&self) -> SqlResult<Vec<usize>> {
+        let mut pages = Vec::new();
+        let mut page_num = self.node(META_NODE_NUM)?.meta_node().get_free_head();
+        while page_num != MISSING_NODE {
+            // A list longer than the file has a cycle
+            if page_num >= self.num_pages.get() || pages.len() >= self.num_pages.get() {
+                return Err(SqlError::CorruptFile);
+            }
+            pages.push(page_num);
+            page_num = self.next_free(page_num)?;
+        }
+        Ok(pages)
+    }
+    /// Page after a free page on the free list
+    pub fn next_free(&self, page_num: usize) -> SqlResult<usize> {
+        let buf = self.read_page(page_num)?;
+        Ok(read_pointer(&buf))
+    }
+    fn set_next_free(&self, page_num: usize, next: usize) -> SqlResult<()> {
+        write_pointer(&mut self.node(page_num)?.page_mut().buf, next);
+        Ok(())
+    }
+    /// Take the free pages at the end of the file off the free list.
+    /// Returns the number of pages left before them.
+    pub fn trim_free_pages(&self) -> SqlResult<usize> {
+        let free = self.free_pages()?;
+        let trailing = free.iter().copied().collect::<HashSet<_>>();
+        let mut end = self.num_pages.get();
+        while end > 0 && trailing.contains(&(end - 1)) {
+            end -= 1;
+        }
+        let kept = free.into_iter().filter(|&n| n < end).collect::<Vec<_>>();
+        // Links already in place are left alone, so their pages stay clean
+        let links = kept.iter().zip(kept.iter().skip(1).chain([&MISSING_NODE]));
+        for (&page_num, &next) in links {
+            if self.next_free(page_num)? != next {
+                self.set_next_free(page_num, next)?;
+            }
+        }
+        let head = kept.first().copied().unwrap_or(MISSING_NODE);
+        if self.node(META_NODE_NUM)?.meta_node().get_free_head() != head {
+            self.node(META_NODE_NUM)?
+                .meta_node_mut()
+                .set_free_head(head);
+        }
+        Ok(end)
+    }
+    /// Cut the file after `num_pages` pages, dropping the cached pages past it.
+    /// Background writes are waited for first, so none lands past the end
+    pub fn truncate(&self, num_pages: usize) -> SqlResult<()> {
+        let failures = self.drain()?;
+        if !failures.is_empty() {
+            return Err(SqlError::CloseIncomplete(failures));
+        }
+        for page in self.pages.borrow_mut().iter_mut().skip(num_pages) {
+            *page = None;
+        }
+        self.storage
+            .borrow_mut()
+            .set_len(num_pages * self.page_size)?;
+        self.file_length.set(num_pages * self.page_size);
+        self.set_num_pages(num_pages);
+        Ok(())
     }
 }
@@ -0,0 +1,1161 @@
+//! Statements and meta commands as the REPL runs them, printing results
+//! to stdout
+use std::cell::Cell;
+use std::io::Write;
+
+use crate::{
+    commands::*,
+    node::NodeLimits,
+    sql_error::{SqlError, SqlResult},
+    table::{self, Table},
+};
+
+/// Print the error of a statement as the output mode wants it: as text on
+/// `out`, or as a line of JSON on `err` for scripts
+pub fn report_error(
+    e: &SqlError,
+    statement: &str,
+    out: &mut impl Write,
+    err: &mut impl Write,
+) -> std::io::Result<()> {
+    if OUTPUT_MODE.get() == OutputMode::Json {
+        return writeln!(
+            err,
+            "{}",
+            json_object(&[
+                ("error", e.code()),
+                ("message", &e.to_string()),
+                ("statement", statement),
+            ])
+        );
+    }
+    match e {
+        SqlError::CloseIncomplete(failures) => {
+            let pages = failures.iter().map(|(page, _)| page).collect::<Vec<_>>();
+            writeln!(
+                out,
+                "Error: pages {:?} were not written, the file may be inconsistent",
+                pages
+            )?;
+            for (page, e) in failures {
+                writeln!(out, "  page {}: {} [{}]", page, e, e.code())?;
+            }
+            Ok(())
+        }
+        e => {
+            if let SqlError::ParseError { input, offset, .. } = e {
+                // The statement with a caret under the word that failed
+                writeln!(out, "  {}", input)?;
+                writeln!(out, "  {}^", " ".repeat(input[..*offset].chars().count()))?;
+            }
+            writeln!(out, "Error: {} [{}]", e, e.code())
+        }
+    }
+}
+
+/// One line JSON object of string fields
+fn json_object(fields: &[(&str, &str)]) -> String {
+    let fields = fields
+        .iter()
+        .map(|(key, value)| format!("{}:{}", json_string(key), json_string(value)))
+        .collect::<Vec<_>>();
+    format!("{{{}}}", fields.join(","))
+}
+/// JSON string literal of `s`, control characters escaped so that it stays
+/// on one line
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// How the REPL reports errors. Results print the same in either mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputMode {
+    /// `Error: ...` lines on stdout
+    Text,
+    /// A JSON object on a line of stderr with the code, message and statement
+    Json,
+}
+
+thread_local! {
+    /// Whether `.stats on` asked for the cache activity of each statement
+    static STATEMENT_STATS: Cell<bool> = const { Cell::new(false) };
+    /// Set by `.mode` or the `--json-errors` flag
+    pub static OUTPUT_MODE: Cell<OutputMode> = const { Cell::new(OutputMode::Text) };
+}
+
+/// Run a statement or a meta command, printing its results
+pub fn exec_buf(buf: &str, table: &mut Table) -> SqlResult<()> {
+    if buf.starts_with(".") {
+        return meta_command(buf, table);
+    }
+    let statement = prepare_statement(buf)?;
+    let before = table.pager.cache_stats();
+    let result = statement.execute_streaming(table, &mut |row| {
+        println!("{}", row);
+        Ok(())
+    })?;
+    if let Some(count) = result.count {
+        println!("{}", count);
+    }
+    if STATEMENT_STATS.get() {
+        println!("-- {}", table.pager.cache_stats().since(&before));
+    }
+    Ok(())
+}
+
+fn meta_command(buf: &str, table: &mut Table) -> SqlResult<()> {
+    match buf {
+        #[cfg(feature = "fs")]
+        ".exit" => {
+            table.close()?;
+            std::process::exit(0);
+        }
+        ".btree" => {
+            println!("{}", table.dump()?);
+            return Ok(());
+        }
+        ".check" => {
+            table.check()?;
+            println!("ok");
+            return Ok(());
+        }
+        ".vacuum" => {
+            return table.vacuum();
+        }
+        ".sync" => {
+            return table.sync();
+        }
+        ".stats" => {
+            println!("{}", table.pager.cache_stats());
+            println!("{}", table.pager.io_stats());
+            println!("{}", table.pager.storage_stats()?);
+            println!("{}", table.tree_stats()?);
+            return Ok(());
+        }
+        ".dbinfo" => {
+            println!("{}", table.db_info()?);
+            return Ok(());
+        }
+        ".stats reset" => {
+            table.pager.reset_cache_stats();
+            table.pager.reset_io_stats();
+            return Ok(());
+        }
+        ".stats on" | ".stats off" => {
+            STATEMENT_STATS.set(buf == ".stats on");
+            return Ok(());
+        }
+        ".mode" => {
+            let mode = match OUTPUT_MODE.get() {
+                OutputMode::Text => "text",
+                OutputMode::Json => "json",
+            };
+            println!("{}", mode);
+            return Ok(());
+        }
+        ".mode text" | ".mode json" => {
+            OUTPUT_MODE.set(match buf {
+                ".mode json" => OutputMode::Json,
+                _ => OutputMode::Text,
+            });
+            return Ok(());
+        }
+        _ if buf.starts_with(".meta ") => {
+            return user_meta(&buf[".meta ".len()..], table);
+        }
+        _ if buf.starts_with(".pragma ") => {
+            return pragma(&buf[".pragma ".len()..], table);
+        }
+        #[cfg(feature = "fs")]
+        _ if buf.starts_with(".restore ") => {
+            // .restore --apply-delta <backup> <delta>
+            let args = buf.split_whitespace().skip(1).collect::<Vec<_>>();
+            let [flag, backup, delta] = args.as_slice() else {
+                return Err(SqlError::InvalidArgs);
+            };
+            if *flag != "--apply-delta" {
+                return Err(SqlError::InvalidArgs);
+            }
+            let pages = crate::changes::apply_delta(backup, delta)?;
+            println!("{} pages applied", pages);
+            return Ok(());
+        }
+        #[cfg(feature = "fs")]
+        _ if buf.starts_with(".import ") => {
+            // .import [--bulk] <file>, each line `<id> <name> <email>`
+            let args = buf.split_whitespace().skip(1).collect::<Vec<_>>();
+            let (bulk, path) = match args.as_slice() {
+                ["--bulk", path] => (true, *path),
+                [path] => (false, *path),
+                _ => return Err(SqlError::InvalidArgs),
+            };
+            let rows = read_rows(path)?;
+            let count = if bulk {
+                table.bulk_load(rows.into_iter())?
+            } else {
+                let count = rows.len();
+                for row in rows {
+                    table.insert(row)?;
+                }
+                count
+            };
+            println!("{} rows imported", count);
+            return Ok(());
+        }
+        #[cfg(feature = "fs")]
+        _ if buf.starts_with(".join ") => {
+            // Inner join on id with another database file
+            let mut other = Table::open(buf[".join ".len()..].trim())?;
+            for row in table::join(table, &mut other)? {
+                println!("{}", row);
+            }
+            return other.close();
+        }
+        _ => {
+            return Err(SqlError::UnknownCommand(buf.to_string()));
+        }
+    }
+}
+/// Rows of a file with a line `<id> <name> <email>` for each
+#[cfg(feature = "fs")]
+fn read_rows(path: &str) -> SqlResult<Vec<table::Row>> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| SqlError::IOError(e, format!("Failed to read rows: {}", path)))?;
+    text.lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            let [id, name, email] = line.split_whitespace().collect::<Vec<_>>()[..] else {
+                return Err(SqlError::InvalidArgs);
+            };
+            let id = id
+                .parse::<i64>()
+                .map_err(|_| SqlError::NotNumber(id.to_string()))?;
+            table::Row::try_new(id, name, email)
+        })
+        .collect()
+}
+
+/// `.meta set <key> <value>`, `.meta get <key>`, `.meta delete <key>` and
+/// `.meta list` for the entries of the application in the meta page
+fn user_meta(args: &str, table: &mut Table) -> SqlResult<()> {
+    let args = args.split_whitespace().collect::<Vec<_>>();
+    match args.as_slice() {
+        ["set", key, value] => table.set_meta(key, value.as_bytes()),
+        ["get", key] => {
+            let value = table.get_meta(key)?.ok_or(SqlError::NoData)?;
+            println!("{}", String::from_utf8_lossy(&value));
+            Ok(())
+        }
+        ["delete", key] => match table.delete_meta(key)? {
+            true => Ok(()),
+            false => Err(SqlError::NoData),
+        },
+        ["list"] => {
+            for (key, value) in table.meta_entries()? {
+                println!("{} {}", key, String::from_utf8_lossy(&value));
+            }
+            Ok(())
+        }
+        _ => Err(SqlError::InvalidArgs),
+    }
+}
+
+/// `.pragma <name>` prints a setting and `.pragma <name> <value>` changes it
+fn pragma(args: &str, table: &mut Table) -> SqlResult<()> {
+    let args = args.split_whitespace().collect::<Vec<_>>();
+    match args.as_slice() {
+        ["cache_size"] => {
+            match table.pager.cache_size.get() {
+                Some(cache_size) => println!("{}", cache_size),
+                None => println!("unlimited"),
+            }
+            Ok(())
+        }
+        ["cache_size", "unlimited"] => table.set_cache_size(None),
+        ["cache_size", value] => {
+            let cache_size = value
+                .parse::<usize>()
+                .map_err(|_| SqlError::NotNumber(value.to_string()))?;
+            table.set_cache_size(Some(cache_size))
+        }
+        ["read_ahead"] => {
+            println!("{}", table.pager.read_ahead.get());
+            Ok(())
+        }
+        ["durability"] => {
+            println!("{}", table.durability);
+            Ok(())
+        }
+        ["durability", value] => {
+            table.durability = table::Durability::parse(value).ok_or(SqlError::InvalidArgs)?;
+            Ok(())
+        }
+        ["read_ahead", value] => {
+            let pages = value
+                .parse::<usize>()
+                .map_err(|_| SqlError::NotNumber(value.to_string()))?;
+            table.pager.read_ahead.set(pages);
+            Ok(())
+        }
+        ["bulk_fill"] => {
+            println!("{}", table.bulk_fill());
+            Ok(())
+        }
+        ["bulk_fill", value] => {
+            let percent = value
+                .parse::<usize>()
+                .map_err(|_| SqlError::NotNumber(value.to_string()))?;
+            table.set_bulk_fill(percent)
+        }
+        ["max_cells"] => {
+            let limits = table.node_limits();
+            println!(
+                "{} {}",
+                limits.leaf_max_cells(),
+                limits.internal_max_cells()
+            );
+            Ok(())
+        }
+        ["max_cells", "full"] => {
+            table.set_node_limits(NodeLimits::FULL);
+            Ok(())
+        }
+        ["max_cells", leaf, internal] => {
+            let [leaf, internal] = [leaf, internal].map(|value| {
+                value
+                    .parse::<usize>()
+                    .map_err(|_| SqlError::NotNumber(value.to_string()))
+            });
+            table.set_node_limits(NodeLimits::new(leaf?, internal?)?);
+            Ok(())
+        }
+        _ => Err(SqlError::UnknownCommand(format!(
+            ".pragma {}",
+            args.join(" ")
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::assert_eq;
+
+    use super::*;
+    use crate::{
+        pager::DEFAULT_PAGE_SIZE,
+        storage::MemoryStorage,
+        table::Row,
+        test::{init_test_db, reopen_test_db},
+    };
+    #[test]
+    fn insert_select() {
+        let db = "insert_select";
+        let mut table = init_test_db(db);
+
+        let statement = prepare_statement("insert 1 wass wass@example.com").unwrap();
+        let row = &statement.execute(&mut table).unwrap()[0];
+        assert_eq!(row.id, 1);
+
+        let statement = prepare_statement("insert 2 nnna nnna@example.com").unwrap();
+        let row = &statement.execute(&mut table).unwrap()[0];
+        assert_eq!(row.id, 2);
+
+        let statement = prepare_statement("select 1").unwrap();
+        let row = &statement.execute(&mut table).unwrap()[0];
+        assert_eq!(row, &Row::try_new(1, "wass", "wass@example.com").unwrap());
+    }
+    #[test]
+    fn close_db() {
+        let db = "close_db";
+        let mut table = init_test_db(db);
+
+        let statement = prepare_statement("insert 1 wass wass@example.com").unwrap();
+        let row = &statement.execute(&mut table).unwrap()[0];
+        assert_eq!(row.id, 1);
+
+        table.close().unwrap();
+
+        let mut table = reopen_test_db(db);
+        let statement = prepare_statement("select 1").unwrap();
+        let row = &statement.execute(&mut table).unwrap()[0];
+        assert_eq!(row, &Row::try_new(1, "wass", "wass@example.com").unwrap());
+    }
+    #[test]
+    fn tough_insert() {
+        let db = "tough_insert";
+        let mut table = init_test_db(db);
+
+        let rows = 60;
+        for i in 0..rows {
+            let statement = prepare_statement(&format!("insert {} name{} {}@a", i, i, i)).unwrap();
+            statement.execute(&mut table).unwrap();
+            println!("\n##### {} #####\n{}", i, table);
+        }
+        table.close().unwrap();
+
+        let mut table = reopen_test_db(db);
+        for i in 0..rows {
+            println!("\n##### {} #####\n{}", i, table);
+            let statement = prepare_statement(&format!("select {}", i)).unwrap();
+            let row = &statement.execute(&mut table).unwrap()[0];
+            println!("{}", row);
+            assert_eq!(row.id, i);
+        }
+    }
+
+    #[test]
+    fn tiny_cache() {
+        let db = "tiny_cache";
+        let mut table = init_test_db(db);
+        exec_buf(".pragma cache_size 8", &mut table).unwrap();
+        assert_eq!(table.pager.cache_size.get(), Some(8));
+
+        let rows = 200;
+        for i in 0..rows {
+            let key = i * 37 % rows;
+            exec_buf(&format!("insert {} name{} {}@a", key, key, key), &mut table).unwrap();
+            assert!(table.pager.resident_pages() <= 8);
+        }
+        for i in 0..rows {
+            let statement = prepare_statement(&format!("select {}", i)).unwrap();
+            assert_eq!(statement.execute(&mut table).unwrap()[0].id, i);
+            assert!(table.pager.resident_pages() <= 8);
+        }
+        table.check().unwrap();
+        table.close().unwrap();
+
+        let mut table = reopen_test_db(db);
+        let ids = prepare_statement("select")
+            .unwrap()
+            .execute(&mut table)
+            .unwrap()
+            .iter()
+            .map(|row| row.id)
+            .collect::<Vec<_>>();
+        assert_eq!(ids, (0..rows).collect::<Vec<_>>());
+        assert!(matches!(
+            exec_buf(".pragma cache_size x", &mut table),
+            Err(SqlError::NotNumber(_))
+        ));
+    }
+
+    #[test]
+    fn cache_stats() {
+        let db = "cache_stats";
+        let mut table = init_test_db(db);
+        let rows = 200;
+        for i in 0..rows {
+            let key = i * 37 % rows;
+            exec_buf(&format!("insert {} name{} {}@a", key, key, key), &mut table).unwrap();
+        }
+        table.close().unwrap();
+
+        // The first lookup reads the root and a leaf, later ones find them cached
+        let mut table = reopen_test_db(db);
+        exec_buf(".stats reset", &mut table).unwrap();
+        let lookup = prepare_statement("select 42").unwrap();
+        lookup.execute(&mut table).unwrap();
+        let first = table.pager.cache_stats();
+        assert!(first.misses > 0);
+        for _ in 0..10 {
+            lookup.execute(&mut table).unwrap();
+        }
+        let repeated = table.pager.cache_stats().since(&first);
+        assert_eq!(repeated.misses, 0);
+        assert!(repeated.hits >= 10);
+        assert_eq!(repeated.evictions, 0);
+
+        // A scan over more pages than the cache holds evicts the oldest
+        exec_buf(".pragma cache_size 4", &mut table).unwrap();
+        exec_buf(".stats reset", &mut table).unwrap();
+        prepare_statement("select")
+            .unwrap()
+            .execute(&mut table)
+            .unwrap();
+        let scan = table.pager.cache_stats();
+        assert!(scan.misses > 4);
+        assert!(scan.evictions > 0);
+        // Evicted pages are only written back when changed
+        assert_eq!(scan.writes, 0);
+
+        exec_buf(".stats reset", &mut table).unwrap();
+        assert_eq!(table.pager.cache_stats(), Default::default());
+    }
+
+    #[test]
+    fn corrupt_pages() {
+        // A file of several levels damaged in the page `corrupt` returns,
+        // closed so that the damage is sealed into the image read back
+        let image = |corrupt: &dyn Fn(&mut Table) -> usize| {
+            let storage = MemoryStorage::new();
+            let mut table = Table::open_with(storage.clone()).unwrap();
+            for i in 0..50 {
+                exec_buf(&format!("insert {} name{} {}@a", i, i, i), &mut table).unwrap();
+            }
+            let page_num = corrupt(&mut table);
+            table.close().unwrap();
+            drop(table);
+            (Table::open_with(storage).unwrap(), page_num)
+        };
+        let corrupt_at = |result: SqlResult<()>| match result {
+            Err(SqlError::Corrupt { page, .. }) => page,
+            other => panic!("expected Corrupt, got {:?}", other),
+        };
+
+        // An unknown type byte, the first of the page
+        let (mut table, bad_num) = image(&|table| {
+            let leaf_num = table.find(0).unwrap().page_num;
+            table.pager.node(leaf_num).unwrap().page_mut().buf[0] = 7;
+            leaf_num
+        });
+        assert_eq!(corrupt_at(exec_buf("select 0", &mut table)), bad_num);
+        assert_eq!(corrupt_at(exec_buf("select", &mut table)), bad_num);
+        assert_eq!(corrupt_at(exec_buf(".btree", &mut table)), bad_num);
+        exec_buf("select 49", &mut table).unwrap();
+
+        // More cells than a leaf holds
+        let (mut table, bad_num) = image(&|table| {
+            let leaf_num = table.find(0).unwrap().page_num;
+            table.leaf_mut(leaf_num).unwrap().set_num_cells(1 << 30);
+            leaf_num
+        });
+        assert_eq!(corrupt_at(exec_buf("select 0", &mut table)), bad_num);
+        assert_eq!(corrupt_at(exec_buf("select", &mut table)), bad_num);
+        assert_eq!(corrupt_at(exec_buf(".btree", &mut table)), bad_num);
+        exec_buf("select 49", &mut table).unwrap();
+
+        // A child past the end of the file, which `.btree` shows as missing
+        let (mut table, bad_num) = image(&|table| {
+            let root_num = table.get_root_num().unwrap();
+            let root = table.internal_mut(root_num).unwrap();
+            root.set_child_at(0, 1000).unwrap();
+            root_num
+        });
+        assert_eq!(corrupt_at(exec_buf("select 0", &mut table)), bad_num);
+        assert_eq!(corrupt_at(exec_buf("select", &mut table)), bad_num);
+        assert!(table.dump().unwrap().contains("Node[1000] <missing>"));
+        exec_buf("select 49", &mut table).unwrap();
+
+        // A leaf linked to a next leaf past the end of the file
+        let (mut table, bad_num) = image(&|table| {
+            let leaf_num = table.find(0).unwrap().page_num;
+            table.leaf_mut(leaf_num).unwrap().set_next_leaf(1000);
+            leaf_num
+        });
+        assert_eq!(corrupt_at(exec_buf("select 0", &mut table)), bad_num);
+        assert_eq!(corrupt_at(exec_buf("select", &mut table)), bad_num);
+    }
+
+    #[test]
+    fn json_errors() {
+        let mut table = Table::open_with(MemoryStorage::new()).unwrap();
+        let report = |table: &mut Table, statement: &str| {
+            let e = exec_buf(statement, table).unwrap_err();
+            let (mut out, mut err) = (Vec::new(), Vec::new());
+            report_error(&e, statement, &mut out, &mut err).unwrap();
+            (
+                String::from_utf8(out).unwrap(),
+                String::from_utf8(err).unwrap(),
+            )
+        };
+        exec_buf("insert 5 a a@x", &mut table).unwrap();
+        let (out, err) = report(&mut table, "insert 5 a a@x");
+        assert!(out.starts_with("Error: "));
+        assert!(err.is_empty());
+
+        exec_buf(".mode json", &mut table).unwrap();
+        let (out, err) = report(&mut table, "insert 5 a a@x");
+        assert!(out.is_empty());
+        assert_eq!(err.lines().count(), 1);
+        let json: serde_json::Value = serde_json::from_str(&err).unwrap();
+        assert_eq!(json["error"], "MINISQL_DUP_KEY");
+        assert_eq!(json["message"], SqlError::DuplicateKey.to_string());
+        assert_eq!(json["statement"], "insert 5 a a@x");
+
+        // Quotes, backslashes and control characters stay on the one line
+        let statement = "select \"a\\b\"\t\u{1}";
+        let (_, err) = report(&mut table, statement);
+        assert_eq!(err.lines().count(), 1);
+        let json: serde_json::Value = serde_json::from_str(&err).unwrap();
+        assert_eq!(json["error"], "MINISQL_PARSE");
+        assert_eq!(json["statement"], statement);
+
+        // Results print as they do in text mode
+        exec_buf("select 5", &mut table).unwrap();
+        exec_buf(".mode text", &mut table).unwrap();
+        assert_eq!(OUTPUT_MODE.get(), OutputMode::Text);
+    }
+
+    #[test]
+    fn io_stats() {
+        let storage = MemoryStorage::new();
+        let mut table = Table::open_with(storage.clone()).unwrap();
+        assert_eq!(table.pager.io_stats(), Default::default());
+        for i in 0..50 {
+            exec_buf(&format!("insert {} name{} {}@a", i, i, i), &mut table).unwrap();
+        }
+        table.close().unwrap();
+        let written = table.pager.io_stats();
+        assert_eq!(written.reads, 0);
+        assert_eq!(written.writes, table.pager.num_pages.get() as u64);
+        assert_eq!(
+            written.bytes_written,
+            written.writes * DEFAULT_PAGE_SIZE as u64
+        );
+        assert_eq!(written.syncs, 1);
+        drop(table);
+
+        let table = Table::open_with(storage.clone()).unwrap();
+        let mut height = 0;
+        let mut node = table.pager.node(table.get_root_num().unwrap()).unwrap();
+        while !node.is_leaf() {
+            height += 1;
+            let child = node.internal_node().unwrap().get_child_at(0).unwrap();
+            node = table.pager.node(child).unwrap();
+        }
+        drop(table);
+
+        // A point select reads each level of the tree once after the meta page
+        let mut table = Table::open_with(storage).unwrap();
+        exec_buf(".stats reset", &mut table).unwrap();
+        exec_buf("select 42", &mut table).unwrap();
+        let select = table.pager.io_stats();
+        assert_eq!(select.reads, height + 1);
+        assert_eq!(select.bytes_read, select.reads * DEFAULT_PAGE_SIZE as u64);
+        assert_eq!(select.writes, 0);
+
+        // An insert flushed on its own writes the pages it changed and syncs
+        exec_buf(".stats reset", &mut table).unwrap();
+        exec_buf("insert 50 name50 50@a", &mut table).unwrap();
+        let dirty = (0..table.pager.num_pages.get())
+            .filter(|&n| table.pager.is_dirty(n))
+            .count();
+        table.flush_all().unwrap();
+        let insert = table.pager.io_stats();
+        assert_eq!(insert.writes, dirty as u64);
+        assert_eq!(insert.syncs, 1);
+        exec_buf(".stats reset", &mut table).unwrap();
+        assert_eq!(table.pager.io_stats(), Default::default());
+    }
+
+    #[test]
+    fn pragma_durability() {
+        let mut table = init_test_db("pragma_durability");
+        assert_eq!(table.durability, table::Durability::OnCommit);
+        exec_buf(".pragma durability on_close", &mut table).unwrap();
+        assert_eq!(table.durability, table::Durability::OnClose);
+        assert_eq!(table.durability.to_string(), "on_close");
+        assert!(matches!(
+            exec_buf(".pragma durability sometimes", &mut table),
+            Err(SqlError::InvalidArgs)
+        ));
+        assert_eq!(table.durability, table::Durability::OnClose);
+    }
+
+    #[test]
+    fn read_ahead_scan() {
+        let db = "read_ahead_scan";
+        let mut table = init_test_db(db);
+        let rows = 200;
+        for i in 0..rows {
+            let key = i * 37 % rows;
+            exec_buf(&format!("insert {} name{} {}@a", key, key, key), &mut table).unwrap();
+        }
+        table.close().unwrap();
+
+        // Crossing onto a leaf not in the cache reads the pages after it too
+        let mut table = reopen_test_db(db);
+        exec_buf(".pragma read_ahead 16", &mut table).unwrap();
+        let mut cursor = table.start().unwrap();
+        let first_leaf = cursor.page_num;
+        let before = cursor.table.pager.resident_pages();
+        while cursor.page_num == first_leaf {
+            cursor.advance().unwrap();
+        }
+        assert!(cursor.table.pager.resident_pages() > before + 1);
+
+        // Rows are unchanged with a cache smaller than the read-ahead
+        exec_buf(".pragma cache_size 8", &mut table).unwrap();
+        let ids = prepare_statement("select")
+            .unwrap()
+            .execute(&mut table)
+            .unwrap()
+            .iter()
+            .map(|row| row.id)
+            .collect::<Vec<_>>();
+        assert_eq!(ids, (0..rows).collect::<Vec<_>>());
+        assert!(table.pager.resident_pages() <= 8);
+        let keys = table
+            .keys()
+            .unwrap()
+            .collect::<SqlResult<Vec<_>>>()
+            .unwrap();
+        assert_eq!(keys, (0..rows).collect::<Vec<_>>());
+        table.check().unwrap();
+    }
+
+    #[test]
+    fn max_cells_pragma() {
+        let mut table = init_test_db("max_cells_pragma");
+        exec_buf(".pragma max_cells 8 3", &mut table).unwrap();
+        assert_eq!(table.node_limits(), NodeLimits::new(8, 3).unwrap());
+        for i in 0..100 {
+            exec_buf(&format!("insert {} a a@a", i), &mut table).unwrap();
+        }
+        table.check().unwrap();
+        let root = table.internal_mut(table.get_root_num().unwrap()).unwrap();
+        assert!(root.get_num_children() <= 3);
+
+        exec_buf(".pragma max_cells full", &mut table).unwrap();
+        assert_eq!(table.node_limits(), NodeLimits::FULL);
+        assert!(matches!(
+            exec_buf(".pragma max_cells 1 4", &mut table),
+            Err(SqlError::InvalidMaxCells(1))
+        ));
+        assert!(matches!(
+            exec_buf(".pragma max_cells 4 x", &mut table),
+            Err(SqlError::NotNumber(_))
+        ));
+        assert_eq!(table.node_limits(), NodeLimits::FULL);
+    }
+
+    #[test]
+    fn import_rows() {
+        let path = "./forTest/import_rows.txt";
+        let lines = (0..50)
+            .map(|i| format!("{} name{} a{}@a", i, i, i))
+            .collect::<Vec<_>>();
+        std::fs::write(path, lines.join("\n")).unwrap();
+        for (db, command) in [
+            ("import_rows", ".import"),
+            ("import_rows_bulk", ".import --bulk"),
+        ] {
+            let mut table = init_test_db(db);
+            exec_buf(&format!("{} {}", command, path), &mut table).unwrap();
+            table.check().unwrap();
+            assert_eq!(table.len().unwrap(), 50);
+            assert_eq!(table.get(49).unwrap().unwrap().name_str(), "name49");
+        }
+
+        // Rows out of order stop a bulk load, and rows loaded row by row
+        // fail on a duplicate id
+        std::fs::write(path, "1 a a@a\n3 b b@b\n2 c c@c\n").unwrap();
+        let mut table = init_test_db("import_rows_unsorted");
+        assert!(matches!(
+            exec_buf(&format!(".import --bulk {}", path), &mut table),
+            Err(SqlError::UnsortedRows {
+                position: 2,
+                key: 2
+            })
+        ));
+        assert_eq!(table.len().unwrap(), 0);
+        std::fs::write(path, "1 a a@a\n1 b b@b\n").unwrap();
+        assert!(matches!(
+            exec_buf(&format!(".import {}", path), &mut table),
+            Err(SqlError::DuplicateKey)
+        ));
+        assert!(matches!(
+            exec_buf(&format!(".import --bulk {}", path), &mut table),
+            Err(SqlError::NotEmpty)
+        ));
+        std::fs::write(path, "1 a\n").unwrap();
+        assert!(matches!(
+            exec_buf(&format!(".import {}", path), &mut table),
+            Err(SqlError::InvalidArgs)
+        ));
+    }
+
+    #[test]
+    fn select_all() {
+        let db = "select_all";
+        let mut table = init_test_db(db);
+
+        let num_rows = 12;
+        for i in 0..num_rows {
+            let statement = prepare_statement(&format!("insert {} name{} {}@a", i, i, i)).unwrap();
+            statement.execute(&mut table).unwrap();
+        }
+        table.close().unwrap();
+
+        let mut table = reopen_test_db(db);
+        println!("{}", table);
+        let statement = prepare_statement("select").unwrap();
+        let rows = statement.execute(&mut table).unwrap();
+        assert_eq!(rows.len(), num_rows);
+        for i in 0..num_rows {
+            let row = &rows[i];
+            println!("{}", row);
+            assert_eq!(row.id, i as i64);
+        }
+    }
+
+    #[test]
+    fn random_insert() {
+        let db = "random_insert";
+        let mut table = init_test_db(db);
+        let order = vec![9, 17, 5, 4, 6, 8, 11, 2, 1, 0, 7, 21, 15, 12, 14, 20, 13];
+        for i in &order {
+            let statement = prepare_statement(&format!("insert {} name{} {}@a", i, i, i)).unwrap();
+            println!("##### {} #####", i);
+            statement.execute(&mut table).unwrap();
+            println!("{}", table);
+        }
+
+        for i in &order {
+            let statement = prepare_statement(&format!("select {}", i)).unwrap();
+            let row = &statement.execute(&mut table).unwrap()[0];
+            assert_eq!(row.id, *i);
+        }
+    }
+    #[test]
+    fn remove_single() {
+        let db = "random_insert";
+        let mut table = init_test_db(db);
+        let order = vec![9, 17, 3, 2, 6];
+        for i in &order {
+            let statement = prepare_statement(&format!("insert {} name{} {}@a", i, i, i)).unwrap();
+            println!("##### ins {} #####", i);
+            statement.execute(&mut table).unwrap();
+            println!("{}", table);
+        }
+
+        for i in &order {
+            let statement = prepare_statement(&format!("delete {}", i)).unwrap();
+            println!("##### del {} #####", i);
+            statement.execute(&mut table).unwrap();
+            println!("{}", table);
+        }
+    }
+    #[test]
+    fn update() {
+        let db = "update";
+        let mut table = init_test_db(db);
+        let order = vec![9, 17, 3, 2, 6];
+        for i in &order {
+            let statement = prepare_statement(&format!("insert {} name{} {}@a", i, i, i)).unwrap();
+            println!("##### ins {} #####", i);
+            statement.execute(&mut table).unwrap();
+            println!("{}", table);
+        }
+
+        for i in &order {
+            let statement = prepare_statement(&format!("update {} name{} {}@b", i, i, i)).unwrap();
+            println!("##### upd {} #####", i);
+            statement.execute(&mut table).unwrap();
+            println!("{}", table);
+        }
+
+        for i in &order {
+            let statement = prepare_statement(&format!("select {}", i)).unwrap();
+            let row = &statement.execute(&mut table).unwrap()[0];
+            let expected = Row::try_new(*i, &format!("name{}", i), &format!("{}@b", i)).unwrap();
+            assert_eq!(row, &expected);
+        }
+    }
+    #[test]
+    fn count() {
+        let db = "count";
+        let mut table = init_test_db(db);
+        let count = |table: &mut Table| {
+            let statement = prepare_statement("count").unwrap();
+            statement.execute(table).unwrap().count.unwrap()
+        };
+        assert_eq!(count(&mut table), 0);
+
+        let order = vec![0, 4, 5, 6, 3, 2, 1];
+        for i in &order {
+            let statement = prepare_statement(&format!("insert {} name{} {}@a", i, i, i)).unwrap();
+            statement.execute(&mut table).unwrap();
+        }
+        assert_eq!(count(&mut table), order.len());
+
+        let statement = prepare_statement("insert 3 dup dup@a").unwrap();
+        assert!(statement.execute(&mut table).is_err());
+        assert_eq!(count(&mut table), order.len());
+
+        let removes = vec![1, 2, 5, 6, 3];
+        for i in &removes {
+            let statement = prepare_statement(&format!("delete {}", i)).unwrap();
+            statement.execute(&mut table).unwrap();
+        }
+        assert_eq!(count(&mut table), order.len() - removes.len());
+        table.check().unwrap();
+        table.close().unwrap();
+
+        let mut table = reopen_test_db(db);
+        assert_eq!(count(&mut table), order.len() - removes.len());
+        table.check().unwrap();
+    }
+    #[test]
+    fn signed_keys() {
+        let db = "signed_keys";
+        let mut table = init_test_db(db);
+        let order = vec![3, -5, 0, i64::MAX, -1, 12, i64::MIN, -300, 7, 1, -2];
+        for i in &order {
+            let statement = prepare_statement(&format!("insert {} name{} {}@a", i, i, i)).unwrap();
+            statement.execute(&mut table).unwrap();
+        }
+        table.close().unwrap();
+
+        let mut table = reopen_test_db(db);
+        let statement = prepare_statement("select -5").unwrap();
+        let row = &statement.execute(&mut table).unwrap()[0];
+        assert_eq!(row.id, -5);
+        assert_eq!(
+            format!("{}", row),
+            "Row { id: -5, name: name-5, email: -5@a }"
+        );
+
+        let statement = prepare_statement("select").unwrap();
+        let ids = statement
+            .execute(&mut table)
+            .unwrap()
+            .iter()
+            .map(|row| row.id)
+            .collect::<Vec<_>>();
+        let mut sorted = order.clone();
+        sorted.sort();
+        assert_eq!(ids, sorted);
+    }
+    #[test]
+    fn auto_increment() {
+        let db = "auto_increment";
+        let mut table = init_test_db(db);
+        let insert = |table: &mut Table, buf: &str| {
+            let statement = prepare_statement(buf).unwrap();
+            statement.execute(table).unwrap()[0].id
+        };
+        assert_eq!(insert(&mut table, "insert a a@x"), 1);
+        assert_eq!(insert(&mut table, "insert b b@x"), 2);
+        assert_eq!(insert(&mut table, "insert 10 c c@x"), 10);
+        assert_eq!(insert(&mut table, "insert d d@x"), 11);
+        assert_eq!(insert(&mut table, "insert 5 e e@x"), 5);
+        assert_eq!(insert(&mut table, "insert -7 f f@x"), -7);
+        assert_eq!(insert(&mut table, "insert g g@x"), 12);
+
+        // The deleted maximum is not reused
+        let statement = prepare_statement("delete 12").unwrap();
+        statement.execute(&mut table).unwrap();
+        assert_eq!(insert(&mut table, "insert h h@x"), 13);
+        table.close().unwrap();
+
+        let mut table = reopen_test_db(db);
+        assert_eq!(insert(&mut table, "insert i i@x"), 14);
+
+        let statement = prepare_statement("select").unwrap();
+        let mut ids = statement
+            .execute(&mut table)
+            .unwrap()
+            .iter()
+            .map(|row| row.id)
+            .collect::<Vec<_>>();
+        assert_eq!(ids, vec![-7, 1, 2, 5, 10, 11, 13, 14]);
+        ids.dedup();
+        assert_eq!(ids.len(), 8);
+    }
+    #[test]
+    fn select_all_streaming() {
+        let db = "select_all_streaming";
+        let mut table = init_test_db(db);
+        for i in 0..30 {
+            let statement = prepare_statement(&format!("insert {} name{} {}@a", i, i, i)).unwrap();
+            statement.execute(&mut table).unwrap();
+        }
+
+        let statement = prepare_statement("select").unwrap();
+        let rows = statement.execute(&mut table).unwrap();
+        let mut streamed = Vec::new();
+        statement
+            .execute_streaming(&mut table, &mut |row| {
+                streamed.push(format!("{}", row));
+                Ok(())
+            })
+            .unwrap();
+        let materialized = rows
+            .iter()
+            .map(|row| format!("{}", row))
+            .collect::<Vec<_>>();
+        assert_eq!(streamed, materialized);
+
+        // Stop after the third row
+        let mut seen = 0;
+        let result = statement.execute_streaming(&mut table, &mut |_| {
+            seen += 1;
+            if seen == 3 {
+                return Err(SqlError::NoData);
+            }
+            Ok(())
+        });
+        assert!(matches!(result, Err(SqlError::NoData)));
+        assert_eq!(seen, 3);
+
+        let statement = prepare_statement("select 4").unwrap();
+        let mut streamed = Vec::new();
+        statement
+            .execute_streaming(&mut table, &mut |row| {
+                streamed.push(row.id());
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(streamed, vec![4]);
+    }
+    #[test]
+    fn select_all_snapshot() {
+        let db = "select_all_snapshot";
+        let mut table = init_test_db(db);
+        for i in 0..15 {
+            let statement = prepare_statement(&format!("insert {} name{} {}@a", i * 2, i, i));
+            statement.unwrap().execute(&mut table).unwrap();
+        }
+        let statement = prepare_statement("select").unwrap();
+        let before = statement.execute(&mut table).unwrap();
+
+        let mut streamed = Vec::new();
+        statement
+            .execute_streaming_with(&mut table, &mut |row, table| {
+                streamed.push(row.to_owned());
+                if row.id() == 10 {
+                    // Split the leaf being scanned and the ones after it
+                    for i in 0..6 {
+                        let key = 11 + i * 2;
+                        let row = Row::try_new(key, "new", "new@a")?;
+                        table.insert(row)?;
+                    }
+                }
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(streamed, *before);
+
+        let after = statement.execute(&mut table).unwrap();
+        assert_eq!(after.len(), 21);
+        table.check().unwrap();
+    }
+    #[test]
+    fn select_nth() {
+        let db = "select_nth";
+        let mut table = init_test_db(db);
+        // Shuffled keys build a tree with two levels of internal nodes
+        for i in 0..51 {
+            let key = i * 7 % 51;
+            let statement = prepare_statement(&format!("insert {} name{} {}@a", key, key, key));
+            statement.unwrap().execute(&mut table).unwrap();
+        }
+        let root = table.internal_ref(table.get_root_num().unwrap()).unwrap();
+        let child = table.pager.node(root.get_child_at(0).unwrap()).unwrap();
+        assert!(child.is_internal());
+
+        let median = prepare_statement("select nth 25").unwrap();
+        assert_eq!(median.execute(&mut table).unwrap()[0].id, 25);
+        table.close().unwrap();
+        drop(table);
+
+        let mut table = reopen_test_db(db);
+        assert_eq!(median.execute(&mut table).unwrap()[0].id, 25);
+        prepare_statement("delete 3")
+            .unwrap()
+            .execute(&mut table)
+            .unwrap();
+        assert_eq!(median.execute(&mut table).unwrap()[0].id, 26);
+        assert!(matches!(
+            prepare_statement("select nth 50")
+                .unwrap()
+                .execute(&mut table),
+            Err(SqlError::NoData)
+        ));
+        assert!(matches!(
+            prepare_statement("select nth x"),
+            Err(SqlError::ParseError { offset: 11, .. })
+        ));
+        table.check().unwrap();
+    }
+    #[test]
+    fn parse_errors() {
+        let error = |statement: &str| match prepare_statement(statement) {
+            Err(SqlError::ParseError {
+                input,
+                offset,
+                expected,
+                found,
+            }) => {
+                assert_eq!(input, statement);
+                (offset, expected, found)
+            }
+            other => panic!("expected ParseError, got {:?}", other),
+        };
+        let at = |offset: usize, expected: &str, found: &str| {
+            (offset, expected.to_string(), found.to_string())
+        };
+        assert_eq!(error("select abc"), at(7, "an id", "abc"));
+        assert_eq!(error("insert x1 a a@a"), at(7, "an id", "x1"));
+        let name = "わ".repeat(11);
+        assert_eq!(
+            error(&format!("insert 1 {} a@a", name)),
+            at(9, "a name of at most 31 bytes", &name)
+        );
+        assert_eq!(
+            error(&format!("update 2 a {}", "e".repeat(255))),
+            at(11, "an email of at most 254 bytes", &"e".repeat(255))
+        );
+        assert_eq!(
+            error("delete 3 now"),
+            at(9, "the end of the statement", "now")
+        );
+        assert_eq!(
+            error("insert 1 a a@a extra"),
+            at(15, "the end of the statement", "extra")
+        );
+        assert_eq!(error("count 1"), at(6, "the end of the statement", "1"));
+        // A missing word is expected at the end
+        assert_eq!(error("select nth"), at(10, "a position", ""));
+        assert_eq!(error("update 4 a"), at(10, "an email", ""));
+        assert_eq!(
+            error("update 4 age=3"),
+            at(9, "name=<value> or email=<value>", "age=3")
+        );
+        let e = prepare_statement("select abc").unwrap_err();
+        assert_eq!(e.to_string(), "expected an id at byte 7, found 'abc'");
+    }
+    #[test]
+    fn select_all_lazy() {
+        let db = "select_all_lazy";
+        let mut table = init_test_db(db);
+        for i in 0..60 {
+            let statement = prepare_statement(&format!("insert {} name{} {}@a", i, i, i)).unwrap();
+            statement.execute(&mut table).unwrap();
+        }
+        table.close().unwrap();
+        drop(table);
+
+        let loaded = |table: &Table| table.pager.pages.borrow().iter().flatten().count();
+        let statement = prepare_statement("select").unwrap();
+        let mut table = reopen_test_db(db);
+        let first = statement.execute_iter(&mut table).unwrap().next();
+        assert_eq!(first.unwrap().unwrap().id, 0);
+        let partial = loaded(&table);
+
+        let rows = statement.execute_iter(&mut table).unwrap();
+        let ids = rows.map(|row| row.unwrap().id).collect::<Vec<_>>();
+        assert_eq!(ids, (0..60).collect::<Vec<_>>());
+        assert!(partial * 3 < loaded(&table));
+
+        let statement = prepare_statement("count").unwrap();
+        let mut rows = statement.execute_iter(&mut table).unwrap();
+        assert!(rows.next().is_none());
+        assert_eq!(rows.count, Some(60));
+    }
+}
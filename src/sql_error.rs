@@ -1,14 +1,482 @@
+use std::fmt::{Display, Formatter};
+
 #[derive(Debug)]
 pub enum SqlError {
     UnknownCommand(String),
     InvalidArgs,
-    TooLargeString,
+    /// The named column does not fit its buffer
+    TooLargeString(String),
     NotNumber(String),
+    /// A statement that does not parse, at the word starting `offset`
+    /// bytes into `input`, or at its end when `found` is empty
+    ParseError {
+        input: String,
+        offset: usize,
+        expected: String,
+        found: String,
+    },
     IOError(std::io::Error, String),
+    /// The file would grow past the page limit set on the pager
     TableFull,
+    /// Memory for the page cache could not be allocated
+    OutOfMemory,
     CorruptFile,
+    /// The file does not start with the header of a database
+    NotADatabase,
+    /// Page sizes must be powers of two between 4096 and 65536 bytes
+    InvalidPageSize(usize),
+    /// Nodes must split at 3 cells or more, and at no more than fit in a page
+    InvalidMaxCells(usize),
+    /// The file ended partway through a page
+    CorruptPage {
+        page: usize,
+    },
+    /// A page read from the file does not match its checksum
+    ChecksumMismatch {
+        page: usize,
+    },
+    /// A page whose contents do not make sense
+    Corrupt {
+        page: usize,
+        detail: String,
+    },
+    /// A page number past the last page of the database
+    PageOutOfRange {
+        page: usize,
+    },
+    /// Every page the cache may hold is pinned by operations in progress,
+    /// besides the meta page
+    CachePinned,
+    /// A page is borrowed for writing elsewhere, or for reading while
+    /// writing to it
+    PageBusy,
     DuplicateKey,
+    /// Bytes written to a value would end at `end`, past its `len` bytes
+    ValueOutOfRange {
+        end: usize,
+        len: usize,
+    },
+    /// Rows loaded in bulk must come in increasing key order, and the row
+    /// at `position` does not
+    UnsortedRows {
+        position: usize,
+        key: i64,
+    },
+    /// The entries set with `Table::set_meta` would not fit in the space the
+    /// meta page keeps for them
+    MetaFull,
+    /// Rows are only loaded in bulk into an empty table
+    NotEmpty,
     NoData,
+    /// The tree changed since the cursor was placed, so its position may
+    /// point at another row or a freed page
+    CursorInvalidated,
+    Closed,
+    CheckFailed(String),
+    /// Pages that could not be written when flushing, each with its failure.
+    /// The other pages were written.
+    CloseIncomplete(Vec<(usize, SqlError)>),
+    /// The file is larger than the allowed number of bytes
+    FileTooLarge {
+        size: usize,
+        limit: usize,
+    },
+    UnsupportedVersion {
+        found: u32,
+        supported: u32,
+    },
+    /// A followed journal no longer starts with the statements already applied
+    Diverged,
+    /// The database is encrypted and was opened without a key
+    KeyRequired,
+    /// The key does not match the one the database was encrypted with
+    #[cfg(feature = "encryption")]
+    WrongKey,
+    /// A key was given for a database that is not encrypted
+    NotEncrypted,
+    /// Pages written since a change counter are not known, because changes
+    /// are not tracked or were tracked from later on
+    UnknownChanges {
+        since: u64,
+    },
+    /// The file holds compressed pages and was opened as a plain file
+    Compressed,
+    /// The file was opened as compressed pages but holds plain pages
+    #[cfg(feature = "compression")]
+    NotCompressed,
 }
 
 pub type SqlResult<T> = Result<T, SqlError>;
+
+impl SqlError {
+    /// Stable name of the kind of error, for scripts to match on
+    pub fn code(&self) -> &'static str {
+        match self {
+            SqlError::UnknownCommand(_) => "MINISQL_UNKNOWN_COMMAND",
+            SqlError::InvalidArgs => "MINISQL_INVALID_ARGS",
+            SqlError::TooLargeString(_) => "MINISQL_TOO_LONG",
+            SqlError::NotNumber(_) => "MINISQL_NOT_NUMBER",
+            SqlError::ParseError { .. } => "MINISQL_PARSE",
+            SqlError::IOError(..) => "MINISQL_IO",
+            SqlError::TableFull => "MINISQL_FULL",
+            SqlError::OutOfMemory => "MINISQL_NO_MEMORY",
+            SqlError::CorruptFile => "MINISQL_CORRUPT_FILE",
+            SqlError::NotADatabase => "MINISQL_NOT_A_DATABASE",
+            SqlError::InvalidPageSize(_) => "MINISQL_PAGE_SIZE",
+            SqlError::InvalidMaxCells(_) => "MINISQL_MAX_CELLS",
+            SqlError::CorruptPage { .. } => "MINISQL_SHORT_PAGE",
+            SqlError::ChecksumMismatch { .. } => "MINISQL_CHECKSUM",
+            SqlError::Corrupt { .. } => "MINISQL_CORRUPT",
+            SqlError::PageOutOfRange { .. } => "MINISQL_PAGE_RANGE",
+            SqlError::CachePinned => "MINISQL_CACHE_PINNED",
+            SqlError::PageBusy => "MINISQL_BUSY",
+            SqlError::DuplicateKey => "MINISQL_DUP_KEY",
+            SqlError::ValueOutOfRange { .. } => "MINISQL_VALUE_RANGE",
+            SqlError::UnsortedRows { .. } => "MINISQL_UNSORTED",
+            SqlError::MetaFull => "MINISQL_META_FULL",
+            SqlError::NotEmpty => "MINISQL_NOT_EMPTY",
+            SqlError::NoData => "MINISQL_NO_DATA",
+            SqlError::CursorInvalidated => "MINISQL_STALE_CURSOR",
+            SqlError::Closed => "MINISQL_CLOSED",
+            SqlError::CheckFailed(_) => "MINISQL_CHECK_FAILED",
+            SqlError::CloseIncomplete(_) => "MINISQL_CLOSE_INCOMPLETE",
+            SqlError::FileTooLarge { .. } => "MINISQL_FILE_TOO_LARGE",
+            SqlError::UnsupportedVersion { .. } => "MINISQL_VERSION",
+            SqlError::Diverged => "MINISQL_DIVERGED",
+            SqlError::KeyRequired => "MINISQL_KEY_REQUIRED",
+            #[cfg(feature = "encryption")]
+            SqlError::WrongKey => "MINISQL_WRONG_KEY",
+            SqlError::NotEncrypted => "MINISQL_NOT_ENCRYPTED",
+            SqlError::UnknownChanges { .. } => "MINISQL_UNKNOWN_CHANGES",
+            SqlError::Compressed => "MINISQL_COMPRESSED",
+            #[cfg(feature = "compression")]
+            SqlError::NotCompressed => "MINISQL_NOT_COMPRESSED",
+        }
+    }
+}
+
+impl Display for SqlError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SqlError::UnknownCommand(command) => write!(f, "unknown command '{}'", command),
+            SqlError::InvalidArgs => write!(f, "invalid arguments"),
+            SqlError::TooLargeString(column) => write!(f, "{} is too long", column),
+            SqlError::NotNumber(s) => write!(f, "'{}' is not a valid number", s),
+            SqlError::ParseError {
+                offset,
+                expected,
+                found,
+                ..
+            } => match found.is_empty() {
+                true => write!(f, "expected {} at byte {}", expected, offset),
+                false => write!(
+                    f,
+                    "expected {} at byte {}, found '{}'",
+                    expected, offset, found
+                ),
+            },
+            SqlError::IOError(e, what) => write!(f, "{}: {}", what, e),
+            SqlError::TableFull => write!(f, "the table is full"),
+            SqlError::OutOfMemory => write!(f, "out of memory for the page cache"),
+            SqlError::CorruptFile => write!(f, "the file is corrupt"),
+            SqlError::NotADatabase => write!(f, "the file is not a database"),
+            SqlError::InvalidPageSize(size) => write!(
+                f,
+                "page size {} is not a power of two from 4096 to 65536",
+                size
+            ),
+            SqlError::InvalidMaxCells(cells) => {
+                write!(f, "nodes cannot split at {} cells", cells)
+            }
+            SqlError::CorruptPage { page } => write!(f, "page {} is cut short", page),
+            SqlError::ChecksumMismatch { page } => {
+                write!(f, "page {} does not match its checksum", page)
+            }
+            SqlError::Corrupt { page, detail } => write!(f, "page {} is corrupt: {}", page, detail),
+            SqlError::PageOutOfRange { page } => {
+                write!(f, "page {} is past the end of the database", page)
+            }
+            SqlError::CachePinned => write!(f, "every page in the cache is pinned"),
+            SqlError::PageBusy => write!(f, "the page is in use"),
+            SqlError::DuplicateKey => write!(f, "a row with the key already exists"),
+            SqlError::ValueOutOfRange { end, len } => {
+                write!(f, "bytes up to {} run past a value of {} bytes", end, len)
+            }
+            SqlError::UnsortedRows { position, key } => {
+                write!(f, "row {} with key {} is out of order", position, key)
+            }
+            SqlError::MetaFull => write!(f, "no room left for meta entries"),
+            SqlError::NotEmpty => write!(f, "the table is not empty"),
+            SqlError::NoData => write!(f, "no such row"),
+            SqlError::CursorInvalidated => {
+                write!(f, "the tree changed since the cursor was placed")
+            }
+            SqlError::Closed => write!(f, "the table is closed"),
+            SqlError::CheckFailed(detail) => write!(f, "check failed: {}", detail),
+            SqlError::CloseIncomplete(failures) => {
+                write!(f, "{} pages were not written", failures.len())
+            }
+            SqlError::FileTooLarge { size, limit } => write!(
+                f,
+                "the file of {} bytes is larger than the limit of {} bytes",
+                size, limit
+            ),
+            SqlError::UnsupportedVersion { found, supported } => write!(
+                f,
+                "format version {} is newer than the supported version {}",
+                found, supported
+            ),
+            SqlError::Diverged => write!(
+                f,
+                "the followed journal no longer starts with the applied statements"
+            ),
+            SqlError::KeyRequired => write!(f, "the database is encrypted and needs a key"),
+            #[cfg(feature = "encryption")]
+            SqlError::WrongKey => write!(f, "the key does not match the database"),
+            SqlError::NotEncrypted => write!(f, "the database is not encrypted"),
+            SqlError::UnknownChanges { since } => {
+                write!(f, "the pages changed since {} are not known", since)
+            }
+            SqlError::Compressed => write!(f, "the file holds compressed pages"),
+            #[cfg(feature = "compression")]
+            SqlError::NotCompressed => write!(f, "the file does not hold compressed pages"),
+        }
+    }
+}
+
+impl std::error::Error for SqlError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SqlError::IOError(e, _) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::error::Error;
+
+    #[test]
+    fn messages() {
+        let io = || std::io::Error::from(std::io::ErrorKind::NotFound);
+        let cases = [
+            (
+                SqlError::UnknownCommand(".foo".to_string()),
+                "unknown command '.foo'",
+                "MINISQL_UNKNOWN_COMMAND",
+            ),
+            (
+                SqlError::InvalidArgs,
+                "invalid arguments",
+                "MINISQL_INVALID_ARGS",
+            ),
+            (
+                SqlError::TooLargeString("name".to_string()),
+                "name is too long",
+                "MINISQL_TOO_LONG",
+            ),
+            (
+                SqlError::NotNumber("abc".to_string()),
+                "'abc' is not a valid number",
+                "MINISQL_NOT_NUMBER",
+            ),
+            (
+                SqlError::ParseError {
+                    input: "select x".to_string(),
+                    offset: 7,
+                    expected: "an id".to_string(),
+                    found: "x".to_string(),
+                },
+                "expected an id at byte 7, found 'x'",
+                "MINISQL_PARSE",
+            ),
+            (
+                SqlError::ParseError {
+                    input: "delete".to_string(),
+                    offset: 6,
+                    expected: "an id".to_string(),
+                    found: String::new(),
+                },
+                "expected an id at byte 6",
+                "MINISQL_PARSE",
+            ),
+            (
+                SqlError::IOError(io(), "Failed to open db".to_string()),
+                "Failed to open db: entity not found",
+                "MINISQL_IO",
+            ),
+            (SqlError::TableFull, "the table is full", "MINISQL_FULL"),
+            (
+                SqlError::OutOfMemory,
+                "out of memory for the page cache",
+                "MINISQL_NO_MEMORY",
+            ),
+            (
+                SqlError::CorruptFile,
+                "the file is corrupt",
+                "MINISQL_CORRUPT_FILE",
+            ),
+            (
+                SqlError::NotADatabase,
+                "the file is not a database",
+                "MINISQL_NOT_A_DATABASE",
+            ),
+            (
+                SqlError::InvalidPageSize(100),
+                "page size 100 is not a power of two from 4096 to 65536",
+                "MINISQL_PAGE_SIZE",
+            ),
+            (
+                SqlError::InvalidMaxCells(2),
+                "nodes cannot split at 2 cells",
+                "MINISQL_MAX_CELLS",
+            ),
+            (
+                SqlError::CorruptPage { page: 3 },
+                "page 3 is cut short",
+                "MINISQL_SHORT_PAGE",
+            ),
+            (
+                SqlError::ChecksumMismatch { page: 3 },
+                "page 3 does not match its checksum",
+                "MINISQL_CHECKSUM",
+            ),
+            (
+                SqlError::Corrupt {
+                    page: 3,
+                    detail: "bad cell".to_string(),
+                },
+                "page 3 is corrupt: bad cell",
+                "MINISQL_CORRUPT",
+            ),
+            (
+                SqlError::PageOutOfRange { page: 9 },
+                "page 9 is past the end of the database",
+                "MINISQL_PAGE_RANGE",
+            ),
+            (
+                SqlError::CachePinned,
+                "every page in the cache is pinned",
+                "MINISQL_CACHE_PINNED",
+            ),
+            (SqlError::PageBusy, "the page is in use", "MINISQL_BUSY"),
+            (
+                SqlError::DuplicateKey,
+                "a row with the key already exists",
+                "MINISQL_DUP_KEY",
+            ),
+            (
+                SqlError::ValueOutOfRange { end: 10, len: 8 },
+                "bytes up to 10 run past a value of 8 bytes",
+                "MINISQL_VALUE_RANGE",
+            ),
+            (
+                SqlError::UnsortedRows {
+                    position: 2,
+                    key: -1,
+                },
+                "row 2 with key -1 is out of order",
+                "MINISQL_UNSORTED",
+            ),
+            (
+                SqlError::MetaFull,
+                "no room left for meta entries",
+                "MINISQL_META_FULL",
+            ),
+            (
+                SqlError::NotEmpty,
+                "the table is not empty",
+                "MINISQL_NOT_EMPTY",
+            ),
+            (SqlError::NoData, "no such row", "MINISQL_NO_DATA"),
+            (
+                SqlError::CursorInvalidated,
+                "the tree changed since the cursor was placed",
+                "MINISQL_STALE_CURSOR",
+            ),
+            (SqlError::Closed, "the table is closed", "MINISQL_CLOSED"),
+            (
+                SqlError::CheckFailed("2 rows".to_string()),
+                "check failed: 2 rows",
+                "MINISQL_CHECK_FAILED",
+            ),
+            (
+                SqlError::CloseIncomplete(vec![(4, SqlError::PageBusy)]),
+                "1 pages were not written",
+                "MINISQL_CLOSE_INCOMPLETE",
+            ),
+            (
+                SqlError::FileTooLarge {
+                    size: 100,
+                    limit: 50,
+                },
+                "the file of 100 bytes is larger than the limit of 50 bytes",
+                "MINISQL_FILE_TOO_LARGE",
+            ),
+            (
+                SqlError::UnsupportedVersion {
+                    found: 30,
+                    supported: 18,
+                },
+                "format version 30 is newer than the supported version 18",
+                "MINISQL_VERSION",
+            ),
+            (
+                SqlError::Diverged,
+                "the followed journal no longer starts with the applied statements",
+                "MINISQL_DIVERGED",
+            ),
+            (
+                SqlError::KeyRequired,
+                "the database is encrypted and needs a key",
+                "MINISQL_KEY_REQUIRED",
+            ),
+            #[cfg(feature = "encryption")]
+            (
+                SqlError::WrongKey,
+                "the key does not match the database",
+                "MINISQL_WRONG_KEY",
+            ),
+            (
+                SqlError::NotEncrypted,
+                "the database is not encrypted",
+                "MINISQL_NOT_ENCRYPTED",
+            ),
+            (
+                SqlError::UnknownChanges { since: 5 },
+                "the pages changed since 5 are not known",
+                "MINISQL_UNKNOWN_CHANGES",
+            ),
+            (
+                SqlError::Compressed,
+                "the file holds compressed pages",
+                "MINISQL_COMPRESSED",
+            ),
+            #[cfg(feature = "compression")]
+            (
+                SqlError::NotCompressed,
+                "the file does not hold compressed pages",
+                "MINISQL_NOT_COMPRESSED",
+            ),
+        ];
+        // Each kind of error has its own code
+        let mut codes = std::collections::HashMap::new();
+        for (e, message, code) in cases {
+            assert_eq!(e.to_string(), message);
+            assert_eq!(e.code(), code);
+            let kind = *codes.entry(code).or_insert(std::mem::discriminant(&e));
+            assert_eq!(kind, std::mem::discriminant(&e), "{} used twice", code);
+        }
+
+        // Usable with `?` as a boxed error, with the IO failure as its source
+        let open = || -> Result<(), Box<dyn Error>> {
+            Err(SqlError::IOError(io(), "Failed to open db".to_string()))?
+        };
+        let e = open().unwrap_err();
+        let source = e.source().unwrap().downcast_ref::<std::io::Error>();
+        assert_eq!(source.unwrap().kind(), std::io::ErrorKind::NotFound);
+        assert!(SqlError::NoData.source().is_none());
+    }
+}
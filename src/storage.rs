@@ -0,0 +1,436 @@
+use std::{cell::RefCell, rc::Rc};
+#[cfg(feature = "fs")]
+use std::{
+    fs::File,
+    io::{self, ErrorKind},
+};
+
+#[cfg(feature = "fs")]
+use crate::sql_error::SqlError;
+use crate::sql_error::SqlResult;
+
+/// Where the pager reads and writes its pages.
+/// Pages are as long as the buffer passed in.
+pub trait Storage {
+    /// Read a page and return how many of its bytes the storage holds.
+    /// Bytes past the end of the storage are left untouched.
+    fn read_page(&mut self, page_num: usize, buf: &mut [u8]) -> SqlResult<usize>;
+    fn write_page(&mut self, page_num: usize, buf: &[u8]) -> SqlResult<()>;
+    /// Read consecutive pages of `page_size` bytes from `page_num` into `buf`
+    /// and return how many of their bytes the storage holds
+    fn read_pages(
+        &mut self,
+        page_num: usize,
+        page_size: usize,
+        buf: &mut [u8],
+    ) -> SqlResult<usize> {
+        let mut read = 0;
+        for (i, page) in buf.chunks_mut(page_size).enumerate() {
+            let n = self.read_page(page_num + i, page)?;
+            read += n;
+            if n < page.len() {
+                break;
+            }
+        }
+        Ok(read)
+    }
+    /// Write consecutive pages of `page_size` bytes from `page_num`
+    fn write_pages(&mut self, page_num: usize, page_size: usize, buf: &[u8]) -> SqlResult<()> {
+        for (i, page) in buf.chunks(page_size).enumerate() {
+            self.write_page(page_num + i, page)?;
+        }
+        Ok(())
+    }
+    /// Length in bytes
+    fn len(&self) -> SqlResult<usize>;
+    fn is_empty(&self) -> SqlResult<bool> {
+        Ok(self.len()? == 0)
+    }
+    /// Bytes the pages take where they are kept, less than `len` if compressed
+    fn stored_len(&self) -> SqlResult<usize> {
+        self.len()
+    }
+    /// Make written pages durable
+    fn sync(&mut self) -> SqlResult<()>;
+    /// Cut or extend the storage to `len` bytes
+    fn set_len(&mut self, len: usize) -> SqlResult<()>;
+}
+
+#[cfg(feature = "fs")]
+pub struct FileStorage {
+    file: File,
+}
+
+#[cfg(feature = "fs")]
+impl FileStorage {
+    pub fn new(file: File) -> Self {
+        FileStorage { file }
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> SqlResult<usize> {
+        read_at(&self.file, offset, buf)
+            .map_err(|e| SqlError::IOError(e, "Failed to read".to_string()))
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> SqlResult<()> {
+        write_all_at(&self.file, offset, buf)
+            .map_err(|e| SqlError::IOError(e, "Failed to write".to_string()))
+    }
+}
+
+/// Read from `offset` until `buf` is full or the file ends, without touching
+/// the file cursor, and return how many bytes were read
+#[cfg(feature = "fs")]
+pub fn read_at(file: &File, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match positioned::read(file, &mut buf[filled..], offset + filled as u64) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+/// Write all of `buf` at `offset` without touching the file cursor
+#[cfg(feature = "fs")]
+pub fn write_all_at(file: &File, offset: u64, buf: &[u8]) -> io::Result<()> {
+    let mut written = 0;
+    while written < buf.len() {
+        match positioned::write(file, &buf[written..], offset + written as u64) {
+            Ok(0) => return Err(io::Error::from(ErrorKind::WriteZero)),
+            Ok(n) => written += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(all(feature = "fs", unix))]
+mod positioned {
+    use std::{fs::File, io, os::unix::fs::FileExt};
+
+    pub fn read(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        file.read_at(buf, offset)
+    }
+    pub fn write(file: &File, buf: &[u8], offset: u64) -> io::Result<usize> {
+        file.write_at(buf, offset)
+    }
+}
+
+/// These move the cursor on Windows, but nothing reads from the cursor
+#[cfg(all(feature = "fs", windows))]
+mod positioned {
+    use std::{fs::File, io, os::windows::fs::FileExt};
+
+    pub fn read(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        file.seek_read(buf, offset)
+    }
+    pub fn write(file: &File, buf: &[u8], offset: u64) -> io::Result<usize> {
+        file.seek_write(buf, offset)
+    }
+}
+
+#[cfg(feature = "fs")]
+impl Storage for FileStorage {
+    fn read_page(&mut self, page_num: usize, buf: &mut [u8]) -> SqlResult<usize> {
+        self.read_at((page_num * buf.len()) as u64, buf)
+    }
+    /// One read for all the pages
+    fn read_pages(
+        &mut self,
+        page_num: usize,
+        page_size: usize,
+        buf: &mut [u8],
+    ) -> SqlResult<usize> {
+        self.read_at((page_num * page_size) as u64, buf)
+    }
+    fn write_page(&mut self, page_num: usize, buf: &[u8]) -> SqlResult<()> {
+        self.write_at((page_num * buf.len()) as u64, buf)
+    }
+    /// One write for all the pages
+    fn write_pages(&mut self, page_num: usize, page_size: usize, buf: &[u8]) -> SqlResult<()> {
+        self.write_at((page_num * page_size) as u64, buf)
+    }
+    fn len(&self) -> SqlResult<usize> {
+        let metadata = self
+            .file
+            .metadata()
+            .map_err(|e| SqlError::IOError(e, "Failed to read metadata".to_string()))?;
+        Ok(metadata.len() as usize)
+    }
+    fn sync(&mut self) -> SqlResult<()> {
+        self.file
+            .sync_data()
+            .map_err(|e| SqlError::IOError(e, "Failed to sync".to_string()))
+    }
+    fn set_len(&mut self, len: usize) -> SqlResult<()> {
+        self.file
+            .set_len(len as u64)
+            .map_err(|e| SqlError::IOError(e, "Failed to truncate".to_string()))
+    }
+}
+
+/// Pages kept in a byte vector. Clones share the same bytes, so a database
+/// can be reopened from a clone after its table is closed.
+#[derive(Clone, Default)]
+pub struct MemoryStorage {
+    data: Rc<RefCell<Vec<u8>>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn from_bytes(data: Vec<u8>) -> Self {
+        MemoryStorage {
+            data: Rc::new(RefCell::new(data)),
+        }
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn read_page(&mut self, page_num: usize, buf: &mut [u8]) -> SqlResult<usize> {
+        let data = self.data.borrow();
+        let start = (page_num * buf.len()).min(data.len());
+        let end = (start + buf.len()).min(data.len());
+        buf[0..end - start].copy_from_slice(&data[start..end]);
+        Ok(end - start)
+    }
+    fn write_page(&mut self, page_num: usize, buf: &[u8]) -> SqlResult<()> {
+        let mut data = self.data.borrow_mut();
+        let start = page_num * buf.len();
+        if data.len() < start + buf.len() {
+            data.resize(start + buf.len(), 0);
+        }
+        data[start..start + buf.len()].copy_from_slice(buf);
+        Ok(())
+    }
+    fn len(&self) -> SqlResult<usize> {
+        Ok(self.data.borrow().len())
+    }
+    fn sync(&mut self) -> SqlResult<()> {
+        Ok(())
+    }
+    fn set_len(&mut self, len: usize) -> SqlResult<()> {
+        self.data.borrow_mut().resize(len, 0);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Seek, SeekFrom};
+
+    use super::*;
+    use crate::{
+        commands::prepare_statement,
+        meta::META_NODE_NUM,
+        table::{Row, Table},
+        test::db_name,
+    };
+
+    /// Storage noting the first page and page count of every write
+    struct Recording {
+        inner: FileStorage,
+        writes: Rc<RefCell<Vec<(usize, usize)>>>,
+    }
+
+    impl Storage for Recording {
+        fn read_page(&mut self, page_num: usize, buf: &mut [u8]) -> SqlResult<usize> {
+            self.inner.read_page(page_num, buf)
+        }
+        fn write_page(&mut self, page_num: usize, buf: &[u8]) -> SqlResult<()> {
+            self.writes.borrow_mut().push((page_num, 1));
+            self.inner.write_page(page_num, buf)
+        }
+        fn write_pages(&mut self, page_num: usize, page_size: usize, buf: &[u8]) -> SqlResult<()> {
+            self.writes
+                .borrow_mut()
+                .push((page_num, buf.len() / page_size));
+            self.inner.write_pages(page_num, page_size, buf)
+        }
+        fn len(&self) -> SqlResult<usize> {
+            self.inner.len()
+        }
+        fn sync(&mut self) -> SqlResult<()> {
+            self.inner.sync()
+        }
+        fn set_len(&mut self, len: usize) -> SqlResult<()> {
+            self.inner.set_len(len)
+        }
+    }
+
+    fn exec(table: &mut Table, statement: &str) -> SqlResult<Vec<i64>> {
+        let result = prepare_statement(statement)?.execute(table)?;
+        Ok(result.iter().map(|row| row.id).collect())
+    }
+
+    // Smoke tests of main.rs against the memory backend
+
+    #[test]
+    fn memory_insert_select() {
+        let mut table = Table::open_with(MemoryStorage::new()).unwrap();
+        exec(&mut table, "insert 1 wass wass@example.com").unwrap();
+        exec(&mut table, "insert 2 nnna nnna@example.com").unwrap();
+        let row = prepare_statement("select 1")
+            .unwrap()
+            .execute(&mut table)
+            .unwrap();
+        assert_eq!(row[0].name_str(), "wass");
+        assert!(matches!(
+            exec(&mut table, "insert 1 a b"),
+            Err(SqlError::DuplicateKey)
+        ));
+    }
+
+    #[test]
+    fn memory_tough_insert() {
+        let storage = MemoryStorage::new();
+        let mut table = Table::open_with(storage.clone()).unwrap();
+        for i in 0..60 {
+            exec(&mut table, &format!("insert {} name{} {}@a", i, i, i)).unwrap();
+        }
+        table.close().unwrap();
+        drop(table);
+
+        let mut table = Table::open_with(storage).unwrap();
+        for i in 0..60 {
+            assert_eq!(exec(&mut table, &format!("select {}", i)).unwrap(), vec![i]);
+        }
+        assert_eq!(
+            exec(&mut table, "select").unwrap(),
+            (0..60).collect::<Vec<_>>()
+        );
+        table.check().unwrap();
+    }
+
+    #[test]
+    fn memory_remove_single() {
+        let mut table = Table::open_with(MemoryStorage::new()).unwrap();
+        let order = vec![9, 17, 3, 2, 6];
+        for i in &order {
+            exec(&mut table, &format!("insert {} name{} {}@a", i, i, i)).unwrap();
+        }
+        for i in &order[0..3] {
+            exec(&mut table, &format!("delete {}", i)).unwrap();
+        }
+        assert_eq!(exec(&mut table, "select").unwrap(), vec![2, 6]);
+        let count = prepare_statement("count")
+            .unwrap()
+            .execute(&mut table)
+            .unwrap()
+            .count;
+        assert_eq!(count, Some(2));
+    }
+
+    #[test]
+    fn positioned_reads_and_writes() {
+        const PAGE: usize = 64;
+        let path = db_name("positioned_reads_and_writes");
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        let mut cursor = file.try_clone().unwrap();
+        let mut storage = FileStorage::new(file);
+        let page = |n: usize| vec![n as u8 + 1; PAGE];
+
+        // Out of order, with the file cursor moved between calls
+        for n in [3, 0, 2, 1] {
+            storage.write_page(n, &page(n)).unwrap();
+            cursor.seek(SeekFrom::Start(1)).unwrap();
+        }
+        assert_eq!(storage.len().unwrap(), 4 * PAGE);
+        for n in [1, 3, 0, 2] {
+            let mut buf = vec![0; PAGE];
+            assert_eq!(storage.read_page(n, &mut buf).unwrap(), PAGE);
+            assert_eq!(buf, page(n));
+            cursor.seek(SeekFrom::End(0)).unwrap();
+        }
+        let mut buf = vec![0; 2 * PAGE];
+        assert_eq!(storage.read_pages(1, PAGE, &mut buf).unwrap(), 2 * PAGE);
+        assert_eq!(buf, [page(1), page(2)].concat());
+        assert_eq!(cursor.stream_position().unwrap(), 4 * PAGE as u64);
+
+        // The last page, and reads reaching past it
+        let mut buf = vec![0; 2 * PAGE];
+        assert_eq!(storage.read_pages(3, PAGE, &mut buf).unwrap(), PAGE);
+        assert_eq!(buf[..PAGE], page(3));
+        assert!(buf[PAGE..].iter().all(|&b| b == 0));
+        let mut buf = vec![7; PAGE];
+        assert_eq!(storage.read_page(4, &mut buf).unwrap(), 0);
+        assert_eq!(buf, [7; PAGE]);
+
+        // Writes of several pages across the end grow the file
+        storage
+            .write_pages(3, PAGE, &[page(9), page(4)].concat())
+            .unwrap();
+        assert_eq!(storage.len().unwrap(), 5 * PAGE);
+        let mut buf = vec![0; 5 * PAGE];
+        assert_eq!(storage.read_pages(0, PAGE, &mut buf).unwrap(), 5 * PAGE);
+        let expected = [page(0), page(1), page(2), page(9), page(4)].concat();
+        assert_eq!(buf, expected);
+        assert_eq!(std::fs::read(&path).unwrap(), expected);
+    }
+
+    #[test]
+    fn flush_writes_runs_in_order() {
+        let path = db_name("flush_writes_runs_in_order");
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        let writes = Rc::new(RefCell::new(vec![]));
+        let storage = Recording {
+            inner: FileStorage::new(file),
+            writes: writes.clone(),
+        };
+        let mut table = Table::open_with(storage).unwrap();
+        for i in 0..40 {
+            table
+                .insert(Row::try_new(i, "name", "a@a").unwrap())
+                .unwrap();
+        }
+        // Every page is new, so they go in one write before the meta page
+        table.flush_all().unwrap();
+        let num_pages = table.pager.num_pages.get();
+        assert_eq!(*writes.borrow(), [(1, num_pages - 1), (META_NODE_NUM, 1)]);
+        writes.borrow_mut().clear();
+
+        // Rows at both ends change pages apart from each other
+        for id in [0, 39] {
+            table
+                .update(Row::try_new(id, "new", "b@b").unwrap())
+                .unwrap();
+        }
+        let dirty = (1..num_pages)
+            .filter(|&n| table.pager.is_dirty(n))
+            .collect::<Vec<_>>();
+        let mut runs = dirty
+            .chunk_by(|a, b| a + 1 == *b)
+            .map(|run| (run[0], run.len()))
+            .collect::<Vec<_>>();
+        assert!(runs.len() > 1);
+        if table.pager.is_dirty(META_NODE_NUM) {
+            runs.push((META_NODE_NUM, 1));
+        }
+        let expected = db_name("flush_writes_runs_in_order_copy");
+        table.backup_to(&expected).unwrap();
+        table.close().unwrap();
+        assert_eq!(*writes.borrow(), runs);
+        assert_eq!(
+            std::fs::read(&path).unwrap(),
+            std::fs::read(&expected).unwrap()
+        );
+    }
+}
@@ -1,16 +1,119 @@
-pub fn copy_null_terminated<const N: usize>(buf: &mut [u8; N], s: &str) {
-    let bytes = s.as_bytes();
-    let len = std::cmp::min(bytes.len(), N - 1);
-    buf[0..len].copy_from_slice(&bytes[0..len]);
+/// Copy a string with a terminator after it. A string too long for the
+/// buffer is cut after its last whole character that fits, and true is
+/// returned so that the caller can reject it.
+#[must_use]
+pub fn copy_null_terminated<const N: usize>(buf: &mut [u8; N], s: &str) -> bool {
+    let mut len = s.len().min(N - 1);
+    while !s.is_char_boundary(len) {
+        len -= 1;
+    }
+    buf[0..len].copy_from_slice(&s.as_bytes()[0..len]);
     buf[len] = 0;
+    len < s.len()
 }
+/// String before the first terminator, or of the whole buffer without one,
+/// with invalid UTF-8 replaced
+#[cfg_attr(not(feature = "serde"), allow(dead_code))]
 pub fn to_string_null_terminated<const N: usize>(buf: &[u8; N]) -> String {
-    let mut len = 0;
-    for i in 0..N {
-        if buf[i] == 0 {
-            len = i;
-            break;
+    String::from_utf8_lossy(bytes_null_terminated(buf)).to_string()
+}
+/// Bytes before the first terminator, or the whole buffer without one
+pub fn bytes_null_terminated(buf: &[u8]) -> &[u8] {
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    &buf[0..len]
+}
+/// Borrowed form of `to_string_null_terminated`. Instead of replacing invalid
+/// UTF-8, the string ends before the first invalid sequence.
+pub fn str_null_terminated(buf: &[u8]) -> &str {
+    let bytes = bytes_null_terminated(buf);
+    match std::str::from_utf8(bytes) {
+        Ok(s) => s,
+        Err(e) => std::str::from_utf8(&bytes[0..e.valid_up_to()]).unwrap(),
+    }
+}
+
+/// Serialize fixed buffers as strings with serde's `with` attribute
+#[cfg(feature = "serde")]
+pub mod serde_null_terminated {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    use super::{copy_null_terminated, to_string_null_terminated};
+
+    pub fn serialize<S: Serializer, const N: usize>(
+        buf: &[u8; N],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&to_string_null_terminated(buf))
+    }
+    pub fn deserialize<'de, D: Deserializer<'de>, const N: usize>(
+        deserializer: D,
+    ) -> Result<[u8; N], D::Error> {
+        let s = String::deserialize(deserializer)?;
+        let mut buf = [0u8; N];
+        if copy_null_terminated(&mut buf, &s) {
+            return Err(D::Error::custom(format!(
+                "string of {} bytes exceeds the limit of {} bytes",
+                s.len(),
+                N - 1
+            )));
+        }
+        Ok(buf)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Copy `s` into a buffer of N bytes, returning what reads back and
+    /// whether it was cut
+    fn round_trip<const N: usize>(s: &str) -> (String, bool) {
+        let mut buf = [0xffu8; N];
+        let truncated = copy_null_terminated(&mut buf, s);
+        (to_string_null_terminated(&buf), truncated)
+    }
+
+    #[test]
+    fn terminators() {
+        assert_eq!(to_string_null_terminated(&[]), "");
+        assert_eq!(to_string_null_terminated(&[0, b'a', b'b']), "");
+        assert_eq!(to_string_null_terminated(&[b'a', b'b', 0, b'c']), "ab");
+        assert_eq!(to_string_null_terminated(b"abcd"), "abcd");
+        // Invalid UTF-8 is replaced without changing where the string ends
+        assert_eq!(
+            to_string_null_terminated(&[b'a', 0xff, b'b', 0]),
+            "a\u{fffd}b"
+        );
+        assert_eq!(to_string_null_terminated(&[0xe3, 0x82, b'c']), "\u{fffd}c");
+    }
+
+    #[test]
+    fn multibyte_boundaries() {
+        // Japanese characters take 3 bytes and emoji 4, with 31 and 254
+        // bytes before the terminator
+        let fits = [
+            "わ".repeat(10) + "a",
+            "😀".repeat(7) + "abc",
+            "す".repeat(10),
+        ];
+        for s in fits {
+            assert_eq!(round_trip::<32>(&s), (s.clone(), false));
+        }
+        assert_eq!(round_trip::<32>(&"わ".repeat(11)), ("わ".repeat(10), true));
+        assert_eq!(round_trip::<32>(&"😀".repeat(8)), ("😀".repeat(7), true));
+        assert_eq!(
+            round_trip::<32>(&("a".repeat(29) + "😀")),
+            ("a".repeat(29), true)
+        );
+
+        let fits = ["わ".repeat(84) + "ab", "😀".repeat(63) + "ab"];
+        for s in fits {
+            assert_eq!(round_trip::<255>(&s), (s.clone(), false));
         }
+        assert_eq!(round_trip::<255>(&"わ".repeat(85)), ("わ".repeat(84), true));
+        assert_eq!(
+            round_trip::<255>(&("😀".repeat(63) + "abc")),
+            ("😀".repeat(63) + "ab", true)
+        );
     }
-    String::from_utf8_lossy(&buf[0..len]).to_string()
 }
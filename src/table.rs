@@ -1,170 +1,773 @@
+#[cfg(feature = "compression")]
+use crate::compressed_storage::CompressedStorage;
+#[cfg(feature = "fs")]
 use crate::{
-    cursor::Cursor,
-    meta::{MetaMut, MetaRef, META_NODE_NUM},
-    node::{InternalMut, InternalRef, LeafMut, LeafRef, NodeRef, NodeType},
-    pager::Pager,
-    sql_error::SqlResult,
-    string_utils::to_string_null_terminated,
+    changes::{changes_path, encode_delta, ChangeMap},
+    journal::journal_path,
+    pager::{open_error, DEFAULT_PAGE_SIZE},
+    storage::{FileStorage, MemoryStorage},
+    wal::wal_path,
+};
+use crate::{
+    commands::prepare_statement,
+    cursor::{Cursor, Keys, SnapshotScan},
+    journal::Journal,
+    meta::{
+        unix_time, MetaMut, MetaRef, CHECKSUM_VERSION, DEFAULT_NEXT_ID, FORMAT_VERSION,
+        INFO_VERSION, KEY_SLOT_VERSION, LINKED_LEAF_VERSION, META_NODE_NUM, PAGE_COUNT_VERSION,
+        SEPARATOR_VERSION, SIBLING_VERSION, USER_META_VERSION, VARIABLE_CELL_VERSION,
+    },
+    node::{
+        inline_cell, max_inline_value, overflow_cell, CellValue, InternalMut, InternalRef, LeafMut,
+        LeafRef, LeafValue, Node, NodeLimits, NodeRef, LEAF_NODE_MAX_CELLS, LEAF_NODE_OFFSET_SIZE,
+        MISSING_NODE,
+    },
+    overflow,
+    pager::{Pager, Snapshot},
+    sql_error::{SqlError, SqlResult},
+    storage::Storage,
+    string_utils::{bytes_null_terminated, copy_null_terminated, str_null_terminated},
+    wal::Wal,
 };
 use std::{
+    cell::Cell,
+    collections::HashSet,
     fmt::{Display, Formatter},
+    ops::{Bound, Range, RangeBounds},
     write,
 };
+#[cfg(feature = "fs")]
+use std::{fs::File, io::Write};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Row {
-    pub id: u64,
-    pub name: [u8; 32],
-    pub email: [u8; 255],
+    pub id: i64,
+    #[cfg_attr(
+        feature = "serde",
+        serde(with = "crate::string_utils::serde_null_terminated")
+    )]
+    pub name: [u8; NAME_SIZE],
+    #[cfg_attr(
+        feature = "serde",
+        serde(with = "crate::string_utils::serde_null_terminated")
+    )]
+    pub email: [u8; EMAIL_SIZE],
 }
 
+fn fmt_row(f: &mut Formatter<'_>, id: i64, name: &str, email: &str) -> std::fmt::Result {
+    write!(f, "Row {{ id: {}, name: {}, email: {} }}", id, name, email)
+}
 impl Display for Row {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        fmt_row(f, self.id, self.name_str(), self.email_str())
+    }
+}
+/// Rows with the same id in two tables, produced by `join`
+#[derive(Debug, Clone, PartialEq)]
+pub struct JoinedRow {
+    pub left: Row,
+    pub right: Row,
+}
+impl Display for JoinedRow {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "Row {{ id: {}, name: {}, email: {} }}",
-            self.id,
-            to_string_null_terminated(&self.name),
-            to_string_null_terminated(&self.email)
+            "Row {{ id: {}, a.name: {}, a.email: {}, b.name: {}, b.email: {} }}",
+            self.left.id,
+            self.left.name_str(),
+            self.left.email_str(),
+            self.right.name_str(),
+            self.right.email_str()
         )
     }
 }
+
+/// Inner join of two tables on id.
+/// The table with fewer rows is scanned and each id is looked up in the other.
+pub fn join(left: &mut Table, right: &mut Table) -> SqlResult<Vec<JoinedRow>> {
+    let scan_right = right.len()? < left.len()?;
+    let (scanned, probed) = if scan_right {
+        (right, left)
+    } else {
+        (left, right)
+    };
+    let mut rows = Vec::new();
+    let mut scan = scanned.snapshot_scan()?;
+    while let Some(value) = scan.next_value(scanned)? {
+        let row = Row::deserialize(&value.get_value()?);
+        if let Some(other) = probed.get(row.id)? {
+            rows.push(if scan_right {
+                JoinedRow {
+                    left: other,
+                    right: row,
+                }
+            } else {
+                JoinedRow {
+                    left: row,
+                    right: other,
+                }
+            });
+        }
+    }
+    Ok(rows)
+}
+
+/// Rows are equal when their ids and the bytes before each terminator match;
+/// whatever follows a terminator in the buffers is ignored.
+impl PartialEq for Row {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+            && bytes_null_terminated(&self.name) == bytes_null_terminated(&other.name)
+            && bytes_null_terminated(&self.email) == bytes_null_terminated(&other.email)
+    }
+}
+impl Eq for Row {}
+
+/// Row read in place from a serialized buffer, usually a page borrowed
+/// through `CursorValue::get_value`. The page stays borrowed while the
+/// `RowRef` lives, so use it within one callback and call `to_owned` to keep it.
+#[derive(Clone, Copy)]
+pub struct RowRef<'a> {
+    buf: &'a [u8],
+}
+impl<'a> RowRef<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        RowRef { buf }
+    }
+    /// Bytes of a field, whose trailing zeros may have been left out
+    fn field(&self, range: Range<usize>) -> &'a [u8] {
+        let end = range.end.min(self.buf.len());
+        &self.buf[range.start.min(end)..end]
+    }
+    pub fn id(&self) -> i64 {
+        let mut id_bytes = [0; 8];
+        let field = self.field(0..8);
+        id_bytes[..field.len()].copy_from_slice(field);
+        i64::from_le_bytes(id_bytes)
+    }
+    /// Name up to its terminator, cut before any invalid UTF-8
+    pub fn name_str(&self) -> &'a str {
+        str_null_terminated(self.field(Field::Name.range()))
+    }
+    /// Email up to its terminator, cut before any invalid UTF-8
+    pub fn email_str(&self) -> &'a str {
+        str_null_terminated(self.field(Field::Email.range()))
+    }
+    pub fn to_owned(self) -> Row {
+        Row::deserialize(self.buf)
+    }
+}
+impl Display for RowRef<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        fmt_row(f, self.id(), self.name_str(), self.email_str())
+    }
+}
+
 pub const ROW_SIZE: usize = 295;
+pub const NAME_SIZE: usize = 32;
+pub const EMAIL_SIZE: usize = 255;
+
+/// Column of a row that can be changed on its own
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Field {
+    Name,
+    Email,
+}
+impl Field {
+    /// Bytes of the field in a serialized row
+    pub fn range(self) -> Range<usize> {
+        match self {
+            Field::Name => 8..8 + NAME_SIZE,
+            Field::Email => 8 + NAME_SIZE..ROW_SIZE,
+        }
+    }
+    pub fn name(self) -> &'static str {
+        match self {
+            Field::Name => "name",
+            Field::Email => "email",
+        }
+    }
+    pub fn parse(name: &str) -> Option<Field> {
+        match name {
+            "name" => Some(Field::Name),
+            "email" => Some(Field::Email),
+            _ => None,
+        }
+    }
+}
 
 impl Row {
-    pub fn serialize(&self) -> [u8; ROW_SIZE] {
-        let mut buf = [0u8; ROW_SIZE];
-        buf[0..8].copy_from_slice(&self.id.to_le_bytes());
-        buf[8..40].copy_from_slice(&self.name);
-        buf[40..295].copy_from_slice(&self.email);
+    /// Build a row from strings, checking they fit with their terminators
+    pub fn try_new(id: i64, name: &str, email: &str) -> SqlResult<Row> {
+        let mut row = Row {
+            id,
+            name: [0; NAME_SIZE],
+            email: [0; EMAIL_SIZE],
+        };
+        if copy_null_terminated(&mut row.name, name) {
+            return Err(SqlError::TooLargeString("name".to_string()));
+        }
+        if copy_null_terminated(&mut row.email, email) {
+            return Err(SqlError::TooLargeString("email".to_string()));
+        }
+        Ok(row)
+    }
+    /// Name up to its terminator, cut before any invalid UTF-8
+    pub fn name_str(&self) -> &str {
+        str_null_terminated(&self.name)
+    }
+    /// Email up to its terminator, cut before any invalid UTF-8
+    pub fn email_str(&self) -> &str {
+        str_null_terminated(&self.email)
+    }
+    /// Fields at their places in ROW_SIZE bytes, without the zeros after
+    /// the last character
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(ROW_SIZE);
+        buf.extend_from_slice(&self.id.to_le_bytes());
+        buf.extend_from_slice(&self.name);
+        buf.extend_from_slice(&self.email);
+        let len = buf.iter().rposition(|&b| b != 0).map_or(0, |i| i + 1);
+        buf.truncate(len.max(8));
         buf
     }
 
+    /// Row of serialized bytes, missing ones read as zeros
     pub fn deserialize(buf: &[u8]) -> Self {
+        let mut full = [0u8; ROW_SIZE];
+        let len = buf.len().min(ROW_SIZE);
+        full[..len].copy_from_slice(&buf[..len]);
         let mut id_bytes = [0; 8];
-        id_bytes.copy_from_slice(&buf[0..8]);
-        let mut name_bytes = [0; 32];
-        name_bytes.copy_from_slice(&buf[8..40]);
-        let mut email_bytes = [0; 255];
-        email_bytes.copy_from_slice(&buf[40..295]);
+        id_bytes.copy_from_slice(&full[0..8]);
+        let mut name_bytes = [0; NAME_SIZE];
+        name_bytes.copy_from_slice(&full[8..40]);
+        let mut email_bytes = [0; EMAIL_SIZE];
+        email_bytes.copy_from_slice(&full[40..295]);
         Row {
-            id: u64::from_le_bytes(id_bytes),
+            id: i64::from_le_bytes(id_bytes),
             name: name_bytes,
             email: email_bytes,
         }
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChangeKind {
+    Insert,
+    Update,
+    Delete,
+}
+
+/// A row change reported to the hook registered with `Table::set_hook`
+#[derive(Debug, Clone)]
+pub struct ChangeEvent {
+    pub kind: ChangeKind,
+    pub key: i64,
+    /// The row before an update or delete
+    pub old_row: Option<Row>,
+}
+
+/// Hooks are `'static` and only receive the event, so they cannot borrow the
+/// table that invokes them and mutate it re-entrantly.
+pub type ChangeHook = Box<dyn FnMut(ChangeEvent)>;
+
+/// What `Table::copy_to` wrote
+#[derive(Debug, PartialEq)]
+pub struct CopyStats {
+    pub rows: usize,
+    pub pages: usize,
+}
+
+/// Shape of the tree and how full its leaves are, as `.stats` shows it
+#[derive(Debug, Default, PartialEq)]
+pub struct TreeStats {
+    pub depth: usize,
+    pub leaves: usize,
+    pub cells: usize,
+    /// Bytes the leaves take of the `space` they have for cells
+    pub used: usize,
+    pub space: usize,
+}
+
+impl std::fmt::Display for TreeStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "depth {}, {} leaves with {} cells",
+            self.depth, self.leaves, self.cells
+        )?;
+        if let Some(full) = (self.used * 100).checked_div(self.space) {
+            write!(f, ", {}% full", full)?;
+        }
+        Ok(())
+    }
+}
+
+/// Where a file comes from and how much it was written to, as `.dbinfo`
+/// shows it
+#[derive(Debug, PartialEq)]
+pub struct DbInfo {
+    pub version: u32,
+    pub page_size: usize,
+    pub page_count: usize,
+    pub root_page: usize,
+    /// Seconds since the Unix epoch, 0 when unknown
+    pub created: u64,
+    pub modified: u64,
+    pub write_count: u64,
+}
+
+impl std::fmt::Display for DbInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f, "format version {}", self.version)?;
+        writeln!(f, "page size {}", self.page_size)?;
+        writeln!(f, "{} pages, root page {}", self.page_count, self.root_page)?;
+        writeln!(f, "created {}", self.created)?;
+        writeln!(f, "modified {}", self.modified)?;
+        write!(f, "{} write statements", self.write_count)
+    }
+}
+
+/// When changes are synced to the disk, so that a power failure keeps them
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Durability {
+    /// Never sync; changes survive the process crashing but not the machine
+    Off,
+    /// Sync once when the table is closed
+    OnClose,
+    /// Sync each statement's journal entry and every flush
+    #[default]
+    OnCommit,
+}
+
+impl Durability {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "off" => Some(Durability::Off),
+            "on_close" => Some(Durability::OnClose),
+            "on_commit" => Some(Durability::OnCommit),
+            _ => None,
+        }
+    }
+}
+
+impl Display for Durability {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Durability::Off => "off",
+            Durability::OnClose => "on_close",
+            Durability::OnCommit => "on_commit",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Settings for opening a table
+#[derive(Debug, Clone, Default)]
+pub struct TableOptions {
+    /// Most pages kept in memory, or None to keep every page read
+    pub cache_size: Option<usize>,
+    /// Page size of a new file, or None for the default. An existing file
+    /// keeps the page size in its header.
+    pub page_size: Option<usize>,
+    /// Pages read at once when a scan reaches a leaf not in the cache, or 0
+    pub read_ahead: usize,
+    /// Write flushed pages from a background thread
+    pub background_flush: bool,
+    pub durability: Durability,
+}
+
 pub struct Table {
     pub pager: Pager,
+    pub journal: Option<Journal>,
+    pub wal: Option<Wal>,
+    pub durability: Durability,
+    hook: Option<ChangeHook>,
+    /// Copy of the root number in the meta page, kept by `set_root_num`
+    root_num: Cell<usize>,
+    /// Percentage of each node `bulk_load` fills
+    bulk_fill: Cell<usize>,
+    /// Bumped by every change moving cells, to tell cursors placed before
+    generation: Cell<u64>,
+    /// Rightmost leaf with its last key and the generation the last insert
+    /// left it at, where a larger key goes while nothing changed since
+    append_hint: Cell<Option<(usize, i64, u64)>>,
+    /// Whether node headers read from the file are checked, which they are
+    /// once it is in the current format
+    check_headers: Cell<bool>,
 }
 
 impl Table {
+    /// Open a database, creating the file and its parent directories if missing
+    #[cfg(feature = "fs")]
     pub fn open(filename: &str) -> SqlResult<Self> {
-        Ok(Table {
-            pager: Pager::open(filename)?,
-        })
+        Self::open_with_options(filename, &TableOptions::default())
     }
-
-    pub fn close(&mut self) -> SqlResult<()> {
-        for i in 0..self.pager.num_pages.get() {
-            if self.pager.pages.borrow()[i].is_none() {
-                continue;
-            }
-            self.pager.flush(i)?;
-            self.pager.drop(i);
+    #[cfg(feature = "fs")]
+    pub fn open_with_options(filename: &str, options: &TableOptions) -> SqlResult<Self> {
+        let page_size = options.page_size.unwrap_or(DEFAULT_PAGE_SIZE);
+        let mut pager = Pager::open_file(filename, false, page_size)?;
+        pager.cache_size.set(options.cache_size);
+        pager.read_ahead.set(options.read_ahead);
+        pager.track_changes(ChangeMap::open(&changes_path(filename))?);
+        let mut wal = Wal::open(&wal_path(filename), pager.page_size)?;
+        wal.recover(&mut pager)?;
+        if options.background_flush {
+            let file = File::options()
+                .write(true)
+                .open(filename)
+                .map_err(|e| open_error(e, filename))?;
+            pager.start_flusher(FileStorage::new(file))?;
         }
-        Ok(())
+        let journal = Journal::open(&journal_path(filename))?;
+        let mut table = Self::with_logs(pager, Some(journal), Some(wal))?;
+        table.durability = options.durability;
+        Ok(table)
+    }
+    /// Create a database, failing if the file already exists
+    #[cfg(feature = "fs")]
+    pub fn create_new(filename: &str) -> SqlResult<Self> {
+        Self::create(filename, &TableOptions::default())
+    }
+    /// Create a database with the given options, failing if the file already exists
+    #[cfg(feature = "fs")]
+    pub fn create(filename: &str, options: &TableOptions) -> SqlResult<Self> {
+        let page_size = options.page_size.unwrap_or(DEFAULT_PAGE_SIZE);
+        let mut pager = Pager::open_file(filename, true, page_size)?;
+        pager.cache_size.set(options.cache_size);
+        pager.read_ahead.set(options.read_ahead);
+        // Logs left from an earlier file of the same name do not apply
+        let mut journal = Journal::open(&journal_path(filename))?;
+        journal.truncate(true)?;
+        let mut changes = ChangeMap::open(&changes_path(filename))?;
+        changes.clear()?;
+        pager.track_changes(changes);
+        let mut wal = Wal::open(&wal_path(filename), page_size)?;
+        wal.truncate(true)?;
+        let mut table = Self::with_logs(pager, Some(journal), Some(wal))?;
+        table.durability = options.durability;
+        Ok(table)
+    }
+    /// Open a database whose pages are encrypted with `key`, creating it when
+    /// the file is new. No journal is kept, as it would hold rows in the clear.
+    #[cfg(feature = "encryption")]
+    pub fn open_encrypted(filename: &str, key: &[u8]) -> SqlResult<Self> {
+        let mut pager = Pager::open_encrypted(filename, key)?;
+        pager.track_changes(ChangeMap::open(&changes_path(filename))?);
+        let mut wal = Wal::open(&wal_path(filename), pager.page_size)?;
+        wal.recover(&mut pager)?;
+        Self::with_logs(pager, None, Some(wal))
     }
+    /// Open a database of compressed pages, creating it when the file is new.
+    /// The page map is kept next to the file.
+    #[cfg(feature = "compression")]
+    pub fn open_compressed(filename: &str) -> SqlResult<Self> {
+        let storage = CompressedStorage::open(filename, DEFAULT_PAGE_SIZE)?;
+        let mut pager = Pager::from_storage(Box::new(storage))?;
+        pager.track_changes(ChangeMap::open(&changes_path(filename))?);
+        let mut wal = Wal::open(&wal_path(filename), pager.page_size)?;
+        wal.recover(&mut pager)?;
+        let journal = Journal::open(&journal_path(filename))?;
+        Self::with_logs(pager, Some(journal), Some(wal))
+    }
+    /// Open a database on any storage backend, without a journal
+    pub fn open_with<S: Storage + 'static>(storage: S) -> SqlResult<Self> {
+        Self::with_pager(Pager::from_storage(Box::new(storage))?, None)
+    }
+    /// Open a database on any storage backend with a write-ahead log,
+    /// recovering the pages it committed
+    #[cfg(feature = "fs")]
+    pub fn open_with_wal<S: Storage + 'static>(storage: S, wal_path: &str) -> SqlResult<Self> {
+        let mut pager = Pager::from_storage(Box::new(storage))?;
+        let mut wal = Wal::open(wal_path, pager.page_size)?;
+        wal.recover(&mut pager)?;
+        Self::with_logs(pager, None, Some(wal))
+    }
+    /// Read a whole database into memory. The returned table never touches the
+    /// file again, so closing it leaves the file as it was.
+    /// Journaled statements not yet in the file are applied to the copy.
+    #[cfg(feature = "fs")]
+    pub fn load_into_memory(filename: &str, limit: usize) -> SqlResult<Self> {
+        let read_error = |e| SqlError::IOError(e, format!("Failed to read {}", filename));
+        let size = std::fs::metadata(filename).map_err(read_error)?.len() as usize;
+        if size > limit {
+            return Err(SqlError::FileTooLarge { size, limit });
+        }
+        let data = std::fs::read(filename).map_err(read_error)?;
+        let pager = Pager::from_storage(Box::new(MemoryStorage::from_bytes(data.clone())))?;
+        pager.load_pages(&data)?;
+        let mut table = Self::with_pager(pager, None)?;
 
-    pub fn start(&mut self) -> SqlResult<Cursor> {
-        let mut cursor = self.find(0)?;
-        if !cursor.has_cell()? {
-            cursor.end_of_table = true;
+        let journal = journal_path(filename);
+        if std::path::Path::new(&journal).exists() {
+            let entries = Journal::open(&journal)?.entries()?;
+            table.replay(entries)?;
         }
-        Ok(cursor)
+        Ok(table)
     }
+    pub(crate) fn with_pager(pager: Pager, journal: Option<Journal>) -> SqlResult<Self> {
+        Self::with_logs(pager, journal, None)
+    }
+    pub(crate) fn with_logs(
+        pager: Pager,
+        journal: Option<Journal>,
+        wal: Option<Wal>,
+    ) -> SqlResult<Self> {
+        // Pages written in place before the log commits them would leave a
+        // crash with changes the log and the journal know nothing of
+        pager.keep_dirty.set(wal.is_some());
+        let root_num = pager.node(META_NODE_NUM)?.meta_node().get_root_num();
+        let mut table = Table {
+            pager,
+            journal: None,
+            wal,
+            durability: Durability::default(),
+            hook: None,
+            root_num: Cell::new(root_num),
+            bulk_fill: Cell::new(100),
+            generation: Cell::new(0),
+            append_hint: Cell::new(None),
+            check_headers: Cell::new(false),
+        };
+        let version = table.meta_ref()?.get_version();
+        if version > FORMAT_VERSION {
+            return Err(SqlError::UnsupportedVersion {
+                found: version,
+                supported: FORMAT_VERSION,
+            });
+        }
+        if version < FORMAT_VERSION {
+            table.upgrade(version)?;
+        }
+        table.check_headers.set(true);
 
-    pub fn find(&mut self, key: u64) -> SqlResult<Cursor> {
-        let root_node = self.pager.node(self.get_root_num()?)?;
-        match root_node.get_type() {
-            NodeType::Leaf => self.find_leaf(self.get_root_num()?, key),
-            NodeType::Internal => self.find_internal(self.get_root_num()?, key),
+        if let Some(mut journal) = journal {
+            let entries = journal.entries()?;
+            if !entries.is_empty() {
+                table.replay(entries)?;
+                table.flush_all()?;
+                journal.truncate(true)?;
+            }
+            table.journal = Some(journal);
         }
+        Ok(table)
     }
-    pub fn find_internal(&mut self, page_num: usize, key: u64) -> SqlResult<Cursor> {
-        let node = self.internal_ref(page_num)?;
-        let index = match node.find_key(key) {
-            Some(index) => index,
-            None => 0,
+
+    /// Bring the meta page of an older file up to the current format
+    fn upgrade(&mut self, from: u32) -> SqlResult<()> {
+        // Pages are rewritten in the current layout before reading keys and
+        // rows through the tree
+        if from < SIBLING_VERSION {
+            self.rebuild_internal_nodes(from)?;
+        }
+        if from < VARIABLE_CELL_VERSION {
+            self.upgrade_leaf_layout(self.get_root_num()?)?;
+        }
+        let spilled = if from < KEY_SLOT_VERSION {
+            self.upgrade_leaf_cells(from)?
+        } else {
+            Vec::new()
         };
-        let child = node.get_child_at(index);
-        let child_node = self.pager.node(child)?;
-        match child_node.get_type() {
-            NodeType::Leaf => self.find_leaf(child, key),
-            NodeType::Internal => self.find_internal(child, key),
+        if from < SIBLING_VERSION {
+            self.upgrade_keys(self.get_root_num()?)?;
         }
-    }
-    pub fn find_leaf(&mut self, page_num: usize, key: u64) -> SqlResult<Cursor> {
-        let node = self.leaf_ref(page_num)?;
-        let mut min_cell = 0usize;
-        let mut max_cell = node.get_num_cells() as usize;
-        while min_cell < max_cell {
-            let mid_cell = (min_cell + max_cell) / 2;
-            let mid_key = node.get_key(mid_cell);
-            if mid_key >= key {
-                max_cell = mid_cell;
+        if from < SIBLING_VERSION || !spilled.is_empty() {
+            self.recount()?;
+        }
+        if !spilled.is_empty() {
+            self.set_row_count(self.len()? - spilled.len())?;
+            for (key, value) in spilled {
+                self.find(key)?.insert(key, &value)?;
+            }
+        }
+        if from == 0 {
+            // Fill in the counters missing from files without the version field
+            let row_count = self.count_rows()?;
+            self.set_row_count(row_count)?;
+            let cursor = self.end()?;
+            let next_id = if cursor.end_of_table {
+                DEFAULT_NEXT_ID
             } else {
-                min_cell = mid_cell + 1;
+                let last_key = cursor.get()?.get_key()?;
+                last_key.saturating_add(1).max(DEFAULT_NEXT_ID)
+            };
+            self.set_next_id(next_id)?;
+        }
+        if from < CHECKSUM_VERSION {
+            // Load every page so that flushing writes it with a checksum
+            for page_num in 0..self.pager.num_pages.get() {
+                self.pager.node(page_num)?;
             }
+            self.pager.checksums.set(true);
         }
-        Ok(Cursor {
-            table: self,
-            page_num,
-            cell_num: max_cell,
-            end_of_table: false,
-        })
+        if from < PAGE_COUNT_VERSION {
+            let num_pages = self.pager.num_pages.get();
+            self.meta_mut()?.set_page_count(num_pages);
+        }
+        if from < INFO_VERSION {
+            self.meta_mut()?.clear_infoless_slots();
+        }
+        if from < USER_META_VERSION {
+            self.meta_mut()?.clear_user_entries();
+        }
+        // Fields added after version 1 are zero in older files, which is their initial value
+        self.meta_mut()?.set_version(FORMAT_VERSION);
+        Ok(())
     }
 
-    pub fn internal_mut(&self, page_num: usize) -> SqlResult<InternalMut> {
-        let node = self.pager.node(page_num)?;
-        Ok(node.internal_node_mut())
-    }
-    pub fn leaf_mut(&self, page_num: usize) -> SqlResult<LeafMut> {
-        let node = self.pager.node(page_num)?;
-        Ok(node.leaf_node_mut())
-    }
-    pub fn leaf_ref(&self, page_num: usize) -> SqlResult<LeafRef> {
-        let node = self.pager.node(page_num)?;
-        Ok(node.leaf_node())
+    /// Replace the internal nodes of files before format version 16, which
+    /// had no links to their neighbours, by levels built over the leaves
+    /// like a bulk load. Nodes of version 14 and 15 can hold a child more
+    /// than the longer header leaves room for. The keys and counts are
+    /// filled in once the leaves are upgraded.
+    fn rebuild_internal_nodes(&self, from: u32) -> SqlResult<()> {
+        let mut leaves = Vec::new();
+        let mut internals = Vec::new();
+        self.collect_old_tree(self.get_root_num()?, from, &mut leaves, &mut internals)?;
+        if internals.is_empty() {
+            return Ok(());
+        }
+        for page_num in internals {
+            self.pager.free_page(page_num)?;
+        }
+        let level = leaves.into_iter().map(|leaf| (leaf, 0, 0)).collect();
+        let root_num = self.load_levels(level, &mut Vec::new())?;
+        let root = self.pager.node(root_num)?;
+        root.set_root(true);
+        root.set_parent(MISSING_NODE);
+        self.set_root_num(root_num)
     }
-    pub fn internal_ref(&self, page_num: usize) -> SqlResult<InternalRef> {
-        let node = self.pager.node(page_num)?;
-        Ok(node.internal_node())
+    /// Leaves and internal nodes under a node of a file of format `from`
+    fn collect_old_tree(
+        &self,
+        node_num: usize,
+        from: u32,
+        leaves: &mut Vec<usize>,
+        internals: &mut Vec<usize>,
+    ) -> SqlResult<()> {
+        let internal = match self.node(node_num)?.as_typed()? {
+            NodeRef::Internal(internal) => internal,
+            NodeRef::Leaf(_) => {
+                leaves.push(node_num);
+                return Ok(());
+            }
+        };
+        internals.push(node_num);
+        let children = if from < 3 {
+            let cells = internal.legacy_cells()?;
+            cells.into_iter().map(|(child, _)| child).collect()
+        } else if from < SEPARATOR_VERSION {
+            let cells = internal.keyed_cells()?;
+            cells.into_iter().map(|(child, _, _)| child).collect()
+        } else {
+            internal.separator_children()?
+        };
+        for child in children {
+            self.collect_old_tree(child, from, leaves, internals)?;
+        }
+        Ok(())
     }
 
-    // Meta
-    pub fn meta_mut(&self) -> SqlResult<MetaMut> {
-        let node = self.pager.node(META_NODE_NUM)?;
-        Ok(node.meta_node_mut())
-    }
-    pub fn meta_ref(&self) -> SqlResult<MetaRef> {
-        let node = self.pager.node(META_NODE_NUM)?;
-        Ok(node.meta_node())
-    }
-    pub fn get_root_num(&self) -> SqlResult<usize> {
-        let meta = self.meta_ref()?;
-        Ok(meta.get_root_num())
-    }
-    pub fn set_root_num(&self, root_num: usize) -> SqlResult<()> {
-        let meta = self.meta_mut()?;
-        meta.set_root_num(root_num);
+    /// Rewrite the fixed cells of leaves written before format version 11,
+    /// leaving out the zeros after each row
+    fn upgrade_leaf_layout(&self, node_num: usize) -> SqlResult<()> {
+        match self.node(node_num)?.as_typed()? {
+            NodeRef::Internal(internal) => {
+                for i in 0..internal.get_num_children() {
+                    self.upgrade_leaf_layout(internal.get_child_at(i)?)?;
+                }
+            }
+            NodeRef::Leaf(leaf) => {
+                let cells = leaf.legacy_cells()?;
+                let leaf = leaf.node.leaf_node_mut()?;
+                leaf.clear();
+                for (i, (key, row)) in cells.iter().enumerate() {
+                    let value = Row::deserialize(row).serialize();
+                    leaf.insert_cell(i, &inline_cell(*key, &value))?;
+                }
+            }
+        }
         Ok(())
     }
-}
 
-impl Display for Table {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    /// Set each key of an internal node to the first key under the child
+    /// after it, returning the first key under the node. Removes before
+    /// format version 12 could leave stale keys, and rebuilt nodes have none.
+    fn upgrade_keys(&self, node_num: usize) -> SqlResult<Option<i64>> {
+        match self.node(node_num)?.as_typed()? {
+            NodeRef::Internal(_) => {
+                let internal = self.internal_mut(node_num)?;
+                let mut first_key = None;
+                for i in 0..internal.get_num_children() {
+                    let key = self.upgrade_keys(internal.get_child_at(i)?)?;
+                    match key {
+                        _ if i == 0 => first_key = key,
+                        Some(key) => internal.set_key_at(i - 1, key)?,
+                        None => {}
+                    }
+                }
+                Ok(first_key)
+            }
+            NodeRef::Leaf(leaf) => leaf.get_first_key(),
+        }
+    }
+
+    /// Point each leaf back to the one before it and rebuild the leaves of
+    /// format versions 11 to 14 with their keys in the slots, returning the
+    /// cells no longer fitting behind the longer header of version 13 to be
+    /// inserted again.
+    fn upgrade_leaf_cells(&mut self, from: u32) -> SqlResult<Vec<(i64, Vec<u8>)>> {
+        let mut leaves = Vec::new();
+        self.collect_leaves(self.get_root_num()?, &mut leaves)?;
+        let mut spilled = Vec::new();
+        let mut prev_leaf = MISSING_NODE;
+        for leaf_num in leaves {
+            let leaf = self.leaf_mut(leaf_num)?;
+            if from >= VARIABLE_CELL_VERSION {
+                let cells = if from < LINKED_LEAF_VERSION {
+                    leaf.unlinked_cells()?
+                } else {
+                    leaf.linked_cells()?
+                };
+                leaf.clear();
+                for (key, value) in cells {
+                    let cell = match &value {
+                        CellValue::Inline(value)
+                            if value.len() > max_inline_value(self.pager.page_size) =>
+                        {
+                            spilled.push((key, value.clone()));
+                            continue;
+                        }
+                        CellValue::Inline(value) => inline_cell(key, value),
+                        &CellValue::Overflow { first_page, len } => {
+                            overflow_cell(key, len, first_page)
+                        }
+                    };
+                    let num_cells = leaf.get_num_cells();
+                    if num_cells < LEAF_NODE_MAX_CELLS && leaf.fits(cell.len())? {
+                        leaf.insert_cell(num_cells, &cell)?;
+                        continue;
+                    }
+                    let value = match value {
+                        CellValue::Inline(value) => value,
+                        CellValue::Overflow { first_page, len } => {
+                            let value = overflow::read_chain(&self.pager, None, first_page, len)?;
+                            overflow::free_chain(&self.pager, first_page, len)?;
+                            value
+                        }
+                    };
+                    spilled.push((key, value));
+                }
+            }
+            leaf.set_prev_leaf(prev_leaf);
+            prev_leaf = leaf_num;
+        }
+        Ok(spilled)
+    }
+    /// Every node of the tree depth first, as `.btree` shows it. Unlike
+    /// formatting the table, a corrupt page fails with its error.
+    pub fn dump(&self) -> SqlResult<String> {
         fn indent(buf: &str, indent_size: usize) -> String {
             let mut buf = buf.to_owned();
             let indent = " ".repeat(indent_size);
@@ -177,57 +780,3112 @@ impl Display for Table {
                 buf.replace("\n", &format!("\n{}", indent))
             )
         }
-        fn print_table(
-            f: &mut Formatter<'_>,
-            table: &Table,
-            node_num: usize,
-            visited: &mut Vec<bool>,
-            indent_size: usize,
-        ) -> std::fmt::Result {
-            if visited[node_num] {
-                write!(f, "Node[{}] <visited>\n", node_num)?;
-                return Ok(());
+        let mut out = format!("Table {{ root_page_num: {} }}\n", self.get_root_num()?);
+        // Depth first with a stack, so that a cycle in a corrupt tree is
+        // printed once rather than followed
+        let mut visited = vec![false; self.pager.num_pages.get()];
+        let mut stack = vec![(self.get_root_num()?, 0)];
+        while let Some((node_num, indent_size)) = stack.pop() {
+            if visited.get(node_num) != Some(&false) {
+                let state = if node_num < visited.len() {
+                    "visited"
+                } else {
+                    "missing"
+                };
+                let line = format!("Node[{}] <{}>", node_num, state);
+                out.push_str(&indent(&line, indent_size));
+                continue;
             }
             visited[node_num] = true;
-            let node = table.pager.node(node_num).unwrap();
-            let buf = format!("Node {} {}", node_num, node);
-            let buf = indent(&buf, indent_size);
-            write!(f, "{}", buf)?;
-            if let NodeRef::Internal(internal) = node.as_typed() {
-                for i in 0..internal.get_num_keys() {
-                    print_table(f, table, internal.get_child_at(i), visited, indent_size + 2)?;
+            let node = self.node(node_num)?;
+            let line = format!("Node {} {}", node_num, node.describe()?);
+            out.push_str(&indent(&line, indent_size));
+            if let NodeRef::Internal(internal) = node.as_typed()? {
+                for (child, _) in internal.children()?.into_iter().rev() {
+                    stack.push((child, indent_size + 2));
                 }
             }
-            Ok(())
         }
-        writeln!(
-            f,
-            "Table {{ root_page_num: {} }}",
-            self.get_root_num().unwrap()
-        )?;
-        let mut visited = vec![false; self.pager.num_pages.get()];
-        print_table(f, self, self.get_root_num().unwrap(), &mut visited, 0)?;
+        Ok(out)
+    }
+    /// Format, size and history of the file
+    pub fn db_info(&self) -> SqlResult<DbInfo> {
+        let meta = self.meta_ref()?;
+        Ok(DbInfo {
+            version: meta.get_version(),
+            page_size: self.pager.page_size,
+            page_count: self.pager.num_pages.get(),
+            root_page: meta.get_root_num(),
+            created: meta.get_created(),
+            modified: meta.get_modified(),
+            write_count: meta.get_write_count(),
+        })
+    }
+    /// Shape of the tree and how full its leaves are
+    pub fn tree_stats(&self) -> SqlResult<TreeStats> {
+        let mut stats = TreeStats::default();
+        self.add_tree_stats(self.get_root_num()?, 1, &mut stats)?;
+        Ok(stats)
+    }
+    fn add_tree_stats(
+        &self,
+        node_num: usize,
+        depth: usize,
+        stats: &mut TreeStats,
+    ) -> SqlResult<()> {
+        stats.depth = stats.depth.max(depth);
+        match self.node(node_num)?.as_typed()? {
+            NodeRef::Internal(internal) => {
+                for (child, _) in internal.children()? {
+                    self.add_tree_stats(child, depth + 1, stats)?;
+                }
+            }
+            NodeRef::Leaf(leaf) => {
+                stats.leaves += 1;
+                stats.cells += leaf.get_num_cells();
+                stats.used += leaf.used_space()?;
+                stats.space += leaf.cell_space();
+            }
+        }
+        Ok(())
+    }
+    /// Leaves under a node in key order
+    fn collect_leaves(&self, node_num: usize, leaves: &mut Vec<usize>) -> SqlResult<()> {
+        match self.node(node_num)?.as_typed()? {
+            NodeRef::Internal(internal) => {
+                for i in 0..internal.get_num_children() {
+                    self.collect_leaves(internal.get_child_at(i)?, leaves)?;
+                }
+            }
+            NodeRef::Leaf(_) => leaves.push(node_num),
+        }
         Ok(())
     }
-}
 
-#[cfg(test)]
-mod test {
-    use crate::test::init_test_db;
+    /// Re-execute journaled statements not yet applied to the pages
+    fn replay(&mut self, entries: Vec<(u64, String)>) -> SqlResult<()> {
+        let applied = self.meta_ref()?.get_journal_seq();
+        for (seq, statement) in entries {
+            if seq <= applied {
+                continue;
+            }
+            prepare_statement(&statement)?.execute(self)?;
+            self.meta_mut()?.set_journal_seq(seq);
+        }
+        Ok(())
+    }
 
-    #[test]
-    fn find_leaf() {
-        let db = "find_leaf";
-        let mut table = init_test_db(db);
-        let node = table.leaf_mut(0).unwrap();
-        node.set_key(0, 2);
-        node.set_key(1, 3);
-        node.set_key(2, 5);
-        node.set_num_cells(3);
-        println!("{}", node.node_ref.node);
-        assert_eq!(table.find_leaf(0, 1).unwrap().cell_num, 0);
-        assert_eq!(table.find_leaf(0, 2).unwrap().cell_num, 0);
-        assert_eq!(table.find_leaf(0, 3).unwrap().cell_num, 1);
-        assert_eq!(table.find_leaf(0, 5).unwrap().cell_num, 2);
+    /// Append a successful mutation to the journal in statement form
+    fn log_statement(&mut self, statement: &str) -> SqlResult<()> {
+        let meta = self.pager.node(META_NODE_NUM)?.meta_node_mut();
+        meta.record_write(unix_time());
+        let journal = match &mut self.journal {
+            Some(journal) => journal,
+            None => return Ok(()),
+        };
+        let seq = meta.node_erf.get_journal_seq() + 1;
+        journal.append(seq, statement, self.durability == Durability::OnCommit)?;
+        meta.set_journal_seq(seq);
+        Ok(())
+    }
+
+    /// Shrink the cache to its size once a statement is done. Changed pages
+    /// kept for the WAL are flushed through it when they alone overflow the
+    /// cache, so that they can be evicted.
+    fn fit_cache(&mut self) -> SqlResult<()> {
+        self.pager.evict()?;
+        let overflowing = match self.pager.cache_size.get() {
+            Some(cache_size) => self.pager.resident_pages() > cache_size,
+            None => false,
+        };
+        if overflowing && self.pager.keep_dirty.get() {
+            self.flush_all()?;
+            self.pager.evict()?;
+        }
+        Ok(())
+    }
+
+    /// Insert a new row, failing with DuplicateKey if the id exists
+    pub fn insert(&mut self, row: Row) -> SqlResult<()> {
+        let cursor = match self.append_leaf(row.id)? {
+            Some(leaf_num) => self.find_leaf(leaf_num, row.id)?,
+            None => self.find(row.id)?,
+        };
+        if cursor.check_key(row.id)? {
+            return Err(SqlError::DuplicateKey);
+        }
+        cursor.insert(row.id, &row.serialize())?;
+        // A split leaves the cursor behind, and the next insert descends
+        let leaf = cursor.table.leaf_ref(cursor.page_num)?;
+        let hint = if cursor.generation.get() == cursor.table.generation()
+            && leaf.get_next_leaf() == MISSING_NODE
+        {
+            let last_key = leaf.get_key(leaf.get_num_cells() - 1)?;
+            Some((cursor.page_num, last_key, cursor.table.generation()))
+        } else {
+            None
+        };
+        self.append_hint.set(hint);
+        self.log_statement(&format!(
+            "insert {} {} {}",
+            row.id,
+            row.name_str(),
+            row.email_str()
+        ))?;
+        // Pages held during the change could not be evicted until now
+        self.fit_cache()?;
+        self.fire_hook(ChangeEvent {
+            kind: ChangeKind::Insert,
+            key: row.id,
+            old_row: None,
+        });
+        Ok(())
+    }
+
+    /// Rightmost leaf for a key past its last one, if the last insert left
+    /// it there and the tree has not changed since
+    fn append_leaf(&self, key: i64) -> SqlResult<Option<usize>> {
+        let (leaf_num, last_key, generation) = match self.append_hint.get() {
+            Some(hint) => hint,
+            None => return Ok(None),
+        };
+        if generation != self.generation() || key <= last_key {
+            return Ok(None);
+        }
+        let rightmost = self.leaf_ref(leaf_num)?.get_next_leaf() == MISSING_NODE;
+        Ok(rightmost.then_some(leaf_num))
+    }
+
+    /// Row with the id, if any
+    pub fn get(&mut self, id: i64) -> SqlResult<Option<Row>> {
+        let cursor = self.find(id)?;
+        if !cursor.check_key(id)? {
+            return Ok(None);
+        }
+        let row = Row::deserialize(&cursor.get()?.get_value()?);
+        Ok(Some(row))
+    }
+
+    /// Rows for many ids, in the order requested. Sorted ids are looked up in
+    /// one pass, descending from the root only when an id is past the current leaf.
+    pub fn get_many(&mut self, ids: &[i64]) -> SqlResult<Vec<Option<Row>>> {
+        let mut order = (0..ids.len()).collect::<Vec<_>>();
+        order.sort_by_key(|&i| ids[i]);
+        let mut rows = vec![None; ids.len()];
+        // (page_num, last key, is rightmost) of the leaf the cursor is on
+        let mut current: Option<(usize, i64, bool)> = None;
+        for i in order {
+            let id = ids[i];
+            let page_num = match current {
+                Some((page_num, last_key, is_rightmost)) if id <= last_key || is_rightmost => {
+                    page_num
+                }
+                _ => self.find(id)?.page_num,
+            };
+            let leaf = self.leaf_ref(page_num)?;
+            let num_cells = leaf.get_num_cells();
+            current = match num_cells {
+                0 => None,
+                _ => Some((
+                    page_num,
+                    leaf.get_key(num_cells - 1)?,
+                    leaf.get_next_leaf() == MISSING_NODE,
+                )),
+            };
+            let cursor = self.find_leaf(page_num, id)?;
+            if cursor.check_key(id)? {
+                rows[i] = Some(Row::deserialize(&cursor.get()?.get_value()?));
+            }
+        }
+        Ok(rows)
+    }
+
+    /// Replace an existing row, failing with NoData if the id is missing
+    pub fn update(&mut self, row: Row) -> SqlResult<()> {
+        let cursor = self.find(row.id)?;
+        if !cursor.check_key(row.id)? {
+            return Err(SqlError::NoData);
+        }
+        let old_row = Row::deserialize(&cursor.get()?.get_value()?);
+        cursor.update(&row.serialize())?;
+        self.log_statement(&format!(
+            "update {} {} {}",
+            row.id,
+            row.name_str(),
+            row.email_str()
+        ))?;
+        self.fit_cache()?;
+        self.fire_hook(ChangeEvent {
+            kind: ChangeKind::Update,
+            key: row.id,
+            old_row: Some(old_row),
+        });
+        Ok(())
+    }
+
+    /// Replace one field of an existing row, keeping the bytes of the others
+    /// as they are, failing with NoData if the id is missing
+    pub fn update_field(&mut self, id: i64, field: Field, value: &str) -> SqlResult<Row> {
+        let range = field.range();
+        if value.len() > range.len() - 1 {
+            return Err(SqlError::TooLargeString(field.name().to_string()));
+        }
+        let cursor = self.find(id)?;
+        if !cursor.check_key(id)? {
+            return Err(SqlError::NoData);
+        }
+        let old_row = Row::deserialize(&cursor.get()?.get_value()?);
+        cursor.update_field(range, value.as_bytes())?;
+        let row = Row::deserialize(&cursor.get()?.get_value()?);
+        self.log_statement(&format!("update {} {}={}", id, field.name(), value))?;
+        self.fit_cache()?;
+        self.fire_hook(ChangeEvent {
+            kind: ChangeKind::Update,
+            key: id,
+            old_row: Some(old_row),
+        });
+        Ok(row)
+    }
+
+    /// Remove the row with the id, returning it if it existed
+    pub fn delete(&mut self, id: i64) -> SqlResult<Option<Row>> {
+        let cursor = self.find(id)?;
+        if !cursor.check_key(id)? {
+            return Ok(None);
+        }
+        let old_row = Row::deserialize(&cursor.get()?.get_value()?);
+        cursor.remove()?;
+        self.log_statement(&format!("delete {}", id))?;
+        self.fit_cache()?;
+        self.fire_hook(ChangeEvent {
+            kind: ChangeKind::Delete,
+            key: id,
+            old_row: Some(old_row.clone()),
+        });
+        Ok(Some(old_row))
+    }
+
+    /// Fill an empty table with rows in increasing key order, writing each
+    /// leaf and then each level of internal nodes once, left to right. The
+    /// rows are not journaled, so the pages are written out before
+    /// returning, and replicas following the journal do not see them. With
+    /// a WAL they stay cached until then, whatever the cache size. Each
+    /// row counts as a write and fires the hook as an insert. Rows out of
+    /// order leave the table empty again.
+    pub fn bulk_load(&mut self, rows: impl Iterator<Item = Row>) -> SqlResult<usize> {
+        self.bulk_load_values(rows.map(|row| Ok((row.id, row.serialize().to_vec()))))
+    }
+    /// `bulk_load` of keys with any values, long ones going to overflow
+    /// pages. An error from `values` stops the load like rows out of order.
+    pub fn bulk_load_values(
+        &mut self,
+        values: impl Iterator<Item = SqlResult<(i64, Vec<u8>)>>,
+    ) -> SqlResult<usize> {
+        let first_num = self.get_root_num()?;
+        if self.len()? > 0 || !self.pager.node(first_num)?.is_leaf() {
+            return Err(SqlError::NotEmpty);
+        }
+        let mut pages = Vec::new();
+        self.bump_generation();
+        let loaded = self
+            .load_leaves(first_num, values, &mut pages)
+            .and_then(|leaves| {
+                let count = leaves.iter().map(|&(_, _, count)| count).sum::<usize>();
+                Ok((self.load_levels(leaves, &mut pages)?, count))
+            });
+        let (root_num, count) = match loaded {
+            Ok(loaded) => loaded,
+            Err(e) => {
+                for page_num in pages {
+                    self.pager.free_page(page_num)?;
+                }
+                let first = self.leaf_mut(first_num)?;
+                first.clear();
+                first.set_next_leaf(MISSING_NODE);
+                return Err(e);
+            }
+        };
+        if root_num != first_num {
+            self.pager.node(first_num)?.set_root(false);
+            self.pager.node(root_num)?.set_root(true);
+            self.set_root_num(root_num)?;
+        }
+        self.set_row_count(count)?;
+        let end = self.end()?;
+        if !end.end_of_table {
+            let last_key = end.get()?.get_key()?;
+            if last_key >= self.get_next_id()? {
+                self.set_next_id(last_key.saturating_add(1))?;
+            }
+        }
+        self.meta_mut()?.record_writes(count, unix_time());
+        self.flush_all()?;
+        if self.hook.is_some() {
+            for key in self.keys()?.collect::<SqlResult<Vec<_>>>()? {
+                self.fire_hook(ChangeEvent {
+                    kind: ChangeKind::Insert,
+                    key,
+                    old_row: None,
+                });
+            }
+        }
+        Ok(count)
+    }
+    /// Leaves filled with the values from `first_num` on, each with its
+    /// first key and number of cells. Overflow pages go to `pages` with the
+    /// nodes, to be freed if the load fails.
+    fn load_leaves(
+        &self,
+        first_num: usize,
+        values: impl Iterator<Item = SqlResult<(i64, Vec<u8>)>>,
+        pages: &mut Vec<usize>,
+    ) -> SqlResult<Vec<(usize, i64, usize)>> {
+        let fill = self.bulk_fill.get();
+        let max_cells = (self.node_limits().leaf_max_cells() * fill / 100).max(1);
+        let mut leaf_num = first_num;
+        let mut leaf = self.leaf_mut(first_num)?;
+        let max_space = leaf.cell_space() * fill / 100;
+        let mut leaves = Vec::new();
+        let mut last_key = None;
+        for (position, value) in values.enumerate() {
+            let (key, value) = value?;
+            if last_key.is_some_and(|last_key| key <= last_key) {
+                return Err(SqlError::UnsortedRows { position, key });
+            }
+            last_key = Some(key);
+            let cell = if value.len() <= max_inline_value(self.pager.page_size) {
+                inline_cell(key, &value)
+            } else {
+                let first_page = overflow::write_chain(&self.pager, &value)?;
+                pages.extend(overflow::chain_pages(&self.pager, first_page, value.len())?);
+                overflow_cell(key, value.len(), first_page)
+            };
+            let num_cells = leaf.get_num_cells();
+            let full = num_cells >= max_cells
+                || leaf.used_space()? + LEAF_NODE_OFFSET_SIZE + cell.len() > max_space
+                || !leaf.fits(cell.len())?;
+            if num_cells > 0 && full {
+                let next_num = self.pager.alloc_page()?;
+                pages.push(next_num);
+                let next = self.pager.node(next_num)?.init_leaf();
+                leaf.set_next_leaf(next_num);
+                next.set_prev_leaf(leaf_num);
+                leaves.push((leaf_num, leaf.get_key(0)?, num_cells));
+                leaf_num = next_num;
+                leaf = next;
+                // Only the leaf being filled is still needed
+                self.pager.evict()?;
+            }
+            leaf.insert_cell(leaf.get_num_cells(), &cell)?;
+        }
+        // No rows leave the first leaf empty, and no key is read from it
+        let first_key = leaf.get_first_key()?.unwrap_or_default();
+        leaves.push((leaf_num, first_key, leaf.get_num_cells()));
+        Ok(leaves)
+    }
+    /// Build internal nodes over `level` until one node is left, the root.
+    /// The nodes of a level share the children as evenly as they can.
+    fn load_levels(
+        &self,
+        mut level: Vec<(usize, i64, usize)>,
+        pages: &mut Vec<usize>,
+    ) -> SqlResult<usize> {
+        let max_children =
+            (self.node_limits().internal_max_cells() * self.bulk_fill.get() / 100).max(3);
+        while level.len() > 1 {
+            let num_nodes = level.len().div_ceil(max_children);
+            let mut next_level: Vec<(usize, i64, usize)> = Vec::with_capacity(num_nodes);
+            let mut rest = &level[..];
+            for i in 0..num_nodes {
+                let (group, after) = rest.split_at(rest.len().div_ceil(num_nodes - i));
+                rest = after;
+                let node_num = self.pager.alloc_page()?;
+                pages.push(node_num);
+                let node = self.pager.node(node_num)?.init_internal();
+                let children = group
+                    .iter()
+                    .map(|&(child, _, count)| (child, count))
+                    .collect::<Vec<_>>();
+                let keys = group[1..]
+                    .iter()
+                    .map(|&(_, key, _)| key)
+                    .collect::<Vec<_>>();
+                node.set_cells(&children, &keys)?;
+                for &(child, _) in &children {
+                    self.pager.node(child)?.set_parent(node_num);
+                }
+                if let Some(&(prev_num, _, _)) = next_level.last() {
+                    node.set_prev_internal(prev_num);
+                    self.internal_mut(prev_num)?.set_next_internal(node_num);
+                }
+                let count = children.iter().map(|&(_, count)| count).sum();
+                next_level.push((node_num, group[0].1, count));
+            }
+            self.pager.evict()?;
+            level = next_level;
+        }
+        Ok(level[0].0)
+    }
+    /// Percentage of each node `bulk_load` fills
+    pub fn bulk_fill(&self) -> usize {
+        self.bulk_fill.get()
+    }
+    /// Fill nodes loaded in bulk to a percentage from 1 to 100
+    pub fn set_bulk_fill(&self, percent: usize) -> SqlResult<()> {
+        if !(1..=100).contains(&percent) {
+            return Err(SqlError::InvalidArgs);
+        }
+        self.bulk_fill.set(percent);
+        Ok(())
+    }
+
+    /// Register a hook called after every row changed by a statement
+    pub fn set_hook(&mut self, hook: impl FnMut(ChangeEvent) + 'static) {
+        self.hook = Some(Box::new(hook));
+    }
+    fn fire_hook(&mut self, event: ChangeEvent) {
+        if let Some(hook) = &mut self.hook {
+            hook(event);
+        }
+    }
+
+    /// Write pages from a worker thread writing to `storage`, which must hold
+    /// the same pages as the storage of this table
+    pub fn start_flusher<S: Storage + Send + 'static>(&mut self, storage: S) -> SqlResult<()> {
+        self.pager.start_flusher(storage)
+    }
+
+    /// Write every changed page to the file and empty the journal.
+    /// Every page is tried, and the failed ones are reported together.
+    /// With a background flusher the pages are only queued, and the logs are
+    /// kept until `sync` has waited for the writes.
+    /// The file is synced only with `Durability::OnCommit`.
+    pub fn flush_all(&mut self) -> SqlResult<()> {
+        let wait = !self.pager.has_flusher();
+        self.write_back(wait, self.durability == Durability::OnCommit)
+    }
+    /// Flush every changed page and wait until the disk holds them,
+    /// whatever the durability
+    pub fn sync(&mut self) -> SqlResult<()> {
+        self.write_back(true, true)
+    }
+    fn write_back(&mut self, wait: bool, durable: bool) -> SqlResult<()> {
+        self.pager.bump_changes()?;
+        let num_pages = self.pager.num_pages.get();
+        self.commit_wal(durable)?;
+        let mut failures = self.pager.flush_dirty();
+        if !wait && failures.is_empty() {
+            return Ok(());
+        }
+        failures.extend(self.pager.drain()?);
+        if durable {
+            self.pager.sync()?;
+        }
+        if !failures.is_empty() {
+            // Written pages can be read back, so only the failed ones stay cached
+            for i in 0..num_pages {
+                if !failures.iter().any(|&(page, _)| page == i) {
+                    self.pager.uncache(i);
+                }
+            }
+            return Err(SqlError::CloseIncomplete(failures));
+        }
+        if let Some(journal) = &mut self.journal {
+            journal.truncate(durable)?;
+        }
+        if let Some(wal) = &mut self.wal {
+            wal.truncate(durable)?;
+        }
+        Ok(())
+    }
+
+    /// Append every changed page to the WAL, if any, and commit them there,
+    /// before they are written in place
+    pub(crate) fn commit_wal(&mut self, durable: bool) -> SqlResult<()> {
+        let wal = match &mut self.wal {
+            Some(wal) => wal,
+            None => return Ok(()),
+        };
+        let pages = (0..self.pager.num_pages.get())
+            .filter(|&i| self.pager.is_dirty(i))
+            .map(|i| {
+                let mut buf = self.pager.read_page(i)?;
+                self.pager.seal_page(i, &mut buf)?;
+                Ok((i, buf))
+            })
+            .collect::<SqlResult<Vec<_>>>()?;
+        wal.commit(&pages, durable)
+    }
+
+    /// Write a consistent copy of the database, including changes not yet
+    /// flushed, while the table stays open. The meta page is written last so
+    /// the copy's root points at pages already written.
+    /// A journal left next to `path` would be replayed onto the copy, so it is removed.
+    /// Returns the change counter to take a delta from with `backup_incremental`.
+    #[cfg(feature = "fs")]
+    pub fn backup_to(&self, path: &str) -> SqlResult<u64> {
+        let file = File::create(path)
+            .map_err(|e| SqlError::IOError(e, format!("Failed to create backup: {}", path)))?;
+        let mut dest = FileStorage::new(file);
+        for page_num in (1..self.pager.num_pages.get()).chain([META_NODE_NUM]) {
+            let mut buf = self.pager.read_page(page_num)?;
+            self.pager.seal_page(page_num, &mut buf)?;
+            dest.write_page(page_num, &buf)?;
+        }
+        dest.sync()?;
+        for stale in [journal_path(path), wal_path(path), changes_path(path)] {
+            match std::fs::remove_file(stale) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                    return Err(SqlError::IOError(
+                        e,
+                        "Failed to remove stale log".to_string(),
+                    ))
+                }
+                _ => {}
+            }
+        }
+        Ok(self.pager.bump_changes()?.unwrap_or(0))
+    }
+
+    /// Write the pages changed since a backup that returned `since`, with the
+    /// meta page, to a delta file at `path`. `changes::apply_delta` writes
+    /// them onto that backup. Returns the change counter for the next delta.
+    #[cfg(feature = "fs")]
+    pub fn backup_incremental(&self, path: &str, since: u64) -> SqlResult<u64> {
+        let changed = self
+            .pager
+            .changed_since(since)
+            .ok_or(SqlError::UnknownChanges { since })?;
+        let num_pages = self.pager.num_pages.get();
+        // Cached pages may hold changes not written yet
+        let pages = (1..num_pages)
+            .filter(|n| self.pager.is_cached(*n) || changed.binary_search(n).is_ok())
+            .chain([META_NODE_NUM])
+            .map(|page_num| {
+                let mut buf = self.pager.read_page(page_num)?;
+                self.pager.seal_page(page_num, &mut buf)?;
+                Ok((page_num, buf))
+            })
+            .collect::<SqlResult<Vec<_>>>()?;
+        let counter = self.pager.bump_changes()?.unwrap_or(0);
+        let delta = encode_delta(self.pager.page_size, since, counter, num_pages, &pages);
+        let mut file = File::create(path)
+            .map_err(|e| SqlError::IOError(e, format!("Failed to create delta: {}", path)))?;
+        file.write_all(&delta)
+            .and_then(|_| file.sync_data())
+            .map_err(|e| SqlError::IOError(e, format!("Failed to write delta: {}", path)))?;
+        Ok(counter)
+    }
+
+    /// Rebuild the rows into a new database file, which must not exist yet.
+    /// Rows are streamed in key order into `bulk_load`, so the copy has no
+    /// half-empty leaves left by random inserts. This table is only read.
+    #[cfg(feature = "fs")]
+    pub fn copy_to(&mut self, path: &str) -> SqlResult<CopyStats> {
+        let mut dest = Table::create_new(path)?;
+        let mut cursor = self.start()?;
+        let values = std::iter::from_fn(|| {
+            if cursor.end_of_table {
+                return None;
+            }
+            let value = cursor.get().and_then(|value| {
+                let key = value.get_key()?;
+                Ok((key, value.get_value()?.to_vec()))
+            });
+            Some(value.and_then(|value| {
+                cursor.advance()?;
+                Ok(value)
+            }))
+        });
+        let rows = dest.bulk_load_values(values)?;
+        dest.set_next_id(self.get_next_id()?)?;
+        dest.meta_mut()?.set_user_entries(&self.meta_entries()?)?;
+        let pages = dest.pager.num_pages.get();
+        dest.close()?;
+        Ok(CopyStats { rows, pages })
+    }
+
+    /// Rewrite the database at `old_path`, of this or any older format, into
+    /// a new file at `new_path` in the current format. The old file is read
+    /// into memory, where its pages are converted, and left untouched. The
+    /// new file must not exist, and is removed again if its rows do not
+    /// match the old ones.
+    #[cfg(feature = "fs")]
+    pub fn migrate(old_path: &str, new_path: &str) -> SqlResult<CopyStats> {
+        if std::path::Path::new(new_path).exists() {
+            let e = std::io::Error::from(std::io::ErrorKind::AlreadyExists);
+            return Err(open_error(e, new_path));
+        }
+        let mut old = Table::load_into_memory(old_path, usize::MAX)?;
+        let expected = old.len()?;
+        let copied = old.copy_to(new_path).and_then(|stats| {
+            let rows = Table::open(new_path)?.count_rows()?;
+            if stats.rows != expected || rows != expected {
+                return Err(SqlError::CheckFailed(format!(
+                    "{} rows migrated, {} in the new file, {} expected",
+                    stats.rows, rows, expected
+                )));
+            }
+            Ok(stats)
+        });
+        if copied.is_err() {
+            for path in [
+                new_path.to_string(),
+                journal_path(new_path),
+                wal_path(new_path),
+                changes_path(new_path),
+            ] {
+                let _ = std::fs::remove_file(path);
+            }
+        }
+        copied
+    }
+
+    /// Flush all pages, syncing unless durability is off.
+    /// Closing an already closed table does nothing.
+    pub fn close(&mut self) -> SqlResult<()> {
+        if self.pager.closed.get() {
+            return Ok(());
+        }
+        self.vacuum_with(self.durability != Durability::Off)?;
+        self.release();
+        Ok(())
+    }
+    /// Flush all pages and give the free pages at the end of the file back.
+    /// The free list stops pointing at them before they are cut off, so a
+    /// crash in between leaves unused pages rather than a broken list.
+    pub fn vacuum(&mut self) -> SqlResult<()> {
+        self.vacuum_with(self.durability == Durability::OnCommit)
+    }
+    fn vacuum_with(&mut self, durable: bool) -> SqlResult<()> {
+        let num_pages = self.pager.trim_free_pages()?;
+        if num_pages < self.pager.num_pages.get() {
+            // Written before the file is cut, so a crash in between leaves
+            // extra pages past the recorded count rather than missing ones
+            self.meta_mut()?.set_page_count(num_pages);
+        }
+        self.write_back(true, durable)?;
+        if num_pages < self.pager.num_pages.get() {
+            self.pager.truncate(num_pages)?;
+            if durable {
+                self.pager.sync()?;
+            }
+        }
+        Ok(())
+    }
+    /// Drop cached pages without writing them and mark the table closed
+    pub fn release(&mut self) {
+        for i in 0..self.pager.num_pages.get() {
+            self.pager.drop(i);
+        }
+        self.pager.closed.set(true);
+    }
+
+    pub fn start(&mut self) -> SqlResult<Cursor> {
+        let mut cursor = self.find(i64::MIN)?;
+        if !cursor.has_cell()? {
+            cursor.end_of_table = true;
+        }
+        Ok(cursor)
+    }
+
+    /// Number of rows, read from the counter in the meta page
+    pub fn len(&self) -> SqlResult<usize> {
+        let meta = self.meta_ref()?;
+        Ok(meta.get_row_count())
+    }
+    pub fn is_empty(&self) -> SqlResult<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Count rows by scanning the keys of the leaf chain
+    pub fn count_rows(&mut self) -> SqlResult<usize> {
+        let mut count = 0;
+        for key in self.keys()? {
+            key?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Row at position `k` in key order, counting from 0 like `Iterator::nth`.
+    /// Descends using the row counts of internal cells.
+    pub fn nth(&self, k: usize) -> SqlResult<Option<Row>> {
+        let mut k = k;
+        let mut page_num = self.get_root_num()?;
+        loop {
+            match self.node(page_num)?.as_typed()? {
+                NodeRef::Internal(internal) => {
+                    let mut next = None;
+                    for i in 0..internal.get_num_children() {
+                        let count = internal.get_count_at(i)?;
+                        if k < count {
+                            next = Some(internal.get_child_at(i)?);
+                            break;
+                        }
+                        k -= count;
+                    }
+                    match next {
+                        Some(child) => page_num = child,
+                        None => return Ok(None),
+                    }
+                }
+                NodeRef::Leaf(leaf) => {
+                    if k >= leaf.get_num_cells() {
+                        return Ok(None);
+                    }
+                    let value = overflow::read_value(&self.pager, None, &leaf, k)?;
+                    return Ok(Some(Row::deserialize(&value)));
+                }
+            }
+        }
+    }
+
+    /// Number of rows under a node
+    pub fn subtree_count(&self, node_num: usize) -> SqlResult<usize> {
+        Ok(match self.node(node_num)?.as_typed()? {
+            NodeRef::Internal(internal) => internal.total_count()?,
+            NodeRef::Leaf(leaf) => leaf.get_num_cells(),
+        })
+    }
+    /// Store the row count of a node in its parent, and so on up to the root
+    pub fn update_counts_up(&self, node_num: usize) -> SqlResult<()> {
+        let mut node_num = node_num;
+        loop {
+            let node = self.pager.node(node_num)?;
+            if node.is_root() {
+                return Ok(());
+            }
+            let parent_num = node.get_parent();
+            let parent = self.internal_mut(parent_num)?;
+            let index = parent.find_child(node_num)?.ok_or(SqlError::CorruptFile)?;
+            parent.set_count_at(index, self.subtree_count(node_num)?)?;
+            node_num = parent_num;
+        }
+    }
+    /// Recompute the row counts of every internal cell
+    pub fn recount(&self) -> SqlResult<()> {
+        self.recount_node(self.get_root_num()?)?;
+        Ok(())
+    }
+    fn recount_node(&self, node_num: usize) -> SqlResult<usize> {
+        match self.node(node_num)?.as_typed()? {
+            NodeRef::Internal(_) => {
+                let internal = self.internal_mut(node_num)?;
+                let mut total = 0;
+                for i in 0..internal.get_num_children() {
+                    let count = self.recount_node(internal.get_child_at(i)?)?;
+                    internal.set_count_at(i, count)?;
+                    total += count;
+                }
+                Ok(total)
+            }
+            NodeRef::Leaf(leaf) => Ok(leaf.get_num_cells()),
+        }
+    }
+    /// Compare the stored row counts with the rows under each child
+    fn check_counts(&self, node_num: usize) -> SqlResult<usize> {
+        match self.node(node_num)?.as_typed()? {
+            NodeRef::Internal(internal) => {
+                let mut total = 0;
+                for i in 0..internal.get_num_children() {
+                    let rows = self.check_counts(internal.get_child_at(i)?)?;
+                    if rows != internal.get_count_at(i)? {
+                        return Err(SqlError::CheckFailed(format!(
+                            "child {} of node {} counts {} rows but has {}",
+                            i,
+                            node_num,
+                            internal.get_count_at(i)?,
+                            rows
+                        )));
+                    }
+                    total += rows;
+                }
+                Ok(total)
+            }
+            NodeRef::Leaf(leaf) => Ok(leaf.get_num_cells()),
+        }
+    }
+
+    /// Limit the pages kept in memory, writing back the ones evicted
+    pub fn set_cache_size(&self, cache_size: Option<usize>) -> SqlResult<()> {
+        self.pager.cache_size.set(cache_size);
+        self.pager.evict()
+    }
+
+    /// Most cells a node holds before it splits
+    pub fn node_limits(&self) -> NodeLimits {
+        self.pager.node_limits.get()
+    }
+    /// Split nodes at other sizes from now on. Nodes already larger keep
+    /// their cells until they next split or merge.
+    pub fn set_node_limits(&self, limits: NodeLimits) {
+        self.pager.node_limits.set(limits);
+    }
+
+    /// Scan the rows as they are now, unaffected by later changes
+    pub fn snapshot_scan(&self) -> SqlResult<SnapshotScan> {
+        SnapshotScan::new(self)
+    }
+
+    /// All keys in order
+    pub fn keys(&mut self) -> SqlResult<Keys<'_>> {
+        self.keys_range(..)
+    }
+    /// Keys within `range` in order
+    pub fn keys_range<R: RangeBounds<i64>>(&mut self, range: R) -> SqlResult<Keys<'_>> {
+        let end = range.end_bound().cloned();
+        let mut cursor = match range.start_bound() {
+            Bound::Included(&start) => self.find(start)?,
+            Bound::Excluded(&start) => {
+                let mut cursor = self.find(start)?;
+                if cursor.check_key(start)? {
+                    cursor.advance()?;
+                }
+                cursor
+            }
+            Bound::Unbounded => self.start()?,
+        };
+        cursor.skip_leaf_end()?;
+        Ok(Keys::new(cursor, end))
+    }
+
+    /// Verify the stored counters against the tree
+    pub fn check(&mut self) -> SqlResult<()> {
+        let missing = self.pager.missing_pages()?;
+        if !missing.is_empty() {
+            return Err(SqlError::CheckFailed(format!(
+                "pages {:?} are missing from the file",
+                missing
+            )));
+        }
+        let row_count = self.len()?;
+        let scanned = self.count_rows()?;
+        if row_count != scanned {
+            return Err(SqlError::CheckFailed(format!(
+                "row count is {} but scan found {} rows",
+                row_count, scanned
+            )));
+        }
+        self.check_counts(self.get_root_num()?)?;
+        self.check_leaf_chain()?;
+        self.check_internal_chain()?;
+        self.check_parents(self.get_root_num()?)?;
+        self.check_free_pages()?;
+        // Reading a page not in the cache verifies its checksum
+        for page_num in 0..self.pager.num_pages.get() {
+            self.pager.read_page(page_num)?;
+        }
+        Ok(())
+    }
+
+    /// Keys of internal nodes must increase and bound the keys under their
+    /// children, from the key before a child up to the key after it
+    fn check_keys(
+        &self,
+        node_num: usize,
+        bounds: (Option<i64>, Option<i64>),
+        leaves: &mut Vec<usize>,
+    ) -> SqlResult<()> {
+        let (lower, upper) = bounds;
+        let in_bounds = |key: i64| {
+            lower.is_none_or(|lower| lower <= key) && upper.is_none_or(|upper| key < upper)
+        };
+        match self.node(node_num)?.as_typed()? {
+            NodeRef::Internal(internal) => {
+                if internal.get_num_keys() == 0 {
+                    return Err(SqlError::CheckFailed(format!(
+                        "internal node {} has no keys",
+                        node_num
+                    )));
+                }
+                let keys = internal.keys()?;
+                for (i, &key) in keys.iter().enumerate() {
+                    if !in_bounds(key) || i > 0 && keys[i - 1] >= key {
+                        return Err(SqlError::CheckFailed(format!(
+                            "key {} of node {} is out of order",
+                            key, node_num
+                        )));
+                    }
+                }
+                for i in 0..internal.get_num_children() {
+                    let lower = if i == 0 { lower } else { Some(keys[i - 1]) };
+                    let upper = keys.get(i).copied().or(upper);
+                    self.check_keys(internal.get_child_at(i)?, (lower, upper), leaves)?;
+                }
+            }
+            NodeRef::Leaf(leaf) => {
+                for cell in 0..leaf.get_num_cells() {
+                    let key = leaf.get_key(cell)?;
+                    if !in_bounds(key) {
+                        return Err(SqlError::CheckFailed(format!(
+                            "key {} of leaf {} is outside the keys of its parent",
+                            key, node_num
+                        )));
+                    }
+                }
+                leaves.push(node_num);
+            }
+        }
+        Ok(())
+    }
+    /// The leaf chain must link the leaves of the tree in order both ways,
+    /// with their keys increasing along it
+    fn check_leaf_chain(&self) -> SqlResult<()> {
+        let mut leaves = Vec::new();
+        self.check_keys(self.get_root_num()?, (None, None), &mut leaves)?;
+        let mut last_key = None;
+        for (i, &leaf_num) in leaves.iter().enumerate() {
+            let leaf = self.leaf_ref(leaf_num)?;
+            for cell in 0..leaf.get_num_cells() {
+                let key = leaf.get_key(cell)?;
+                if last_key.is_some_and(|last_key| last_key >= key) {
+                    return Err(SqlError::CheckFailed(format!(
+                        "key {} of leaf {} is out of order",
+                        key, leaf_num
+                    )));
+                }
+                last_key = Some(key);
+            }
+            let expected = leaves.get(i + 1).copied().unwrap_or(MISSING_NODE);
+            if leaf.get_next_leaf() != expected {
+                return Err(SqlError::CheckFailed(format!(
+                    "leaf {} is followed by {} instead of {}",
+                    leaf_num,
+                    leaf.get_next_leaf(),
+                    expected
+                )));
+            }
+            let expected = if i == 0 { MISSING_NODE } else { leaves[i - 1] };
+            if leaf.get_prev_leaf() != expected {
+                return Err(SqlError::CheckFailed(format!(
+                    "leaf {} points back to {} instead of {}",
+                    leaf_num,
+                    leaf.get_prev_leaf(),
+                    expected
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Each internal node must link to the nodes before and after it on its
+    /// level, as found by walking down from the root
+    fn check_internal_chain(&self) -> SqlResult<()> {
+        let mut level = vec![self.get_root_num()?];
+        while self.pager.node(level[0])?.is_internal() {
+            let mut next_level = Vec::new();
+            for (i, &node_num) in level.iter().enumerate() {
+                let internal = self.internal_ref(node_num)?;
+                let expected = level.get(i + 1).copied().unwrap_or(MISSING_NODE);
+                if internal.get_next_internal() != expected {
+                    return Err(SqlError::CheckFailed(format!(
+                        "internal node {} is followed by {} instead of {}",
+                        node_num,
+                        internal.get_next_internal(),
+                        expected
+                    )));
+                }
+                let expected = if i == 0 { MISSING_NODE } else { level[i - 1] };
+                if internal.get_prev_internal() != expected {
+                    return Err(SqlError::CheckFailed(format!(
+                        "internal node {} points back to {} instead of {}",
+                        node_num,
+                        internal.get_prev_internal(),
+                        expected
+                    )));
+                }
+                next_level.extend(internal.children()?.into_iter().map(|(child, _)| child));
+            }
+            level = next_level;
+        }
+        Ok(())
+    }
+
+    /// Every node under an internal node must point back at it
+    fn check_parents(&self, node_num: usize) -> SqlResult<()> {
+        if let NodeRef::Internal(internal) = self.node(node_num)?.as_typed()? {
+            for (child, _) in internal.children()? {
+                let parent = self.pager.node(child)?.get_parent();
+                if parent != node_num {
+                    return Err(SqlError::CheckFailed(format!(
+                        "node {} under node {} points up at {}",
+                        child, node_num, parent
+                    )));
+                }
+                self.check_parents(child)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Free pages must not be in the tree or on the free list twice
+    fn check_free_pages(&self) -> SqlResult<()> {
+        let mut in_tree = HashSet::from([META_NODE_NUM]);
+        self.collect_pages(self.get_root_num()?, &mut in_tree)?;
+        let mut free = HashSet::new();
+        let mut page_num = self.meta_ref()?.get_free_head();
+        while page_num != MISSING_NODE {
+            if in_tree.contains(&page_num) || !free.insert(page_num) {
+                return Err(SqlError::CheckFailed(format!(
+                    "free page {} is in use",
+                    page_num
+                )));
+            }
+            if page_num >= self.pager.num_pages.get() {
+                return Err(SqlError::CheckFailed(format!(
+                    "free page {} is past the end of the file",
+                    page_num
+                )));
+            }
+            page_num = self.pager.next_free(page_num)?;
+        }
+        Ok(())
+    }
+    fn collect_pages(&self, node_num: usize, pages: &mut HashSet<usize>) -> SqlResult<()> {
+        pages.insert(node_num);
+        match self.node(node_num)?.as_typed()? {
+            NodeRef::Internal(internal) => {
+                for i in 0..internal.get_num_children() {
+                    self.collect_pages(internal.get_child_at(i)?, pages)?;
+                }
+            }
+            NodeRef::Leaf(leaf) => {
+                for i in 0..leaf.get_num_cells() {
+                    if let LeafValue::Overflow { first_page, len } = leaf.get_value(i)? {
+                        pages.extend(overflow::chain_pages(&self.pager, first_page, len)?);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Cursor on the last cell of the rightmost leaf
+    pub fn end(&mut self) -> SqlResult<Cursor> {
+        let page_num = self.descend(self.get_root_num()?, |internal| {
+            internal.get_child_at(internal.get_num_keys())
+        })?;
+        let num_cells = self.leaf_ref(page_num)?.get_num_cells();
+        Ok(Cursor {
+            generation: Cell::new(self.generation()),
+            table: self,
+            page_num,
+            cell_num: num_cells.saturating_sub(1),
+            end_of_table: num_cells == 0,
+            begin_of_table: false,
+        })
+    }
+    /// Cursor on the last cell, to walk the table backwards with `retreat`
+    pub fn start_rev(&mut self) -> SqlResult<Cursor<'_>> {
+        let mut cursor = self.end()?;
+        cursor.begin_of_table = cursor.end_of_table;
+        Ok(cursor)
+    }
+
+    pub fn find(&mut self, key: i64) -> SqlResult<Cursor> {
+        self.find_internal(self.get_root_num()?, key)
+    }
+    pub fn find_internal(&mut self, page_num: usize, key: i64) -> SqlResult<Cursor> {
+        let leaf_num = self.descend(page_num, |internal| {
+            internal.get_child_at(internal.find_key(key)?)
+        })?;
+        self.find_leaf(leaf_num, key)
+    }
+    /// Leaf reached from `page_num` through the child `pick` chooses at each
+    /// internal node. A child already passed on the way down, or a way
+    /// longer than the file has pages, means a cycle in a corrupt tree.
+    fn descend(
+        &self,
+        page_num: usize,
+        pick: impl Fn(&InternalRef) -> SqlResult<usize>,
+    ) -> SqlResult<usize> {
+        let mut trail = vec![page_num];
+        let mut page_num = page_num;
+        while let NodeRef::Internal(internal) = self.node(page_num)?.as_typed()? {
+            page_num = self.pager.check_link(page_num, pick(&internal)?)?;
+            if trail.contains(&page_num) || trail.len() >= self.pager.num_pages.get() {
+                return Err(SqlError::Corrupt {
+                    page: page_num,
+                    detail: format!("child of the pages {:?} on the way down", trail),
+                });
+            }
+            trail.push(page_num);
+        }
+        Ok(page_num)
+    }
+    pub fn find_leaf(&mut self, page_num: usize, key: i64) -> SqlResult<Cursor> {
+        let cell_num = self.leaf_ref(page_num)?.find_cell(key)?;
+        Ok(Cursor {
+            generation: Cell::new(self.generation()),
+            table: self,
+            page_num,
+            cell_num,
+            end_of_table: false,
+            begin_of_table: false,
+        })
+    }
+
+    /// Number of changes made to the tree, which cursors compare with the
+    /// one they were placed at
+    pub fn generation(&self) -> u64 {
+        self.generation.get()
+    }
+    pub fn bump_generation(&self) {
+        self.generation.set(self.generation.get() + 1);
+    }
+
+    /// Page of the tree, its header checked the first time it is read
+    /// from the file
+    pub fn node(&self, page_num: usize) -> SqlResult<Node> {
+        let node = self.pager.node(page_num)?;
+        self.check_node(&node)?;
+        Ok(node)
+    }
+    /// Page of the tree as it was when the snapshot was taken, checked
+    /// like `node`
+    pub fn snapshot_node(&self, snapshot: &Snapshot, page_num: usize) -> SqlResult<Node> {
+        let node = self.pager.snapshot_node(snapshot, page_num)?;
+        self.check_node(&node)?;
+        Ok(node)
+    }
+    fn check_node(&self, node: &Node) -> SqlResult<()> {
+        if self.check_headers.get() && !node.page.borrow().checked {
+            node.check_header(self.pager.num_pages.get())?;
+            node.page.borrow_mut().checked = true;
+        }
+        Ok(())
+    }
+    pub fn internal_mut(&self, page_num: usize) -> SqlResult<InternalMut> {
+        let node = self.node(page_num)?;
+        node.internal_node_mut()
+    }
+    pub fn leaf_mut(&self, page_num: usize) -> SqlResult<LeafMut> {
+        let node = self.node(page_num)?;
+        node.leaf_node_mut()
+    }
+    pub fn leaf_ref(&self, page_num: usize) -> SqlResult<LeafRef> {
+        let node = self.node(page_num)?;
+        node.leaf_node()
+    }
+    pub fn internal_ref(&self, page_num: usize) -> SqlResult<InternalRef> {
+        let node = self.node(page_num)?;
+        node.internal_node()
+    }
+
+    // Meta
+    pub fn meta_mut(&self) -> SqlResult<MetaMut> {
+        let node = self.pager.node(META_NODE_NUM)?;
+        Ok(node.meta_node_mut())
+    }
+    pub fn meta_ref(&self) -> SqlResult<MetaRef> {
+        let node = self.pager.node(META_NODE_NUM)?;
+        Ok(node.meta_node())
+    }
+    pub fn get_root_num(&self) -> SqlResult<usize> {
+        Ok(self.root_num.get())
+    }
+    pub fn set_root_num(&self, root_num: usize) -> SqlResult<()> {
+        let meta = self.meta_mut()?;
+        meta.set_root_num(root_num);
+        self.root_num.set(root_num);
+        Ok(())
+    }
+    pub fn set_row_count(&self, row_count: usize) -> SqlResult<()> {
+        let meta = self.meta_mut()?;
+        meta.set_row_count(row_count);
+        Ok(())
+    }
+    /// Value the application stored under `key` with `set_meta`
+    pub fn get_meta(&self, key: &str) -> SqlResult<Option<Vec<u8>>> {
+        let entries = self.meta_entries()?;
+        Ok(entries.into_iter().find(|(k, _)| k == key).map(|(_, v)| v))
+    }
+    /// Store a small value of the application in the meta page, replacing
+    /// the one under the same key. Entries share USER_META_SIZE bytes with
+    /// their keys and lengths, and MetaFull leaves them as they were.
+    /// They are written with the next flush and not journaled.
+    pub fn set_meta(&self, key: &str, value: &[u8]) -> SqlResult<()> {
+        let mut entries = self.meta_entries()?;
+        match entries.iter_mut().find(|(k, _)| k == key) {
+            Some(entry) => entry.1 = value.to_vec(),
+            None => entries.push((key.to_string(), value.to_vec())),
+        }
+        self.meta_mut()?.set_user_entries(&entries)
+    }
+    /// Remove the entry under `key`, returning whether there was one
+    pub fn delete_meta(&self, key: &str) -> SqlResult<bool> {
+        let mut entries = self.meta_entries()?;
+        let len = entries.len();
+        entries.retain(|(k, _)| k != key);
+        if entries.len() == len {
+            return Ok(false);
+        }
+        self.meta_mut()?.set_user_entries(&entries)?;
+        Ok(true)
+    }
+    /// Entries stored with `set_meta`, in the order they were first set
+    pub fn meta_entries(&self) -> SqlResult<Vec<(String, Vec<u8>)>> {
+        self.meta_ref()?.user_entries()
+    }
+    /// Id assigned to the next insert without an explicit id
+    pub fn get_next_id(&self) -> SqlResult<i64> {
+        let meta = self.meta_ref()?;
+        Ok(meta.get_next_id())
+    }
+    pub fn set_next_id(&self, next_id: i64) -> SqlResult<()> {
+        let meta = self.meta_mut()?;
+        meta.set_next_id(next_id);
+        Ok(())
+    }
+}
+
+impl Drop for Table {
+    fn drop(&mut self) {
+        if let Err(e) = self.close() {
+            eprintln!("Failed to close table: {:?}", e);
+        }
+    }
+}
+
+impl Display for Table {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.dump().map_err(|_| std::fmt::Error)?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        changes,
+        legacy::{to_legacy_leaves, widen_file, FORMAT8_FIXTURE},
+        meta::{DEFAULT_ROOT_NUM, USER_META_SIZE},
+        node::{INTERNAL_NODE_MAX_CELLS, LEAF_NODE_MAX_CELLS},
+        pager::LengthMismatch,
+        string_utils::to_string_null_terminated,
+        test::{clean_db, db_name, init_test_db, reopen_test_db},
+    };
+    use std::{cell::RefCell, rc::Rc};
+
+    #[test]
+    fn find_leaf() {
+        let db = "find_leaf";
+        let mut table = init_test_db(db);
+        let node = table.leaf_mut(DEFAULT_ROOT_NUM).unwrap();
+        for (i, key) in [2, 3, 5].into_iter().enumerate() {
+            node.insert_cell(i, &inline_cell(key, &[])).unwrap();
+        }
+        println!("{}", node.node_ref.node);
+        assert_eq!(table.find_leaf(DEFAULT_ROOT_NUM, 1).unwrap().cell_num, 0);
+        assert_eq!(table.find_leaf(DEFAULT_ROOT_NUM, 2).unwrap().cell_num, 0);
+        assert_eq!(table.find_leaf(DEFAULT_ROOT_NUM, 3).unwrap().cell_num, 1);
+        assert_eq!(table.find_leaf(DEFAULT_ROOT_NUM, 5).unwrap().cell_num, 2);
+    }
+
+    #[test]
+    fn upgrade_legacy() {
+        let db = "upgrade_legacy";
+        let mut table = init_test_db(db);
+        for i in 0..10i64 {
+            table
+                .find(i)
+                .unwrap()
+                .insert(i, &[i as u8; ROW_SIZE])
+                .unwrap();
+        }
+        assert_eq!(table.len().unwrap(), 10);
+
+        // Emulate a file written before the version field existed
+        table.set_row_count(0).unwrap();
+        table.set_next_id(0).unwrap();
+        assert!(table.check().is_err());
+        to_legacy_leaves(&table, table.get_root_num().unwrap());
+        to_legacy_layout(&table, table.get_root_num().unwrap());
+        table.meta_mut().unwrap().set_version(0);
+        table.close().unwrap();
+        drop(table);
+        to_legacy_meta(db);
+        widen_file(&db_name(db), 0);
+
+        let mut table = reopen_test_db(db);
+        assert_eq!(table.meta_ref().unwrap().get_version(), FORMAT_VERSION);
+        assert_eq!(table.len().unwrap(), 10);
+        assert_eq!(table.get_next_id().unwrap(), 10);
+        table.check().unwrap();
+    }
+    /// Rewrite the meta page of a closed database without the header of
+    /// format version 7, keeping the version it has
+    fn to_legacy_meta(db: &str) {
+        let mut data = std::fs::read(db_name(db)).unwrap();
+        let meta = data[0..DEFAULT_PAGE_SIZE].to_vec();
+        data[0..DEFAULT_PAGE_SIZE].fill(0);
+        data[0..20].copy_from_slice(&meta[16..36]);
+        data[20..24].copy_from_slice(&meta[12..16]);
+        data[24..48].copy_from_slice(&meta[36..60]);
+        std::fs::write(db_name(db), &data).unwrap();
+    }
+    #[test]
+    fn migrate_fixture() {
+        let (old, new) = (db_name("migrate_old"), db_name("migrate_new"));
+        init_test_db("migrate_new").close().unwrap();
+        std::fs::remove_file(&new).unwrap();
+        std::fs::write(&old, FORMAT8_FIXTURE).unwrap();
+
+        let stats = Table::migrate(&old, &new).unwrap();
+        assert_eq!(stats.rows, 12);
+        assert_eq!(std::fs::read(&old).unwrap(), FORMAT8_FIXTURE);
+
+        let mut source = Table::load_into_memory(&old, usize::MAX).unwrap();
+        let mut table = Table::open(&new).unwrap();
+        assert_eq!(table.meta_ref().unwrap().get_version(), FORMAT_VERSION);
+        table.check().unwrap();
+        assert_eq!(table.get_next_id().unwrap(), source.get_next_id().unwrap());
+        let rows = |table: &mut Table| {
+            prepare_statement("select")
+                .unwrap()
+                .execute(table)
+                .unwrap()
+                .iter()
+                .map(|row| row.to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(rows(&mut table), rows(&mut source));
+        table.close().unwrap();
+        drop(table);
+
+        // An existing file is not overwritten
+        let migrated = std::fs::read(&new).unwrap();
+        assert!(matches!(
+            Table::migrate(&old, &new),
+            Err(SqlError::IOError(e, _)) if e.kind() == std::io::ErrorKind::AlreadyExists
+        ));
+        assert_eq!(std::fs::read(&new).unwrap(), migrated);
+    }
+    #[test]
+    fn open_not_a_database() {
+        let db = "open_not_a_database";
+        let mut table = init_test_db(db);
+        for i in 0..20 {
+            table.find(i).unwrap().insert(i, &[1; ROW_SIZE]).unwrap();
+        }
+        table.close().unwrap();
+        drop(table);
+        let healthy = std::fs::read(db_name(db)).unwrap();
+        let mut table = reopen_test_db(db);
+        assert_eq!(table.len().unwrap(), 20);
+        table.close().unwrap();
+        drop(table);
+
+        let random = (0..2 * DEFAULT_PAGE_SIZE)
+            .map(|i| (i * 7919 % 251) as u8)
+            .collect::<Vec<_>>();
+        std::fs::write(db_name(db), random).unwrap();
+        assert!(matches!(
+            Table::open(&db_name(db)),
+            Err(SqlError::NotADatabase)
+        ));
+
+        // Cut inside a page, and after the meta page: the page count in the
+        // meta page is trusted and the check reports what is missing
+        for len in [healthy.len() - 100, DEFAULT_PAGE_SIZE] {
+            std::fs::write(db_name(db), &healthy[..len]).unwrap();
+            let mut table = Table::open(&db_name(db)).unwrap();
+            assert!(table.pager.length_mismatch.is_some());
+            assert!(matches!(table.check(), Err(SqlError::CheckFailed(_))));
+            table.release();
+        }
+    }
+    /// Rewrite internal nodes of the layout from format version 3 to 13 in
+    /// the layout before version 3
+    fn to_legacy_layout(table: &Table, node_num: usize) {
+        let node = table.pager.node(node_num).unwrap();
+        if !node.is_internal() {
+            return;
+        }
+        let internal = node.internal_node_mut().unwrap();
+        let cells = internal
+            .keyed_cells()
+            .unwrap()
+            .into_iter()
+            .map(|(child, key, _)| (child, key))
+            .collect::<Vec<_>>();
+        internal.set_legacy_cells(&cells);
+        for (child, _) in cells {
+            to_legacy_layout(table, child);
+        }
+    }
+    #[test]
+    fn upgrade_row_counts() {
+        let db = "upgrade_row_counts";
+        let mut table = init_test_db(db);
+        for i in 0..40 {
+            let row = Row::try_new(i, &format!("name{}", i), "a@a").unwrap();
+            table.insert(row).unwrap();
+        }
+        to_legacy_leaves(&table, table.get_root_num().unwrap());
+        to_legacy_layout(&table, table.get_root_num().unwrap());
+        table.meta_mut().unwrap().set_version(2);
+        table.close().unwrap();
+        drop(table);
+        widen_file(&db_name(db), 2);
+
+        let mut table = reopen_test_db(db);
+        assert_eq!(table.meta_ref().unwrap().get_version(), FORMAT_VERSION);
+        table.check().unwrap();
+        for i in 0..40 {
+            assert_eq!(table.nth(i).unwrap().unwrap().id, i as i64);
+        }
+        assert!(table.nth(40).unwrap().is_none());
+    }
+    #[test]
+    fn nth_matches_scan() {
+        let db = "nth_matches_scan";
+        let mut table = init_test_db(db);
+        assert!(table.nth(0).unwrap().is_none());
+        for i in (0..60).rev() {
+            let row = Row::try_new(i * 2, "name", "a@a").unwrap();
+            table.insert(row).unwrap();
+        }
+        let keys = table
+            .keys()
+            .unwrap()
+            .collect::<SqlResult<Vec<_>>>()
+            .unwrap();
+        for (k, key) in keys.iter().enumerate() {
+            assert_eq!(table.nth(k).unwrap().unwrap().id, *key);
+        }
+
+        // A stale count is reported by the check
+        let root = table.internal_mut(table.get_root_num().unwrap()).unwrap();
+        root.set_count_at(0, root.get_count_at(0).unwrap() + 1)
+            .unwrap();
+        assert!(matches!(table.check(), Err(SqlError::CheckFailed(_))));
+    }
+    #[test]
+    fn join_scans_smaller_table() {
+        let (a, b) = ("join_a", "join_b");
+        let mut big = init_test_db(a);
+        for i in 0..40 {
+            big.insert(Row::try_new(i, &format!("a{}", i), "a@a").unwrap())
+                .unwrap();
+        }
+        big.close().unwrap();
+        let mut small = init_test_db(b);
+        for i in 35..45 {
+            small
+                .insert(Row::try_new(i, &format!("b{}", i), "b@b").unwrap())
+                .unwrap();
+        }
+
+        let mut big = reopen_test_db(a);
+        let before = big.pager.io_stats();
+        let joined = join(&mut big, &mut small).unwrap();
+        let reads = big.pager.io_stats().reads - before.reads;
+        let ids = joined.iter().map(|row| row.left.id).collect::<Vec<_>>();
+        assert_eq!(ids, (35..40).collect::<Vec<_>>());
+        assert_eq!(joined[0].left.name_str(), "a35");
+        assert_eq!(joined[0].right.name_str(), "b35");
+        assert_eq!(
+            joined[0].to_string(),
+            "Row { id: 35, a.name: a35, a.email: a@a, b.name: b35, b.email: b@b }"
+        );
+        // The big table was probed, so only a few of its pages were read
+        assert!(reads > 0);
+        assert!(reads * 2 < big.pager.num_pages.get() as u64);
+
+        let joined = join(&mut small, &mut big).unwrap();
+        assert_eq!(joined[0].left.name_str(), "b35");
+        assert_eq!(joined.len(), 5);
+    }
+    #[test]
+    fn free_pages_reused() {
+        let db = "free_pages_reused";
+        let mut table = init_test_db(db);
+        let cycle = |table: &mut Table| {
+            for i in 0..12 {
+                table.insert(Row::try_new(i, "a", "a@a").unwrap()).unwrap();
+            }
+            // Deleting from the end merges leaves and collapses the root
+            for i in (0..12).rev() {
+                table.delete(i).unwrap();
+            }
+            table.check().unwrap();
+            table.pager.num_pages.get()
+        };
+        let pages = cycle(&mut table);
+        assert!(!table.pager.free_pages().unwrap().is_empty());
+        for _ in 0..20 {
+            assert_eq!(cycle(&mut table), pages);
+        }
+        table.close().unwrap();
+
+        // The free list survives reopening
+        let mut table = reopen_test_db(db);
+        assert_eq!(cycle(&mut table), pages);
+
+        // A page both free and in the tree is reported
+        let root = table.get_root_num().unwrap();
+        table.meta_mut().unwrap().set_free_head(root);
+        assert!(matches!(table.check(), Err(SqlError::CheckFailed(_))));
+    }
+    #[test]
+    fn vacuum_truncates() {
+        let db = "vacuum_truncates";
+        let mut table = init_test_db(db);
+        for i in 0..12 {
+            table.insert(Row::try_new(i, "a", "a@a").unwrap()).unwrap();
+        }
+        table.close().unwrap();
+        let full = std::fs::metadata(db_name(db)).unwrap().len();
+
+        let mut table = reopen_test_db(db);
+        for i in (2..12).rev() {
+            table.delete(i).unwrap();
+        }
+        table.close().unwrap();
+        let shrunk = std::fs::metadata(db_name(db)).unwrap().len();
+        assert!(shrunk < full);
+
+        let mut table = reopen_test_db(db);
+        assert_eq!(
+            table.pager.num_pages.get() as u64 * DEFAULT_PAGE_SIZE as u64,
+            shrunk
+        );
+        assert!(table.pager.length_mismatch.is_none());
+        assert_eq!(table.len().unwrap(), 2);
+        assert!(table.get(1).unwrap().is_some());
+        table.check().unwrap();
+        for i in 2..12 {
+            table.insert(Row::try_new(i, "a", "a@a").unwrap()).unwrap();
+        }
+        table.check().unwrap();
+    }
+    #[test]
+    fn zero_pages_left_as_holes() {
+        let db = "zero_pages_left_as_holes";
+        let mut table = init_test_db(db);
+        for i in 0..10 {
+            table.insert(Row::try_new(i, "a", "a@a").unwrap()).unwrap();
+        }
+        table.close().unwrap();
+        drop(table);
+
+        // Pages allocated but never filled in are not written
+        let mut table = reopen_test_db(db);
+        let full = table.pager.num_pages.get();
+        table.pager.reset_io_stats();
+        let holes = (0..3)
+            .map(|_| table.pager.alloc_page().unwrap())
+            .collect::<Vec<_>>();
+        let dirty = (0..table.pager.num_pages.get())
+            .filter(|&n| table.pager.is_dirty(n))
+            .count();
+        table.close().unwrap();
+        assert_eq!(table.pager.io_stats().writes, (dirty - holes.len()) as u64);
+        let num_pages = table.pager.num_pages.get();
+        drop(table);
+        assert_eq!(
+            std::fs::metadata(db_name(db)).unwrap().len(),
+            (num_pages * DEFAULT_PAGE_SIZE) as u64
+        );
+
+        // They read back as zeros, with the rows as they were
+        let mut table = reopen_test_db(db);
+        assert!(table.pager.length_mismatch.is_none());
+        for &page_num in &holes {
+            let page = table.pager.read_page(page_num).unwrap();
+            assert!(page.iter().all(|&b| b == 0));
+        }
+        assert_eq!(table.len().unwrap(), 10);
+        assert_eq!(table.get(7).unwrap().unwrap().id, 7);
+
+        // Freed at the end of the file, they are cut off when closing
+        for &page_num in &holes {
+            table.pager.free_page(page_num).unwrap();
+        }
+        table.close().unwrap();
+        drop(table);
+        let mut table = reopen_test_db(db);
+        assert_eq!(table.pager.num_pages.get(), full);
+        table.check().unwrap();
+    }
+    #[test]
+    fn corrupt_cell_count() {
+        let db = "corrupt_cell_count";
+        let mut table = init_test_db(db);
+        for i in 0..20 {
+            table.insert(Row::try_new(i, "a", "a@a").unwrap()).unwrap();
+        }
+        let leaf = table.find(10).unwrap().page_num;
+        table.leaf_mut(leaf).unwrap().set_num_cells(10_000);
+
+        // Reads from the leaf fail with an error naming it
+        assert!(matches!(
+            table.get(10),
+            Err(SqlError::Corrupt { page, .. }) if page == leaf
+        ));
+        let rows = crate::commands::prepare_statement("select")
+            .unwrap()
+            .execute_iter(&mut table)
+            .unwrap()
+            .collect::<SqlResult<Vec<_>>>();
+        assert!(matches!(rows, Err(SqlError::Corrupt { page, .. }) if page == leaf));
+        assert!(table.check().is_err());
+        assert!(table.get(0).unwrap().is_some());
+    }
+    #[test]
+    fn truncated_file() {
+        let db = "truncated_file";
+        let mut table = init_test_db(db);
+        for i in 0..60 {
+            table.insert(Row::try_new(i, "a", "a@a").unwrap()).unwrap();
+        }
+        table.close().unwrap();
+        let num_pages = table.pager.num_pages.get();
+        let len = std::fs::metadata(db_name(db)).unwrap().len();
+        assert_eq!(len, (num_pages * DEFAULT_PAGE_SIZE) as u64);
+
+        // Half of the last page is lost
+        let file = File::options().write(true).open(db_name(db)).unwrap();
+        file.set_len(len - DEFAULT_PAGE_SIZE as u64 / 2).unwrap();
+        drop(file);
+        let mut table = reopen_test_db(db);
+        assert_eq!(table.pager.num_pages.get(), num_pages);
+        assert_eq!(
+            table.pager.length_mismatch,
+            Some(LengthMismatch {
+                recorded_pages: num_pages,
+                file_length: len as usize - DEFAULT_PAGE_SIZE / 2,
+            })
+        );
+        match table.check() {
+            Err(SqlError::CheckFailed(message)) => {
+                assert!(
+                    message.contains(&format!("[{}]", num_pages - 1)),
+                    "{}",
+                    message
+                )
+            }
+            other => panic!("expected CheckFailed, got {:?}", other),
+        }
+    }
+    #[test]
+    fn refresh_file_length() {
+        let db = "refresh_file_length";
+        init_test_db(db).close().unwrap();
+        let writer = Pager::open(&db_name(db)).unwrap();
+        let reader = Pager::open(&db_name(db)).unwrap();
+        let num_pages = writer.num_pages.get();
+
+        for page_num in num_pages..num_pages + 2 {
+            assert_eq!(writer.alloc_page().unwrap(), page_num);
+            let node = writer.node(page_num).unwrap();
+            node.page.borrow_mut().buf[100] = page_num as u8;
+            writer.flush(page_num).unwrap();
+        }
+        let len = (num_pages + 2) * DEFAULT_PAGE_SIZE;
+        assert_eq!(writer.file_length.get(), len);
+        assert_eq!(reader.file_length.get(), num_pages * DEFAULT_PAGE_SIZE);
+
+        reader.refresh().unwrap();
+        assert_eq!(reader.file_length.get(), len);
+        assert_eq!(reader.num_pages.get(), num_pages + 2);
+        for page_num in num_pages..num_pages + 2 {
+            let node = reader.node(page_num).unwrap();
+            assert_eq!(node.page.borrow().buf[100], page_num as u8);
+        }
+    }
+    #[test]
+    fn alloc_page() {
+        let db = "alloc_page";
+        let mut table = init_test_db(db);
+        let num_pages = table.pager.num_pages.get();
+        assert!(matches!(
+            table.pager.node(num_pages),
+            Err(SqlError::PageOutOfRange { page }) if page == num_pages
+        ));
+
+        // Splitting the root leaf takes a new leaf and a new root
+        let mut count = 0;
+        while table.pager.num_pages.get() == num_pages {
+            let row = Row::try_new(count, &format!("name{}", count), "a@a").unwrap();
+            table.insert(row).unwrap();
+            count += 1;
+        }
+        assert_eq!(table.pager.num_pages.get(), num_pages + 2);
+        assert_ne!(table.get_root_num().unwrap(), DEFAULT_ROOT_NUM);
+        table.check().unwrap();
+
+        // A page from the free list comes back zeroed
+        let page_num = table.pager.alloc_page().unwrap();
+        table.pager.node(page_num).unwrap().page.borrow_mut().buf[100] = 1;
+        table.pager.free_page(page_num).unwrap();
+        assert_eq!(table.pager.alloc_page().unwrap(), page_num);
+        assert!(table
+            .pager
+            .read_page(page_num)
+            .unwrap()
+            .iter()
+            .all(|&b| b == 0));
+        assert_eq!(table.pager.alloc_page().unwrap(), page_num + 1);
+    }
+    #[test]
+    fn more_than_100_pages() {
+        let db = "more_than_100_pages";
+        let mut table = init_test_db(db);
+        for i in 0..500 {
+            let row = Row::try_new(i, &format!("name{}", i), "a@a").unwrap();
+            table.insert(row).unwrap();
+        }
+        assert!(table.pager.num_pages.get() > 100);
+        table.close().unwrap();
+
+        let mut table = reopen_test_db(db);
+        assert_eq!(table.len().unwrap(), 500);
+        for i in 0..500 {
+            assert_eq!(
+                table.get(i).unwrap().unwrap().name_str(),
+                format!("name{}", i)
+            );
+        }
+        table.check().unwrap();
+    }
+    #[test]
+    fn max_pages() {
+        let db = "max_pages";
+        let mut table = init_test_db(db);
+        table.pager.max_pages.set(Some(10));
+        let full = (0..100)
+            .map(|i| table.insert(Row::try_new(i, "a", "a@a").unwrap()))
+            .find(|result| result.is_err());
+        assert!(matches!(full, Some(Err(SqlError::TableFull))));
+        assert!(table.pager.num_pages.get() <= 10);
+    }
+    #[test]
+    fn unsupported_version() {
+        let db = "unsupported_version";
+        let mut table = init_test_db(db);
+        table.meta_mut().unwrap().set_version(FORMAT_VERSION + 1);
+        table.close().unwrap();
+
+        match Table::open(&db_name(db)) {
+            Err(SqlError::UnsupportedVersion { found, supported }) => {
+                assert_eq!(found, FORMAT_VERSION + 1);
+                assert_eq!(supported, FORMAT_VERSION);
+            }
+            _ => panic!("expected UnsupportedVersion"),
+        }
+    }
+
+    #[test]
+    fn db_info() {
+        let db = "db_info";
+        let mut table = init_test_db(db);
+        let info = table.db_info().unwrap();
+        assert_eq!(info.version, FORMAT_VERSION);
+        assert_eq!(info.page_size, DEFAULT_PAGE_SIZE);
+        assert!(info.created > 0);
+        assert_eq!((info.modified, info.write_count), (0, 0));
+        for statement in [
+            "insert 1 a a@a",
+            "insert 2 b b@a",
+            "update 1 c c@a",
+            "update 2 email=d@a",
+            "delete 1",
+        ] {
+            prepare_statement(statement)
+                .unwrap()
+                .execute(&mut table)
+                .unwrap();
+        }
+        assert_eq!(table.db_info().unwrap().write_count, 5);
+        // Statements that fail or change nothing are not counted
+        assert!(table.insert(Row::try_new(2, "b", "b@a").unwrap()).is_err());
+        assert_eq!(table.delete(1).unwrap(), None);
+        assert_eq!(table.db_info().unwrap().write_count, 5);
+        table.close().unwrap();
+        drop(table);
+
+        let mut table = reopen_test_db(db);
+        let reopened = table.db_info().unwrap();
+        assert_eq!(reopened.created, info.created);
+        assert!(reopened.modified >= info.created);
+        assert_eq!(reopened.write_count, 5);
+        assert_eq!(reopened.page_count, table.pager.num_pages.get());
+        assert_eq!(reopened.root_page, table.get_root_num().unwrap());
+        table.insert(Row::try_new(3, "c", "c@a").unwrap()).unwrap();
+        assert_eq!(table.db_info().unwrap().write_count, 6);
+
+        // Files before the fields keep their slots where the fields now go
+        table.meta_mut().unwrap().set_version(INFO_VERSION - 1);
+        table.close().unwrap();
+        drop(table);
+        let mut table = reopen_test_db(db);
+        table.check().unwrap();
+        assert_eq!(table.len().unwrap(), 2);
+        let upgraded = table.db_info().unwrap();
+        assert_eq!(upgraded.version, FORMAT_VERSION);
+        assert_eq!(
+            (upgraded.created, upgraded.modified, upgraded.write_count),
+            (0, 0, 0)
+        );
+        table.delete(2).unwrap();
+        table.close().unwrap();
+        drop(table);
+        let table = reopen_test_db(db);
+        assert_eq!(table.db_info().unwrap().write_count, 1);
+        assert_eq!(table.len().unwrap(), 1);
+    }
+
+    #[test]
+    fn end() {
+        let db = "end";
+        let mut table = init_test_db(db);
+        let cursor = table.end().unwrap();
+        assert!(cursor.end_of_table);
+        assert!(!cursor.is_last().unwrap());
+
+        table.find(3).unwrap().insert(3, &[3; ROW_SIZE]).unwrap();
+        table.find(1).unwrap().insert(1, &[1; ROW_SIZE]).unwrap();
+        let cursor = table.end().unwrap();
+        assert!(!cursor.end_of_table);
+        assert!(cursor.is_last().unwrap());
+        assert_eq!(cursor.get().unwrap().get_key().unwrap(), 3);
+        let cursor = table.start().unwrap();
+        assert!(!cursor.is_last().unwrap());
+
+        for i in 4..40i64 {
+            table
+                .find(i)
+                .unwrap()
+                .insert(i, &[i as u8; ROW_SIZE])
+                .unwrap();
+        }
+        let mut cursor = table.end().unwrap();
+        assert!(cursor.is_last().unwrap());
+        assert_eq!(cursor.get().unwrap().get_key().unwrap(), 39);
+        cursor.advance().unwrap();
+        assert!(cursor.end_of_table);
+
+        // Empty the original rightmost leaf
+        for i in (35..40i64).rev() {
+            table.find(i).unwrap().remove().unwrap();
+            let cursor = table.end().unwrap();
+            assert!(cursor.is_last().unwrap());
+            assert_eq!(cursor.get().unwrap().get_key().unwrap(), i - 1);
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_row() {
+        let row = Row::try_new(-3, "わっす", "wass@example.com").unwrap();
+        let json = serde_json::to_string(&row).unwrap();
+        assert_eq!(
+            json,
+            r#"{"id":-3,"name":"わっす","email":"wass@example.com"}"#
+        );
+        let back: Row = serde_json::from_str(&json).unwrap();
+        assert_eq!(back, row);
+
+        let json = format!(r#"{{"id":1,"name":"a","email":"{}"}}"#, "x".repeat(255));
+        assert!(serde_json::from_str::<Row>(&json).is_err());
+    }
+
+    #[test]
+    fn row_try_new() {
+        let row = Row::try_new(7, "wass", "wass@example.com").unwrap();
+        assert_eq!(row.id, 7);
+        assert_eq!(row.name_str(), "wass");
+        assert_eq!(row.email_str(), "wass@example.com");
+
+        let row = Row::try_new(1, &"n".repeat(NAME_SIZE - 1), &"e".repeat(EMAIL_SIZE - 1)).unwrap();
+        assert_eq!(row.name_str().len(), NAME_SIZE - 1);
+        assert_eq!(row.email_str().len(), EMAIL_SIZE - 1);
+
+        match Row::try_new(1, &"n".repeat(NAME_SIZE), "e") {
+            Err(SqlError::TooLargeString(field)) => assert_eq!(field, "name"),
+            _ => panic!("expected TooLargeString"),
+        }
+        match Row::try_new(1, "n", &"e".repeat(EMAIL_SIZE)) {
+            Err(SqlError::TooLargeString(field)) => assert_eq!(field, "email"),
+            _ => panic!("expected TooLargeString"),
+        }
+
+        // Multibyte names at the limit are kept whole, and one byte over is
+        // rejected rather than cut inside a character
+        let name = "わ".repeat(10) + "a";
+        let email = "😀".repeat(63) + "ab";
+        let row = Row::try_new(1, &name, &email).unwrap();
+        assert_eq!((row.name_str(), row.email_str()), (&name[..], &email[..]));
+        assert!(matches!(
+            Row::try_new(1, &"😀".repeat(8), "e"),
+            Err(SqlError::TooLargeString(field)) if field == "name"
+        ));
+        assert!(matches!(
+            Row::try_new(1, "n", &"わ".repeat(85)),
+            Err(SqlError::TooLargeString(field)) if field == "email"
+        ));
+    }
+
+    #[test]
+    fn unterminated_fields() {
+        let db = "unterminated_fields";
+        let mut table = init_test_db(db);
+        table.find(4).unwrap().insert(4, &[1; ROW_SIZE]).unwrap();
+        let row = table.get(4).unwrap().unwrap();
+        // Fields filling their buffers read whole
+        assert_eq!(
+            to_string_null_terminated(&row.name),
+            "\u{1}".repeat(NAME_SIZE)
+        );
+        assert_eq!(
+            to_string_null_terminated(&row.email),
+            "\u{1}".repeat(EMAIL_SIZE)
+        );
+        assert_eq!(row.name_str(), "\u{1}".repeat(NAME_SIZE));
+        let cursor = table.find(4).unwrap();
+        let value = cursor.get().unwrap();
+        let row_ref = RowRef::new(&value.get_value().unwrap()).to_owned();
+        assert_eq!(row_ref, row);
+    }
+
+    #[test]
+    fn drop_flushes() {
+        let db = "drop_flushes";
+        let mut table = init_test_db(db);
+        table.find(4).unwrap().insert(4, &[4; ROW_SIZE]).unwrap();
+        drop(table);
+
+        let mut table = reopen_test_db(db);
+        assert!(table.find(4).unwrap().check_key(4).unwrap());
+        assert_eq!(table.len().unwrap(), 1);
+
+        table.close().unwrap();
+        table.close().unwrap();
+        assert!(matches!(table.find(4), Err(SqlError::Closed)));
+    }
+
+    #[test]
+    fn checksum_mismatch() {
+        let db = "checksum_mismatch";
+        let mut table = init_test_db(db);
+        for i in 0..20 {
+            table.find(i).unwrap().insert(i, &[1; ROW_SIZE]).unwrap();
+        }
+        let leaf = table.find(7).unwrap().page_num;
+        table.close().unwrap();
+        drop(table);
+
+        let mut data = std::fs::read(db_name(db)).unwrap();
+        data[leaf * DEFAULT_PAGE_SIZE + 100] ^= 1;
+        std::fs::write(db_name(db), &data).unwrap();
+        let mut table = reopen_test_db(db);
+        assert!(matches!(
+            table.check(),
+            Err(SqlError::ChecksumMismatch { page }) if page == leaf
+        ));
+        assert!(matches!(
+            table.get(7),
+            Err(SqlError::ChecksumMismatch { page }) if page == leaf
+        ));
+    }
+
+    #[test]
+    fn journal_replay() {
+        let db = "journal_replay";
+        let mut table = init_test_db(db);
+        for i in 0..20 {
+            let statement = prepare_statement(&format!("insert {} name{} {}@a", i, i, i)).unwrap();
+            statement.execute(&mut table).unwrap();
+        }
+        prepare_statement("insert auto auto@a")
+            .unwrap()
+            .execute(&mut table)
+            .unwrap();
+        prepare_statement("update 3 three 3@b")
+            .unwrap()
+            .execute(&mut table)
+            .unwrap();
+        prepare_statement("delete 5")
+            .unwrap()
+            .execute(&mut table)
+            .unwrap();
+        // Crash without flushing any page
+        std::mem::forget(table);
+
+        let mut table = reopen_test_db(db);
+        assert_eq!(table.len().unwrap(), 20);
+        table.check().unwrap();
+        let rows = prepare_statement("select")
+            .unwrap()
+            .execute(&mut table)
+            .unwrap();
+        let ids = rows.iter().map(|row| row.id).collect::<Vec<_>>();
+        let mut expected = (0..21).filter(|i| *i != 5).collect::<Vec<_>>();
+        expected.sort();
+        assert_eq!(ids, expected);
+        assert_eq!(rows[3].name_str(), "three");
+        assert_eq!(rows[19].name_str(), "auto");
+        table.close().unwrap();
+        let journal = std::fs::metadata(journal_path(&db_name(db))).unwrap();
+        assert_eq!(journal.len(), 0);
+    }
+    #[test]
+    fn journal_already_applied() {
+        let db = "journal_already_applied";
+        let mut table = init_test_db(db);
+        for i in 0..10 {
+            let statement = prepare_statement(&format!("insert {} name{} {}@a", i, i, i)).unwrap();
+            statement.execute(&mut table).unwrap();
+        }
+        // Crash after the pages are written but before the journal is emptied
+        for i in 0..table.pager.num_pages.get() {
+            table.pager.flush(i).unwrap();
+        }
+        std::mem::forget(table);
+
+        let mut table = reopen_test_db(db);
+        assert_eq!(table.len().unwrap(), 10);
+        table.check().unwrap();
+    }
+
+    #[test]
+    fn update_field() {
+        let db = "update_field";
+        let mut table = init_test_db(db);
+        for i in 0..10 {
+            let row = Row::try_new(i, &format!("name{}", i), "someone@example.com").unwrap();
+            table.insert(row).unwrap();
+        }
+        // Bytes of the name with one set after its terminator, which the
+        // updates below must leave as they are
+        let name_bytes = |table: &mut Table| {
+            let cursor = table.find(4).unwrap();
+            let leaf = cursor.table.leaf_mut(cursor.page_num).unwrap();
+            let mut value = leaf.value(cursor.cell_num).unwrap();
+            let name = &mut value[Field::Name.range()];
+            name[20] = 0xaa;
+            name.to_vec()
+        };
+        let before = name_bytes(&mut table);
+        let value_len = |table: &mut Table| {
+            let cursor = table.find(4).unwrap();
+            let leaf = cursor.table.leaf_ref(cursor.page_num).unwrap();
+            leaf.get_value_len(cursor.cell_num).unwrap()
+        };
+        let len = value_len(&mut table);
+
+        // A shorter email is written in place
+        let row = table.update_field(4, Field::Email, "a@b").unwrap();
+        assert_eq!(row.email_str(), "a@b");
+        assert_eq!(value_len(&mut table), len);
+        assert_eq!(name_bytes(&mut table), before);
+
+        // A longer one rewrites the cell, with the same name bytes
+        let long = "x".repeat(EMAIL_SIZE - 1);
+        prepare_statement(&format!("update 4 email={}", long))
+            .unwrap()
+            .execute(&mut table)
+            .unwrap();
+        assert_eq!(value_len(&mut table), ROW_SIZE);
+        assert_eq!(name_bytes(&mut table), before);
+        prepare_statement("update 4 name=four")
+            .unwrap()
+            .execute(&mut table)
+            .unwrap();
+        table.check().unwrap();
+
+        assert!(matches!(
+            prepare_statement(&format!("update 4 email={}", "x".repeat(EMAIL_SIZE))),
+            Err(SqlError::ParseError { offset: 15, .. })
+        ));
+        assert!(matches!(
+            prepare_statement("update 4 id=5"),
+            Err(SqlError::ParseError { offset: 9, .. })
+        ));
+        assert!(matches!(
+            table.update_field(40, Field::Email, "a@b"),
+            Err(SqlError::NoData)
+        ));
+        let cursor = table.find(4).unwrap();
+        let leaf = cursor.table.leaf_mut(cursor.page_num).unwrap();
+        assert!(matches!(
+            leaf.update_value_range(cursor.cell_num, ROW_SIZE - 1, &[1, 2]),
+            Err(SqlError::ValueOutOfRange { end, len }) if end == ROW_SIZE + 1 && len == ROW_SIZE
+        ));
+
+        // Replayed from the journal after a crash
+        std::mem::forget(table);
+        let mut table = reopen_test_db(db);
+        let row = table.get(4).unwrap().unwrap();
+        assert_eq!(row.name_str(), "four");
+        assert_eq!(row.email_str(), long);
+        assert_eq!(
+            table.get(5).unwrap().unwrap().email_str(),
+            "someone@example.com"
+        );
+    }
+
+    #[test]
+    fn change_hook() {
+        let db = "change_hook";
+        let mut table = init_test_db(db);
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let recorded = events.clone();
+        table.set_hook(move |event| recorded.borrow_mut().push(event));
+
+        for statement in [
+            "insert 1 a a@x",
+            "insert 2 b b@x",
+            "select 1",
+            "update 1 c c@x",
+            "insert 2 dup dup@x",
+            "delete 2",
+            "delete 2",
+            "insert d d@x",
+        ] {
+            let _ = prepare_statement(statement).unwrap().execute(&mut table);
+        }
+
+        let events = events.borrow();
+        let kinds = events
+            .iter()
+            .map(|event| (event.kind, event.key))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            vec![
+                (ChangeKind::Insert, 1),
+                (ChangeKind::Insert, 2),
+                (ChangeKind::Update, 1),
+                (ChangeKind::Delete, 2),
+                (ChangeKind::Insert, 3),
+            ]
+        );
+        assert!(events[0].old_row.is_none());
+        assert_eq!(events[2].old_row.as_ref().unwrap().name_str(), "a");
+        assert_eq!(events[3].old_row.as_ref().unwrap().name_str(), "b");
+        drop(events);
+
+        // A bulk load fires once per row and counts each as a write
+        let mut table = init_test_db("change_hook_bulk");
+        let recorded = Rc::new(RefCell::new(Vec::new()));
+        let events = recorded.clone();
+        table.set_hook(move |event| events.borrow_mut().push((event.kind, event.key)));
+        let rows = (0..40).map(|i| Row::try_new(i * 3, "a", "a@x").unwrap());
+        table.bulk_load(rows).unwrap();
+        let expected = (0..40)
+            .map(|i| (ChangeKind::Insert, i * 3))
+            .collect::<Vec<_>>();
+        assert_eq!(*recorded.borrow(), expected);
+        assert_eq!(table.db_info().unwrap().write_count, 40);
+    }
+
+    #[test]
+    fn typed_api() {
+        let db = "typed_api";
+        let mut table = init_test_db(db);
+        for i in [5, 1, 9, 3, 7, 2, 8] {
+            let row = Row::try_new(i, &format!("name{}", i), &format!("{}@a", i)).unwrap();
+            table.insert(row).unwrap();
+        }
+        let dup = Row::try_new(5, "dup", "dup@a").unwrap();
+        assert!(matches!(table.insert(dup), Err(SqlError::DuplicateKey)));
+        assert_eq!(table.len().unwrap(), 7);
+
+        assert_eq!(table.get(9).unwrap().unwrap().name_str(), "name9");
+        assert!(table.get(4).unwrap().is_none());
+
+        table
+            .update(Row::try_new(9, "nine", "9@b").unwrap())
+            .unwrap();
+        assert_eq!(table.get(9).unwrap().unwrap().email_str(), "9@b");
+        let missing = Row::try_new(4, "four", "4@b").unwrap();
+        assert!(matches!(table.update(missing), Err(SqlError::NoData)));
+
+        assert_eq!(table.delete(3).unwrap().unwrap().name_str(), "name3");
+        assert!(table.delete(3).unwrap().is_none());
+        assert!(table.get(3).unwrap().is_none());
+        assert_eq!(table.len().unwrap(), 6);
+        table.check().unwrap();
+    }
+
+    #[test]
+    fn get_many() {
+        let db = "get_many";
+        let mut table = init_test_db(db);
+        for i in (0..60).step_by(2) {
+            let row = Row::try_new(i, &format!("name{}", i), &format!("{}@a", i)).unwrap();
+            table.insert(row).unwrap();
+        }
+        let ids = vec![31, 4, 58, -1, 4, 100, 17, 0, 58, 12, 61, 30];
+        let rows = table.get_many(&ids).unwrap();
+        assert_eq!(rows.len(), ids.len());
+        for (id, row) in ids.iter().zip(rows) {
+            let expected = table.get(*id).unwrap();
+            assert_eq!(row.is_some(), expected.is_some(), "id {}", id);
+            if let Some(row) = row {
+                assert_eq!(row.id, *id);
+                assert_eq!(row.name_str(), format!("name{}", id));
+            }
+        }
+        assert!(table.get_many(&[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn row_eq() {
+        let row = Row::try_new(1, "wass", "wass@example.com").unwrap();
+        let mut other = row.clone();
+        assert_eq!(row, other);
+        // Bytes after the terminator do not matter
+        other.name[10] = b'x';
+        assert_eq!(row, other);
+        other.name[1] = b'x';
+        assert_ne!(row, other);
+        assert_ne!(row, Row::try_new(2, "wass", "wass@example.com").unwrap());
+
+        let mut row = Row::try_new(1, "", "").unwrap();
+        row.name.copy_from_slice(&[b'a'; NAME_SIZE]);
+        assert_eq!(row.name_str(), "a".repeat(NAME_SIZE));
+        row.name[..4].copy_from_slice(&[b'o', b'k', 0xff, 0]);
+        assert_eq!(row.name_str(), "ok");
+    }
+
+    #[test]
+    fn open_nested_dir() {
+        let dir = "./forTest/open_nested_dir";
+        let _ = std::fs::remove_dir_all(dir);
+        let path = format!("{}/a/b/test.db", dir);
+        let mut table = Table::open(&path).unwrap();
+        table.insert(Row::try_new(1, "a", "a@b").unwrap()).unwrap();
+        table.close().unwrap();
+        drop(table);
+        let mut table = Table::open(&path).unwrap();
+        assert_eq!(table.get(1).unwrap().unwrap().name_str(), "a");
+    }
+    #[test]
+    fn page_sizes() {
+        for page_size in [8192, 16384] {
+            let path = clean_db(&format!("page_size_{}", page_size));
+            let options = TableOptions {
+                page_size: Some(page_size),
+                ..Default::default()
+            };
+            let mut table = Table::create(&path, &options).unwrap();
+            for i in 0..12 {
+                table.insert(Row::try_new(i, "a", "a@a").unwrap()).unwrap();
+            }
+            for i in (6..12).rev() {
+                table.delete(i).unwrap();
+            }
+            for i in 6..60 {
+                table.insert(Row::try_new(i, "b", "b@b").unwrap()).unwrap();
+            }
+            table.check().unwrap();
+            table.close().unwrap();
+            let len = std::fs::metadata(&path).unwrap().len() as usize;
+            assert_eq!(len % page_size, 0);
+
+            // The header decides the page size, not the default
+            let mut table = Table::open(&path).unwrap();
+            assert_eq!(table.pager.page_size, page_size);
+            assert_eq!(table.pager.num_pages.get(), len / page_size);
+            assert_eq!(table.len().unwrap(), 60);
+            assert_eq!(table.get(59).unwrap().unwrap().name_str(), "b");
+            table.check().unwrap();
+        }
+
+        for page_size in [2048, 5000, 131072] {
+            let path = clean_db("page_size_invalid");
+            let options = TableOptions {
+                page_size: Some(page_size),
+                ..Default::default()
+            };
+            assert!(matches!(
+                Table::create(&path, &options),
+                Err(SqlError::InvalidPageSize(size)) if size == page_size
+            ));
+            assert!(!std::path::Path::new(&path).exists());
+        }
+    }
+    #[test]
+    fn background_flush() {
+        let db = "background_flush";
+        init_test_db(db).close().unwrap();
+        let options = TableOptions {
+            cache_size: Some(4),
+            background_flush: true,
+            ..Default::default()
+        };
+        let mut table = Table::open_with_options(&db_name(db), &options).unwrap();
+        for i in 0..60 {
+            table.insert(Row::try_new(i, "a", "a@a").unwrap()).unwrap();
+        }
+        // The logs are kept until the queued pages are written
+        table.flush_all().unwrap();
+        assert!(std::fs::metadata(wal_path(&db_name(db))).unwrap().len() > 0);
+        table.sync().unwrap();
+        assert_eq!(std::fs::metadata(wal_path(&db_name(db))).unwrap().len(), 0);
+        table.close().unwrap();
+
+        let mut table = reopen_test_db(db);
+        assert_eq!(table.len().unwrap(), 60);
+        table.check().unwrap();
+    }
+    #[test]
+    fn pinned_pages_are_not_evicted() {
+        let db = "pinned_pages_are_not_evicted";
+        let mut table = init_test_db(db);
+        for i in 0..40 {
+            table.insert(Row::try_new(i, "a", "a@a").unwrap()).unwrap();
+        }
+        table.close().unwrap();
+        drop(table);
+
+        let table = reopen_test_db(db);
+        table.set_cache_size(Some(3)).unwrap();
+        let num_pages = table.pager.num_pages.get();
+        let first = table.pager.pin(1).unwrap();
+        for page_num in 2..num_pages {
+            table.pager.node(page_num).unwrap();
+        }
+        assert!(table.pager.is_cached(1));
+        assert!(table.pager.cache_stats().evictions > 0);
+
+        // With the pins filling the cache, nothing more can be read
+        let second = table.pager.pin(2).unwrap();
+        assert!(matches!(table.pager.pin(3), Err(SqlError::CachePinned)));
+        assert!(matches!(table.pager.node(4), Err(SqlError::CachePinned)));
+        assert!(!table.pager.is_cached(4));
+        drop((first, second));
+        assert!(!table.pager.is_pinned(1));
+        table.pager.node(4).unwrap();
+        assert!(table.pager.resident_pages() <= 3);
+    }
+    #[test]
+    fn create_new_conflict() {
+        let path = clean_db("create_new_conflict");
+        let table = Table::create_new(&path).unwrap();
+        drop(table);
+        match Table::create_new(&path) {
+            Err(SqlError::IOError(e, msg)) => {
+                assert_eq!(e.kind(), std::io::ErrorKind::AlreadyExists);
+                assert!(msg.contains(&path));
+            }
+            _ => panic!("expected an error"),
+        }
+        assert!(Table::open(&path).is_ok());
+
+        match Table::open("./forTest") {
+            Err(SqlError::IOError(e, msg)) => {
+                assert_eq!(e.kind(), std::io::ErrorKind::IsADirectory);
+                assert_eq!(msg, "Database path is a directory: ./forTest");
+            }
+            _ => panic!("expected an error"),
+        }
+    }
+    #[test]
+    fn backup_to() {
+        let mut table = init_test_db("backup_to");
+        for i in 0..20 {
+            let row = Row::try_new(i, &format!("name{}", i), &format!("{}@a", i)).unwrap();
+            table.insert(row).unwrap();
+        }
+        // Nothing flushed yet
+        let backup = db_name("backup_to_copy");
+        table.backup_to(&backup).unwrap();
+        table
+            .insert(Row::try_new(20, "late", "late@a").unwrap())
+            .unwrap();
+
+        let mut copy = Table::open(&backup).unwrap();
+        assert_eq!(copy.len().unwrap(), 20);
+        for i in 0..20 {
+            assert_eq!(
+                copy.get(i).unwrap().unwrap().name_str(),
+                format!("name{}", i)
+            );
+        }
+        assert!(copy.get(20).unwrap().is_none());
+        copy.check().unwrap();
+        assert_eq!(table.len().unwrap(), 21);
+    }
+    #[test]
+    fn backup_incremental() {
+        let mut table = init_test_db("backup_incremental");
+        for i in 0..200 {
+            let row = Row::try_new(i, &format!("name{}", i), &format!("{}@a", i)).unwrap();
+            table.insert(row).unwrap();
+        }
+        table.close().unwrap();
+        drop(table);
+
+        let mut table = reopen_test_db("backup_incremental");
+        let backup = db_name("backup_incremental_copy");
+        let since = table.backup_to(&backup).unwrap();
+        table
+            .update(Row::try_new(3, "three", "3@b").unwrap())
+            .unwrap();
+        table.flush_all().unwrap();
+        table
+            .insert(Row::try_new(500, "late", "late@a").unwrap())
+            .unwrap();
+
+        // Only the changed pages go into the delta
+        let delta = db_name("backup_incremental_delta");
+        let next = table.backup_incremental(&delta, since).unwrap();
+        assert!(next > since);
+        let applied = changes::apply_delta(&backup, &delta).unwrap();
+        assert!(applied < table.pager.num_pages.get());
+
+        table.sync().unwrap();
+        let live = std::fs::read(db_name("backup_incremental")).unwrap();
+        assert_eq!(std::fs::read(&backup).unwrap(), live);
+        let mut copy = Table::open(&backup).unwrap();
+        assert_eq!(copy.get(3).unwrap().unwrap().name_str(), "three");
+        assert_eq!(copy.get(500).unwrap().unwrap().name_str(), "late");
+        assert_eq!(copy.len().unwrap(), 201);
+        copy.check().unwrap();
+
+        // A delta needs a counter the change map has seen
+        assert!(matches!(
+            table.backup_incremental(&delta, next + 5),
+            Err(SqlError::UnknownChanges { .. })
+        ));
+        let untracked = Table::open_with(MemoryStorage::new()).unwrap();
+        assert!(matches!(
+            untracked.backup_incremental(&delta, 0),
+            Err(SqlError::UnknownChanges { since: 0 })
+        ));
+    }
+    #[test]
+    fn load_into_memory() {
+        let db = "load_into_memory";
+        let mut table = init_test_db(db);
+        for i in 0..30 {
+            let row = Row::try_new(i, &format!("name{}", i), &format!("{}@a", i)).unwrap();
+            table.insert(row).unwrap();
+        }
+        table.close().unwrap();
+        drop(table);
+        let mut table = reopen_test_db(db);
+        let statement = prepare_statement("select").unwrap();
+        let expected = statement.execute(&mut table).unwrap();
+        let size = std::fs::metadata(db_name(db)).unwrap().len() as usize;
+        assert!(matches!(
+            Table::load_into_memory(&db_name(db), size - 1),
+            Err(SqlError::FileTooLarge { .. })
+        ));
+
+        let mut memory = Table::load_into_memory(&db_name(db), size).unwrap();
+        // Queries keep working without the file
+        drop(table);
+        std::fs::remove_file(db_name(db)).unwrap();
+        assert_eq!(statement.execute(&mut memory).unwrap().rows, expected.rows);
+        memory.insert(Row::try_new(30, "a", "b").unwrap()).unwrap();
+        memory.close().unwrap();
+        assert!(!std::path::Path::new(&db_name(db)).exists());
+    }
+    #[test]
+    fn delete_every_row() {
+        let db = "delete_every_row";
+        let mut table = init_test_db(db);
+        // Enough rows to split the root leaf, merged back into it by the deletes
+        let n = 8;
+        for round in 0..2 {
+            for i in 0..n {
+                let key = if round == 0 { i } else { n - 1 - i };
+                table
+                    .insert(Row::try_new(key, "name", "a@a").unwrap())
+                    .unwrap();
+            }
+            assert_ne!(table.get_root_num().unwrap(), DEFAULT_ROOT_NUM);
+            for key in 0..n {
+                assert!(table.delete(key).unwrap().is_some(), "{}", key);
+                table.check().unwrap();
+            }
+
+            // Every query sees an empty table
+            let root = table.get_root_num().unwrap();
+            assert_eq!(table.leaf_ref(root).unwrap().get_num_cells(), 0);
+            assert_eq!(table.len().unwrap(), 0);
+            assert!(table.start().unwrap().end_of_table);
+            assert!(table.end().unwrap().end_of_table);
+            assert!(table.nth(0).unwrap().is_none());
+            assert!(table.get(3).unwrap().is_none());
+            assert!(table.delete(3).unwrap().is_none());
+            assert_eq!(table.keys().unwrap().count(), 0);
+            let run = |table: &mut Table, statement: &str| {
+                prepare_statement(statement).unwrap().execute(table)
+            };
+            assert!(matches!(run(&mut table, "select 3"), Err(SqlError::NoData)));
+            assert!(matches!(
+                run(&mut table, "select nth 0"),
+                Err(SqlError::NoData)
+            ));
+            assert!(run(&mut table, "select").unwrap().is_empty());
+            assert_eq!(run(&mut table, "count").unwrap().count, Some(0));
+            assert!(matches!(
+                run(&mut table, "update 3 a b"),
+                Err(SqlError::NoData)
+            ));
+        }
+
+        table.insert(Row::try_new(5, "a", "b").unwrap()).unwrap();
+        table.close().unwrap();
+        drop(table);
+        let mut table = reopen_test_db(db);
+        table.check().unwrap();
+        assert_eq!(table.get(5).unwrap().unwrap().name_str(), "a");
+    }
+    /// Keys below `n` in an order picked by `seed`
+    fn shuffled(n: i64, seed: u64) -> Vec<i64> {
+        let mut keys = (0..n).collect::<Vec<_>>();
+        let mut state = seed.max(1);
+        for i in (1..keys.len()).rev() {
+            // xorshift64
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            keys.swap(i, (state % (i as u64 + 1)) as usize);
+        }
+        keys
+    }
+    /// Values that are keys of more than one internal node
+    fn repeated_internal_keys(table: &Table) -> Vec<i64> {
+        let mut keys = Vec::new();
+        let mut nodes = vec![table.get_root_num().unwrap()];
+        while let Some(node_num) = nodes.pop() {
+            if let NodeRef::Internal(internal) =
+                table.pager.node(node_num).unwrap().as_typed().unwrap()
+            {
+                keys.extend(internal.keys().unwrap());
+                nodes.extend(internal.children().unwrap().iter().map(|&(child, _)| child));
+            }
+        }
+        keys.sort();
+        let mut repeated = keys
+            .windows(2)
+            .filter(|pair| pair[0] == pair[1])
+            .map(|pair| pair[0])
+            .collect::<Vec<_>>();
+        repeated.dedup();
+        repeated
+    }
+    #[test]
+    fn shared_boundary_keys() {
+        // Keys at and around the boundaries between sibling subtrees are
+        // removed and added again, so separators go stale and take the
+        // values of keys moved between siblings
+        let db = "shared_boundary_keys";
+        let mut table = init_test_db(db);
+        for key in shuffled(60, 3) {
+            let row = Row::try_new(key * 10, "a", "b").unwrap();
+            table.insert(row).unwrap();
+        }
+        let mut boundaries = Vec::new();
+        let mut nodes = vec![table.get_root_num().unwrap()];
+        while let Some(node_num) = nodes.pop() {
+            if let NodeRef::Internal(internal) =
+                table.pager.node(node_num).unwrap().as_typed().unwrap()
+            {
+                boundaries.extend(internal.keys().unwrap());
+                nodes.extend(internal.children().unwrap().iter().map(|&(child, _)| child));
+            }
+        }
+        assert!(boundaries.len() > 10);
+        for &key in &boundaries {
+            table.delete(key).unwrap();
+            table.check().unwrap();
+            for key in [key, key - 1, key + 1] {
+                table.insert(Row::try_new(key, "a", "b").unwrap()).unwrap();
+                table.check().unwrap();
+            }
+            table.delete(key - 1).unwrap();
+            table.check().unwrap();
+            assert!(repeated_internal_keys(&table).is_empty());
+        }
+        let mut expected = (0..60)
+            .map(|key| key * 10)
+            .chain(boundaries.iter().map(|key| key + 1))
+            .collect::<Vec<_>>();
+        expected.sort();
+        let keys = table
+            .keys()
+            .unwrap()
+            .collect::<SqlResult<Vec<_>>>()
+            .unwrap();
+        assert_eq!(keys, expected);
+
+        // Keys toggled in random orders never leave a value separating two
+        // pairs of subtrees
+        for seed in 1..4 {
+            let mut present = keys.iter().copied().collect::<HashSet<_>>();
+            for key in shuffled(600, seed).into_iter().take(300) {
+                if present.remove(&key) {
+                    table.delete(key).unwrap();
+                } else {
+                    table.insert(Row::try_new(key, "a", "b").unwrap()).unwrap();
+                    present.insert(key);
+                }
+                table.check().unwrap();
+                assert!(repeated_internal_keys(&table).is_empty());
+            }
+            for key in present.difference(&keys.iter().copied().collect()) {
+                table.delete(*key).unwrap();
+            }
+            for &key in &keys {
+                if table.get(key).unwrap().is_none() {
+                    table.insert(Row::try_new(key, "a", "b").unwrap()).unwrap();
+                }
+            }
+            table.check().unwrap();
+        }
+    }
+    #[test]
+    fn delete_all_cycles() {
+        let db = "delete_all_cycles";
+        let mut table = init_test_db(db);
+        let mut first_pages = None;
+        let limits = [
+            NodeLimits::TINY,
+            NodeLimits::new(3, 3).unwrap(),
+            NodeLimits::new(6, 5).unwrap(),
+        ];
+        for cycle in 0..12 {
+            table.set_node_limits(limits[cycle as usize % 3]);
+            let num_keys = [200, 37, 120, 9][cycle as usize % 4];
+            let mut keys = shuffled(1000, cycle * 2 + 1);
+            keys.truncate(num_keys);
+            for &key in &keys {
+                table
+                    .insert(Row::try_new(key, "name", "a@a").unwrap())
+                    .unwrap();
+            }
+            table.check().unwrap();
+            assert_eq!(table.len().unwrap(), num_keys);
+
+            let order = shuffled(num_keys as i64, cycle * 2 + 2);
+            for (removed, &i) in order.iter().enumerate() {
+                let key = keys[i as usize];
+                assert!(table.delete(key).unwrap().is_some(), "{}", key);
+                table.check().unwrap();
+                assert_eq!(table.len().unwrap(), num_keys - removed - 1);
+            }
+
+            // Back to a single empty leaf, with every other page free
+            let root = table.get_root_num().unwrap();
+            assert!(table.pager.node(root).unwrap().is_leaf());
+            assert_eq!(table.leaf_ref(root).unwrap().get_num_cells(), 0);
+            let num_pages = table.pager.num_pages.get();
+            assert_eq!(table.pager.free_pages().unwrap().len(), num_pages - 2);
+            let first_pages = *first_pages.get_or_insert(num_pages);
+            assert!(num_pages <= first_pages * 2, "{} pages", num_pages);
+        }
+        table.close().unwrap();
+        drop(table);
+        let mut table = reopen_test_db(db);
+        table.check().unwrap();
+        assert_eq!(table.len().unwrap(), 0);
+    }
+    #[test]
+    fn child_cycle() {
+        let db = "child_cycle";
+        let mut table = init_test_db(db);
+        for i in 0..20 {
+            table.insert(Row::try_new(i, "a", "b").unwrap()).unwrap();
+        }
+        // The first and last child of a node under the root point back at it
+        let root_num = table.get_root_num().unwrap();
+        let root = table.internal_ref(root_num).unwrap();
+        let node_num = root.get_child_at(0).unwrap();
+        let last_num = root.get_child_at(root.get_num_keys()).unwrap();
+        for page_num in [node_num, last_num] {
+            assert!(table.pager.node(page_num).unwrap().is_internal());
+            let node = table.internal_mut(page_num).unwrap();
+            for i in [0, node.get_num_keys()] {
+                node.set_child_at(i, root_num).unwrap();
+            }
+        }
+        let trail = format!("{:?}", [root_num, node_num]);
+        match table.find(0) {
+            Err(SqlError::Corrupt { page, detail }) => {
+                assert_eq!(page, root_num);
+                assert!(detail.contains(&trail), "{}", detail);
+            }
+            _ => panic!("descended through a cycle"),
+        }
+        assert!(matches!(table.get(0), Err(SqlError::Corrupt { .. })));
+        assert!(matches!(table.end(), Err(SqlError::Corrupt { .. })));
+        let printed = table.to_string();
+        assert!(printed.contains(&format!("Node[{}] <visited>", root_num)));
+    }
+    #[test]
+    fn cached_root_num() {
+        let db = "cached_root_num";
+        let mut table = init_test_db(db);
+        for i in 0..=table.node_limits().leaf_max_cells() as i64 {
+            table.insert(Row::try_new(i, "a", "b").unwrap()).unwrap();
+        }
+        // The root leaf was split into a new root
+        let root_num = table.get_root_num().unwrap();
+        assert_ne!(root_num, DEFAULT_ROOT_NUM);
+        assert_eq!(table.meta_ref().unwrap().get_root_num(), root_num);
+        assert!(table.get(0).unwrap().is_some());
+        table.close().unwrap();
+        drop(table);
+
+        let table = reopen_test_db(db);
+        assert_eq!(table.get_root_num().unwrap(), root_num);
+    }
+    #[test]
+    fn bulk_load() {
+        let db = "bulk_load";
+        let mut table = init_test_db(db);
+        let rows = (0..10_000).map(|i| Row::try_new(i * 2, "name", "a@a").unwrap());
+        assert_eq!(table.bulk_load(rows).unwrap(), 10_000);
+        table.check().unwrap();
+        assert_eq!(table.get_next_id().unwrap(), 19_999);
+        table.close().unwrap();
+        drop(table);
+
+        let mut table = reopen_test_db(db);
+        table.check().unwrap();
+        assert_eq!(table.len().unwrap(), 10_000);
+        for key in [0, 2, 5_000, 19_998] {
+            assert_eq!(table.get(key).unwrap().unwrap().id, key);
+        }
+        assert!(table.get(7).unwrap().is_none());
+        let keys = table
+            .keys()
+            .unwrap()
+            .collect::<SqlResult<Vec<_>>>()
+            .unwrap();
+        assert_eq!(keys, (0..10_000).map(|i| i * 2).collect::<Vec<_>>());
+        let mut cursor = table.start_rev().unwrap();
+        for key in (0..10_000).rev().map(|i| i * 2).take(100) {
+            assert_eq!(cursor.get_key().unwrap(), key);
+            cursor.retreat().unwrap();
+        }
+        // Nodes are full, so the tree takes no more pages than appending
+        let stats = table.tree_stats().unwrap();
+        assert_eq!(stats.leaves, 2_500);
+        table
+            .insert(Row::try_new(1, "name", "a@a").unwrap())
+            .unwrap();
+        table.delete(0).unwrap();
+        table.check().unwrap();
+
+        // A lower fill leaves room in each node, and rows out of order or
+        // repeated leave the table empty
+        let mut table = init_test_db("bulk_load_fill");
+        table.set_bulk_fill(50).unwrap();
+        let rows = (0..100).map(|i| Row::try_new(i, "name", "a@a").unwrap());
+        table.bulk_load(rows).unwrap();
+        table.check().unwrap();
+        assert_eq!(table.tree_stats().unwrap().leaves, 50);
+        assert!(matches!(table.set_bulk_fill(0), Err(SqlError::InvalidArgs)));
+
+        let mut table = init_test_db("bulk_load_unsorted");
+        let free = table.pager.free_pages().unwrap().len();
+        let rows = [0, 1, 2, 3, 4, 5, 5].map(|i| Row::try_new(i, "name", "a@a").unwrap());
+        assert!(matches!(
+            table.bulk_load(rows.into_iter()),
+            Err(SqlError::UnsortedRows {
+                position: 6,
+                key: 5
+            })
+        ));
+        assert_eq!(table.pager.free_pages().unwrap().len(), free + 1);
+        assert_eq!(table.len().unwrap(), 0);
+        table.check().unwrap();
+        table
+            .insert(Row::try_new(3, "name", "a@a").unwrap())
+            .unwrap();
+        table.check().unwrap();
+    }
+
+    #[test]
+    fn append_hint() {
+        let db = "append_hint";
+        let mut table = init_test_db(db);
+        let row = |key: i64| Row::try_new(key, "name", "a@a").unwrap();
+        let mut expected = std::collections::BTreeSet::new();
+        // Appended rows interleaved with rows going before them, removed
+        // rows and a failed insert past the end
+        for i in 0..300 {
+            table.insert(row(i * 2)).unwrap();
+            expected.insert(i * 2);
+            if i % 5 == 0 {
+                table.insert(row(i * 2 - 101)).unwrap();
+                expected.insert(i * 2 - 101);
+            }
+            if i % 7 == 0 && i >= 50 {
+                table.delete((i - 50) * 2).unwrap();
+                expected.remove(&((i - 50) * 2));
+            }
+            if i % 13 == 0 {
+                assert!(matches!(
+                    table.insert(row(i * 2)),
+                    Err(SqlError::DuplicateKey)
+                ));
+                table.check().unwrap();
+            }
+        }
+        table.check().unwrap();
+        let keys = table
+            .keys()
+            .unwrap()
+            .collect::<SqlResult<Vec<_>>>()
+            .unwrap();
+        assert_eq!(keys, expected.into_iter().collect::<Vec<_>>());
+
+        // Right after a split of the last leaf, appends fit in it
+        let mut key = 1000;
+        loop {
+            let last_leaf = table.end().unwrap().page_num;
+            if table.leaf_ref(last_leaf).unwrap().get_num_cells() == 1 {
+                break;
+            }
+            table.insert(row(key)).unwrap();
+            key += 1;
+        }
+        table.insert(row(key)).unwrap();
+        let before = table.pager.cache_stats();
+        table.insert(row(key + 1)).unwrap();
+        let hinted = table.pager.cache_stats().since(&before);
+        // A change elsewhere leaves the next append to descend from the root
+        table.insert(row(1)).unwrap();
+        let before = table.pager.cache_stats();
+        table.insert(row(key + 2)).unwrap();
+        let descended = table.pager.cache_stats().since(&before);
+        assert!(
+            hinted.hits + hinted.misses + 2 < descended.hits + descended.misses,
+            "{} {}",
+            hinted,
+            descended
+        );
+        table.check().unwrap();
+    }
+    #[test]
+    fn sequential_fill() {
+        // Rows appended in key order fill every leaf but the last
+        let mut table = init_test_db("sequential_fill");
+        for i in 0..100 {
+            table
+                .insert(Row::try_new(i, "name", "a@a").unwrap())
+                .unwrap();
+        }
+        table.check().unwrap();
+        let stats = table.tree_stats().unwrap();
+        assert_eq!((stats.leaves, stats.cells), (25, 100));
+        assert_eq!(stats.depth, 4);
+
+        // while rows in any order split leaves in halves
+        let mut table = init_test_db("random_fill");
+        for i in 0..100 {
+            let key = i * 37 % 100;
+            table
+                .insert(Row::try_new(key, "name", "a@a").unwrap())
+                .unwrap();
+        }
+        table.check().unwrap();
+        let stats = table.tree_stats().unwrap();
+        assert_eq!(stats.cells, 100);
+        assert!(stats.leaves > 30, "{}", stats);
+    }
+
+    #[test]
+    fn full_nodes() {
+        let db = "full_nodes";
+        let mut table = init_test_db(db);
+        assert_eq!(table.node_limits(), NodeLimits::TINY);
+        table.set_node_limits(NodeLimits::FULL);
+        // Leaves take rows until their page is full, however short the rows
+        assert_eq!(LEAF_NODE_MAX_CELLS, 252);
+        assert_eq!(INTERNAL_NODE_MAX_CELLS, 202);
+        let value = Row::try_new(0, "a", "b").unwrap().serialize();
+        assert_eq!(value.len(), 41);
+        let cell = inline_cell(0, &value).len() + LEAF_NODE_OFFSET_SIZE;
+        let rows = table.leaf_ref(DEFAULT_ROOT_NUM).unwrap().cell_space() / cell;
+        for i in 0..rows as i64 {
+            table.insert(Row::try_new(i, "a", "b").unwrap()).unwrap();
+        }
+        assert_eq!(table.get_root_num().unwrap(), DEFAULT_ROOT_NUM);
+        let root = table.leaf_mut(DEFAULT_ROOT_NUM).unwrap();
+        assert_eq!(root.get_num_cells(), rows);
+        assert!(!root.fits(cell - LEAF_NODE_OFFSET_SIZE).unwrap());
+        table.insert(Row::try_new(100, "a", "b").unwrap()).unwrap();
+        assert_ne!(table.get_root_num().unwrap(), DEFAULT_ROOT_NUM);
+        table.check().unwrap();
+        table.close().unwrap();
+        drop(table);
+
+        // Full leaves split in halves once tiny nodes are back
+        let mut table = reopen_test_db(db);
+        let rows = rows as i64;
+        for i in rows..rows + 7 {
+            table.insert(Row::try_new(i, "a", "b").unwrap()).unwrap();
+        }
+        table.check().unwrap();
+        assert_eq!(table.len().unwrap(), rows as usize + 8);
+        let keys = table
+            .keys()
+            .unwrap()
+            .collect::<SqlResult<Vec<_>>>()
+            .unwrap();
+        assert_eq!(keys, (0..rows + 7).chain([100]).collect::<Vec<_>>());
+
+        assert!(matches!(
+            NodeLimits::new(2, 4),
+            Err(SqlError::InvalidMaxCells(2))
+        ));
+        assert!(matches!(
+            NodeLimits::new(4, INTERNAL_NODE_MAX_CELLS + 1),
+            Err(SqlError::InvalidMaxCells(203))
+        ));
+        assert_eq!(NodeLimits::new(4, 4).unwrap(), NodeLimits::TINY);
+    }
+    #[test]
+    fn row_ref() {
+        let mut table = init_test_db("row_ref");
+        let row = Row::try_new(-3, "wass", "wass@example.com").unwrap();
+        table.insert(row.clone()).unwrap();
+        let cursor = table.find(-3).unwrap();
+        let value = cursor.get().unwrap();
+        let buf = value.get_value().unwrap();
+        let row_ref = RowRef::new(&buf);
+        assert_eq!(row_ref.id(), -3);
+        assert_eq!(row_ref.name_str(), "wass");
+        assert_eq!(row_ref.email_str(), "wass@example.com");
+        assert_eq!(format!("{}", row_ref), format!("{}", row));
+        assert_eq!(row_ref.to_owned(), row);
+    }
+    #[test]
+    fn copy_to() {
+        let mut table = init_test_db("copy_to");
+        // Descending inserts leave every split leaf half empty
+        for i in (0..30).rev() {
+            let row = Row::try_new(i, &format!("name{}", i), &format!("{}@a", i)).unwrap();
+            table.insert(row).unwrap();
+        }
+        // A value in overflow pages is copied whole
+        let long = vec![7; table.pager.page_size * 2];
+        table.find(50).unwrap().insert(50, &long).unwrap();
+        table.set_next_id(100).unwrap();
+        let source_pages = table.pager.num_pages.get();
+
+        let path = clean_db("copy_to_dest");
+        let stats = table.copy_to(&path).unwrap();
+        assert_eq!(stats.rows, 31);
+        assert!(stats.pages < source_pages);
+        assert!(matches!(table.copy_to(&path), Err(SqlError::IOError(..))));
+
+        let mut copy = Table::open(&path).unwrap();
+        assert_eq!(copy.pager.num_pages.get(), stats.pages);
+        assert_eq!(copy.len().unwrap(), 31);
+        assert_eq!(copy.get_next_id().unwrap(), 100);
+        for i in 0..30 {
+            assert_eq!(copy.get(i).unwrap(), table.get(i).unwrap());
+        }
+        let cursor = copy.find(50).unwrap();
+        assert_eq!(*cursor.get().unwrap().get_value().unwrap(), long[..]);
+        copy.check().unwrap();
+        assert_eq!(table.pager.num_pages.get(), source_pages);
+    }
+    #[test]
+    fn user_meta() {
+        let db = "user_meta";
+        let mut table = init_test_db(db);
+        assert_eq!(table.get_meta("schema").unwrap(), None);
+        table.set_meta("schema", b"3").unwrap();
+        table.set_meta("cursor", &[0, 1, 2, 0]).unwrap();
+        table.set_meta("schema", b"4").unwrap();
+        assert_eq!(table.get_meta("schema").unwrap(), Some(b"4".to_vec()));
+        assert!(table.delete_meta("cursor").unwrap());
+        assert!(!table.delete_meta("cursor").unwrap());
+        table.set_meta("cursor", &[9; 40]).unwrap();
+        let keys = table.meta_entries().unwrap();
+        let keys = keys.iter().map(|(k, _)| k.as_str()).collect::<Vec<_>>();
+        assert_eq!(keys, ["schema", "cursor"]);
+        assert!(matches!(
+            table.set_meta("", b"a"),
+            Err(SqlError::InvalidArgs)
+        ));
+
+        // Entries fill the space with their keys and lengths, and one too
+        // many changes nothing
+        let used = 3 + 6 + 1 + 3 + 6 + 40;
+        let filler = vec![1; USER_META_SIZE - used - 3 - 6];
+        table.set_meta("filler", &filler).unwrap();
+        assert!(matches!(
+            table.set_meta("filler", &[filler.clone(), vec![1]].concat()),
+            Err(SqlError::MetaFull)
+        ));
+        assert!(matches!(table.set_meta("x", b""), Err(SqlError::MetaFull)));
+        assert_eq!(table.get_meta("filler").unwrap(), Some(filler.clone()));
+        table.delete_meta("filler").unwrap();
+        table.insert(Row::try_new(1, "a", "a@a").unwrap()).unwrap();
+        table.vacuum().unwrap();
+        table.close().unwrap();
+        drop(table);
+
+        let mut table = reopen_test_db(db);
+        assert_eq!(table.get_meta("schema").unwrap(), Some(b"4".to_vec()));
+        assert_eq!(table.get_meta("cursor").unwrap(), Some(vec![9; 40]));
+        assert_eq!(table.get_meta("filler").unwrap(), None);
+        let path = clean_db("user_meta_copy");
+        table.copy_to(&path).unwrap();
+        let copy = Table::open(&path).unwrap();
+        assert_eq!(copy.meta_entries().unwrap(), table.meta_entries().unwrap());
+    }
+    #[test]
+    fn keys() {
+        let mut table = init_test_db("keys");
+        for i in [5, -2, 9, 0, 14, 3, 7, 11, 1, 20, -8] {
+            table.insert(Row::try_new(i, "a", "b").unwrap()).unwrap();
+        }
+        let statement = prepare_statement("select").unwrap();
+        let ids = statement
+            .execute(&mut table)
+            .unwrap()
+            .iter()
+            .map(|row| row.id)
+            .collect::<Vec<_>>();
+        let keys = table
+            .keys()
+            .unwrap()
+            .collect::<SqlResult<Vec<_>>>()
+            .unwrap();
+        assert_eq!(keys, ids);
+
+        let range = |table: &mut Table, range: (Bound<i64>, Bound<i64>)| {
+            let keys = table.keys_range(range).unwrap();
+            keys.collect::<SqlResult<Vec<_>>>().unwrap()
+        };
+        use Bound::*;
+        assert_eq!(
+            range(&mut table, (Included(3), Excluded(11))),
+            vec![3, 5, 7, 9]
+        );
+        assert_eq!(
+            range(&mut table, (Excluded(3), Included(11))),
+            vec![5, 7, 9, 11]
+        );
+        assert_eq!(
+            range(&mut table, (Included(4), Unbounded)),
+            vec![5, 7, 9, 11, 14, 20]
+        );
+        assert_eq!(range(&mut table, (Unbounded, Excluded(0))), vec![-8, -2]);
+        assert_eq!(
+            range(&mut table, (Excluded(20), Unbounded)),
+            Vec::<i64>::new()
+        );
+        assert_eq!(
+            range(&mut table, (Included(12), Included(13))),
+            Vec::<i64>::new()
+        );
     }
 }
@@ -0,0 +1,319 @@
+//! Write-ahead log of page images.
+//! Flushing first appends every page to be written, then a commit record with
+//! a checksum of those frames, and syncs the log. Only then are the pages
+//! written in place, after which the log is emptied. Opening a database
+//! writes the pages of a committed log again and drops any uncommitted tail.
+//! Changed pages stay cached past the cache size until a flush has logged
+//! them, since pages written in place ahead of the log would survive a crash
+//! that the log and the journal roll back.
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom, Write},
+};
+
+use crate::{
+    crc32::crc32,
+    pager::Pager,
+    sql_error::{SqlError, SqlResult},
+};
+
+// FRAME: {PAGE_NUM, PAGE}
+// COMMIT: {COMMIT_MARKER, FRAME_COUNT, CHECKSUM}
+// The checksum covers the frames since the previous commit record.
+// Pages are as long as the pages of the database.
+const PAGE_NUM_SIZE: usize = 8;
+const COMMIT_MARKER: u64 = u64::MAX;
+const COMMIT_SIZE: usize = PAGE_NUM_SIZE + 8 + 4;
+
+pub fn wal_path(filename: &str) -> String {
+    format!("{}.wal", filename)
+}
+
+pub struct Wal {
+    file: File,
+    page_size: usize,
+}
+
+impl Wal {
+    #[cfg(feature = "fs")]
+    pub fn open(path: &str, page_size: usize) -> SqlResult<Self> {
+        let file = File::options()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)
+            .map_err(|e| SqlError::IOError(e, "Failed to open WAL".to_string()))?;
+        Ok(Wal { file, page_size })
+    }
+
+    fn write_frames(&mut self, pages: &[(usize, Vec<u8>)]) -> SqlResult<u32> {
+        let mut checksum = 0;
+        for (page_num, buf) in pages {
+            let mut frame = Vec::with_capacity(PAGE_NUM_SIZE + self.page_size);
+            frame.extend_from_slice(&(*page_num as u64).to_le_bytes());
+            frame.extend_from_slice(buf);
+            checksum = crc32(checksum, &frame);
+            self.file
+                .write_all(&frame)
+                .map_err(|e| SqlError::IOError(e, "Failed to write WAL".to_string()))?;
+        }
+        Ok(checksum)
+    }
+
+    /// Append page images followed by their commit record, and sync with `sync`
+    pub fn commit(&mut self, pages: &[(usize, Vec<u8>)], sync: bool) -> SqlResult<()> {
+        let checksum = self.write_frames(pages)?;
+        let mut record = Vec::with_capacity(COMMIT_SIZE);
+        record.extend_from_slice(&COMMIT_MARKER.to_le_bytes());
+        record.extend_from_slice(&(pages.len() as u64).to_le_bytes());
+        record.extend_from_slice(&checksum.to_le_bytes());
+        self.file
+            .write_all(&record)
+            .map_err(|e| SqlError::IOError(e, "Failed to write WAL".to_string()))?;
+        if sync {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    /// Pages of the committed transactions, in the order written.
+    /// Reading stops at the first incomplete or damaged record.
+    pub fn committed(&mut self) -> SqlResult<Vec<(usize, Vec<u8>)>> {
+        let mut buf = Vec::new();
+        self.file
+            .seek(SeekFrom::Start(0))
+            .map_err(|e| SqlError::IOError(e, "Failed to seek WAL".to_string()))?;
+        self.file
+            .read_to_end(&mut buf)
+            .map_err(|e| SqlError::IOError(e, "Failed to read WAL".to_string()))?;
+
+        let frame_size = PAGE_NUM_SIZE + self.page_size;
+        let mut committed = Vec::new();
+        let mut pending = Vec::new();
+        let mut checksum = 0;
+        let mut rest = &buf[..];
+        while rest.len() >= PAGE_NUM_SIZE {
+            let page_num = u64::from_le_bytes(rest[0..PAGE_NUM_SIZE].try_into().unwrap());
+            if page_num == COMMIT_MARKER {
+                if rest.len() < COMMIT_SIZE {
+                    break;
+                }
+                let count = u64::from_le_bytes(rest[8..16].try_into().unwrap());
+                let expected = u32::from_le_bytes(rest[16..20].try_into().unwrap());
+                if count != pending.len() as u64 || expected != checksum {
+                    break;
+                }
+                committed.append(&mut pending);
+                checksum = 0;
+                rest = &rest[COMMIT_SIZE..];
+            } else {
+                if rest.len() < frame_size {
+                    break;
+                }
+                checksum = crc32(checksum, &rest[..frame_size]);
+                let page = rest[PAGE_NUM_SIZE..frame_size].to_vec();
+                pending.push((page_num as usize, page));
+                rest = &rest[frame_size..];
+            }
+        }
+        Ok(committed)
+    }
+
+    /// Write the committed pages in place, then empty the log.
+    /// Call before any page of `pager` is read. Returns the pages written.
+    pub fn recover(&mut self, pager: &mut Pager) -> SqlResult<usize> {
+        let committed = self.committed()?;
+        for (page_num, buf) in &committed {
+            pager.record_write(*page_num)?;
+            pager.storage.borrow_mut().write_page(*page_num, buf)?;
+            // Drop pages cached when an empty file was initialized
+            pager.drop(*page_num);
+            if *page_num >= pager.num_pages.get() {
+                pager.num_pages.set(page_num + 1);
+            }
+        }
+        if !committed.is_empty() {
+            pager.sync()?;
+        }
+        self.truncate(true)?;
+        Ok(committed.len())
+    }
+
+    pub fn truncate(&mut self, sync: bool) -> SqlResult<()> {
+        self.file
+            .set_len(0)
+            .map_err(|e| SqlError::IOError(e, "Failed to truncate WAL".to_string()))?;
+        if sync {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    fn sync(&mut self) -> SqlResult<()> {
+        self.file
+            .sync_data()
+            .map_err(|e| SqlError::IOError(e, "Failed to sync WAL".to_string()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        commands::prepare_statement,
+        faulty_storage::FaultyStorage,
+        pager::DEFAULT_PAGE_SIZE,
+        storage::MemoryStorage,
+        table::{Row, Table},
+        test::clean_db,
+    };
+
+    fn fresh_wal(name: &str) -> String {
+        wal_path(&clean_db(name))
+    }
+
+    fn insert_rows(table: &mut Table, ids: std::ops::Range<i64>) {
+        for i in ids {
+            let row = Row::try_new(i, &format!("name{}", i), "a@a").unwrap();
+            table.insert(row).unwrap();
+        }
+    }
+
+    #[test]
+    fn wal_clean_close() {
+        let path = fresh_wal("wal_clean_close");
+        let storage = FaultyStorage::new(MemoryStorage::new());
+        let mut table = Table::open_with_wal(storage.clone(), &path).unwrap();
+        insert_rows(&mut table, 0..10);
+        table.close().unwrap();
+        drop(table);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 0);
+
+        let mut table = Table::open_with_wal(storage, &path).unwrap();
+        assert_eq!(table.len().unwrap(), 10);
+        table.check().unwrap();
+    }
+
+    #[test]
+    fn wal_crash_after_commit() {
+        let path = fresh_wal("wal_crash_after_commit");
+        let storage = FaultyStorage::new(MemoryStorage::new());
+        let mut table = Table::open_with_wal(storage.clone(), &path).unwrap();
+        insert_rows(&mut table, 0..10);
+        table.close().unwrap();
+        drop(table);
+
+        let mut table = Table::open_with_wal(storage.clone(), &path).unwrap();
+        insert_rows(&mut table, 10..30);
+        // The log is committed, then writing in place stops partway
+        storage.fail_nth_write(3);
+        assert!(table.close().is_err());
+        drop(table);
+        let torn = MemoryStorage::from_bytes(storage.snapshot().unwrap());
+
+        let mut table = Table::open_with_wal(torn, &path).unwrap();
+        assert_eq!(table.len().unwrap(), 30);
+        table.check().unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 0);
+    }
+
+    #[test]
+    fn wal_crash_after_eviction() {
+        let path = fresh_wal("wal_crash_after_eviction");
+        let storage = FaultyStorage::new(MemoryStorage::new());
+        let mut table = Table::open_with_wal(storage.clone(), &path).unwrap();
+        insert_rows(&mut table, 0..10);
+        table.close().unwrap();
+        drop(table);
+
+        // Rows spread over the leaves overflow a tiny cache
+        let mut table = Table::open_with_wal(storage.clone(), &path).unwrap();
+        table.set_cache_size(Some(4)).unwrap();
+        for i in 0..50 {
+            let id = 10 + i * 37 % 50;
+            let row = Row::try_new(id, &format!("name{}", id), "a@a").unwrap();
+            table.insert(row).unwrap();
+            assert!(table.pager.resident_pages() <= 4);
+        }
+        assert!(table.pager.cache_stats().evictions > 0);
+        // The process stops here: only what the log committed is recovered,
+        // and no evicted page reached the file ahead of it
+        storage.crash_after_writes(0);
+        let copy = fresh_wal("wal_crash_after_eviction_copy");
+        std::fs::copy(&path, &copy).unwrap();
+        let crashed = MemoryStorage::from_bytes(storage.snapshot().unwrap());
+        let mut recovered = Table::open_with_wal(crashed, &copy).unwrap();
+        recovered.check().unwrap();
+        let len = recovered.len().unwrap();
+        assert!(len >= 10);
+        for row in prepare_statement("select")
+            .unwrap()
+            .execute(&mut recovered)
+            .unwrap()
+        {
+            assert_eq!(row.name_str(), format!("name{}", row.id));
+        }
+        drop(table);
+    }
+
+    #[test]
+    fn wal_disk_full() {
+        let path = fresh_wal("wal_disk_full");
+        let storage = FaultyStorage::new(MemoryStorage::new());
+        let mut table = Table::open_with_wal(storage.clone(), &path).unwrap();
+        insert_rows(&mut table, 0..10);
+        table.close().unwrap();
+        drop(table);
+
+        // The pages are in the log, but the database has no room for them,
+        // so the log is kept for recovery rather than emptied
+        let mut table = Table::open_with_wal(storage.clone(), &path).unwrap();
+        insert_rows(&mut table, 10..30);
+        storage.fill_disk_after(1);
+        assert!(table.close().is_err());
+        assert!(std::fs::metadata(&path).unwrap().len() > 0);
+        // Had the process stopped here, the log would finish the job
+        let copy = fresh_wal("wal_disk_full_copy");
+        std::fs::copy(&path, &copy).unwrap();
+        let torn = MemoryStorage::from_bytes(storage.snapshot().unwrap());
+        let mut recovered = Table::open_with_wal(torn, &copy).unwrap();
+        assert_eq!(recovered.len().unwrap(), 30);
+        recovered.check().unwrap();
+
+        // With room again, closing writes the pages and empties the log
+        storage.free_space();
+        table.close().unwrap();
+        drop(table);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 0);
+        let mut table = Table::open_with_wal(storage, &path).unwrap();
+        assert_eq!(table.len().unwrap(), 30);
+        table.check().unwrap();
+    }
+
+    #[test]
+    fn wal_crash_before_commit() {
+        let path = fresh_wal("wal_crash_before_commit");
+        let storage = MemoryStorage::new();
+        let mut table = Table::open_with_wal(storage.clone(), &path).unwrap();
+        insert_rows(&mut table, 0..10);
+        table.close().unwrap();
+        drop(table);
+
+        // Frames whose commit record never made it to the log
+        let page = vec![0xff; DEFAULT_PAGE_SIZE];
+        let mut wal = Wal::open(&path, DEFAULT_PAGE_SIZE).unwrap();
+        wal.write_frames(&[(0, page.clone()), (1, page.clone())])
+            .unwrap();
+        // A damaged commit record is discarded as well
+        wal.commit(&[(2, page)], true).unwrap();
+        let len = std::fs::metadata(&path).unwrap().len();
+        wal.file.set_len(len - 1).unwrap();
+        assert!(wal.committed().unwrap().is_empty());
+        drop(wal);
+
+        let mut table = Table::open_with_wal(storage, &path).unwrap();
+        assert_eq!(table.len().unwrap(), 10);
+        table.check().unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 0);
+    }
+}
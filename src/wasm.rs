@@ -0,0 +1,52 @@
+//! Table for JavaScript through wasm-bindgen. The browser has no files, so
+//! the pages are kept in memory and go away with the table.
+use js_sys::{Array, Object, Reflect};
+use wasm_bindgen::prelude::*;
+
+use crate::{
+    commands::prepare_statement, sql_error::SqlError, storage::MemoryStorage, table::Table,
+};
+
+#[wasm_bindgen]
+pub struct WasmTable {
+    table: Table,
+}
+
+#[wasm_bindgen]
+impl WasmTable {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Result<WasmTable, JsError> {
+        let table = Table::open_with(MemoryStorage::new()).map_err(js_error)?;
+        Ok(WasmTable { table })
+    }
+
+    /// Run one statement. Returns the rows as `{id, name, email}` objects,
+    /// or the number of rows for `count`.
+    pub fn exec(&mut self, statement: &str) -> Result<JsValue, JsError> {
+        let statement = prepare_statement(statement).map_err(js_error)?;
+        let rows = Array::new();
+        let result = statement
+            .execute_streaming(&mut self.table, &mut |row| {
+                let object = Object::new();
+                // Ids past 2^53 lose precision as JavaScript numbers
+                for (key, value) in [
+                    ("id", JsValue::from(row.id() as f64)),
+                    ("name", JsValue::from(row.name_str())),
+                    ("email", JsValue::from(row.email_str())),
+                ] {
+                    Reflect::set(&object, &key.into(), &value).unwrap();
+                }
+                rows.push(&object);
+                Ok(())
+            })
+            .map_err(js_error)?;
+        Ok(match result.count {
+            Some(count) => JsValue::from(count as f64),
+            None => rows.into(),
+        })
+    }
+}
+
+fn js_error(e: SqlError) -> JsError {
+    JsError::new(&format!("{} [{}]", e, e.code()))
+}
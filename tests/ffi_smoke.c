@@ -0,0 +1,57 @@
+/* Drives the C interface the way an application would. Returns 0, or 1 after
+   printing the first unexpected result to stderr. */
+#include <stdio.h>
+#include <string.h>
+
+#include "minisql.h"
+
+#define CHECK(call)                                                                   \
+    do {                                                                              \
+        int rc = (call);                                                              \
+        if (rc != MINISQL_OK) {                                                       \
+            fprintf(stderr, "%s: %d %s\n", #call, rc, minisql_errmsg());              \
+            return 1;                                                                 \
+        }                                                                             \
+    } while (0)
+
+static const char *names[] = {"alice", "bob"};
+
+static int check_row(void *ctx, int64_t id, const char *name, const char *email) {
+    int *rows = ctx;
+    if (*rows >= 2 || id != *rows + 1 || strcmp(name, names[*rows]) != 0 ||
+        strstr(email, name) != email) {
+        fprintf(stderr, "unexpected row %lld %s %s\n", (long long)id, name, email);
+        return 1;
+    }
+    (*rows)++;
+    return 0;
+}
+
+int minisql_smoke(const char *path) {
+    MinisqlDb *db;
+    CHECK(minisql_open(path, &db));
+    CHECK(minisql_exec(db, "insert 1 alice alice@example.com", NULL, NULL, NULL));
+    CHECK(minisql_exec(db, "insert 2 bob bob@example.com", NULL, NULL, NULL));
+
+    int rows = 0;
+    int64_t count = 0;
+    CHECK(minisql_exec(db, "select", check_row, &rows, &count));
+    if (rows != 2 || count != -1) {
+        fprintf(stderr, "select: %d rows, count %lld\n", rows, (long long)count);
+        return 1;
+    }
+    CHECK(minisql_exec(db, "count", NULL, NULL, &count));
+    if (count != 2) {
+        fprintf(stderr, "count: %lld\n", (long long)count);
+        return 1;
+    }
+
+    if (minisql_exec(db, "frobnicate", NULL, NULL, NULL) != MINISQL_ERROR ||
+        strlen(minisql_errmsg()) == 0 ||
+        strcmp(minisql_errcode(), "MINISQL_UNKNOWN_COMMAND") != 0) {
+        fprintf(stderr, "bad statement was not reported\n");
+        return 1;
+    }
+    CHECK(minisql_close(db));
+    return 0;
+}
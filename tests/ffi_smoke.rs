@@ -0,0 +1,19 @@
+//! Run tests/ffi_smoke.c, compiled against include/minisql.h by build.rs
+#![cfg(feature = "ffi")]
+
+use std::ffi::{c_char, c_int, CString};
+
+extern "C" {
+    fn minisql_smoke(path: *const c_char) -> c_int;
+}
+
+#[test]
+fn c_smoke() {
+    let dir = std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join("ffi_smoke");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = CString::new(dir.join("smoke.db").to_str().unwrap()).unwrap();
+    // Use the library so that it is linked in for the C code to call
+    let _ = minisql::ffi::minisql_errmsg;
+    assert_eq!(unsafe { minisql_smoke(path.as_ptr()) }, 0);
+}
@@ -0,0 +1,34 @@
+//! WasmTable through the interface JavaScript sees, run with
+//! wasm-bindgen-test-runner:
+//! `cargo test --target wasm32-unknown-unknown --no-default-features --features wasm --test wasm`
+#![cfg(all(feature = "wasm", target_arch = "wasm32"))]
+
+use js_sys::{Array, Reflect};
+use minisql::wasm::WasmTable;
+use wasm_bindgen::JsValue;
+use wasm_bindgen_test::wasm_bindgen_test;
+
+fn field(row: &JsValue, key: &str) -> JsValue {
+    Reflect::get(row, &key.into()).unwrap()
+}
+
+#[wasm_bindgen_test]
+fn insert_select() {
+    let mut table = WasmTable::new().unwrap();
+    for i in 1..=3 {
+        let rows = table
+            .exec(&format!("insert {} name{} {}@a", i, i, i))
+            .unwrap();
+        assert_eq!(Array::from(&rows).length(), 1);
+    }
+    let rows = Array::from(&table.exec("select 2").unwrap());
+    assert_eq!(rows.length(), 1);
+    let row = rows.get(0);
+    assert_eq!(field(&row, "id").as_f64(), Some(2.0));
+    assert_eq!(field(&row, "name").as_string().as_deref(), Some("name2"));
+    assert_eq!(field(&row, "email").as_string().as_deref(), Some("2@a"));
+
+    assert_eq!(Array::from(&table.exec("select").unwrap()).length(), 3);
+    assert_eq!(table.exec("count").unwrap().as_f64(), Some(3.0));
+    assert!(table.exec("select x").is_err());
+}